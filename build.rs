@@ -0,0 +1,48 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+///
+/// With the "core" feature, compile src/bpf/filters.bpf.c into a BPF object embedded in the
+/// binary. The vmlinux.h of the build host is enough: libbpf relocates the object with the BTF of
+/// the kernel it is loaded on.
+///
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/bpf/filters.bpf.c");
+    println!("cargo:rerun-if-changed=src/bpf/records.h");
+
+    if env::var_os("CARGO_FEATURE_CORE").is_none() {
+        return;
+    }
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let arch = match env::var("CARGO_CFG_TARGET_ARCH").unwrap().as_str() {
+        "x86_64" => "x86",
+        "aarch64" => "arm64",
+        "powerpc64" => "powerpc",
+        "s390x" => "s390",
+        other => other,
+    }.to_string();
+
+    let vmlinux = Command::new("bpftool")
+        .args(&["btf", "dump", "file", "/sys/kernel/btf/vmlinux", "format", "c"])
+        .output()
+        .expect("bpftool is needed to build the CO-RE backend");
+    if !vmlinux.status.success() {
+        panic!("bpftool btf dump: {}", String::from_utf8_lossy(&vmlinux.stderr));
+    }
+    fs::write(out_dir.join("vmlinux.h"), &vmlinux.stdout).unwrap();
+
+    let status = Command::new("clang")
+        .args(&["-g", "-O2", "-target", "bpf", &format!("-D__TARGET_ARCH_{}", arch), "-I"])
+        .arg(&out_dir)
+        .args(&["-c", "src/bpf/filters.bpf.c", "-o"])
+        .arg(out_dir.join("filters.bpf.o"))
+        .status()
+        .expect("clang is needed to build the CO-RE backend");
+    if !status.success() {
+        panic!("failed to compile src/bpf/filters.bpf.c");
+    }
+}
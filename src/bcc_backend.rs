@@ -0,0 +1,320 @@
+use bcc::{BPF, Kprobe, Kretprobe, Tracepoint, table::Table};
+
+use std::error::Error;
+use std::fs;
+use std::convert::TryInto;
+use std::time::Duration;
+
+use crate::net::{self, SharedProcs};
+use crate::capture::{CaptureBackend, Options};
+use crate::replay::recording;
+
+// Value of the "generation" map of filters.c which stops the probes from adding up the traffic
+const GENERATION_STOPPED: u32 = 0xffffffff;
+
+///
+/// Capture with bcc: filters.c is compiled against the headers of the running kernel when the
+/// capture starts. Needs clang and the kernel headers on the host.
+///
+pub struct BccBackend {
+    ringbuf: bool, // A single ring buffer for all the probes, kernels >= 5.8
+    filters: Option<BPF>,
+    lost: Option<Table>,
+    traffic: Option<Table>,
+    generation: Option<Table>, // Of the entries of "traffic" the probes add to, see filters.c
+    current: u16,
+    procs: Option<SharedProcs>,
+}
+
+impl BccBackend {
+    pub fn new() -> Self {
+        let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+
+        BccBackend {
+            ringbuf: net::has_ringbuf(&release),
+            filters: None,
+            lost: None,
+            traffic: None,
+            generation: None,
+            current: 0,
+            procs: None,
+        }
+    }
+
+    ///
+    /// Write 'value' to the "generation" map of filters.c. Returns whether it was written.
+    ///
+    fn set_generation(&mut self, value: u32) -> bool {
+        match self.generation.as_mut() {
+            Some(generation) => {
+                generation.set(&mut 0u32.to_ne_bytes(), &mut value.to_ne_bytes()).is_ok()
+            },
+            None => false,
+        }
+    }
+
+    ///
+    /// Account and empty the entries of "traffic" the probes no longer add to, when they are in
+    /// the generation 'current', all of them without it. See net::flow_drained.
+    ///
+    fn drain(&mut self, current: Option<u16>) {
+        let (traffic, procs) = match (self.traffic.as_mut(), self.procs.as_ref()) {
+            (Some(traffic), Some(procs)) => (traffic, procs),
+            _ => return,
+        };
+
+        let entries: Vec<_> = traffic.iter()
+            .filter(|entry| net::flow_drained(&entry.key, current))
+            .collect();
+
+        for mut entry in entries {
+            net::account_flow(procs, &entry.key, &entry.value);
+            let _ = traffic.delete(&mut entry.key);
+        }
+    }
+}
+
+impl CaptureBackend for BccBackend {
+    fn description(&self, options: &Options) -> String {
+        format!(
+            "bcc, {}{}",
+            if self.ringbuf { "ring buffer" } else { "perf buffers" },
+            if options.aggregate { ", traffic added up in the kernel" } else { "" },
+        )
+    }
+
+    fn start(&mut self, options: &Options, procs: SharedProcs) -> Result<(), Box<dyn Error>> {
+        let mut code = String::new();
+
+        // See filters.c
+        if self.ringbuf {
+            code.push_str("#define USE_RINGBUF\n");
+        }
+        if options.aggregate {
+            code.push_str("#define AGGREGATE\n");
+        }
+        // bcc compiles the text alone, without the directory of filters.c to include from
+        code.push_str(
+            &include_str!("bpf/filters.c")
+                .replace("#include \"records.h\"", include_str!("bpf/records.h")),
+        );
+
+        // Without any packet sent to user space, only the TCP connection events are
+        let per_packet = !self.ringbuf && !options.aggregate;
+
+        let mut filters = BPF::new(&code)?;
+        let cb = || callback(&procs, options);
+
+        // TCP probes
+        if options.tcp {
+            Kprobe::new()
+                .handler("kprobe__tcp_sendmsg")
+                .function("tcp_sendmsg")
+                .attach(&mut filters)?;
+            Kprobe::new()
+                .handler("kprobe__tcp_cleanup_rbuf")
+                .function("tcp_cleanup_rbuf")
+                .attach(&mut filters)?;
+
+            // Connection lifecycle
+            Kprobe::new()
+                .handler("kprobe__tcp_connect")
+                .function("tcp_connect")
+                .attach(&mut filters)?;
+            Kretprobe::new()
+                .handler("kretprobe__inet_csk_accept")
+                .function("inet_csk_accept")
+                .attach(&mut filters)?;
+            Kprobe::new()
+                .handler("kprobe__tcp_close")
+                .function("tcp_close")
+                .attach(&mut filters)?;
+            Tracepoint::new()
+                .handler("tracepoint__sock__inet_sock_set_state")
+                .subsystem("sock")
+                .tracepoint("inet_sock_set_state")
+                .attach(&mut filters)?;
+
+            // The DNS responses over TCP
+            Kprobe::new()
+                .handler("kprobe__tcp_recvmsg")
+                .function("tcp_recvmsg")
+                .attach(&mut filters)?;
+            Kretprobe::new()
+                .handler("kretprobe__tcp_recvmsg")
+                .function("tcp_recvmsg")
+                .attach(&mut filters)?;
+
+            if per_packet {
+                let tcp4_table = filters.table("tcp4_data")?;
+                let tcp6_table = filters.table("tcp6_data")?;
+                filters.init_perf_map(tcp4_table, cb())?;
+                filters.init_perf_map(tcp6_table, cb())?;
+            }
+
+            if !self.ringbuf {
+                let tcp4_events = filters.table("tcp4_events")?;
+                let tcp6_events = filters.table("tcp6_events")?;
+                filters.init_perf_map(tcp4_events, cb())?;
+                filters.init_perf_map(tcp6_events, cb())?;
+            }
+        }
+
+        // UDP probes
+        if options.udp {
+            Kprobe::new()
+                .handler("kprobe__udp_sendmsg")
+                .function("udp_sendmsg")
+                .attach(&mut filters)?;
+            Kprobe::new()
+                .handler("kprobe__udpv6_sendmsg")
+                .function("udpv6_sendmsg")
+                .attach(&mut filters)?;
+
+            // The sender of a datagram is only known once received
+            for function in ["udp_recvmsg", "udpv6_recvmsg"] {
+                Kprobe::new()
+                    .handler(&format!("kprobe__{}", function))
+                    .function(function)
+                    .attach(&mut filters)?;
+                Kretprobe::new()
+                    .handler(&format!("kretprobe__{}", function))
+                    .function(function)
+                    .attach(&mut filters)?;
+            }
+
+            if per_packet {
+                let udp4_table = filters.table("udp4_data")?;
+                let udp6_table = filters.table("udp6_data")?;
+                filters.init_perf_map(udp4_table, cb())?;
+                filters.init_perf_map(udp6_table, cb())?;
+            }
+        }
+
+        // The first bytes sent on the links and the DNS responses, even when the traffic is added
+        // up
+        if (options.tcp || options.udp) && !self.ringbuf {
+            let payload_table = filters.table("payload_data")?;
+            let dns_table = filters.table("dns_data")?;
+            filters.init_perf_map(payload_table, cb())?;
+            filters.init_perf_map(dns_table, cb())?;
+        }
+
+        // ICMP probes, ping sockets and raw sockets
+        if options.icmp {
+            Kprobe::new()
+                .handler("kprobe__ping_v4_sendmsg")
+                .function("ping_v4_sendmsg")
+                .attach(&mut filters)?;
+            Kprobe::new()
+                .handler("kprobe__ping_v6_sendmsg")
+                .function("ping_v6_sendmsg")
+                .attach(&mut filters)?;
+            Kprobe::new()
+                .handler("kprobe__raw_sendmsg")
+                .function("raw_sendmsg")
+                .attach(&mut filters)?;
+            Kprobe::new()
+                .handler("kprobe__rawv6_sendmsg")
+                .function("rawv6_sendmsg")
+                .attach(&mut filters)?;
+
+            // The messages are only known once copied to the user buffer
+            for function in ["ping_recvmsg", "raw_recvmsg", "rawv6_recvmsg"] {
+                Kprobe::new()
+                    .handler(&format!("kprobe__{}", function))
+                    .function(function)
+                    .attach(&mut filters)?;
+                Kretprobe::new()
+                    .handler(&format!("kretprobe__{}", function))
+                    .function(function)
+                    .attach(&mut filters)?;
+            }
+
+            if per_packet {
+                let icmp4_table = filters.table("icmp4_data")?;
+                let icmp6_table = filters.table("icmp6_data")?;
+                filters.init_perf_map(icmp4_table, cb())?;
+                filters.init_perf_map(icmp6_table, cb())?;
+            }
+        }
+
+        // The programs executed and the processes exiting, whatever the protocols
+        Tracepoint::new()
+            .handler("tracepoint__sched__sched_process_exec")
+            .subsystem("sched")
+            .tracepoint("sched_process_exec")
+            .attach(&mut filters)?;
+        Tracepoint::new()
+            .handler("tracepoint__sched__sched_process_exit")
+            .subsystem("sched")
+            .tracepoint("sched_process_exit")
+            .attach(&mut filters)?;
+        if !self.ringbuf {
+            let exec_table = filters.table("exec_data")?;
+            let exit_table = filters.table("exit_data")?;
+            filters.init_perf_map(exec_table, cb())?;
+            filters.init_perf_map(exit_table, cb())?;
+        }
+
+        if self.ringbuf {
+            let events = filters.table("events")?;
+            filters.init_ring_buf(events, cb())?;
+        }
+
+        self.lost = Some(filters.table("lost")?);
+        if options.aggregate {
+            self.traffic = Some(filters.table("traffic")?);
+            self.generation = Some(filters.table("generation")?);
+        }
+        self.filters = Some(filters);
+        self.procs = Some(procs);
+
+        Ok(())
+    }
+
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        let filters = self.filters.as_mut().unwrap();
+        let timeout = timeout.as_millis() as i32;
+
+        if self.ringbuf {
+            filters.ring_buf_poll(timeout);
+        } else {
+            filters.perf_map_poll(timeout);
+        }
+
+        Ok(())
+    }
+
+    fn drain_traffic(&mut self) {
+        // The probes move to a new generation, the entries they may still be adding to are kept
+        self.current = self.current.wrapping_add(1);
+        if self.set_generation(self.current as u32) {
+            self.drain(Some(self.current));
+        }
+    }
+
+    fn stop(&mut self) {
+        // The probes stay attached as long as 'filters' lives, but account nothing more
+        if self.set_generation(GENERATION_STOPPED) {
+            self.drain(None);
+        }
+    }
+
+    fn lost_events(&mut self) -> Option<u64> {
+        let count = self.lost.as_mut()?.get(&mut 0u32.to_ne_bytes()).ok()?;
+
+        Some(u64::from_ne_bytes(count[..8].try_into().unwrap()))
+    }
+}
+
+///
+/// Factory of the callbacks of a perf map or of the ring buffer, as bcc wants them. Every record
+/// has its header, see filters.c. The records are also recorded when asked in 'options'.
+///
+fn callback(procs: &SharedProcs, options: &Options) -> impl Fn() -> Box<dyn FnMut(&[u8]) + Send> {
+    let procs = procs.clone();
+    let recorder = options.recorder.clone();
+
+    move || recording(&recorder, net::record_cb(procs.clone()))
+}
@@ -0,0 +1,955 @@
+/*
+ * CO-RE version of filters.c, compiled once with clang (see build.rs) and loaded with libbpf on
+ * kernels with BTF: no compiler nor kernel headers are needed where netw runs.
+ *
+ * The records are the ones of filters.c, sent through the ring buffer "events". Adding the
+ * traffic up in the kernel is switched on by user space in "config" once loaded.
+ */
+
+#include "vmlinux.h"
+#include <bpf/bpf_helpers.h>
+#include <bpf/bpf_tracing.h>
+#include <bpf/bpf_core_read.h>
+#include <bpf/bpf_endian.h>
+
+#define AF_INET         2
+#define AF_INET6        10
+#define IPPROTO_ICMP    1
+#define IPPROTO_ICMPV6  58
+
+#define SOCKET_PING 0
+#define SOCKET_RAW  1
+
+#define DNS_PORT    53
+
+#define TASK_COMM_LEN 16
+
+#include "records.h"
+
+#define CONFIG_AGGREGATE  0
+#define CONFIG_GENERATION 1
+
+char LICENSE[] SEC("license") = "GPL";
+
+struct {
+    __uint(type, BPF_MAP_TYPE_RINGBUF);
+    __uint(max_entries, 256 * 4096);
+} events SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, u32);
+    __type(value, u64);
+} lost SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_ARRAY);
+    __uint(max_entries, 2);
+    __type(key, u32);
+    __type(value, u32);
+} config SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 65536);
+    __type(key, struct flow_t);
+    __type(value, struct bytes_t);
+} traffic SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, u32);
+    __type(value, struct dns_record_t);
+} dns_records SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, u32);
+    __type(value, struct exec_record_t);
+} exec_records SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_PERCPU_ARRAY);
+    __uint(max_entries, 1);
+    __type(key, u32);
+    __type(value, struct payload_record_t);
+} payload_records SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 65536);
+    __type(key, u64);
+    __type(value, u8);
+} first_send SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_LRU_HASH);
+    __uint(max_entries, 65536);
+    __type(key, struct link_t);
+    __type(value, u8);
+} udp_peeked SEC(".maps");
+
+/*
+ * Arguments of the recvmsg() calls, only useful once they return.
+ */
+struct recv_t {
+    struct sock *sk;
+    struct msghdr *msg;
+    void *buffer;
+    u32 socket;
+};
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 10240);
+    __type(key, u64);
+    __type(value, struct recv_t);
+} udp_recv SEC(".maps");
+
+// Reads of the TCP connections to port 53, see filters.c
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 10240);
+    __type(key, u64);
+    __type(value, struct recv_t);
+} tcp_recv SEC(".maps");
+
+struct {
+    __uint(type, BPF_MAP_TYPE_HASH);
+    __uint(max_entries, 10240);
+    __type(key, u64);
+    __type(value, struct recv_t);
+} icmp_recv SEC(".maps");
+
+/*
+ * Transport
+ */
+
+static __always_inline void count_lost(void)
+{
+    u32 zero = 0;
+    u64 *count = bpf_map_lookup_elem(&lost, &zero);
+
+    if (count)
+        __sync_fetch_and_add(count, 1);
+}
+
+static __always_inline int aggregate(void)
+{
+    u32 key = CONFIG_AGGREGATE;
+    u32 *value = bpf_map_lookup_elem(&config, &key);
+
+    return value && *value;
+}
+
+#define SUBMIT(record_kind, value) ({                                      \
+        struct { struct header_t header; typeof(value) data; } record = {   \
+            .header = {                                                     \
+                .kind = record_kind,                                        \
+                .version = RECORD_VERSION,                                  \
+                .length = sizeof(value),                                    \
+            },                                                              \
+            .data = value                                                   \
+        };                                                                  \
+        if (bpf_ringbuf_output(&events, &record, sizeof(record), 0) < 0)    \
+            count_lost();                                                   \
+    })
+
+// Keyed by the generation of the reads of user space too, see filters.c
+static __always_inline void account(struct flow_t *flow, u32 size, u32 is_rx)
+{
+    u32 slot = CONFIG_GENERATION;
+    u32 *current = bpf_map_lookup_elem(&config, &slot);
+    struct bytes_t zero = {};
+    struct bytes_t *bytes;
+
+    flow->generation = current ? *current : 0;
+    bytes = bpf_map_lookup_elem(&traffic, flow);
+
+    if (!bytes) {
+        bpf_map_update_elem(&traffic, flow, &zero, BPF_NOEXIST);
+        bytes = bpf_map_lookup_elem(&traffic, flow);
+    }
+
+    // Map full until the next read
+    if (!bytes) {
+        count_lost();
+        return;
+    }
+
+    if (is_rx)
+        __sync_fetch_and_add(&bytes->rx, size);
+    else
+        __sync_fetch_and_add(&bytes->tx, size);
+}
+
+static __always_inline void account4(u32 kind, struct ipv4_data_t *data)
+{
+    if (aggregate()) {
+        struct flow_t flow = {
+            .saddr = data->saddr, .daddr = data->daddr, .kind = kind, .pid = data->pid,
+            .lport = data->lport, .dport = data->dport,
+        };
+
+        __builtin_memcpy(flow.comm, data->comm, sizeof(flow.comm));
+        account(&flow, data->size, data->is_rx);
+    } else {
+        SUBMIT(kind, *data);
+    }
+}
+
+static __always_inline void account6(u32 kind, struct ipv6_data_t *data)
+{
+    if (aggregate()) {
+        struct flow_t flow = {
+            .saddr = data->saddr, .daddr = data->daddr, .kind = kind, .pid = data->pid,
+            .lport = data->lport, .dport = data->dport,
+        };
+
+        __builtin_memcpy(flow.comm, data->comm, sizeof(flow.comm));
+        account(&flow, data->size, data->is_rx);
+    } else {
+        SUBMIT(kind, *data);
+    }
+}
+
+// The identifier of the ICMP messages as the local port, their type and code apart
+static __always_inline void account_icmp4(u32 kind, struct icmp4_data_t *data)
+{
+    if (aggregate()) {
+        struct flow_t flow = {
+            .saddr = data->saddr, .daddr = data->daddr, .kind = kind, .pid = data->pid,
+            .lport = data->id, .icmp_type = data->type, .icmp_code = data->code,
+        };
+
+        __builtin_memcpy(flow.comm, data->comm, sizeof(flow.comm));
+        account(&flow, data->size, data->is_rx);
+    } else {
+        SUBMIT(kind, *data);
+    }
+}
+
+static __always_inline void account_icmp6(u32 kind, struct icmp6_data_t *data)
+{
+    if (aggregate()) {
+        struct flow_t flow = {
+            .saddr = data->saddr, .daddr = data->daddr, .kind = kind, .pid = data->pid,
+            .lport = data->id, .icmp_type = data->type, .icmp_code = data->code,
+        };
+
+        __builtin_memcpy(flow.comm, data->comm, sizeof(flow.comm));
+        account(&flow, data->size, data->is_rx);
+    } else {
+        SUBMIT(kind, *data);
+    }
+}
+
+/*
+ * Socket addresses
+ */
+
+static __always_inline void read_addrs4(struct sock *sk, u32 *saddr, u32 *daddr)
+{
+    *saddr = BPF_CORE_READ(sk, __sk_common.skc_rcv_saddr);
+    *daddr = BPF_CORE_READ(sk, __sk_common.skc_daddr);
+}
+
+static __always_inline void read_addrs6(struct sock *sk, unsigned __int128 *saddr,
+    unsigned __int128 *daddr)
+{
+    BPF_CORE_READ_INTO(saddr, sk, __sk_common.skc_v6_rcv_saddr.in6_u.u6_addr32);
+    BPF_CORE_READ_INTO(daddr, sk, __sk_common.skc_v6_daddr.in6_u.u6_addr32);
+}
+
+/*
+ * Messages
+ */
+
+// Layouts of struct iov_iter before 6.4, when 'iov' became '__iov'
+struct iov_iter___old {
+    const struct iovec *iov;
+} __attribute__((preserve_access_index));
+
+static __always_inline void *user_buffer(struct msghdr *msg)
+{
+    struct iov_iter *iter = &msg->msg_iter;
+    const struct iovec *iov;
+
+    if (bpf_core_enum_value_exists(enum iter_type, ITER_UBUF)
+        && BPF_CORE_READ(iter, iter_type) == bpf_core_enum_value(enum iter_type, ITER_UBUF))
+        return BPF_CORE_READ(iter, ubuf);
+
+    if (bpf_core_field_exists(iter->__iov))
+        iov = BPF_CORE_READ(iter, __iov);
+    else
+        iov = BPF_CORE_READ((struct iov_iter___old *)iter, iov);
+
+    return BPF_CORE_READ(iov, iov_base);
+}
+
+/*
+ * First bytes sent on the TCP connections opened and to each peer of the UDP sockets, see
+ * filters.c
+ */
+static __always_inline void submit_payload(u32 kind, struct link_t *link, void *buffer, u32 size)
+{
+    u32 zero = 0;
+    struct payload_record_t *record = bpf_map_lookup_elem(&payload_records, &zero);
+
+    if (!record || !buffer)
+        return;
+
+    if (size > PEEK_PAYLOAD)
+        size = PEEK_PAYLOAD;
+
+    record->header.kind = kind;
+    record->header.version = RECORD_VERSION;
+    record->header.length = sizeof(record->data);
+    record->data.saddr = link->saddr;
+    record->data.daddr = link->daddr;
+    record->data.pid = link->pid;
+    record->data.lport = link->lport;
+    record->data.dport = link->dport;
+    record->data.family = link->family;
+    record->data.size = size;
+    bpf_get_current_comm(&record->data.comm, sizeof(record->data.comm));
+
+    if (bpf_probe_read_user(record->data.payload, size, buffer) < 0)
+        return;
+
+    if (bpf_ringbuf_output(&events, record, sizeof(*record), 0) < 0)
+        count_lost();
+}
+
+static __always_inline void peek_tcp(struct sock *sk, struct msghdr *msg, u32 size)
+{
+    u64 key = (u64)sk;
+    struct link_t link = {.pid = bpf_get_current_pid_tgid() >> 32};
+
+    if (!bpf_map_lookup_elem(&first_send, &key))
+        return;
+    bpf_map_delete_elem(&first_send, &key);
+
+    link.family = BPF_CORE_READ(sk, __sk_common.skc_family);
+    link.lport = BPF_CORE_READ(sk, __sk_common.skc_num);
+    link.dport = bpf_ntohs(BPF_CORE_READ(sk, __sk_common.skc_dport));
+
+    if (link.family == AF_INET) {
+        u32 saddr, daddr;
+
+        read_addrs4(sk, &saddr, &daddr);
+        link.saddr = saddr;
+        link.daddr = daddr;
+    } else {
+        read_addrs6(sk, &link.saddr, &link.daddr);
+    }
+
+    submit_payload(RECORD_TCP_PAYLOAD, &link, user_buffer(msg), size);
+}
+
+static __always_inline void peek_udp(struct link_t *link, struct msghdr *msg, u32 size)
+{
+    u8 yes = 1;
+
+    if (bpf_map_lookup_elem(&udp_peeked, link))
+        return;
+    bpf_map_update_elem(&udp_peeked, link, &yes, BPF_ANY);
+
+    submit_payload(RECORD_UDP_PAYLOAD, link, user_buffer(msg), size);
+}
+
+/*
+ * TCP traffic
+ */
+
+static __always_inline int submit_tcp(struct sock *sk, u32 size, u32 is_rx)
+{
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+    u16 family = BPF_CORE_READ(sk, __sk_common.skc_family);
+    u16 lport = BPF_CORE_READ(sk, __sk_common.skc_num);
+    u16 dport = bpf_ntohs(BPF_CORE_READ(sk, __sk_common.skc_dport));
+
+    if (family == AF_INET) {
+        struct ipv4_data_t tcp4 = {
+            .pid = pid, .lport = lport, .dport = dport, .size = size, .is_rx = is_rx
+        };
+
+        bpf_get_current_comm(&tcp4.comm, sizeof(tcp4.comm));
+        read_addrs4(sk, &tcp4.saddr, &tcp4.daddr);
+        account4(RECORD_TCP4, &tcp4);
+
+    } else if (family == AF_INET6) {
+        struct ipv6_data_t tcp6 = {
+            .pid = pid, .lport = lport, .dport = dport, .size = size, .is_rx = is_rx
+        };
+
+        bpf_get_current_comm(&tcp6.comm, sizeof(tcp6.comm));
+        read_addrs6(sk, &tcp6.saddr, &tcp6.daddr);
+        account6(RECORD_TCP6, &tcp6);
+    }
+    // else drop
+
+    return 0;
+}
+
+SEC("kprobe/tcp_sendmsg")
+int BPF_KPROBE(tcp_sendmsg, struct sock *sk, struct msghdr *msg, size_t size)
+{
+    peek_tcp(sk, msg, size);
+
+    return submit_tcp(sk, size, 0);
+}
+
+SEC("kprobe/tcp_cleanup_rbuf")
+int BPF_KPROBE(tcp_cleanup_rbuf, struct sock *sk, int copied)
+{
+    if (copied <= 0)
+        return 0;
+
+    return submit_tcp(sk, copied, 1);
+}
+
+/*
+ * TCP connection lifecycle, and the other changes of state of the sockets
+ */
+
+static __always_inline int submit_event(struct sock *sk, u32 event)
+{
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+    u16 family = BPF_CORE_READ(sk, __sk_common.skc_family);
+    u16 lport = BPF_CORE_READ(sk, __sk_common.skc_num);
+    u16 dport = BPF_CORE_READ(sk, __sk_common.skc_dport);
+    u32 state = BPF_CORE_READ(sk, __sk_common.skc_state);
+
+    // Listening or never connected socket, not a link
+    if (dport == 0)
+        return 0;
+
+    if (family == AF_INET) {
+        struct ipv4_event_t tcp4 = {
+            .pid = pid, .lport = lport, .dport = bpf_ntohs(dport), .event = event,
+            .state = state
+        };
+
+        bpf_get_current_comm(&tcp4.comm, sizeof(tcp4.comm));
+        read_addrs4(sk, &tcp4.saddr, &tcp4.daddr);
+        SUBMIT(RECORD_TCP4_EVENT, tcp4);
+
+    } else if (family == AF_INET6) {
+        struct ipv6_event_t tcp6 = {
+            .pid = pid, .lport = lport, .dport = bpf_ntohs(dport), .event = event,
+            .state = state
+        };
+
+        bpf_get_current_comm(&tcp6.comm, sizeof(tcp6.comm));
+        read_addrs6(sk, &tcp6.saddr, &tcp6.daddr);
+        SUBMIT(RECORD_TCP6_EVENT, tcp6);
+    }
+    // else drop
+
+    return 0;
+}
+
+SEC("kprobe/tcp_connect")
+int BPF_KPROBE(tcp_connect, struct sock *sk)
+{
+    u64 key = (u64)sk;
+    u8 yes = 1;
+
+    bpf_map_update_elem(&first_send, &key, &yes, BPF_ANY);
+
+    return submit_event(sk, EVENT_CONNECT);
+}
+
+SEC("kretprobe/inet_csk_accept")
+int BPF_KRETPROBE(inet_csk_accept_ret, struct sock *sk)
+{
+    if (!sk)
+        return 0;
+
+    return submit_event(sk, EVENT_ACCEPT);
+}
+
+SEC("kprobe/tcp_close")
+int BPF_KPROBE(tcp_close, struct sock *sk)
+{
+    u64 key = (u64)sk;
+
+    bpf_map_delete_elem(&first_send, &key);
+
+    return submit_event(sk, EVENT_CLOSE);
+}
+
+SEC("tracepoint/sock/inet_sock_set_state")
+int inet_sock_set_state(struct trace_event_raw_inet_sock_set_state *ctx)
+{
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+
+    // Listening or never connected socket, not a link
+    if (ctx->protocol != IPPROTO_TCP || ctx->dport == 0)
+        return 0;
+
+    if (ctx->family == AF_INET) {
+        struct ipv4_event_t tcp4 = {
+            .pid = pid, .lport = ctx->sport, .dport = ctx->dport, .event = EVENT_STATE,
+            .state = ctx->newstate
+        };
+
+        bpf_get_current_comm(&tcp4.comm, sizeof(tcp4.comm));
+        __builtin_memcpy(&tcp4.saddr, ctx->saddr, sizeof(tcp4.saddr));
+        __builtin_memcpy(&tcp4.daddr, ctx->daddr, sizeof(tcp4.daddr));
+        SUBMIT(RECORD_TCP4_EVENT, tcp4);
+
+    } else if (ctx->family == AF_INET6) {
+        struct ipv6_event_t tcp6 = {
+            .pid = pid, .lport = ctx->sport, .dport = ctx->dport, .event = EVENT_STATE,
+            .state = ctx->newstate
+        };
+
+        bpf_get_current_comm(&tcp6.comm, sizeof(tcp6.comm));
+        __builtin_memcpy(&tcp6.saddr, ctx->saddr_v6, sizeof(tcp6.saddr));
+        __builtin_memcpy(&tcp6.daddr, ctx->daddr_v6, sizeof(tcp6.daddr));
+        SUBMIT(RECORD_TCP6_EVENT, tcp6);
+    }
+
+    return 0;
+}
+
+/*
+ * UDP traffic, the peer of unconnected sockets is in 'msg_name'
+ */
+
+static __always_inline int submit_udp(struct sock *sk, struct msghdr *msg, u32 size, u32 is_rx,
+    u16 family)
+{
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+    u16 lport = BPF_CORE_READ(sk, __sk_common.skc_num);
+    u16 dport = BPF_CORE_READ(sk, __sk_common.skc_dport);
+    u16 name_family = 0;
+    void *name = BPF_CORE_READ(msg, msg_name);
+
+    if (name)
+        bpf_probe_read_kernel(&name_family, sizeof(name_family), name);
+
+    if (family == AF_INET) {
+        struct ipv4_data_t udp4 = {.pid = pid, .lport = lport, .size = size, .is_rx = is_rx};
+
+        bpf_get_current_comm(&udp4.comm, sizeof(udp4.comm));
+        read_addrs4(sk, &udp4.saddr, &udp4.daddr);
+        if (name_family == AF_INET) {
+            struct sockaddr_in *sin = name;
+
+            udp4.daddr = BPF_CORE_READ(sin, sin_addr.s_addr);
+            dport = BPF_CORE_READ(sin, sin_port);
+        }
+        udp4.dport = bpf_ntohs(dport);
+
+        account4(RECORD_UDP4, &udp4);
+
+        if (!is_rx) {
+            struct link_t link = {
+                .saddr = udp4.saddr, .daddr = udp4.daddr, .pid = pid, .lport = lport,
+                .dport = udp4.dport, .family = AF_INET,
+            };
+            peek_udp(&link, msg, size);
+        }
+
+    } else if (family == AF_INET6) {
+        struct ipv6_data_t udp6 = {.pid = pid, .lport = lport, .size = size, .is_rx = is_rx};
+
+        bpf_get_current_comm(&udp6.comm, sizeof(udp6.comm));
+        read_addrs6(sk, &udp6.saddr, &udp6.daddr);
+        if (name_family == AF_INET6) {
+            struct sockaddr_in6 *sin6 = name;
+
+            BPF_CORE_READ_INTO(&udp6.daddr, sin6, sin6_addr.in6_u.u6_addr32);
+            dport = BPF_CORE_READ(sin6, sin6_port);
+        }
+        udp6.dport = bpf_ntohs(dport);
+
+        account6(RECORD_UDP6, &udp6);
+
+        if (!is_rx) {
+            struct link_t link = {
+                .saddr = udp6.saddr, .daddr = udp6.daddr, .pid = pid, .lport = lport,
+                .dport = udp6.dport, .family = AF_INET6,
+            };
+            peek_udp(&link, msg, size);
+        }
+    }
+    // else drop
+
+    return 0;
+}
+
+/*
+ * IPv4 destinations of IPv6 sockets are accounted by udp_sendmsg()
+ */
+static __always_inline int is_v4_destination(struct sock *sk, struct msghdr *msg)
+{
+    struct in6_addr daddr = {};
+    u16 name_family = 0;
+    void *name = BPF_CORE_READ(msg, msg_name);
+
+    if (name) {
+        bpf_probe_read_kernel(&name_family, sizeof(name_family), name);
+        if (name_family == AF_INET)
+            return 1;
+
+        BPF_CORE_READ_INTO(&daddr, (struct sockaddr_in6 *)name, sin6_addr);
+    } else {
+        BPF_CORE_READ_INTO(&daddr, sk, __sk_common.skc_v6_daddr);
+    }
+
+    // ::ffff:a.b.c.d
+    return daddr.in6_u.u6_addr32[0] == 0 && daddr.in6_u.u6_addr32[1] == 0
+        && daddr.in6_u.u6_addr32[2] == bpf_htonl(0x0000ffff);
+}
+
+SEC("kprobe/udp_sendmsg")
+int BPF_KPROBE(udp_sendmsg, struct sock *sk, struct msghdr *msg, size_t len)
+{
+    return submit_udp(sk, msg, len, 0, AF_INET);
+}
+
+SEC("kprobe/udpv6_sendmsg")
+int BPF_KPROBE(udpv6_sendmsg, struct sock *sk, struct msghdr *msg, size_t len)
+{
+    if (len <= 0 || is_v4_destination(sk, msg))
+        return 0;
+
+    return submit_udp(sk, msg, len, 0, AF_INET6);
+}
+
+static __always_inline int enter_recvmsg(void *recvs, struct sock *sk, struct msghdr *msg,
+    void *buffer, u32 socket)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    struct recv_t recv = {.sk = sk, .msg = msg, .buffer = buffer, .socket = socket};
+
+    bpf_map_update_elem(recvs, &pid_tgid, &recv, BPF_ANY);
+
+    return 0;
+}
+
+SEC("kprobe/udp_recvmsg")
+int BPF_KPROBE(udp_recvmsg, struct sock *sk, struct msghdr *msg)
+{
+    return enter_recvmsg(&udp_recv, sk, msg, user_buffer(msg), 0);
+}
+
+SEC("kprobe/udpv6_recvmsg")
+int BPF_KPROBE(udpv6_recvmsg, struct sock *sk, struct msghdr *msg)
+{
+    return enter_recvmsg(&udp_recv, sk, msg, user_buffer(msg), 0);
+}
+
+/*
+ * Passive DNS: the start of the responses received from port 53, see filters.c
+ */
+static __always_inline u16 peer_port(struct sock *sk, struct msghdr *msg)
+{
+    void *name = BPF_CORE_READ(msg, msg_name);
+
+    if (name)
+        return bpf_ntohs(BPF_CORE_READ((struct sockaddr_in *)name, sin_port));
+
+    return bpf_ntohs(BPF_CORE_READ(sk, __sk_common.skc_dport));
+}
+
+static __always_inline void submit_dns(void *buffer, u32 size, u32 is_tcp)
+{
+    u32 zero = 0;
+    struct dns_record_t *record = bpf_map_lookup_elem(&dns_records, &zero);
+
+    if (!record || !buffer)
+        return;
+
+    if (size > DNS_PAYLOAD)
+        size = DNS_PAYLOAD;
+
+    record->header.kind = RECORD_DNS;
+    record->header.version = RECORD_VERSION;
+    record->header.length = sizeof(record->data);
+    record->data.pid = bpf_get_current_pid_tgid() >> 32;
+    record->data.size = size;
+    record->data.is_tcp = is_tcp;
+    bpf_get_current_comm(&record->data.comm, sizeof(record->data.comm));
+
+    if (bpf_probe_read_user(record->data.payload, size, buffer) < 0)
+        return;
+
+    if (bpf_ringbuf_output(&events, record, sizeof(*record), 0) < 0)
+        count_lost();
+}
+
+SEC("kprobe/tcp_recvmsg")
+int BPF_KPROBE(tcp_recvmsg, struct sock *sk, struct msghdr *msg)
+{
+    if (bpf_ntohs(BPF_CORE_READ(sk, __sk_common.skc_dport)) != DNS_PORT)
+        return 0;
+
+    return enter_recvmsg(&tcp_recv, sk, msg, user_buffer(msg), 0);
+}
+
+SEC("kretprobe/tcp_recvmsg")
+int BPF_KRETPROBE(tcp_recvmsg_ret, int ret)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    struct recv_t *recv = bpf_map_lookup_elem(&tcp_recv, &pid_tgid);
+
+    if (!recv)
+        return 0;
+
+    if (ret > 0)
+        submit_dns(recv->buffer, ret, 1);
+
+    bpf_map_delete_elem(&tcp_recv, &pid_tgid);
+
+    return 0;
+}
+
+static __always_inline int return_udp_recvmsg(int ret)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    struct recv_t *recv = bpf_map_lookup_elem(&udp_recv, &pid_tgid);
+
+    if (!recv)
+        return 0;
+
+    // IPv4 datagrams received by an IPv6 socket come from v4-mapped addresses
+    if (ret > 0) {
+        submit_udp(recv->sk, recv->msg, ret, 1, BPF_CORE_READ(recv->sk, __sk_common.skc_family));
+
+        if (peer_port(recv->sk, recv->msg) == DNS_PORT)
+            submit_dns(recv->buffer, ret, 0);
+    }
+
+    bpf_map_delete_elem(&udp_recv, &pid_tgid);
+
+    return 0;
+}
+
+SEC("kretprobe/udp_recvmsg")
+int BPF_KRETPROBE(udp_recvmsg_ret, int ret)
+{
+    return return_udp_recvmsg(ret);
+}
+
+SEC("kretprobe/udpv6_recvmsg")
+int BPF_KRETPROBE(udpv6_recvmsg_ret, int ret)
+{
+    return return_udp_recvmsg(ret);
+}
+
+/*
+ * ICMP messages of ping and raw sockets, the type and code are read from the user buffer
+ */
+
+static __always_inline int is_icmp_raw(struct sock *sk)
+{
+    u16 protocol = BPF_CORE_READ(sk, __sk_common.skc_num);
+
+    return protocol == IPPROTO_ICMP || protocol == IPPROTO_ICMPV6;
+}
+
+static __always_inline int submit_icmp(struct sock *sk, struct msghdr *msg, void *buffer,
+    u32 socket, u32 size, u32 is_rx)
+{
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+    u16 family = BPF_CORE_READ(sk, __sk_common.skc_family);
+    u16 id = socket == SOCKET_PING ? BPF_CORE_READ(sk, __sk_common.skc_num) : 0;
+    void *name = BPF_CORE_READ(msg, msg_name);
+    u8 header[2] = {};
+
+    if (family == AF_INET) {
+        struct icmp4_data_t icmp4 = {.pid = pid, .id = id, .size = size, .is_rx = is_rx};
+
+        bpf_get_current_comm(&icmp4.comm, sizeof(icmp4.comm));
+        if (socket == SOCKET_RAW && is_rx) {
+            u8 ihl = 0;
+
+            bpf_probe_read_user(&ihl, sizeof(ihl), buffer);
+            buffer += (ihl & 0x0f) * 4;
+        }
+        bpf_probe_read_user(&header, sizeof(header), buffer);
+        icmp4.type = header[0];
+        icmp4.code = header[1];
+
+        read_addrs4(sk, &icmp4.saddr, &icmp4.daddr);
+        if (name)
+            icmp4.daddr = BPF_CORE_READ((struct sockaddr_in *)name, sin_addr.s_addr);
+
+        account_icmp4(RECORD_ICMP4, &icmp4);
+
+    } else if (family == AF_INET6) {
+        struct icmp6_data_t icmp6 = {.pid = pid, .id = id, .size = size, .is_rx = is_rx};
+
+        bpf_get_current_comm(&icmp6.comm, sizeof(icmp6.comm));
+        bpf_probe_read_user(&header, sizeof(header), buffer);
+        icmp6.type = header[0];
+        icmp6.code = header[1];
+
+        read_addrs6(sk, &icmp6.saddr, &icmp6.daddr);
+        if (name)
+            BPF_CORE_READ_INTO(&icmp6.daddr, (struct sockaddr_in6 *)name,
+                sin6_addr.in6_u.u6_addr32);
+
+        account_icmp6(RECORD_ICMP6, &icmp6);
+    }
+    // else drop
+
+    return 0;
+}
+
+SEC("kprobe/ping_v4_sendmsg")
+int BPF_KPROBE(ping_v4_sendmsg, struct sock *sk, struct msghdr *msg, size_t len)
+{
+    return submit_icmp(sk, msg, user_buffer(msg), SOCKET_PING, len, 0);
+}
+
+SEC("kprobe/ping_v6_sendmsg")
+int BPF_KPROBE(ping_v6_sendmsg, struct sock *sk, struct msghdr *msg, size_t len)
+{
+    return submit_icmp(sk, msg, user_buffer(msg), SOCKET_PING, len, 0);
+}
+
+SEC("kprobe/raw_sendmsg")
+int BPF_KPROBE(raw_sendmsg, struct sock *sk, struct msghdr *msg, size_t len)
+{
+    if (!is_icmp_raw(sk))
+        return 0;
+
+    return submit_icmp(sk, msg, user_buffer(msg), SOCKET_RAW, len, 0);
+}
+
+SEC("kprobe/rawv6_sendmsg")
+int BPF_KPROBE(rawv6_sendmsg, struct sock *sk, struct msghdr *msg, size_t len)
+{
+    if (!is_icmp_raw(sk))
+        return 0;
+
+    return submit_icmp(sk, msg, user_buffer(msg), SOCKET_RAW, len, 0);
+}
+
+static __always_inline int return_icmp_recvmsg(int ret)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    struct recv_t *recv = bpf_map_lookup_elem(&icmp_recv, &pid_tgid);
+
+    if (!recv)
+        return 0;
+
+    if (ret > 0)
+        submit_icmp(recv->sk, recv->msg, recv->buffer, recv->socket, ret, 1);
+
+    bpf_map_delete_elem(&icmp_recv, &pid_tgid);
+
+    return 0;
+}
+
+SEC("kprobe/ping_recvmsg")
+int BPF_KPROBE(ping_recvmsg, struct sock *sk, struct msghdr *msg)
+{
+    return enter_recvmsg(&icmp_recv, sk, msg, user_buffer(msg), SOCKET_PING);
+}
+
+SEC("kretprobe/ping_recvmsg")
+int BPF_KRETPROBE(ping_recvmsg_ret, int ret)
+{
+    return return_icmp_recvmsg(ret);
+}
+
+SEC("kprobe/raw_recvmsg")
+int BPF_KPROBE(raw_recvmsg, struct sock *sk, struct msghdr *msg)
+{
+    if (!is_icmp_raw(sk))
+        return 0;
+
+    return enter_recvmsg(&icmp_recv, sk, msg, user_buffer(msg), SOCKET_RAW);
+}
+
+SEC("kretprobe/raw_recvmsg")
+int BPF_KRETPROBE(raw_recvmsg_ret, int ret)
+{
+    return return_icmp_recvmsg(ret);
+}
+
+SEC("kprobe/rawv6_recvmsg")
+int BPF_KPROBE(rawv6_recvmsg, struct sock *sk, struct msghdr *msg)
+{
+    if (!is_icmp_raw(sk))
+        return 0;
+
+    return enter_recvmsg(&icmp_recv, sk, msg, user_buffer(msg), SOCKET_RAW);
+}
+
+SEC("kretprobe/rawv6_recvmsg")
+int BPF_KRETPROBE(rawv6_recvmsg_ret, int ret)
+{
+    return return_icmp_recvmsg(ret);
+}
+
+/*
+ * Programs executed
+ */
+
+SEC("tracepoint/sched/sched_process_exec")
+int sched_process_exec(struct trace_event_raw_sched_process_exec *ctx)
+{
+    u32 zero = 0;
+    struct exec_record_t *record = bpf_map_lookup_elem(&exec_records, &zero);
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task();
+    unsigned long arg_start = BPF_CORE_READ(task, mm, arg_start);
+    unsigned long arg_end = BPF_CORE_READ(task, mm, arg_end);
+    u32 size = 0;
+
+    if (!record)
+        return 0;
+
+    record->header.kind = RECORD_EXEC;
+    record->header.version = RECORD_VERSION;
+    record->header.length = sizeof(record->data);
+    record->data.pid = bpf_get_current_pid_tgid() >> 32;
+    record->data.ppid = BPF_CORE_READ(task, real_parent, tgid);
+    record->data.uid = bpf_get_current_uid_gid();
+    record->data.euid = BPF_CORE_READ(task, cred, euid.val);
+    record->data.start = BPF_CORE_READ(task, start_boottime);
+    bpf_get_current_comm(&record->data.comm, sizeof(record->data.comm));
+    bpf_probe_read_kernel_str(record->data.exe, sizeof(record->data.exe),
+        (void *)ctx + (ctx->__data_loc_filename & 0xffff));
+
+    if (arg_end > arg_start)
+        size = arg_end - arg_start;
+    if (size > CMDLINE_LEN)
+        size = CMDLINE_LEN;
+
+    if (bpf_probe_read_user(record->data.cmdline, size, (void *)arg_start) < 0)
+        size = 0;
+    record->data.size = size;
+
+    if (bpf_ringbuf_output(&events, record, sizeof(*record), 0) < 0)
+        count_lost();
+
+    return 0;
+}
+
+SEC("tracepoint/sched/sched_process_exit")
+int sched_process_exit(void *ctx)
+{
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task();
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    struct exit_data_t data = {.pid = pid_tgid >> 32};
+
+    // A thread, the process lives on
+    if ((u32)pid_tgid != data.pid)
+        return 0;
+
+    data.start = BPF_CORE_READ(task, start_boottime);
+    SUBMIT(RECORD_EXIT, data);
+
+    return 0;
+}
@@ -4,33 +4,266 @@
  */
 
 #include <uapi/linux/ptrace.h>
+#include <linux/version.h>
+#include <linux/in.h>
+#include <linux/in6.h>
+#include <linux/sched.h>
+#include <linux/cred.h>
 #include <net/sock.h>
 #include <bcc/proto.h>
 
-struct ipv4_data_t {
-    u32 pid;
-    u32 saddr;
-    u32 daddr;
-    u16 lport;
-    u16 dport;
-    u32 size;
-    u32 is_rx;
-};
+#include "records.h"
 
-struct ipv6_data_t {
-    unsigned __int128 saddr;
-    unsigned __int128 daddr;
-    u32 pid;
-    u16 lport;
-    u16 dport;
-    u32 size;
-    u32 is_rx;
-};
+/*
+ * Everything captured goes to user space through a single BPF ring buffer, "events", when the
+ * kernel has them (5.8 and later, USE_RINGBUF is then defined by the loader). Older kernels get
+ * one perf buffer per kind of data instead. The layouts of the records are in records.h.
+ *
+ * A record which does not fit in the buffer is lost, they are counted in "lost".
+ *
+ * The records of a process carry its name, 'comm', read when it sent or received: user space
+ * still knows the processes which exited before it saw their first record.
+ */
+BPF_ARRAY(lost, u64, 1);
+
+static void count_lost(void)
+{
+    int zero = 0;
+    u64 *count = lost.lookup(&zero);
+
+    if (count != NULL)
+        __sync_fetch_and_add(count, 1);
+}
+
+// Declares 'record': the header of a record of kind 'record_kind', then 'value'
+#define RECORD(record_kind, value)                                          \
+        struct { struct header_t header; typeof(value) data; } record = {   \
+            .header = {                                                     \
+                .kind = record_kind,                                        \
+                .version = RECORD_VERSION,                                  \
+                .length = sizeof(value),                                    \
+            },                                                              \
+            .data = value                                                   \
+        }
+
+#ifdef USE_RINGBUF
+BPF_RINGBUF_OUTPUT(events, 256);
+
+// No perf buffer, only a declaration to keep the semicolon which follows valid
+#define OUTPUT(channel) struct channel##_unused
+
+#define SUBMIT(ctx, channel, record_kind, value) ({                         \
+        RECORD(record_kind, value);                                         \
+        if (events.ringbuf_output(&record, sizeof(record), 0) < 0)          \
+            count_lost();                                                   \
+    })
+#else
+#define OUTPUT(channel) BPF_PERF_OUTPUT(channel)
+
+#define SUBMIT(ctx, channel, record_kind, value) ({                         \
+        RECORD(record_kind, value);                                         \
+        if (channel.perf_submit(ctx, &record, sizeof(record)) < 0)          \
+            count_lost();                                                   \
+    })
+#endif
+
+/*
+ * With AGGREGATE defined by the loader, the traffic is not sent to user space packet by packet:
+ * the bytes are added up per process and per link in "traffic", read and emptied regularly by
+ * user space. Only the lifecycle of the TCP connections is still sent as events.
+ *
+ * The entries are keyed by the generation set by user space in "generation" too. User space moves
+ * to the next generation before each read and only empties the entries older than the previous
+ * one, so that a probe still adding to an entry when it is read cannot lose its bytes. At exit,
+ * user space sets GENERATION_STOPPED, the probes add nothing more and every entry is read.
+ *
+ * The ICMP messages have their type and code in the key, their identifier as the local port.
+ * IPv4 addresses are stored in the lowest 32 bits of the addresses of the key.
+ */
+#ifdef AGGREGATE
+BPF_HASH(traffic, struct flow_t, struct bytes_t, 65536);
+BPF_ARRAY(generation, u32, 1);
+
+// Out of the range of the generations, which are 16 bits in the keys
+#define GENERATION_STOPPED 0xffffffff
+
+static void account(struct flow_t *flow, u32 size, u32 is_rx)
+{
+    int first = 0;
+    u32 *current = generation.lookup(&first);
+    struct bytes_t zero = {};
+    struct bytes_t *bytes;
 
-BPF_PERF_OUTPUT(tcp4_data);
-BPF_PERF_OUTPUT(udp4_data);
-BPF_PERF_OUTPUT(tcp6_data);
-BPF_PERF_OUTPUT(udp6_data);
+    if (current != NULL && *current == GENERATION_STOPPED)
+        return;
+
+    flow->generation = current != NULL ? *current : 0;
+    bytes = traffic.lookup_or_try_init(flow, &zero);
+
+    // Map full until the next read
+    if (bytes == NULL) {
+        count_lost();
+        return;
+    }
+
+    if (is_rx)
+        __sync_fetch_and_add(&bytes->rx, size);
+    else
+        __sync_fetch_and_add(&bytes->tx, size);
+}
+
+#define ACCOUNT(ctx, channel, record_kind, value) ({                        \
+        struct flow_t flow = {.kind = record_kind};                         \
+        if (sizeof(value.saddr) == sizeof(u32)) {                           \
+            struct ipv4_data_t *v4 = (void *)&value;                        \
+            flow.pid = v4->pid; flow.saddr = v4->saddr; flow.daddr = v4->daddr; \
+            flow.lport = v4->lport; flow.dport = v4->dport;                 \
+            __builtin_memcpy(flow.comm, v4->comm, sizeof(flow.comm));       \
+            account(&flow, v4->size, v4->is_rx);                            \
+        } else {                                                            \
+            struct ipv6_data_t *v6 = (void *)&value;                        \
+            flow.pid = v6->pid; flow.saddr = v6->saddr; flow.daddr = v6->daddr; \
+            flow.lport = v6->lport; flow.dport = v6->dport;                 \
+            __builtin_memcpy(flow.comm, v6->comm, sizeof(flow.comm));       \
+            account(&flow, v6->size, v6->is_rx);                            \
+        }                                                                   \
+    })
+
+#define ACCOUNT_ICMP(ctx, channel, record_kind, value) ({                   \
+        struct flow_t flow = {                                              \
+            .saddr = value.saddr, .daddr = value.daddr,                     \
+            .kind = record_kind, .pid = value.pid, .lport = value.id,       \
+            .icmp_type = value.type, .icmp_code = value.code,               \
+        };                                                                  \
+        __builtin_memcpy(flow.comm, value.comm, sizeof(flow.comm));         \
+        account(&flow, value.size, value.is_rx);                            \
+    })
+#else
+#define ACCOUNT(ctx, channel, record_kind, value) SUBMIT(ctx, channel, record_kind, value)
+#define ACCOUNT_ICMP(ctx, channel, record_kind, value) SUBMIT(ctx, channel, record_kind, value)
+#endif
+
+/*
+ * Start of the first user buffer of the message.
+ */
+static void *user_buffer(struct msghdr *msg)
+{
+    void *base = NULL;
+    struct iovec *iov = NULL;
+
+#if LINUX_VERSION_CODE >= KERNEL_VERSION(6, 0, 0)
+    u8 iter_type = 0;
+
+    bpf_probe_read(&iter_type, sizeof(iter_type), &msg->msg_iter.iter_type);
+    if (iter_type == ITER_UBUF) {
+        bpf_probe_read(&base, sizeof(base), &msg->msg_iter.ubuf);
+        return base;
+    }
+#endif
+
+#if LINUX_VERSION_CODE >= KERNEL_VERSION(6, 4, 0)
+    bpf_probe_read(&iov, sizeof(iov), &msg->msg_iter.__iov);
+#else
+    bpf_probe_read(&iov, sizeof(iov), &msg->msg_iter.iov);
+#endif
+    bpf_probe_read(&base, sizeof(base), &iov->iov_base);
+
+    return base;
+}
+
+/*
+ * First bytes sent on a link: on the TCP connections a process opened, and to each peer of its
+ * UDP sockets. User space recognizes in them the protocol spoken and, over TCP, reads the name of
+ * the server asked for from a TLS ClientHello or an HTTP request. Sent even when the traffic is
+ * added up. The TCP sockets connected and yet to send anything are kept in "first_send", the UDP
+ * links already peeked at in "udp_peeked".
+ *
+ * IPv4 addresses are stored in the lowest 32 bits of the addresses, as in flow_t.
+ */
+BPF_TABLE("lru_hash", u64, u8, first_send, 65536);
+BPF_TABLE("lru_hash", struct link_t, u8, udp_peeked, 65536);
+BPF_PERCPU_ARRAY(payload_records, struct payload_record_t, 1);
+OUTPUT(payload_data);
+
+static void submit_payload(struct pt_regs *ctx, u32 kind, struct link_t *link, void *buffer,
+    u32 size)
+{
+    u32 zero = 0;
+    struct payload_record_t *record = payload_records.lookup(&zero);
+
+    if (record == NULL || buffer == NULL)
+        return;
+
+    if (size > PEEK_PAYLOAD)
+        size = PEEK_PAYLOAD;
+
+    record->header.kind = kind;
+    record->header.version = RECORD_VERSION;
+    record->header.length = sizeof(record->data);
+    record->data.saddr = link->saddr;
+    record->data.daddr = link->daddr;
+    record->data.pid = link->pid;
+    record->data.lport = link->lport;
+    record->data.dport = link->dport;
+    record->data.family = link->family;
+    record->data.size = size;
+    bpf_get_current_comm(&record->data.comm, sizeof(record->data.comm));
+
+    if (bpf_probe_read_user(record->data.payload, size, buffer) < 0)
+        return;
+
+#ifdef USE_RINGBUF
+    if (events.ringbuf_output(record, sizeof(*record), 0) < 0)
+#else
+    if (payload_data.perf_submit(ctx, record, sizeof(*record)) < 0)
+#endif
+        count_lost();
+}
+
+static void peek_tcp(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg, u32 size)
+{
+    u64 key = (u64)sk;
+    u16 dport = 0, family = 0;
+    struct link_t link = {.pid = bpf_get_current_pid_tgid() >> 32};
+
+    if (first_send.lookup(&key) == NULL)
+        return;
+    first_send.delete(&key);
+
+    bpf_probe_read(&family, sizeof(family), &sk->__sk_common.skc_family);
+    bpf_probe_read(&dport, sizeof(dport), &sk->__sk_common.skc_dport);
+    bpf_probe_read(&link.lport, sizeof(link.lport), &sk->__sk_common.skc_num);
+    link.family = family;
+    link.dport = ntohs(dport);
+
+    if (family == AF_INET) {
+        bpf_probe_read(&link.saddr, sizeof(u32), &sk->__sk_common.skc_rcv_saddr);
+        bpf_probe_read(&link.daddr, sizeof(u32), &sk->__sk_common.skc_daddr);
+    } else {
+        bpf_probe_read(&link.saddr, sizeof(link.saddr),
+            &sk->__sk_common.skc_v6_rcv_saddr.in6_u.u6_addr32);
+        bpf_probe_read(&link.daddr, sizeof(link.daddr),
+            &sk->__sk_common.skc_v6_daddr.in6_u.u6_addr32);
+    }
+
+    submit_payload(ctx, RECORD_TCP_PAYLOAD, &link, user_buffer(msg), size);
+}
+
+static void peek_udp(struct pt_regs *ctx, struct link_t *link, struct msghdr *msg, u32 size)
+{
+    u8 yes = 1;
+
+    if (udp_peeked.lookup(link) != NULL)
+        return;
+    udp_peeked.update(link, &yes);
+
+    submit_payload(ctx, RECORD_UDP_PAYLOAD, link, user_buffer(msg), size);
+}
+
+OUTPUT(tcp4_data);
+OUTPUT(udp4_data);
+OUTPUT(tcp6_data);
+OUTPUT(udp6_data);
 
 int kprobe__tcp_sendmsg(struct pt_regs *ctx, struct sock *sk,
     struct msghdr *msg, size_t size)
@@ -38,9 +271,12 @@ int kprobe__tcp_sendmsg(struct pt_regs *ctx, struct sock *sk,
     u32 pid = bpf_get_current_pid_tgid() >> 32;
     u16 dport = 0, family = sk->__sk_common.skc_family;
 
+    peek_tcp(ctx, sk, msg, size);
+
     if (family == AF_INET) {
         struct ipv4_data_t tcp4 = {.pid = pid};
 
+        bpf_get_current_comm(&tcp4.comm, sizeof(tcp4.comm));
         tcp4.saddr = sk->__sk_common.skc_rcv_saddr;
         tcp4.daddr = sk->__sk_common.skc_daddr;
         tcp4.lport = sk->__sk_common.skc_num;
@@ -49,11 +285,12 @@ int kprobe__tcp_sendmsg(struct pt_regs *ctx, struct sock *sk,
         tcp4.size = size;
 	tcp4.is_rx = 0;
 	
-	tcp4_data.perf_submit(ctx, &tcp4, sizeof(tcp4));
+	ACCOUNT(ctx, tcp4_data, RECORD_TCP4, tcp4);
 
     } else if (family == AF_INET6) {
         struct ipv6_data_t tcp6 = {.pid = pid};
 
+        bpf_get_current_comm(&tcp6.comm, sizeof(tcp6.comm));
         bpf_probe_read(&tcp6.saddr, sizeof(tcp6.saddr),
             &sk->__sk_common.skc_v6_rcv_saddr.in6_u.u6_addr32);
         bpf_probe_read(&tcp6.daddr, sizeof(tcp6.daddr),
@@ -64,7 +301,7 @@ int kprobe__tcp_sendmsg(struct pt_regs *ctx, struct sock *sk,
         tcp6.size = size;
 	tcp6.is_rx = 0;
 
-	tcp6_data.perf_submit(ctx, &tcp6, sizeof(tcp6));
+	ACCOUNT(ctx, tcp6_data, RECORD_TCP6, tcp6);
     }
     // else drop
 
@@ -89,6 +326,7 @@ int kprobe__tcp_cleanup_rbuf(struct pt_regs *ctx, struct sock *sk, int copied)
     if (family == AF_INET) {
         struct ipv4_data_t tcp4 = {.pid = pid};
 
+        bpf_get_current_comm(&tcp4.comm, sizeof(tcp4.comm));
         tcp4.saddr = sk->__sk_common.skc_rcv_saddr;
         tcp4.daddr = sk->__sk_common.skc_daddr;
         tcp4.lport = sk->__sk_common.skc_num;
@@ -97,11 +335,12 @@ int kprobe__tcp_cleanup_rbuf(struct pt_regs *ctx, struct sock *sk, int copied)
         tcp4.size = copied;
 	tcp4.is_rx = 1;
 
-	tcp4_data.perf_submit(ctx, &tcp4, sizeof(tcp4));
+	ACCOUNT(ctx, tcp4_data, RECORD_TCP4, tcp4);
 
     } else if (family == AF_INET6) {
         struct ipv6_data_t tcp6 = {.pid = pid};
 
+        bpf_get_current_comm(&tcp6.comm, sizeof(tcp6.comm));
         bpf_probe_read(&tcp6.saddr, sizeof(tcp6.saddr),
             &sk->__sk_common.skc_v6_rcv_saddr.in6_u.u6_addr32);
         bpf_probe_read(&tcp6.daddr, sizeof(tcp6.daddr),
@@ -112,7 +351,7 @@ int kprobe__tcp_cleanup_rbuf(struct pt_regs *ctx, struct sock *sk, int copied)
         tcp6.size = copied;
 	tcp6.is_rx = 1;
 
-	tcp6_data.perf_submit(ctx, &tcp6, sizeof(tcp6));
+	ACCOUNT(ctx, tcp6_data, RECORD_TCP6, tcp6);
     }
     // else drop
 
@@ -120,117 +359,702 @@ int kprobe__tcp_cleanup_rbuf(struct pt_regs *ctx, struct sock *sk, int copied)
 }
 
 /*
- * "size_t len" instead of "size_t size" to match with udp_sendmsg() arg name.
- * The struct field does not change.
+ * A UDP socket is not always connected: the peer of a datagram sent with sendto() is given with
+ * the message, in 'msg_name', and the peer of a datagram received is written there by the kernel
+ * when recvmsg() returns. The address of the socket is used when there is none.
  *
+ * 'family' is the one of the datagram, an IPv6 socket may send IPv4 datagrams.
  */
-int kprobe__udp_sendmsg(struct pt_regs *ctx, struct sock *sk,
-    struct msghdr *msg, size_t len)
+static int submit_udp(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg, u32 size,
+    u32 is_rx, u16 family)
 {
     u32 pid = bpf_get_current_pid_tgid() >> 32;
-    u16 dport = 0, family = sk->__sk_common.skc_family;
+    u16 dport = 0, name_family = 0;
+    void *name = NULL;
+
+    bpf_probe_read(&dport, sizeof(dport), &sk->__sk_common.skc_dport);
+    bpf_probe_read(&name, sizeof(name), &msg->msg_name);
+
+    if (name != NULL)
+        bpf_probe_read(&name_family, sizeof(name_family), name);
 
     if (family == AF_INET) {
-        struct ipv4_data_t udp4 = {.pid = pid};
+        struct ipv4_data_t udp4 = {.pid = pid, .size = size, .is_rx = is_rx};
 
-        udp4.saddr = sk->__sk_common.skc_rcv_saddr;
-        udp4.daddr = sk->__sk_common.skc_daddr;
-        udp4.lport = sk->__sk_common.skc_num;
-        dport = sk->__sk_common.skc_dport;
+        bpf_get_current_comm(&udp4.comm, sizeof(udp4.comm));
+        bpf_probe_read(&udp4.saddr, sizeof(udp4.saddr), &sk->__sk_common.skc_rcv_saddr);
+        bpf_probe_read(&udp4.lport, sizeof(udp4.lport), &sk->__sk_common.skc_num);
+
+        if (name_family == AF_INET) {
+            struct sockaddr_in *sin = name;
+
+            bpf_probe_read(&udp4.daddr, sizeof(udp4.daddr), &sin->sin_addr.s_addr);
+            bpf_probe_read(&dport, sizeof(dport), &sin->sin_port);
+        } else {
+            bpf_probe_read(&udp4.daddr, sizeof(udp4.daddr), &sk->__sk_common.skc_daddr);
+        }
         udp4.dport = ntohs(dport);
-        udp4.size = len;
-	udp4.is_rx = 0;
-	
-	udp4_data.perf_submit(ctx, &udp4, sizeof(udp4));
 
+        ACCOUNT(ctx, udp4_data, RECORD_UDP4, udp4);
+
+        if (!is_rx) {
+            struct link_t link = {
+                .saddr = udp4.saddr, .daddr = udp4.daddr, .pid = pid, .lport = udp4.lport,
+                .dport = udp4.dport, .family = AF_INET,
+            };
+            peek_udp(ctx, &link, msg, size);
+        }
+
+    } else if (family == AF_INET6) {
+        struct ipv6_data_t udp6 = {.pid = pid, .size = size, .is_rx = is_rx};
+
+        bpf_get_current_comm(&udp6.comm, sizeof(udp6.comm));
+        bpf_probe_read(&udp6.saddr, sizeof(udp6.saddr),
+            &sk->__sk_common.skc_v6_rcv_saddr.in6_u.u6_addr32);
+        bpf_probe_read(&udp6.lport, sizeof(udp6.lport), &sk->__sk_common.skc_num);
+
+        if (name_family == AF_INET6) {
+            struct sockaddr_in6 *sin6 = name;
+
+            bpf_probe_read(&udp6.daddr, sizeof(udp6.daddr), &sin6->sin6_addr.in6_u.u6_addr32);
+            bpf_probe_read(&dport, sizeof(dport), &sin6->sin6_port);
+        } else {
+            bpf_probe_read(&udp6.daddr, sizeof(udp6.daddr),
+                &sk->__sk_common.skc_v6_daddr.in6_u.u6_addr32);
+        }
+        udp6.dport = ntohs(dport);
+
+        ACCOUNT(ctx, udp6_data, RECORD_UDP6, udp6);
+
+        if (!is_rx) {
+            struct link_t link = {
+                .saddr = udp6.saddr, .daddr = udp6.daddr, .pid = pid, .lport = udp6.lport,
+                .dport = udp6.dport, .family = AF_INET6,
+            };
+            peek_udp(ctx, &link, msg, size);
+        }
     }
     // else drop
 
     return 0;
 }
 
-int kprobe__udp_recvmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg,
-    size_t len, int noblock, int flags, int *addr_len)
+/*
+ * "size_t len" instead of "size_t size" to match with udp_sendmsg() arg name.
+ * The struct field does not change.
+ *
+ */
+int kprobe__udp_sendmsg(struct pt_regs *ctx, struct sock *sk,
+    struct msghdr *msg, size_t len)
 {
-    u32 pid = bpf_get_current_pid_tgid() >> 32;
-    u16 dport = 0, family = sk->__sk_common.skc_family;
+    return submit_udp(ctx, sk, msg, len, 0, AF_INET);
+}
+
+/*
+ * Whether the destination of the datagram is an IPv4 address: udpv6_sendmsg() then hands it to
+ * udp_sendmsg() which accounts it, with the address given as a 'struct sockaddr_in'.
+ */
+static int is_v4_destination(struct sock *sk, struct msghdr *msg)
+{
+    struct in6_addr daddr = {};
+    u16 name_family = 0;
+    void *name = NULL;
+
+    bpf_probe_read(&name, sizeof(name), &msg->msg_name);
+
+    if (name != NULL) {
+        bpf_probe_read(&name_family, sizeof(name_family), name);
+        if (name_family == AF_INET)
+            return 1;
+
+        bpf_probe_read(&daddr, sizeof(daddr), &((struct sockaddr_in6 *)name)->sin6_addr);
+    } else {
+        bpf_probe_read(&daddr, sizeof(daddr), &sk->__sk_common.skc_v6_daddr);
+    }
+
+    // ::ffff:a.b.c.d
+    return daddr.in6_u.u6_addr32[0] == 0 && daddr.in6_u.u6_addr32[1] == 0
+        && daddr.in6_u.u6_addr32[2] == htonl(0x0000ffff);
+}
 
-    if (len <= 0)
+int kprobe__udpv6_sendmsg(struct pt_regs *ctx, struct sock *sk,
+    struct msghdr *msg, size_t len)
+{
+    if (len <= 0 || is_v4_destination(sk, msg))
         return 0;
 
-    if (family == AF_INET) {
-        struct ipv4_data_t udp4 = {.pid = pid};
+    return submit_udp(ctx, sk, msg, len, 0, AF_INET6);
+}
 
-        udp4.saddr = sk->__sk_common.skc_rcv_saddr;
-        udp4.daddr = sk->__sk_common.skc_daddr;
-        udp4.lport = sk->__sk_common.skc_num;
-        dport = sk->__sk_common.skc_dport;
-        udp4.dport = ntohs(dport);
-        udp4.size = len;
-	udp4.is_rx = 1;
+/*
+ * The sender of a datagram and its size are only known when recvmsg() returns.
+ */
+struct recv_t {
+    struct sock *sk;
+    struct msghdr *msg;
+    void *buffer;
+};
+
+BPF_HASH(udp_recv, u64, struct recv_t);
+
+static int enter_udp_recvmsg(struct sock *sk, struct msghdr *msg)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    struct recv_t recv = {.sk = sk, .msg = msg, .buffer = user_buffer(msg)};
+
+    udp_recv.update(&pid_tgid, &recv);
+
+    return 0;
+}
+
+/*
+ * Passive DNS: the start of the responses received from port 53 is sent to user space, which
+ * learns the names the processes asked for before connecting to the addresses of the answers.
+ * Sent even when the traffic is added up.
+ *
+ * Over TCP, what each read returns is sent: user space finds the responses in the stream, each
+ * one after its length on 2 bytes, see passive_dns.rs.
+ */
+#define DNS_PORT    53
 
-	udp4_data.perf_submit(ctx, &udp4, sizeof(udp4));
+BPF_PERCPU_ARRAY(dns_records, struct dns_record_t, 1);
+OUTPUT(dns_data);
 
+// Port of the other end of a datagram received, at the same place in both kinds of addresses
+static u16 peer_port(struct sock *sk, struct msghdr *msg)
+{
+    u16 port = 0;
+    void *name = NULL;
+
+    bpf_probe_read(&name, sizeof(name), &msg->msg_name);
+
+    if (name != NULL)
+        bpf_probe_read(&port, sizeof(port), &((struct sockaddr_in *)name)->sin_port);
+    else
+        bpf_probe_read(&port, sizeof(port), &sk->__sk_common.skc_dport);
+
+    return ntohs(port);
+}
+
+static void submit_dns(struct pt_regs *ctx, void *buffer, u32 size, u32 is_tcp)
+{
+    u32 zero = 0;
+    struct dns_record_t *record = dns_records.lookup(&zero);
+
+    if (record == NULL || buffer == NULL)
+        return;
+
+    if (size > DNS_PAYLOAD)
+        size = DNS_PAYLOAD;
+
+    record->header.kind = RECORD_DNS;
+    record->header.version = RECORD_VERSION;
+    record->header.length = sizeof(record->data);
+    record->data.pid = bpf_get_current_pid_tgid() >> 32;
+    record->data.size = size;
+    record->data.is_tcp = is_tcp;
+    bpf_get_current_comm(&record->data.comm, sizeof(record->data.comm));
+
+    if (bpf_probe_read_user(record->data.payload, size, buffer) < 0)
+        return;
+
+#ifdef USE_RINGBUF
+    if (events.ringbuf_output(record, sizeof(*record), 0) < 0)
+#else
+    if (dns_data.perf_submit(ctx, record, sizeof(*record)) < 0)
+#endif
+        count_lost();
+}
+
+// Reads of the TCP connections to port 53, their traffic is accounted by tcp_cleanup_rbuf()
+BPF_HASH(tcp_recv, u64, struct recv_t);
+
+int kprobe__tcp_recvmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    u16 dport = 0;
+
+    bpf_probe_read(&dport, sizeof(dport), &sk->__sk_common.skc_dport);
+    if (ntohs(dport) != DNS_PORT)
+        return 0;
+
+    struct recv_t recv = {.sk = sk, .msg = msg, .buffer = user_buffer(msg)};
+
+    tcp_recv.update(&pid_tgid, &recv);
+
+    return 0;
+}
+
+int kretprobe__tcp_recvmsg(struct pt_regs *ctx)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    int ret = PT_REGS_RC(ctx);
+    struct recv_t *recv = tcp_recv.lookup(&pid_tgid);
+
+    if (recv == NULL)
+        return 0;
+
+    if (ret > 0)
+        submit_dns(ctx, recv->buffer, ret, 1);
+
+    tcp_recv.delete(&pid_tgid);
+
+    return 0;
+}
+
+static int return_udp_recvmsg(struct pt_regs *ctx)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    int ret = PT_REGS_RC(ctx);
+    struct recv_t *recv = udp_recv.lookup(&pid_tgid);
+
+    if (recv == NULL)
+        return 0;
+
+    // IPv4 datagrams received by an IPv6 socket come from v4-mapped addresses
+    if (ret > 0) {
+        u16 family = 0;
+
+        bpf_probe_read(&family, sizeof(family), &recv->sk->__sk_common.skc_family);
+        submit_udp(ctx, recv->sk, recv->msg, ret, 1, family);
+
+        if (peer_port(recv->sk, recv->msg) == DNS_PORT)
+            submit_dns(ctx, recv->buffer, ret, 0);
     }
-    // else drop
+
+    udp_recv.delete(&pid_tgid);
 
     return 0;
 }
 
-int kprobe__udpv6_sendmsg(struct pt_regs *ctx, struct sock *sk,
-    struct msghdr *msg, size_t len)
+int kprobe__udp_recvmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg)
+{
+    return enter_udp_recvmsg(sk, msg);
+}
+
+int kretprobe__udp_recvmsg(struct pt_regs *ctx)
+{
+    return return_udp_recvmsg(ctx);
+}
+
+int kprobe__udpv6_recvmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg)
+{
+    return enter_udp_recvmsg(sk, msg);
+}
+
+int kretprobe__udpv6_recvmsg(struct pt_regs *ctx)
+{
+    return return_udp_recvmsg(ctx);
+}
+
+/*
+ * TCP connection lifecycle: a link is opened when the process connects or accepts, and closed
+ * when the process closes its socket. The events carry no data, only the link, what happened and
+ * the TCP state of the socket then. The other changes of state, e.g. the connection established
+ * or reset by the peer, are events of their own.
+ */
+OUTPUT(tcp4_events);
+OUTPUT(tcp6_events);
+
+static int submit_event(struct pt_regs *ctx, struct sock *sk, u32 event)
 {
     u32 pid = bpf_get_current_pid_tgid() >> 32;
-    u16 dport = 0, family = sk->__sk_common.skc_family;
+    u16 dport = 0, lport = 0, family = 0;
+    u8 state = 0;
+
+    bpf_probe_read(&family, sizeof(family), &sk->__sk_common.skc_family);
+    bpf_probe_read(&lport, sizeof(lport), &sk->__sk_common.skc_num);
+    bpf_probe_read(&dport, sizeof(dport), &sk->__sk_common.skc_dport);
+    bpf_probe_read(&state, sizeof(state), (void *)&sk->__sk_common.skc_state);
 
-    if (len <= 0)
+    // Listening or never connected socket, not a link
+    if (dport == 0)
         return 0;
 
-    if (family == AF_INET6) {
-        struct ipv6_data_t udp6 = {.pid = pid};
+    if (family == AF_INET) {
+        struct ipv4_event_t tcp4 = {.pid = pid, .event = event, .state = state};
 
-        bpf_probe_read(&udp6.saddr, sizeof(udp6.saddr),
+        bpf_get_current_comm(&tcp4.comm, sizeof(tcp4.comm));
+        bpf_probe_read(&tcp4.saddr, sizeof(tcp4.saddr), &sk->__sk_common.skc_rcv_saddr);
+        bpf_probe_read(&tcp4.daddr, sizeof(tcp4.daddr), &sk->__sk_common.skc_daddr);
+        tcp4.lport = lport;
+        tcp4.dport = ntohs(dport);
+
+        SUBMIT(ctx, tcp4_events, RECORD_TCP4_EVENT, tcp4);
+
+    } else if (family == AF_INET6) {
+        struct ipv6_event_t tcp6 = {.pid = pid, .event = event, .state = state};
+
+        bpf_get_current_comm(&tcp6.comm, sizeof(tcp6.comm));
+        bpf_probe_read(&tcp6.saddr, sizeof(tcp6.saddr),
             &sk->__sk_common.skc_v6_rcv_saddr.in6_u.u6_addr32);
-        bpf_probe_read(&udp6.daddr, sizeof(udp6.daddr),
+        bpf_probe_read(&tcp6.daddr, sizeof(tcp6.daddr),
             &sk->__sk_common.skc_v6_daddr.in6_u.u6_addr32);
-        udp6.lport = sk->__sk_common.skc_num;
-        dport = sk->__sk_common.skc_dport;
-        udp6.dport = ntohs(dport);
-        udp6.size = len;
-	udp6.is_rx = 0;
+        tcp6.lport = lport;
+        tcp6.dport = ntohs(dport);
 
-	udp6_data.perf_submit(ctx, &udp6, sizeof(udp6));
+        SUBMIT(ctx, tcp6_events, RECORD_TCP6_EVENT, tcp6);
     }
     // else drop
 
     return 0;
 }
 
-int kprobe__udpv6_recvmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg,
-    size_t len, int noblock, int flags, int *addr_len)
+/*
+ * Called once the local address and port are chosen, right before sending the SYN.
+ */
+int kprobe__tcp_connect(struct pt_regs *ctx, struct sock *sk)
+{
+    u64 key = (u64)sk;
+    u8 yes = 1;
+
+    first_send.update(&key, &yes);
+
+    return submit_event(ctx, sk, EVENT_CONNECT);
+}
+
+/*
+ * The socket of the new connection is only known when accept() returns. inet_csk_accept() is
+ * shared with DCCP, rare enough to be accounted as TCP.
+ */
+int kretprobe__inet_csk_accept(struct pt_regs *ctx)
+{
+    struct sock *sk = (struct sock *)PT_REGS_RC(ctx);
+
+    if (sk == NULL)
+        return 0;
+
+    return submit_event(ctx, sk, EVENT_ACCEPT);
+}
+
+int kprobe__tcp_close(struct pt_regs *ctx, struct sock *sk, long timeout)
+{
+    u64 key = (u64)sk;
+
+    first_send.delete(&key);
+
+    return submit_event(ctx, sk, EVENT_CLOSE);
+}
+
+/*
+ * Any change of state of a TCP socket, as the kernel makes it (4.16 and later). Often made when
+ * a packet is received, whatever process runs then: user space finds the link by its addresses
+ * and ports, not by the process.
+ */
+TRACEPOINT_PROBE(sock, inet_sock_set_state)
 {
     u32 pid = bpf_get_current_pid_tgid() >> 32;
-    u16 dport = 0, family = sk->__sk_common.skc_family;
 
-    if (len <= 0)
+    // Listening or never connected socket, not a link
+    if (args->protocol != IPPROTO_TCP || args->dport == 0)
         return 0;
 
-    if (family == AF_INET6) {
-        struct ipv6_data_t udp6 = {.pid = pid};
+    if (args->family == AF_INET) {
+        struct ipv4_event_t tcp4 = {.pid = pid, .event = EVENT_STATE, .state = args->newstate};
 
-        bpf_probe_read(&udp6.saddr, sizeof(udp6.saddr),
+        bpf_get_current_comm(&tcp4.comm, sizeof(tcp4.comm));
+        __builtin_memcpy(&tcp4.saddr, args->saddr, sizeof(tcp4.saddr));
+        __builtin_memcpy(&tcp4.daddr, args->daddr, sizeof(tcp4.daddr));
+        tcp4.lport = args->sport;
+        tcp4.dport = args->dport;
+
+        SUBMIT(args, tcp4_events, RECORD_TCP4_EVENT, tcp4);
+
+    } else if (args->family == AF_INET6) {
+        struct ipv6_event_t tcp6 = {.pid = pid, .event = EVENT_STATE, .state = args->newstate};
+
+        bpf_get_current_comm(&tcp6.comm, sizeof(tcp6.comm));
+        __builtin_memcpy(&tcp6.saddr, args->saddr_v6, sizeof(tcp6.saddr));
+        __builtin_memcpy(&tcp6.daddr, args->daddr_v6, sizeof(tcp6.daddr));
+        tcp6.lport = args->sport;
+        tcp6.dport = args->dport;
+
+        SUBMIT(args, tcp6_events, RECORD_TCP6_EVENT, tcp6);
+    }
+
+    return 0;
+}
+
+/*
+ * ICMP and ICMPv6 messages of ping sockets (SOCK_DGRAM, IPPROTO_ICMP) and raw sockets. The type
+ * and code are read from the message itself, in the user buffer. Ping sockets are bound to an
+ * echo identifier, used as local port, raw sockets have none.
+ */
+OUTPUT(icmp4_data);
+OUTPUT(icmp6_data);
+
+#define SOCKET_PING 0
+#define SOCKET_RAW  1
+
+/*
+ * A received message is only in the user buffer when recvmsg() returns, by then the iterator of
+ * the message has moved past it.
+ */
+struct icmp_recv_t {
+    struct sock *sk;
+    struct msghdr *msg;
+    void *buffer;
+    u32 socket;
+};
+
+BPF_HASH(icmp_recv, u64, struct icmp_recv_t);
+
+/*
+ * Only ICMP raw sockets, the protocol of a raw socket is stored as its local port.
+ */
+static int is_icmp_raw(struct sock *sk)
+{
+    u16 protocol = 0;
+
+    bpf_probe_read(&protocol, sizeof(protocol), &sk->__sk_common.skc_num);
+
+    return protocol == IPPROTO_ICMP || protocol == IPPROTO_ICMPV6;
+}
+
+/*
+ * 'buffer' is the ICMP message, preceded by the IP header for IPv4 raw sockets receiving.
+ * 'msg_name', when given, is the address of the other end.
+ */
+static int submit_icmp(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg, void *buffer,
+    u32 socket, u32 size, u32 is_rx)
+{
+    u32 pid = bpf_get_current_pid_tgid() >> 32;
+    u16 id = 0, family = 0;
+    u8 header[2] = {0, 0};
+    void *name = NULL;
+
+    bpf_probe_read(&family, sizeof(family), &sk->__sk_common.skc_family);
+    bpf_probe_read(&name, sizeof(name), &msg->msg_name);
+
+    if (socket == SOCKET_PING)
+        bpf_probe_read(&id, sizeof(id), &sk->__sk_common.skc_num);
+
+    if (family == AF_INET) {
+        struct icmp4_data_t icmp4 = {.pid = pid, .id = id, .size = size, .is_rx = is_rx};
+
+        bpf_get_current_comm(&icmp4.comm, sizeof(icmp4.comm));
+        if (socket == SOCKET_RAW && is_rx) {
+            u8 ihl = 0;
+
+            bpf_probe_read_user(&ihl, sizeof(ihl), buffer);
+            buffer += (ihl & 0x0f) * 4;
+        }
+        bpf_probe_read_user(&header, sizeof(header), buffer);
+        icmp4.type = header[0];
+        icmp4.code = header[1];
+
+        bpf_probe_read(&icmp4.saddr, sizeof(icmp4.saddr), &sk->__sk_common.skc_rcv_saddr);
+        if (name != NULL)
+            bpf_probe_read(&icmp4.daddr, sizeof(icmp4.daddr),
+                &((struct sockaddr_in *)name)->sin_addr.s_addr);
+        else
+            bpf_probe_read(&icmp4.daddr, sizeof(icmp4.daddr), &sk->__sk_common.skc_daddr);
+
+        ACCOUNT_ICMP(ctx, icmp4_data, RECORD_ICMP4, icmp4);
+
+    } else if (family == AF_INET6) {
+        struct icmp6_data_t icmp6 = {.pid = pid, .id = id, .size = size, .is_rx = is_rx};
+
+        bpf_get_current_comm(&icmp6.comm, sizeof(icmp6.comm));
+        bpf_probe_read_user(&header, sizeof(header), buffer);
+        icmp6.type = header[0];
+        icmp6.code = header[1];
+
+        bpf_probe_read(&icmp6.saddr, sizeof(icmp6.saddr),
             &sk->__sk_common.skc_v6_rcv_saddr.in6_u.u6_addr32);
-        bpf_probe_read(&udp6.daddr, sizeof(udp6.daddr),
-            &sk->__sk_common.skc_v6_daddr.in6_u.u6_addr32);
-        udp6.lport = sk->__sk_common.skc_num;
-        dport = sk->__sk_common.skc_dport;
-        udp6.dport = ntohs(dport);
-        udp6.size = len;
-	udp6.is_rx = 1;
+        if (name != NULL)
+            bpf_probe_read(&icmp6.daddr, sizeof(icmp6.daddr),
+                &((struct sockaddr_in6 *)name)->sin6_addr.in6_u.u6_addr32);
+        else
+            bpf_probe_read(&icmp6.daddr, sizeof(icmp6.daddr),
+                &sk->__sk_common.skc_v6_daddr.in6_u.u6_addr32);
 
-	udp6_data.perf_submit(ctx, &udp6, sizeof(udp6));
+        ACCOUNT_ICMP(ctx, icmp6_data, RECORD_ICMP6, icmp6);
     }
     // else drop
 
     return 0;
 }
+
+int kprobe__ping_v4_sendmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg, size_t len)
+{
+    return submit_icmp(ctx, sk, msg, user_buffer(msg), SOCKET_PING, len, 0);
+}
+
+int kprobe__ping_v6_sendmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg, size_t len)
+{
+    return submit_icmp(ctx, sk, msg, user_buffer(msg), SOCKET_PING, len, 0);
+}
+
+int kprobe__raw_sendmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg, size_t len)
+{
+    if (!is_icmp_raw(sk))
+        return 0;
+
+    return submit_icmp(ctx, sk, msg, user_buffer(msg), SOCKET_RAW, len, 0);
+}
+
+int kprobe__rawv6_sendmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg, size_t len)
+{
+    if (!is_icmp_raw(sk))
+        return 0;
+
+    return submit_icmp(ctx, sk, msg, user_buffer(msg), SOCKET_RAW, len, 0);
+}
+
+static int enter_recvmsg(struct sock *sk, struct msghdr *msg, u32 socket)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    struct icmp_recv_t recv = {.sk = sk, .msg = msg, .socket = socket};
+
+    if (socket == SOCKET_RAW && !is_icmp_raw(sk))
+        return 0;
+
+    recv.buffer = user_buffer(msg);
+    icmp_recv.update(&pid_tgid, &recv);
+
+    return 0;
+}
+
+static int return_recvmsg(struct pt_regs *ctx)
+{
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    int ret = PT_REGS_RC(ctx);
+    struct icmp_recv_t *recv = icmp_recv.lookup(&pid_tgid);
+
+    if (recv == NULL)
+        return 0;
+
+    if (ret > 0)
+        submit_icmp(ctx, recv->sk, recv->msg, recv->buffer, recv->socket, ret, 1);
+
+    icmp_recv.delete(&pid_tgid);
+
+    return 0;
+}
+
+int kprobe__ping_recvmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg)
+{
+    return enter_recvmsg(sk, msg, SOCKET_PING);
+}
+
+int kretprobe__ping_recvmsg(struct pt_regs *ctx)
+{
+    return return_recvmsg(ctx);
+}
+
+int kprobe__raw_recvmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg)
+{
+    return enter_recvmsg(sk, msg, SOCKET_RAW);
+}
+
+int kretprobe__raw_recvmsg(struct pt_regs *ctx)
+{
+    return return_recvmsg(ctx);
+}
+
+int kprobe__rawv6_recvmsg(struct pt_regs *ctx, struct sock *sk, struct msghdr *msg)
+{
+    return enter_recvmsg(sk, msg, SOCKET_RAW);
+}
+
+int kretprobe__rawv6_recvmsg(struct pt_regs *ctx)
+{
+    return return_recvmsg(ctx);
+}
+
+/*
+ * Programs executed: the path of the executable and the arguments of each process which calls
+ * exec(), with its parent, its users and when it started, for user space to describe the
+ * processes which are gone when it looks for them. Sent even when the traffic is added up,
+ * whatever the protocols traced.
+ */
+BPF_PERCPU_ARRAY(exec_records, struct exec_record_t, 1);
+OUTPUT(exec_data);
+
+TRACEPOINT_PROBE(sched, sched_process_exec)
+{
+    u32 zero = 0;
+    struct exec_record_t *record = exec_records.lookup(&zero);
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task();
+    struct task_struct *parent = NULL;
+    const struct cred *cred = NULL;
+    struct mm_struct *mm = NULL;
+    unsigned long arg_start = 0, arg_end = 0;
+    u32 size = 0;
+
+    if (record == NULL)
+        return 0;
+
+    record->header.kind = RECORD_EXEC;
+    record->header.version = RECORD_VERSION;
+    record->header.length = sizeof(record->data);
+    record->data.pid = bpf_get_current_pid_tgid() >> 32;
+    record->data.uid = bpf_get_current_uid_gid();
+    bpf_get_current_comm(&record->data.comm, sizeof(record->data.comm));
+
+    bpf_probe_read(&parent, sizeof(parent), &task->real_parent);
+    bpf_probe_read(&record->data.ppid, sizeof(record->data.ppid), &parent->tgid);
+    bpf_probe_read(&cred, sizeof(cred), &task->cred);
+    bpf_probe_read(&record->data.euid, sizeof(record->data.euid), &cred->euid);
+#if LINUX_VERSION_CODE >= KERNEL_VERSION(5, 5, 0)
+    bpf_probe_read(&record->data.start, sizeof(record->data.start), &task->start_boottime);
+#else
+    bpf_probe_read(&record->data.start, sizeof(record->data.start), &task->real_start_time);
+#endif
+
+    bpf_probe_read_str(record->data.exe, sizeof(record->data.exe),
+        (void *)args + (args->data_loc_filename & 0xffff));
+
+    // The arguments are in the memory of the new program, already set up
+    bpf_probe_read(&mm, sizeof(mm), &task->mm);
+    if (mm != NULL) {
+        bpf_probe_read(&arg_start, sizeof(arg_start), &mm->arg_start);
+        bpf_probe_read(&arg_end, sizeof(arg_end), &mm->arg_end);
+    }
+
+    if (arg_end > arg_start)
+        size = arg_end - arg_start;
+    if (size > CMDLINE_LEN)
+        size = CMDLINE_LEN;
+
+    if (bpf_probe_read_user(record->data.cmdline, size, (void *)arg_start) < 0)
+        size = 0;
+    record->data.size = size;
+
+#ifdef USE_RINGBUF
+    if (events.ringbuf_output(record, sizeof(*record), 0) < 0)
+#else
+    if (exec_data.perf_submit(args, record, sizeof(*record)) < 0)
+#endif
+        count_lost();
+
+    return 0;
+}
+
+/*
+ * Processes exiting, when their main thread does: user space forgets what they executed and counts
+ * what their pid does next for a new process. Their start tells which process it was if the pid
+ * was reused since.
+ */
+OUTPUT(exit_data);
+
+TRACEPOINT_PROBE(sched, sched_process_exit)
+{
+    struct task_struct *task = (struct task_struct *)bpf_get_current_task();
+    u64 pid_tgid = bpf_get_current_pid_tgid();
+    struct exit_data_t data = {.pid = pid_tgid >> 32};
+
+    // A thread, the process lives on
+    if ((u32)pid_tgid != data.pid)
+        return 0;
+
+#if LINUX_VERSION_CODE >= KERNEL_VERSION(5, 5, 0)
+    bpf_probe_read(&data.start, sizeof(data.start), &task->start_boottime);
+#else
+    bpf_probe_read(&data.start, sizeof(data.start), &task->real_start_time);
+#endif
+    SUBMIT(args, exit_data, RECORD_EXIT, data);
+
+    return 0;
+}
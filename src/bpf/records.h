@@ -0,0 +1,201 @@
+/*
+ * Layouts of the records sent to user space, shared by filters.c (BCC) and filters.bpf.c (CO-RE)
+ * and decoded in net.rs, where a test checks them against the Rust structures.
+ *
+ * Each record starts with a header: the kind of data it holds, the version of the layouts of the
+ * data and its length, padded so that the data is aligned. RECORD_VERSION must be increased with
+ * any change to the layout of a record, user space rejects the records it does not know.
+ *
+ * Needs u8 to u64, unsigned __int128 and TASK_COMM_LEN from the includer.
+ */
+#ifndef RECORDS_H
+#define RECORDS_H
+
+#define RECORD_TCP4        0
+#define RECORD_TCP6        1
+#define RECORD_UDP4        2
+#define RECORD_UDP6        3
+#define RECORD_TCP4_EVENT  4
+#define RECORD_TCP6_EVENT  5
+#define RECORD_ICMP4       6
+#define RECORD_ICMP6       7
+#define RECORD_DNS         8
+#define RECORD_TCP_PAYLOAD 9
+#define RECORD_UDP_PAYLOAD 10
+#define RECORD_EXEC        11
+#define RECORD_EXIT        12
+
+#define RECORD_VERSION     5
+
+#define EVENT_CONNECT 0
+#define EVENT_ACCEPT  1
+#define EVENT_CLOSE   2
+#define EVENT_STATE   3
+
+#define DNS_PAYLOAD  512
+#define PEEK_PAYLOAD 2048
+#define EXE_LEN      256
+#define CMDLINE_LEN  512
+
+struct header_t {
+    u32 kind;
+    u16 version;
+    u16 length;
+    u32 pad[2];
+};
+
+struct ipv4_data_t {
+    u32 pid;
+    u32 saddr;
+    u32 daddr;
+    u16 lport;
+    u16 dport;
+    u32 size;
+    u32 is_rx;
+    char comm[TASK_COMM_LEN];
+};
+
+struct ipv6_data_t {
+    unsigned __int128 saddr;
+    unsigned __int128 daddr;
+    u32 pid;
+    u16 lport;
+    u16 dport;
+    u32 size;
+    u32 is_rx;
+    char comm[TASK_COMM_LEN];
+};
+
+struct icmp4_data_t {
+    u32 pid;
+    u32 saddr;
+    u32 daddr;
+    u16 id;
+    u8 type;
+    u8 code;
+    u32 size;
+    u32 is_rx;
+    char comm[TASK_COMM_LEN];
+};
+
+struct icmp6_data_t {
+    unsigned __int128 saddr;
+    unsigned __int128 daddr;
+    u32 pid;
+    u16 id;
+    u8 type;
+    u8 code;
+    u32 size;
+    u32 is_rx;
+    char comm[TASK_COMM_LEN];
+};
+
+struct ipv4_event_t {
+    u32 pid;
+    u32 saddr;
+    u32 daddr;
+    u16 lport;
+    u16 dport;
+    u32 event;
+    u32 state; // TCP state of the socket once the event happened, TCP_* of the kernel
+    char comm[TASK_COMM_LEN];
+};
+
+struct ipv6_event_t {
+    unsigned __int128 saddr;
+    unsigned __int128 daddr;
+    u32 pid;
+    u16 lport;
+    u16 dport;
+    u32 event;
+    u32 state;
+    char comm[TASK_COMM_LEN];
+};
+
+// Traffic added up per flow, see AGGREGATE
+struct flow_t {
+    unsigned __int128 saddr;
+    unsigned __int128 daddr;
+    u32 kind;
+    u32 pid;
+    u16 lport;
+    u16 dport;
+    u8 icmp_type;
+    u8 icmp_code;
+    u16 generation;
+    char comm[TASK_COMM_LEN];
+};
+
+struct bytes_t {
+    u64 rx;
+    u64 tx;
+};
+
+struct dns_data_t {
+    u32 pid;
+    u32 size; // Bytes of 'payload' used
+    u32 is_tcp;
+    char comm[TASK_COMM_LEN];
+    u8 payload[DNS_PAYLOAD];
+};
+
+// Too large for the stack of a probe
+struct dns_record_t {
+    struct header_t header;
+    struct dns_data_t data;
+};
+
+struct payload_data_t {
+    unsigned __int128 saddr;
+    unsigned __int128 daddr;
+    u32 pid;
+    u16 lport;
+    u16 dport;
+    u32 family;
+    u32 size; // Bytes of 'payload' used
+    char comm[TASK_COMM_LEN];
+    u8 payload[PEEK_PAYLOAD];
+};
+
+// Too large for the stack of a probe
+struct payload_record_t {
+    struct header_t header;
+    struct payload_data_t data;
+};
+
+// The link of a payload record, without padding to be a key
+struct link_t {
+    unsigned __int128 saddr;
+    unsigned __int128 daddr;
+    u32 pid;
+    u16 lport;
+    u16 dport;
+    u32 family;
+    u32 pad;
+};
+
+struct exec_data_t {
+    u32 pid;
+    u32 ppid;
+    u32 uid;
+    u32 euid;
+    u64 start; // Nanoseconds since boot, as /proc/<pid>/stat
+    u32 size;  // Bytes of 'cmdline' used, the arguments separated by NUL
+    char comm[TASK_COMM_LEN];
+    char exe[EXE_LEN];
+    u8 cmdline[CMDLINE_LEN];
+};
+
+// Too large for the stack of a probe
+struct exec_record_t {
+    struct header_t header;
+    struct exec_data_t data;
+};
+
+struct exit_data_t {
+    u64 start; // Nanoseconds since boot, as for exec_data_t
+    u32 pid;
+    u32 pad;
+};
+
+#endif
@@ -0,0 +1,133 @@
+use std::error::Error;
+use std::time::Duration;
+
+use crate::net::SharedProcs;
+use crate::bcc_backend::BccBackend;
+use crate::synthetic::Synthetic;
+use crate::replay::{Replay, SharedRecorder};
+#[cfg(feature = "core")]
+use crate::core_backend::CoreBackend;
+
+///
+/// What to capture, from the command line.
+///
+#[derive(Clone)]
+pub struct Options {
+    pub tcp: bool,
+    pub udp: bool,
+    pub icmp: bool,
+    pub aggregate: bool, // Traffic added up in the kernel instead of an event per packet
+    pub recorder: Option<SharedRecorder>, // Where to also write the records, see replay.rs
+}
+
+impl Options {
+    ///
+    /// Everything, an event per packet, nothing recorded.
+    ///
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Options {
+            tcp: true,
+            udp: true,
+            icmp: true,
+            aggregate: false,
+            recorder: None,
+        }
+    }
+}
+
+///
+/// A way of loading the probes in the kernel and reading what they capture. The records of the
+/// probes are handed to the callbacks of net, which update the processes and their links.
+///
+pub trait CaptureBackend {
+    ///
+    /// Short description of the backend for the logs, with the mode used for 'options'.
+    ///
+    fn description(&self, options: &Options) -> String;
+
+    ///
+    /// Load the probes in the kernel and attach those enabled in 'options'. What they capture is
+    /// added to 'procs'.
+    ///
+    fn start(&mut self, options: &Options, procs: SharedProcs) -> Result<(), Box<dyn Error>>;
+
+    ///
+    /// Wait up to 'timeout' for records and hand them to the callbacks.
+    ///
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>>;
+
+    ///
+    /// Account the traffic added up in the kernel since the previous call and empty the map.
+    ///
+    fn drain_traffic(&mut self);
+
+    ///
+    /// Detach the probes and account all the traffic added up in the kernel and not read yet,
+    /// whatever its generation. Nothing more is captured after.
+    ///
+    fn stop(&mut self) {}
+
+    ///
+    /// Number of records lost by the probes since they were loaded, if it could be read.
+    ///
+    fn lost_events(&mut self) -> Option<u64>;
+
+    ///
+    /// Whether nothing more will be captured, e.g. at the end of a recording.
+    ///
+    fn finished(&self) -> bool {
+        false
+    }
+}
+
+// Names accepted on the command line
+pub const BACKENDS: [&str; 4] = ["bcc", "core", "synthetic", "replay"];
+
+///
+/// Returns the backend called 'name': "bcc" compiles filters.c when starting, "core" loads the
+/// object compiled from filters.bpf.c with the binary and needs a kernel with BTF, "synthetic"
+/// reads the records from the file 'events' and "replay" replays the recording 'events' at
+/// 'speed' times the speed it was recorded.
+///
+pub fn backend(name: &str, events: Option<&str>, speed: f64)
+    -> Result<Box<dyn CaptureBackend>, Box<dyn Error>>
+{
+    match name {
+        "bcc" => Ok(Box::new(BccBackend::new())),
+        #[cfg(feature = "core")]
+        "core" => Ok(Box::new(CoreBackend::new())),
+        #[cfg(not(feature = "core"))]
+        "core" => Err("netw was built without the CO-RE backend, rebuild with --features core"
+            .into()),
+        "synthetic" => match events {
+            Some(path) => Ok(Box::new(Synthetic::from_file(path)?)),
+            None => Err("the synthetic backend needs a file of events, see --events".into()),
+        },
+        "replay" => match events {
+            Some(path) => Ok(Box::new(Replay::open(path, speed)?)),
+            None => Err("the replay backend needs a recording, see --events".into()),
+        },
+        _ => Err(format!("unknown capture backend '{}', expected one of {:?}", name, BACKENDS)
+            .into()),
+    }
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backend_names() {
+        assert!(backend("bcc", None, 1.0).is_ok());
+        assert_eq!(backend("core", None, 1.0).is_ok(), cfg!(feature = "core"));
+        assert!(backend("synthetic", None, 1.0).is_err());
+        assert!(backend("synthetic", Some("/nonexistent"), 1.0).is_err());
+        assert!(backend("replay", Some("/nonexistent"), 1.0).is_err());
+        assert!(backend("pcap", None, 1.0).is_err());
+    }
+}
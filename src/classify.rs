@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::net::Prot;
+use crate::server_name;
+
+// Names of the ports registered on the host, the fixture of dns in the tests
+use crate::dns::service as registered;
+
+/*
+ * Application protocol spoken on a link: what the first bytes sent on it look like when the
+ * probes peeked at them, else the service its ports are registered for (/etc/services).
+ *
+ * The signatures only look at the start of what a client sends first:
+ *
+ *     TLS          handshake record, 0x16 0x03 then the minor version
+ *     HTTP         request line "METHOD target HTTP/1.x", or the HTTP/2 preface "PRI * HTTP/2.0"
+ *     SSH          "SSH-" and the version
+ *     BitTorrent   "\x13BitTorrent protocol" over TCP, over UDP a DHT query (bencoded dictionary),
+ *                  a uTP SYN or a tracker connection request
+ *     QUIC         long header packet (two highest bits set) of a known version
+ *     WireGuard    message type 1 to 4, three zero bytes, and the length of the message
+ */
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n";
+const BITTORRENT_HANDSHAKE: &[u8] = b"\x13BitTorrent protocol";
+const BITTORRENT_TRACKER: [u8; 8] = [0x00, 0x00, 0x04, 0x17, 0x27, 0x10, 0x19, 0x80];
+const UTP_SYN: u8 = 0x41; // Type 4, version 1
+const QUIC_VERSIONS: [u32; 2] = [0x00000001, 0x6b3343cf];
+const QUIC_DRAFTS: u32 = 0xff000000;
+
+// Services better known under another name
+const ALIASES: [(&str, &str); 1] = [("domain", "dns")];
+
+lazy_static! {
+    // Names of the ports per protocol, UDP or not, as looked up
+    static ref SERVICES: Mutex<HashMap<(u16, bool), String>> = Mutex::new(HashMap::new());
+}
+
+///
+/// Returns the application protocol the ports of a link of protocol 'prot' are registered for,
+/// the lowest port first: the service of the server, whichever end the link is. Empty when none
+/// is, and for ICMP.
+///
+pub fn by_port(prot: Prot, lport: u16, dport: u16) -> String {
+    let udp = match prot {
+        Prot::TCP => false,
+        Prot::UDP => true,
+        _ => return String::new(),
+    };
+
+    let (low, high) = if lport < dport { (lport, dport) } else { (dport, lport) };
+
+    [low, high].iter()
+        .filter(|&&port| port != 0)
+        .map(|&port| service(port, udp))
+        .find(|service| !service.is_empty())
+        .unwrap_or_default()
+}
+
+///
+/// Returns the application protocol of a link of protocol 'prot' if recognized in 'payload', the
+/// first bytes sent on it. TLS is named after the service of the ports when they have one, e.g.
+/// "https" or "imaps".
+///
+pub fn by_payload(prot: Prot, lport: u16, dport: u16, payload: &[u8]) -> Option<String> {
+    let app_proto = match prot {
+        Prot::TCP => tcp_signature(payload)?,
+        Prot::UDP => udp_signature(payload)?,
+        _ => return None,
+    };
+
+    if app_proto == "tls" {
+        match by_port(prot, lport, dport).as_str() {
+            "" | "http" => (),
+            service => return Some(service.to_string()),
+        }
+    }
+
+    Some(app_proto.to_string())
+}
+
+fn tcp_signature(payload: &[u8]) -> Option<&'static str> {
+    match payload {
+        [TLS_HANDSHAKE, 0x03, minor, ..] if *minor <= 0x04 => Some("tls"),
+        _ if payload.starts_with(HTTP2_PREFACE) => Some("http2"),
+        _ if server_name::is_http_request(payload) => Some("http"),
+        _ if payload.starts_with(b"SSH-") => Some("ssh"),
+        _ if payload.starts_with(BITTORRENT_HANDSHAKE) => Some("bittorrent"),
+        _ => None,
+    }
+}
+
+fn udp_signature(payload: &[u8]) -> Option<&'static str> {
+    if is_quic(payload) {
+        return Some("quic");
+    }
+
+    match (payload, payload.len()) {
+        // Handshake initiation, response, cookie reply and transport data
+        ([1, 0, 0, 0, ..], 148) | ([2, 0, 0, 0, ..], 92) | ([3, 0, 0, 0, ..], 64) => {
+            Some("wireguard")
+        }
+        ([4, 0, 0, 0, ..], n) if n >= 32 && n % 16 == 0 => Some("wireguard"),
+        // DHT queries, e.g. "d1:ad2:id20:...e1:q4:ping1:t2:aa1:y1:qe"
+        _ if payload.starts_with(b"d1:") && contains(payload, b"1:y1:q") => Some("bittorrent"),
+        ([UTP_SYN, 0, ..], n) if n >= 20 => Some("bittorrent"),
+        _ if payload.len() >= 16 && payload.starts_with(&BITTORRENT_TRACKER) => Some("bittorrent"),
+        _ => None,
+    }
+}
+
+// QUIC long header: flags, then the version on 32 bits
+fn is_quic(payload: &[u8]) -> bool {
+    match payload {
+        [flags, a, b, c, d, ..] if flags & 0xc0 == 0xc0 => {
+            let version = u32::from_be_bytes([*a, *b, *c, *d]);
+
+            QUIC_VERSIONS.contains(&version) || version & 0xffffff00 == QUIC_DRAFTS
+        }
+        _ => false,
+    }
+}
+
+fn contains(x: &[u8], pattern: &[u8]) -> bool {
+    x.windows(pattern.len()).any(|window| window == pattern)
+}
+
+// The name of 'port', aliased, empty if none
+fn service(port: u16, udp: bool) -> String {
+    let mut services = SERVICES.lock().unwrap();
+
+    services.entry((port, udp))
+        .or_insert_with(|| {
+            let service = registered(port, udp).unwrap_or_default();
+
+            match ALIASES.iter().find(|(name, _)| *name == service) {
+                Some((_, alias)) => alias.to_string(),
+                None => service,
+            }
+        })
+        .clone()
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_ports() {
+        assert_eq!(by_port(Prot::TCP, 43210, 443), "https");
+        assert_eq!(by_port(Prot::TCP, 22, 43210), "ssh", "server side not classified");
+        assert_eq!(by_port(Prot::UDP, 5353, 53), "dns");
+        assert_eq!(by_port(Prot::UDP, 5353, 5353), "mdns");
+        assert_eq!(by_port(Prot::TCP, 5353, 43210), "", "UDP service of a TCP link");
+        assert_eq!(by_port(Prot::TCP, 43210, 44333), "");
+        assert_eq!(by_port(Prot::ICMP(8, 0), 42, 0), "");
+    }
+
+    #[test]
+    fn classify_tcp_payloads() {
+        let hello = server_name::client_hello("example.com");
+        let payload = |x: &[u8]| by_payload(Prot::TCP, 43210, 44333, x);
+
+        assert_eq!(by_payload(Prot::TCP, 43210, 443, &hello), Some(String::from("https")));
+        assert_eq!(payload(&hello), Some(String::from("tls")));
+        assert_eq!(payload(&server_name::http_request("example.com")),
+            Some(String::from("http")));
+        assert_eq!(payload(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"), Some(String::from("http2")));
+        assert_eq!(payload(b"SSH-2.0-OpenSSH_9.6\r\n"), Some(String::from("ssh")));
+        assert_eq!(payload(b"\x13BitTorrent protocol\0\0\0\0"), Some(String::from("bittorrent")));
+        assert_eq!(payload(b"\x16\x03"), None, "cut TLS record recognized");
+        assert_eq!(payload(b"EHLO example.com\r\n"), None);
+    }
+
+    #[test]
+    fn classify_udp_payloads() {
+        let payload = |x: &[u8]| by_payload(Prot::UDP, 43210, 4433, x);
+
+        let mut initial = vec![0xc3, 0, 0, 0, 1];
+        initial.resize(1200, 0);
+        assert_eq!(payload(&initial), Some(String::from("quic")));
+        initial[4] = 2;
+        assert_eq!(payload(&initial), None, "unknown QUIC version recognized");
+
+        let mut handshake = vec![1, 0, 0, 0];
+        handshake.resize(148, 0x5a);
+        assert_eq!(payload(&handshake), Some(String::from("wireguard")));
+        assert_eq!(payload(&handshake[..100]), None);
+
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert_eq!(payload(ping), Some(String::from("bittorrent")));
+        let mut syn = vec![UTP_SYN, 0];
+        syn.resize(20, 0);
+        assert_eq!(payload(&syn), Some(String::from("bittorrent")));
+
+        assert_eq!(payload(&[0u8; 48]), None);
+    }
+}
@@ -0,0 +1,199 @@
+use chrono::{FixedOffset, Local, Utc, TimeZone, NaiveDate, NaiveDateTime};
+
+///
+/// Time zone used to decide to which day the captured traffic belongs.
+///
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Tz {
+    Local,
+    Utc,
+    Fixed(FixedOffset),
+}
+
+impl Tz {
+    ///
+    /// Parse the value given on the command line: "local", "utc" or an offset to UTC in the form
+    /// "+HH:MM", "-HH:MM", "+HHMM" or "+HH". The offsets are whole quarters of an hour, as those
+    /// of all the time zones: the days then start on a quarter, see database::get_dates().
+    ///
+    pub fn parse(value: &str) -> Option<Tz> {
+        match value.to_lowercase().as_str() {
+            "local" => return Some(Tz::Local),
+            "utc"   => return Some(Tz::Utc),
+            _ => (),
+        }
+
+        let sign = match value.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return None,
+        };
+
+        let digits: String = value[1..].chars().filter(|c| *c != ':').collect();
+        if !digits.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        let (hours, minutes) = match digits.len() {
+            2 => (&digits[0..2], "0"),
+            4 => (&digits[0..2], &digits[2..4]),
+            _ => return None,
+        };
+        let hours: i32 = hours.parse().ok()?;
+        let minutes: i32 = minutes.parse().ok()?;
+
+        if hours > 23 || minutes > 59 || minutes % 15 != 0 {
+            return None;
+        }
+
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).map(Tz::Fixed)
+    }
+
+    ///
+    /// Returns the UNIX timestamps of the first second of the day containing 'ts' and of the first
+    /// second of the following day.
+    ///
+    pub fn day_bounds(&self, ts: i64) -> (i64, i64) {
+        match self {
+            Tz::Local => day_bounds(&Local, ts),
+            Tz::Utc => day_bounds(&Utc, ts),
+            Tz::Fixed(offset) => day_bounds(offset, ts),
+        }
+    }
+
+    ///
+    /// Parse a point in time given on the command line in this time zone: a day "YYYY-MM-DD"
+    /// (its first second), "YYYY-MM-DD HH:MM", "YYYY-MM-DD HH:MM:SS" or a UNIX timestamp.
+    ///
+    pub fn parse_time(&self, value: &str) -> Option<i64> {
+        if let Ok(ts) = value.parse::<i64>() {
+            return Some(ts);
+        }
+
+        if let Ok(day) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+            let noon = day.and_hms_opt(12, 0, 0).unwrap();
+            return self.local_timestamp(&noon).map(|ts| self.day_bounds(ts).0);
+        }
+
+        ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+            .iter()
+            .find_map(|fmt| NaiveDateTime::parse_from_str(value, fmt).ok())
+            .and_then(|dt| self.local_timestamp(&dt))
+    }
+
+    ///
+    /// Returns the UNIX timestamp of a date and time read on a clock of this time zone, the
+    /// earliest one if it is ambiguous.
+    ///
+    fn local_timestamp(&self, dt: &NaiveDateTime) -> Option<i64> {
+        match self {
+            Tz::Local => Local.from_local_datetime(dt).earliest().map(|dt| dt.timestamp()),
+            Tz::Utc => Utc.from_local_datetime(dt).earliest().map(|dt| dt.timestamp()),
+            Tz::Fixed(offset) => offset.from_local_datetime(dt).earliest().map(|dt| dt.timestamp()),
+        }
+    }
+
+    ///
+    /// Format the UNIX timestamp 'ts' in this time zone (see chrono::format::strftime).
+    ///
+    pub fn format(&self, ts: i64, fmt: &str) -> String {
+        match self {
+            Tz::Local => Local.timestamp_opt(ts, 0).unwrap().format(fmt).to_string(),
+            Tz::Utc => Utc.timestamp_opt(ts, 0).unwrap().format(fmt).to_string(),
+            Tz::Fixed(offset) => offset.timestamp_opt(ts, 0).unwrap().format(fmt).to_string(),
+        }
+    }
+}
+
+///
+/// Returns the current UNIX timestamp.
+///
+pub fn now() -> i64 {
+    Utc::now().timestamp()
+}
+
+fn day_bounds<T: TimeZone>(tz: &T, ts: i64) -> (i64, i64) {
+    let day = tz.timestamp_opt(ts, 0).unwrap().naive_local().date();
+    let next_day = day.succ_opt().unwrap();
+
+    // When a DST change happens at midnight the day starts at the first valid local time
+    let first_second = |date: chrono::NaiveDate| {
+        let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+        match tz.from_local_datetime(&midnight).earliest() {
+            Some(dt) => dt.timestamp(),
+            None => tz.from_local_datetime(&(midnight + chrono::Duration::hours(1)))
+                .earliest()
+                .map(|dt| dt.timestamp())
+                .unwrap_or(ts),
+        }
+    };
+
+    (first_second(day), first_second(next_day))
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_names() {
+        assert_eq!(Tz::parse("local"), Some(Tz::Local));
+        assert_eq!(Tz::parse("UTC"), Some(Tz::Utc));
+    }
+
+    #[test]
+    fn parse_offsets() {
+        let plus_two = FixedOffset::east_opt(2 * 3600).unwrap();
+        let minus_five_thirty = FixedOffset::west_opt(5 * 3600 + 30 * 60).unwrap();
+        let plus_nine = FixedOffset::east_opt(9 * 3600).unwrap();
+
+        assert_eq!(Tz::parse("+02:00"), Some(Tz::Fixed(plus_two)));
+        assert_eq!(Tz::parse("-0530"), Some(Tz::Fixed(minus_five_thirty)));
+        assert_eq!(Tz::parse("+09"), Some(Tz::Fixed(plus_nine)));
+        assert!(Tz::parse("+05:45").is_some(), "quarter of an hour rejected");
+    }
+
+    #[test]
+    fn day_bounds_utc() {
+        // 07/03/2021 14:02:05 UTC
+        let ts = 1625320925;
+
+        assert_eq!(Tz::Utc.day_bounds(ts), (1625270400, 1625356800));
+        assert_eq!(Tz::Utc.format(ts, "%m/%d/%y %H:%M"), "07/03/21 14:02");
+    }
+
+    #[test]
+    fn day_bounds_fixed_offset() {
+        // 07/03/2021 23:30:00 UTC, already the next day at UTC+02:00
+        let ts = 1625355000;
+        let tz = Tz::parse("+02:00").unwrap();
+
+        assert_eq!(tz.day_bounds(ts), (1625349600, 1625436000));
+        assert_eq!(tz.format(ts, "%m/%d/%y"), "07/04/21");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(Tz::parse("Europe/Paris"), None);
+        assert_eq!(Tz::parse("+2"), None);
+        assert_eq!(Tz::parse("+25:00"), None);
+        assert_eq!(Tz::parse("+02:xx"), None);
+        assert_eq!(Tz::parse("+05:10"), None, "offset not a whole quarter of an hour");
+    }
+
+    #[test]
+    fn parse_time() {
+        let tz = Tz::parse("+02:00").unwrap();
+
+        assert_eq!(Tz::Utc.parse_time("2021-07-03"), Some(1625270400));
+        assert_eq!(tz.parse_time("2021-07-03"), Some(1625263200));
+        assert_eq!(tz.parse_time("2021-07-03 16:02"), Some(1625320920));
+        assert_eq!(tz.parse_time("2021-07-03T16:02:05"), Some(1625320925));
+        assert_eq!(tz.parse_time("1625320925"), Some(1625320925));
+        assert_eq!(tz.parse_time("07/03/21"), None);
+    }
+}
@@ -0,0 +1,175 @@
+use libbpf_rs::{Link, MapFlags, Object, ObjectBuilder, RingBuffer, RingBufferBuilder};
+
+use std::error::Error;
+use std::fs;
+use std::convert::TryInto;
+use std::time::Duration;
+
+use crate::net::{self, SharedProcs};
+use crate::capture::{CaptureBackend, Options};
+use crate::replay::recording;
+
+// filters.bpf.c, compiled by build.rs
+static OBJECT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/filters.bpf.o"));
+
+// Programs of filters.bpf.c per protocol, a program is named after the function it probes
+const TCP_PROGRAMS: [&str; 8] = [
+    "tcp_sendmsg", "tcp_cleanup_rbuf", "tcp_connect", "inet_csk_accept_ret", "tcp_close",
+    "inet_sock_set_state", "tcp_recvmsg", "tcp_recvmsg_ret",
+];
+const UDP_PROGRAMS: [&str; 6] = [
+    "udp_sendmsg", "udpv6_sendmsg",
+    "udp_recvmsg", "udp_recvmsg_ret", "udpv6_recvmsg", "udpv6_recvmsg_ret",
+];
+const ICMP_PROGRAMS: [&str; 10] = [
+    "ping_v4_sendmsg", "ping_v6_sendmsg", "raw_sendmsg", "rawv6_sendmsg",
+    "ping_recvmsg", "ping_recvmsg_ret", "raw_recvmsg", "raw_recvmsg_ret",
+    "rawv6_recvmsg", "rawv6_recvmsg_ret",
+];
+const PROCESS_PROGRAMS: [&str; 2] = ["sched_process_exec", "sched_process_exit"];
+
+// Index of the switches in the "config" map of filters.bpf.c
+const CONFIG_AGGREGATE: u32 = 0;
+const CONFIG_GENERATION: u32 = 1;
+
+///
+/// Capture with the object compiled once from filters.bpf.c and relocated by libbpf with the BTF
+/// of the running kernel: no compiler nor headers on the host, but a kernel >= 5.8 built with
+/// CONFIG_DEBUG_INFO_BTF.
+///
+pub struct CoreBackend {
+    ringbuf: Option<RingBuffer<'static>>,
+    links: Vec<Link>, // The probes stay attached as long as their link lives
+    object: Option<Object>,
+    current: u16, // Generation of the entries of "traffic" the probes add to, see filters.c
+    procs: Option<SharedProcs>,
+}
+
+impl CoreBackend {
+    pub fn new() -> Self {
+        CoreBackend {
+            ringbuf: None,
+            links: Vec::new(),
+            object: None,
+            current: 0,
+            procs: None,
+        }
+    }
+
+    ///
+    /// Account and empty the entries of "traffic" the probes no longer add to, when they are in
+    /// the generation 'current', all of them without it. See net::flow_drained.
+    ///
+    fn drain(&self, current: Option<u16>) {
+        let (object, procs) = match (self.object.as_ref(), self.procs.as_ref()) {
+            (Some(object), Some(procs)) => (object, procs),
+            _ => return,
+        };
+        let traffic = object.map("traffic").unwrap();
+
+        let keys: Vec<Vec<u8>> = traffic.keys()
+            .filter(|key| net::flow_drained(key, current))
+            .collect();
+
+        for key in keys {
+            if let Ok(Some(value)) = traffic.lookup(&key, MapFlags::ANY) {
+                net::account_flow(procs, &key, &value);
+            }
+            let _ = traffic.delete(&key);
+        }
+    }
+}
+
+impl CaptureBackend for CoreBackend {
+    fn description(&self, options: &Options) -> String {
+        format!(
+            "CO-RE, ring buffer{}",
+            if options.aggregate { ", traffic added up in the kernel" } else { "" },
+        )
+    }
+
+    fn start(&mut self, options: &Options, procs: SharedProcs) -> Result<(), Box<dyn Error>> {
+        let release = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+
+        if !net::has_ringbuf(&release) {
+            return Err(format!("kernel {} has no BPF ring buffer, use the bcc backend",
+                release.trim()).into());
+        }
+        if !std::path::Path::new("/sys/kernel/btf/vmlinux").exists() {
+            return Err("kernel built without BTF, use the bcc backend".into());
+        }
+
+        let mut object = ObjectBuilder::default().open_memory(OBJECT)?.load()?;
+
+        if options.aggregate {
+            object.map("config").unwrap().update(
+                &CONFIG_AGGREGATE.to_ne_bytes(),
+                &1u32.to_ne_bytes(),
+                MapFlags::ANY,
+            )?;
+        }
+
+        let programs = [
+            (options.tcp, &TCP_PROGRAMS[..]),
+            (options.udp, &UDP_PROGRAMS[..]),
+            (options.icmp, &ICMP_PROGRAMS[..]),
+            (true, &PROCESS_PROGRAMS[..]),
+        ];
+        for (_, names) in programs.iter().filter(|(enabled, _)| *enabled) {
+            for name in names.iter() {
+                let program = object.prog_mut(name)
+                    .ok_or_else(|| format!("no program {} in filters.bpf.o", name))?;
+                self.links.push(program.attach()?);
+            }
+        }
+
+        let mut callback = recording(&options.recorder, net::record_cb(procs.clone()));
+        let mut builder = RingBufferBuilder::new();
+        builder.add(object.map("events").unwrap(), move |data: &[u8]| {
+            callback(data);
+            0
+        })?;
+
+        self.ringbuf = Some(builder.build()?);
+        self.object = Some(object);
+        self.procs = Some(procs);
+
+        Ok(())
+    }
+
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        self.ringbuf.as_ref().unwrap().poll(timeout)?;
+        Ok(())
+    }
+
+    fn drain_traffic(&mut self) {
+        let object = match self.object.as_ref() {
+            Some(object) => object,
+            None => return,
+        };
+
+        // The probes move to a new generation, the entries they may still be adding to are kept
+        self.current = self.current.wrapping_add(1);
+        let moved = object.map("config").unwrap().update(
+            &CONFIG_GENERATION.to_ne_bytes(),
+            &(self.current as u32).to_ne_bytes(),
+            MapFlags::ANY,
+        );
+        if moved.is_ok() {
+            self.drain(Some(self.current));
+        }
+    }
+
+    fn stop(&mut self) {
+        // Once their links are dropped, the probes add nothing more
+        self.links.clear();
+        self.drain(None);
+    }
+
+    fn lost_events(&mut self) -> Option<u64> {
+        let lost = self.object.as_ref()?.map("lost")?;
+        let count = lost.lookup(&0u32.to_ne_bytes(), MapFlags::ANY).ok()??;
+
+        Some(u64::from_ne_bytes(count[..8].try_into().unwrap()))
+    }
+}
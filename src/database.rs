@@ -1,9 +1,13 @@
-use rusqlite::{Connection, Result, NO_PARAMS, params, Transaction};
+use rusqlite::{Connection, OpenFlags, Result, NO_PARAMS, params, Transaction};
 use rusqlite::types::{FromSql, FromSqlResult, FromSqlError, ValueRef};
 
-use crate::net::{Process, Link, Prot};
+use crate::net::{Process, Link, Prot, ProtFilter, TcpState};
+use crate::clock::Tz;
+use crate::migrations::{check_version, migrate};
 use crate::{DATES, Date};
 use std::net::{IpAddr};
+use std::time::Duration;
+use std::collections::HashMap;
 
 ///
 /// Newtype pattern. Wrapper around net::IpAddr to implement a the FromSql trait on a foreign type.
@@ -32,60 +36,50 @@ impl FromSql for IpAddrWrapper {
     }
 }
 
+// Width, in seconds, of the time buckets the traffic is recorded in
+pub const BUCKET_WIDTH: i64 = 60;
+
+// How long to wait for another connection to release the database before giving up
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+///
+/// Returns the UNIX timestamp of the start of the bucket containing 'ts'.
+///
+pub fn bucket_of(ts: i64) -> i64 {
+    ts - ts.rem_euclid(BUCKET_WIDTH)
+}
+
+///
+/// The traffic is recorded per process and per link in fixed time buckets: each row holds what was
+/// captured between 'ts' (a UNIX timestamp) and 'ts' + BUCKET_WIDTH. See migrations.rs for the
+/// schema.
+///
 pub fn create_db(db_name: &String) -> Result<Connection> {
-    let db = Connection::open(db_name).unwrap();
-
-    db.execute(
-        "CREATE TABLE processes (
-            p_pid       INTEGER NOT NULL,
-            p_date_id   TEXT NOT NULL,
-            p_name      TEXT NOT NULL DEFAULT '',
-            p_rx        INTEGER,
-            p_tx        INTEGER,
-            CONSTRAINT processes_fk_0 FOREIGN KEY (p_date_id) REFERENCES dates(date_id),
-            PRIMARY KEY (p_pid, p_date_id)
-        );",
-        NO_PARAMS,
-    )?;
-    db.execute(
-        "CREATE TABLE protocols (
-            prot_id     INTEGER PRIMARY KEY ASC,
-            prot_name   TEXT NOT NULL DEFAULT ''
-        );",
-        NO_PARAMS,
-    )?;
-    db.execute(
-        "CREATE TABLE dates (
-            date_id     INTEGER PRIMARY KEY ASC,
-            date_int    INTEGER UNIQUE NOT NULL
-        );",
-        NO_PARAMS,
-    )?;
-    db.execute(
-        "CREATE TABLE links (
-            l_p_pid     INTEGER,
-            l_date_id   TEXT NOT NULL,
-            l_saddr     TEXT NULL DEFAULT '',
-            l_daddr     TEXT NULL DEFAULT '',
-            l_lport     INTEGER,
-            l_dport     INTEGER,
-            l_rx        INTEGER,
-            l_tx        INTEGER,
-            l_prot_id   INTEGER,
-            l_domain    TEXT NOT NULL DEFAULT '',
-            CONSTRAINT links_fk_0 FOREIGN KEY (l_p_pid) REFERENCES processes(p_id),
-            CONSTRAINT links_fk_1 FOREIGN KEY (l_date_id) REFERENCES dates(date_id),
-            CONSTRAINT links_fk_2 FOREIGN KEY (l_prot_id) REFERENCES protocols(prot_id),
-            PRIMARY KEY (l_p_pid, l_date_id, l_saddr, l_daddr, l_lport, l_dport)
-        );",
-        NO_PARAMS,
-    )?;
+    open_db(db_name)
+}
+
+///
+/// Open the database 'db_name', creating it or upgrading its schema if needed.
+///
+pub fn open_db(db_name: &String) -> Result<Connection> {
+    let mut db = Connection::open(db_name)?;
+
+    // Several threads or processes may use the database at the same time, wait for each other
+    db.busy_timeout(BUSY_TIMEOUT)?;
+    migrate(&mut db)?;
 
     Ok(db)
 }
 
-pub fn open_db(db_name: &String) -> Result<Connection> {
-    let db = Connection::open(db_name).unwrap();
+///
+/// Open the existing database 'db_name' without writing to it, e.g. to query it while the daemon
+/// records. Its schema must be the one of this build, see migrations::check_version().
+///
+pub fn open_db_read_only(db_name: &str) -> Result<Connection> {
+    let db = Connection::open_with_flags(db_name, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    db.busy_timeout(BUSY_TIMEOUT)?;
+    check_version(&db)?;
 
     Ok(db)
 }
@@ -93,14 +87,14 @@ pub fn open_db(db_name: &String) -> Result<Connection> {
 ///
 /// Returns the number of rows changed.
 ///
-fn insert_proc(transaction: &Transaction, p: &Process, date: u32) -> Result<usize> {
+fn insert_proc(transaction: &Transaction, p: &Process, bucket: i64) -> Result<usize> {
     let (pid, name, _tlinks, _ulinks, rx, tx) = p.get_all_info();
 
     let ret = transaction.execute(
-        "INSERT INTO processes (p_pid, p_date_id, p_name, p_rx, p_tx)
-         VALUES (?1, (SELECT date_id FROM dates WHERE date_int=?2), ?3, ?4, ?5)
-         ON CONFLICT(p_pid, p_date_id) DO UPDATE SET p_rx = p_rx+?4, p_tx = p_tx+?5",
-        params![pid, date, name, rx, tx]
+        "INSERT INTO processes (p_pid, p_start, p_ts, p_name, p_rx, p_tx)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+         ON CONFLICT(p_pid, p_start, p_ts) DO UPDATE SET p_rx = p_rx+?5, p_tx = p_tx+?6",
+        params![pid, p.start.unwrap_or(0), bucket, name, rx, tx]
     )?;
 
     Ok(ret)
@@ -109,148 +103,454 @@ fn insert_proc(transaction: &Transaction, p: &Process, date: u32) -> Result<usiz
 ///
 /// Returns the number of rows changed.
 ///
-fn insert_link(transaction: &Transaction, pid: u32, l: &Link, date: u32) -> Result<usize> {
-    let (saddr, daddr, lport, dport, rx, tx, prot, domain) = l.get_all_info();
-
-    let ret = transaction.execute(
-        "INSERT INTO links (l_p_pid, l_date_id,
-            l_saddr, l_daddr, l_lport, l_dport, l_rx, l_tx, l_prot_id, l_domain)
-         VALUES (?1, (SELECT date_id FROM dates WHERE date_int=?2),
-            ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
-         ON CONFLICT(l_p_pid, l_date_id, l_saddr, l_daddr, l_lport, l_dport)
-         DO UPDATE SET l_rx = l_rx+?7, l_tx = l_tx+?8",
-        params![pid, date, saddr, daddr, lport, dport, rx, tx, prot, domain]
+fn insert_info(transaction: &Transaction, p: &Process) -> Result<usize> {
+    let mut ret = transaction.execute(
+        "INSERT INTO process_info (pi_pid, pi_start, pi_exe, pi_cmdline, pi_ppid, pi_uid, pi_euid,
+            pi_exited)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+         ON CONFLICT(pi_pid, pi_start) DO UPDATE SET
+            pi_exe = CASE WHEN ?3 = '' THEN pi_exe ELSE ?3 END,
+            pi_cmdline = CASE WHEN ?4 = '' THEN pi_cmdline ELSE ?4 END,
+            pi_ppid = COALESCE(?5, pi_ppid), pi_uid = COALESCE(?6, pi_uid),
+            pi_euid = COALESCE(?7, pi_euid), pi_exited = COALESCE(?8, pi_exited)",
+        params![
+            p.pid, p.start.unwrap_or(0), p.exe, p.cmdline.join("\0"), p.ppid, p.uid, p.euid,
+            p.exited
+        ]
     )?;
 
+    for (uid, name) in [(p.uid, &p.user), (p.euid, &p.euser)] {
+        if let (Some(uid), false) = (uid, name.is_empty()) {
+            ret += transaction.execute(
+                "INSERT OR REPLACE INTO users (u_uid, u_name) VALUES (?1, ?2)",
+                params![uid, name],
+            )?;
+        }
+    }
+
     Ok(ret)
 }
 
 ///
-/// Add processes and links contained in 'procs' to the database 'db'.
+/// Returns the number of rows changed.
 ///
-pub fn update_db(db: &mut Connection, procs: &Vec<Process>, date: u32) -> Result<()> {
-    let transaction = db.transaction().unwrap();
+fn insert_link(transaction: &Transaction, p: &Process, l: &Link, bucket: i64) -> Result<usize> {
+    let (saddr, daddr, lport, dport, rx, tx, prot, domain, server, opened, closed) =
+        l.get_all_info();
+
+    // There is a protocol per type and code of ICMP message, known once seen
+    if let Prot::ICMP(..) = l.prot {
+        transaction.execute(
+            "INSERT OR IGNORE INTO protocols (prot_id, prot_name) VALUES (?1, ?2)",
+            params![prot, l.prot.name()],
+        )?;
+    }
 
     let ret = transaction.execute(
-        "INSERT OR IGNORE INTO dates (date_int) VALUES (?1)",
-        params![date],
+        "INSERT INTO links (l_p_pid, l_p_start, l_ts,
+            l_saddr, l_daddr, l_lport, l_dport, l_rx, l_tx, l_prot_id, l_domain,
+            l_opened, l_closed, l_server, l_app_proto, l_tcp_state)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)
+         ON CONFLICT(l_p_pid, l_p_start, l_ts, l_prot_id, l_saddr, l_daddr, l_lport, l_dport)
+         DO UPDATE SET l_rx = l_rx+?8, l_tx = l_tx+?9,
+            l_domain = CASE WHEN ?11 = '' THEN l_domain ELSE ?11 END,
+            l_opened = COALESCE(?12, l_opened), l_closed = COALESCE(?13, l_closed),
+            l_server = CASE WHEN ?14 = '' THEN l_server ELSE ?14 END,
+            l_app_proto = CASE WHEN ?15 = '' THEN l_app_proto ELSE ?15 END,
+            l_tcp_state = COALESCE(?16, l_tcp_state)",
+        params![
+            p.pid, p.start.unwrap_or(0), bucket, saddr, daddr, lport, dport, rx, tx, prot, domain,
+            opened, closed, server, l.app_proto, l.tcp_state.map(|state| state.name())
+        ]
     )?;
 
+    Ok(ret)
+}
+
+///
+/// Add processes and links contained in 'procs' to the database 'db', in the bucket containing the
+/// UNIX timestamp 'ts'.
+///
+pub fn update_db(db: &mut Connection, procs: &Vec<Process>, ts: i64) -> Result<()> {
+    let transaction = db.transaction().unwrap();
+    let bucket = bucket_of(ts);
+
     for p in procs {
-        insert_proc(&transaction, &p, date)?;
+        insert_proc(&transaction, &p, bucket)?;
 
-        let pid = p.get_pid();
+        if p.has_details() {
+            insert_info(&transaction, p)?;
+        }
 
-        for tl in p.get_tlinks() {
-            insert_link(&transaction, pid, &tl, date)?;
+        for l in p.links() {
+            insert_link(&transaction, p, l, bucket)?;
         }
+    }
+
+    transaction.commit()
+}
+
+///
+/// State of the in-memory counters at the time of the last successful write to the database. The
+/// counters kept in memory are cumulative, so only the difference with the checkpoint must be
+/// added to the database, otherwise the same traffic is counted again at each flush.
+///
+pub struct Checkpoint {
+    persisted: Vec<Process>,
+    lost: u64,  // records lost by the probes, see flush_lost()
+}
 
-        for ul in p.get_ulinks() {
-            insert_link(&transaction, pid, &ul, date)?;
+impl Checkpoint {
+    pub fn new() -> Self {
+        Checkpoint {
+            persisted: Vec::new(),
+            lost: 0,
         }
     }
 
-    if ret == 1 {
-        let tmp = date / 10000;
-        let month = tmp / 100;
-        let day = tmp % 100;
-        let date_str = String::from(format!("{:02}/{:02}", month, day));
+    ///
+    /// Returns what has not been written to the database yet, per process and per link.
+    ///
+    pub fn deltas(&self, procs: &Vec<Process>) -> Vec<Process> {
+        procs
+            .iter()
+            .filter_map(|p| p.delta(self.persisted.iter().find(|x| *x == p)))
+            .collect()
+    }
 
-        let mut dates = DATES.lock().unwrap();
-        dates.push( Date { int_form: date, str_form: date_str } );
+    ///
+    /// The counters start from zero again (see net::rollover()), the state of the connections
+    /// already written is kept so that they are not written again.
+    ///
+    pub fn restart(&mut self) {
+        for p in self.persisted.iter_mut() {
+            p.reset();
+        }
     }
 
-    transaction.commit()
+    ///
+    /// Mark 'procs' as written to the database.
+    ///
+    pub fn commit(&mut self, procs: Vec<Process>) {
+        self.persisted = procs;
+    }
 }
 
-fn get_links(db: &Connection, p: &mut Process) {
-    let mut stmt = db.prepare_cached(
-        "SELECT l.l_saddr, l.l_daddr, l.l_lport,
-            l.l_dport, l.l_rx, l.l_tx, l.l_prot_id, l.l_domain
-         FROM links l, dates
-         WHERE l.l_p_pid = :pid AND dates.date_int = :date_int;"
+///
+/// Write to the database the traffic seen since the last flush, in the bucket containing 'ts'. The
+/// checkpoint is only moved forward if the write succeeded, so that nothing is lost if the
+/// database is busy.
+///
+/// Returns the number of processes written.
+///
+pub fn flush(db: &mut Connection, checkpoint: &mut Checkpoint, procs: Vec<Process>, ts: i64)
+    -> Result<usize>
+{
+    let deltas = checkpoint.deltas(&procs);
+
+    if !deltas.is_empty() {
+        update_db(db, &deltas, ts)?;
+    }
+    checkpoint.commit(procs);
+
+    Ok(deltas.len())
+}
+
+///
+/// Write to the database the number of records lost by the probes since the last flush, 'lost'
+/// being the number lost since the capture started (see net::lost_events()).
+///
+/// Returns the number of records written.
+///
+pub fn flush_lost(db: &Connection, checkpoint: &mut Checkpoint, lost: u64, ts: i64)
+    -> Result<u64>
+{
+    let count = lost.saturating_sub(checkpoint.lost);
+
+    if count > 0 {
+        db.execute(
+            "INSERT INTO lost (lo_ts, lo_count) VALUES (?1, ?2)
+             ON CONFLICT(lo_ts) DO UPDATE SET lo_count = lo_count+?2",
+            params![bucket_of(ts), count as i64],
+        )?;
+    }
+    checkpoint.lost = lost;
+
+    Ok(count)
+}
+
+///
+/// Returns the number of records lost by the probes between the UNIX timestamps 'from'
+/// (included) and 'to' (excluded).
+///
+pub fn load_lost(db: &Connection, from: i64, to: i64) -> u64 {
+    let count: i64 = db.query_row(
+        "SELECT COALESCE(SUM(lo_count), 0) FROM lost WHERE lo_ts >= ?1 AND lo_ts < ?2;",
+        params![from, to],
+        |row| row.get(0)
     ).unwrap();
 
-    let links = stmt.query_map_named(
-        &[(":pid", &p.pid), (":date_int", &p.date)], |row| {
-        let saddr: IpAddrWrapper = row.get(0)?;
-        let daddr: IpAddrWrapper = row.get(1)?;
-
-        Ok(
-            Link {
-                saddr: saddr.0,
-                daddr: daddr.0,
-                lport: row.get(2)?,
-                dport: row.get(3)?,
-                rx: row.get(4)?,
-                tx: row.get(5)?,
-                prot: row.get(6)?,
-                domain: row.get(7)?,
-            }
-        )
-    }).unwrap();
+    count as u64
+}
 
-    for item in links {
-        let link = item.unwrap();
+///
+/// What to load from the database: the traffic recorded between the UNIX timestamps 'from'
+/// (included) and 'to' (excluded), optionally restricted to some processes or to a protocol.
+///
+#[derive(Clone)]
+pub struct Selection {
+    pub from: i64,
+    pub to: i64,
+    pub pid: Option<u32>,
+    pub name: Option<String>,
+    pub prot: Option<ProtFilter>, // only the links of this protocol, and their traffic
+    pub limit: Option<usize>,  // only the processes with the most traffic
+}
 
-        //println!("    link: {} {} {} {}", link.saddr, link.prot, link.rx, link.tx);
+impl Selection {
+    pub fn new(from: i64, to: i64) -> Self {
+        Selection {
+            from,
+            to,
+            pid: None,
+            name: None,
+            prot: None,
+            limit: None,
+        }
+    }
 
-        match link.prot {
-            Prot::TCP => p.tlinks.push(link),
-            Prot::UDP => p.ulinks.push(link),
-            _ => (),
-        };
+    pub fn pid(&mut self, pid: u32) -> &mut Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    pub fn name(&mut self, name: String) -> &mut Self {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn prot(&mut self, prot: ProtFilter) -> &mut Self {
+        self.prot = Some(prot);
+        self
+    }
+
+    pub fn limit(&mut self, limit: usize) -> &mut Self {
+        self.limit = Some(limit);
+        self
     }
-    //println!("tlinks: {} ulinks: {}", p.tlinks.len(), p.ulinks.len());
 }
 
-pub fn get_procs(db: &Connection) -> Vec<Process> {
-    let mut stmt = db.prepare(
-        "SELECT p.p_pid, p.p_name, p.p_rx, p.p_tx, dates.date_int
-         FROM processes p
-         LEFT JOIN dates ON dates.date_id = p.p_date_id;"
+///
+/// Returns the processes, and their links, selected by 'selection', the ones with the most
+/// traffic first. Everything is loaded in a single query.
+///
+/// When a protocol is selected, the traffic of a process is the one of its links of that protocol
+/// and the processes without any are left out. ICMP selects all the types of ICMP messages unless
+/// one is given.
+///
+/// The processes of a same pid started at different times are different processes.
+///
+/// The links of the days rolled up (see retention.rs) are the ones of their daily summaries,
+/// without local port nor lifecycle, taken in when their day starts in the time range. The TCP
+/// state of a link is the last one recorded in the time range.
+///
+pub fn load_procs(db: &Connection, selection: &Selection) -> Vec<Process> {
+    let mut stmt = db.prepare_cached(
+        "WITH
+         l AS (
+            SELECT l_p_pid, l_p_start, l_prot_id, l_saddr, l_daddr, l_lport, l_dport,
+                SUM(l_rx) AS rx, SUM(l_tx) AS tx, MAX(l_domain) AS domain,
+                MAX(l_opened) AS opened, MAX(l_closed) AS closed, MAX(l_server) AS server,
+                MAX(l_app_proto) AS app_proto
+            FROM (
+                SELECT l_p_pid, l_p_start, l_prot_id, l_saddr, l_daddr, l_lport, l_dport,
+                    l_rx, l_tx, l_domain, l_opened, l_closed, l_server, l_app_proto
+                FROM links
+                WHERE l_ts >= :from AND l_ts < :to
+                UNION ALL
+                SELECT s_p_pid, s_p_start, s_prot_id, s_saddr, s_daddr, 0, s_dport,
+                    s_rx, s_tx, s_domain, NULL, NULL, s_server, s_app_proto
+                FROM summaries
+                WHERE s_day >= :from AND s_day < :to
+            )
+            WHERE (:pid IS NULL OR l_p_pid = :pid)
+                AND (:prot IS NULL
+                    OR l_prot_id IN (SELECT prot_id FROM protocols WHERE prot_name = :prot
+                        AND (:prot_id IS NULL OR prot_id = :prot_id)))
+            GROUP BY l_p_pid, l_p_start, l_prot_id, l_saddr, l_daddr, l_lport, l_dport
+         ),
+         s AS (
+            SELECT l_p_pid, l_p_start, l_prot_id, l_saddr, l_daddr, l_lport, l_dport,
+                l_tcp_state, MAX(l_ts)
+            FROM links
+            WHERE l_ts >= :from AND l_ts < :to AND l_tcp_state IS NOT NULL
+            GROUP BY l_p_pid, l_p_start, l_prot_id, l_saddr, l_daddr, l_lport, l_dport
+         ),
+         p AS (
+            SELECT p_pid, p_start, MAX(p_name) AS name, SUM(p_rx) AS rx, SUM(p_tx) AS tx
+            FROM processes
+            WHERE p_ts >= :from AND p_ts < :to AND (:pid IS NULL OR p_pid = :pid)
+            GROUP BY p_pid, p_start
+            HAVING :name IS NULL OR MAX(p_name) = :name
+         ),
+         t AS (
+            SELECT * FROM (
+                SELECT p.p_pid, p.p_start, p.name, p.rx, p.tx FROM p WHERE :prot IS NULL
+                UNION ALL
+                SELECT p.p_pid, p.p_start, p.name, SUM(l.rx), SUM(l.tx)
+                FROM p JOIN l ON l.l_p_pid = p.p_pid AND l.l_p_start = p.p_start
+                WHERE :prot IS NOT NULL
+                GROUP BY p.p_pid, p.p_start
+            )
+            ORDER BY rx + tx DESC, p_pid, p_start
+            LIMIT :limit
+         )
+         SELECT t.p_pid, t.name, t.rx, t.tx,
+            l.l_saddr, l.l_daddr, l.l_lport, l.l_dport, l.rx, l.tx, l.l_prot_id, l.domain,
+            l.opened, l.closed, l.server, l.app_proto,
+            i.pi_exe, i.pi_cmdline, i.pi_ppid, i.pi_uid, i.pi_euid, t.p_start, u.u_name,
+            eu.u_name, i.pi_exited, s.l_tcp_state
+         FROM t
+         LEFT JOIN process_info i ON i.pi_pid = t.p_pid AND i.pi_start = t.p_start
+         LEFT JOIN users u ON u.u_uid = i.pi_uid
+         LEFT JOIN users eu ON eu.u_uid = i.pi_euid
+         LEFT JOIN l ON l.l_p_pid = t.p_pid AND l.l_p_start = t.p_start
+         LEFT JOIN s ON s.l_p_pid = l.l_p_pid AND s.l_p_start = l.l_p_start
+            AND s.l_prot_id = l.l_prot_id AND s.l_saddr = l.l_saddr AND s.l_daddr = l.l_daddr
+            AND s.l_lport = l.l_lport AND s.l_dport = l.l_dport
+         ORDER BY t.rx + t.tx DESC, t.p_pid, t.p_start, l.rx + l.tx DESC;"
     ).unwrap();
 
-    let rows = stmt.query_map(NO_PARAMS, |row| {
-        Ok( Process {
-            pid: row.get(0)?,
-            name: row.get(1)?,
-            tlinks: Vec::new(),
-            ulinks: Vec::new(),
-            rx: row.get(2)?,
-            tx: row.get(3)?,
-            date: row.get(4)?,
-        })
-    }).unwrap();
+    // A negative limit means no limit for SQLite
+    let limit = selection.limit.map(|n| n as i64).unwrap_or(-1);
+    let prot = selection.prot.map(|prot| prot.name());
+    let prot_id = selection.prot.and_then(|prot| prot.id());
+
+    let mut rows = stmt.query_named(&[
+        (":from", &selection.from),
+        (":to", &selection.to),
+        (":pid", &selection.pid),
+        (":name", &selection.name),
+        (":prot", &prot),
+        (":prot_id", &prot_id),
+        (":limit", &limit),
+    ]).unwrap();
 
     let mut procs: Vec<Process> = Vec::new();
 
-    for item in rows {
-        let mut p = item.unwrap();
-        //println!("procs: {} {}", p, p.date);
+    while let Some(row) = rows.next().unwrap() {
+        let pid: u32 = row.get(0).unwrap();
+        let start: i64 = row.get(21).unwrap();
+        let start = Some(start).filter(|start| *start != 0);
+
+        if procs.last().map(|p| (p.pid, p.start)) != Some((pid, start)) {
+            let cmdline: Option<String> = row.get(17).unwrap();
+            let user: Option<String> = row.get(22).unwrap();
+            let euser: Option<String> = row.get(23).unwrap();
+
+            procs.push(Process {
+                pid,
+                name: row.get(1).unwrap(),
+                exe: row.get::<_, Option<String>>(16).unwrap().unwrap_or_default(),
+                cmdline: match cmdline.as_deref() {
+                    None | Some("") => Vec::new(),
+                    Some(cmdline) => cmdline.split('\0').map(String::from).collect(),
+                },
+                ppid: row.get(18).unwrap(),
+                uid: row.get(19).unwrap(),
+                euid: row.get(20).unwrap(),
+                user: user.unwrap_or_default(),
+                euser: euser.unwrap_or_default(),
+                start,
+                exited: row.get(24).unwrap(),
+                tlinks: Vec::new(),
+                ulinks: Vec::new(),
+                ilinks: Vec::new(),
+                rx: row.get(2).unwrap(),
+                tx: row.get(3).unwrap(),
+                names: HashMap::new(),
+            });
+        }
 
-        get_links(db, &mut p);
+        // No link for this process in the time range
+        let saddr: Option<IpAddrWrapper> = row.get(4).unwrap();
+        let daddr: Option<IpAddrWrapper> = row.get(5).unwrap();
+        let (saddr, daddr) = match (saddr, daddr) {
+            (Some(saddr), Some(daddr)) => (saddr.0, daddr.0),
+            _ => continue,
+        };
 
-        procs.push(p);
+        let tcp_state: Option<String> = row.get(25).unwrap();
+        let link = Link {
+            saddr,
+            daddr,
+            lport: row.get(6).unwrap(),
+            dport: row.get(7).unwrap(),
+            rx: row.get(8).unwrap(),
+            tx: row.get(9).unwrap(),
+            prot: row.get(10).unwrap(),
+            domain: row.get(11).unwrap(),
+            opened: row.get(12).unwrap(),
+            closed: row.get(13).unwrap(),
+            server: row.get(14).unwrap(),
+            app_proto: row.get(15).unwrap(),
+            tcp_state: tcp_state.and_then(|name| TcpState::parse(&name)),
+        };
+
+        let p = procs.last_mut().unwrap();
+        p.links_of(link.prot).push(link);
     }
 
     procs
 }
 
-pub fn get_dates(db: &Connection) -> Vec<u32> {
+///
+/// Returns the days, in the time zone 'tz', for which some traffic has been recorded.
+///
+/// The traffic is looked up by quarter of an hour, in a single query: the offsets of the time
+/// zones, and their changes, are whole quarters so that each one is within a single day.
+///
+pub fn get_dates(db: &Connection, tz: Tz) -> Vec<Date> {
     let mut stmt = db.prepare_cached(
-        "SELECT date_int FROM dates;"
+        "SELECT p_ts - p_ts % 900 AS quarter FROM processes GROUP BY quarter ORDER BY quarter;"
     ).unwrap();
 
-    let rows = stmt.query_map(NO_PARAMS, |row| {
-        row.get(0)
-    }).unwrap();
+    let quarters = stmt.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+    let mut dates: Vec<Date> = Vec::new();
 
-    let dates = rows.map(|r| r.unwrap()).collect();
+    for quarter in quarters {
+        let day = Date::day_of(quarter.unwrap(), tz);
+
+        if dates.last().map(|last| last.start) != Some(day.start) {
+            dates.push(day);
+        }
+    }
 
     dates
 }
 
+///
+/// Returns the starts of the first and last days, in the time zone 'tz', for which some traffic
+/// has been recorded: the days known change when they do.
+///
+pub fn get_days_range(db: &Connection, tz: Tz) -> Option<(i64, i64)> {
+    let (first, last): (Option<i64>, Option<i64>) = db.query_row(
+        "SELECT MIN(p_ts), MAX(p_ts) FROM processes;", NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?))
+    ).unwrap();
+
+    Some((tz.day_bounds(first?).0, tz.day_bounds(last?).0))
+}
+
+///
+/// Refresh the list of dates available in the database, e.g. when the daemon started a new day.
+///
+pub fn load_dates(db: &Connection, tz: Tz) {
+    let dates = get_dates(db, tz);
+
+    *DATES.lock().unwrap() = dates;
+}
+
 /*
  * TESTS
  */
@@ -277,9 +577,28 @@ mod tests {
     }
 
     #[test]
-    fn test_insert_proc_and_link() {
-        let db_name = String::from("test.db");
+    fn open_read_only() {
+        let path = std::env::temp_dir().join(format!("netw-read-only-{}.db", std::process::id()));
+        let db_name = path.to_str().unwrap();
+        let _ = fs::remove_file(db_name);
+
+        assert!(open_db_read_only(db_name).is_err(), "missing database created");
+
         let mut db = Connection::open(db_name).unwrap();
+        crate::migrations::migrate_to(&mut db, 1).unwrap();
+        assert!(open_db_read_only(db_name).is_err(), "outdated database read");
+        assert_eq!(crate::migrations::get_version(&db), Ok(1), "database upgraded");
+
+        create_db(&String::from(db_name)).unwrap();
+        let db = open_db_read_only(db_name).unwrap();
+        assert!(db.execute_batch("DELETE FROM processes;").is_err(), "database written");
+
+        fs::remove_file(db_name).unwrap();
+    }
+
+    #[test]
+    fn test_insert_proc_and_link() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
         let tx = db.transaction().unwrap();
 
         let mut p0 = Process::new(1);
@@ -316,26 +635,312 @@ mod tests {
         l1.tx(70);
         l0.domain(String::from("somewhere.inthe.cloud"));
 
-        let pid = 2;
-        let date = 07032021;
-        tx.execute(
-            "INSERT INTO dates (date_int) VALUES (?1)",
-            params![date],
-        );
+        let bucket = bucket_of(1625320925); // 07/03/2021 14:02:05 UTC
 
         // Should add new procs
-        assert_eq!(insert_proc(&tx, &p0, date), Ok(1));
-        assert_eq!(insert_proc(&tx, &p1, date), Ok(1));
+        assert_eq!(insert_proc(&tx, &p0, bucket), Ok(1));
+        assert_eq!(insert_proc(&tx, &p1, bucket), Ok(1));
 
         // Should update existing one
-        assert_eq!(insert_proc(&tx, &p2, date), Ok(1));
+        assert_eq!(insert_proc(&tx, &p2, bucket), Ok(1));
 
         // Should add a new entry
-        assert_eq!(insert_link(&tx, pid, &l0, date), Ok(1));
+        assert_eq!(insert_link(&tx, &p1, &l0, bucket), Ok(1));
 
         // Should update an existing entry
-        assert_eq!(insert_link(&tx, pid, &l1, date), Ok(1));
+        assert_eq!(insert_link(&tx, &p1, &l1, bucket), Ok(1));
 
         tx.commit();
     }
+
+    #[test]
+    fn test_flush_only_writes_deltas() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+        let mut checkpoint = Checkpoint::new();
+        let ts = 1625320925; // 07/03/2021 14:02:05 UTC
+
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            80,
+        );
+        l.prot(Prot::TCP);
+        l.rx(100).tx(10);
+
+        let mut p = Process::new(1234);
+        p.name(String::from("curl"));
+        p.rx(100).tx(10);
+        p.tlinks.push(l);
+
+        assert_eq!(flush(&mut db, &mut checkpoint, vec![p.clone()], ts), Ok(1));
+
+        // Nothing new since the last flush
+        assert_eq!(flush(&mut db, &mut checkpoint, vec![p.clone()], ts), Ok(0));
+
+        p.rx(150).tx(30);
+        p.tlinks[0].rx(150).tx(30);
+        assert_eq!(flush(&mut db, &mut checkpoint, vec![p], ts + 5), Ok(1));
+
+        let (p_rx, p_tx): (isize, isize) = db.query_row(
+            "SELECT p_rx, p_tx FROM processes WHERE p_pid = 1234", NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap();
+        let (l_rx, l_tx): (isize, isize) = db.query_row(
+            "SELECT l_rx, l_tx FROM links WHERE l_p_pid = 1234", NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).unwrap();
+
+        assert_eq!((p_rx, p_tx), (150, 30), "process totals do not match the capture");
+        assert_eq!((l_rx, l_tx), (150, 30), "link totals do not match the capture");
+    }
+
+    #[test]
+    fn test_load_procs_time_range() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+        let ts = 1625320925; // 07/03/2021 14:02:05 UTC
+
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            443,
+        );
+        l.prot(Prot::TCP);
+        l.rx(100).tx(10);
+
+        let mut p = Process::new(1234);
+        p.name(String::from("firefox"));
+        p.rx(100).tx(10);
+        p.tlinks.push(l);
+
+        // Same traffic at 14:02, 14:03 and the next day
+        update_db(&mut db, &vec![p.clone()], ts).unwrap();
+        update_db(&mut db, &vec![p.clone()], ts + 60).unwrap();
+        update_db(&mut db, &vec![p], ts + 86400).unwrap();
+
+        // Between 14:00 and 14:05
+        let procs = load_procs(&db, &Selection::new(ts - 125, ts + 175));
+        assert_eq!(procs.len(), 1, "number of process incorrect");
+        assert_eq!((procs[0].rx, procs[0].tx), (200, 20), "process traffic incorrect");
+        assert_eq!(procs[0].tlinks.len(), 1, "number of links incorrect");
+        assert_eq!((procs[0].tlinks[0].rx, procs[0].tlinks[0].tx), (200, 20));
+
+        // Only the 14:02 bucket
+        let procs = load_procs(&db, &Selection::new(bucket_of(ts), bucket_of(ts) + BUCKET_WIDTH));
+        assert_eq!((procs[0].rx, procs[0].tx), (100, 10), "bucket traffic incorrect");
+
+        let dates = get_dates(&db, Tz::Utc);
+        let dates: Vec<&str> = dates.iter().map(|d| d.str_form.as_str()).collect();
+        assert_eq!(dates, vec!["07/03/21", "07/04/21"]);
+        assert_eq!(get_days_range(&db, Tz::Utc), Some((1625270400, 1625356800)));
+
+        // 14:02 UTC is 00:32 the next day in UTC+10:30
+        let dates = get_dates(&db, Tz::parse("+10:30").unwrap());
+        let dates: Vec<&str> = dates.iter().map(|d| d.str_form.as_str()).collect();
+        assert_eq!(dates, vec!["07/04/21", "07/05/21"]);
+    }
+
+    #[test]
+    fn test_load_procs_selection() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+        let ts = 1625320925; // 07/03/2021 14:02:05 UTC
+
+        let mut tl = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            443,
+        );
+        tl.prot(Prot::TCP);
+        tl.rx(1000).tx(100);
+
+        let mut ul = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 1) ),
+            5353,
+            53,
+        );
+        ul.prot(Prot::UDP);
+        ul.rx(20).tx(10);
+
+        let mut firefox = Process::new(1000);
+        firefox.name(String::from("firefox"));
+        firefox.rx(1020).tx(110);
+        firefox.tlinks.push(tl.clone());
+        firefox.ulinks.push(ul);
+
+        let mut curl = Process::new(2000);
+        curl.name(String::from("curl"));
+        curl.rx(5000).tx(50);
+        tl.rx(5000).tx(50);
+        curl.tlinks.push(tl);
+
+        // Without any link
+        let mut sleep = Process::new(3000);
+        sleep.name(String::from("sleep"));
+
+        update_db(&mut db, &vec![firefox.clone(), curl, sleep], ts).unwrap();
+
+        // The next day, must never show up
+        update_db(&mut db, &vec![firefox], ts + 86400).unwrap();
+
+        let selection = Selection::new(ts - 3600, ts + 3600);
+
+        let procs = load_procs(&db, &selection);
+        let pids: Vec<u32> = procs.iter().map(|p| p.pid).collect();
+        assert_eq!(pids, vec![2000, 1000, 3000], "processes not sorted by traffic");
+        assert_eq!((procs[1].tlinks.len(), procs[1].ulinks.len()), (1, 1));
+        assert_eq!((procs[1].rx, procs[1].tx), (1020, 110), "traffic of another day loaded");
+        assert!(procs[2].tlinks.is_empty() && procs[2].ulinks.is_empty());
+
+        let procs = load_procs(&db, selection.clone().limit(1));
+        assert_eq!(procs.len(), 1, "limit not applied");
+        assert_eq!(procs[0].tlinks.len(), 1, "links of the process cut by the limit");
+
+        let procs = load_procs(&db, selection.clone().name(String::from("firefox")));
+        assert_eq!(procs.len(), 1);
+        assert_eq!(procs[0].pid, 1000);
+
+        let procs = load_procs(&db, selection.clone().pid(2000));
+        assert_eq!(procs.len(), 1);
+        assert_eq!(procs[0].name, "curl");
+
+        // Only the UDP traffic of firefox
+        let procs = load_procs(&db, selection.clone().prot(ProtFilter::UDP));
+        assert_eq!(procs.len(), 1, "processes without UDP traffic loaded");
+        assert_eq!((procs[0].rx, procs[0].tx), (20, 10), "process traffic not the UDP one");
+        assert_eq!((procs[0].tlinks.len(), procs[0].ulinks.len()), (0, 1));
+    }
+
+    #[test]
+    fn test_flush_lost() {
+        let db = create_db(&String::from(":memory:")).unwrap();
+        let mut checkpoint = Checkpoint::new();
+        let ts = 1625320925; // 07/03/2021 14:02:05 UTC
+
+        assert_eq!(flush_lost(&db, &mut checkpoint, 0, ts), Ok(0));
+        assert_eq!(flush_lost(&db, &mut checkpoint, 10, ts), Ok(10));
+        assert_eq!(flush_lost(&db, &mut checkpoint, 10, ts + 5), Ok(0), "counted twice");
+        assert_eq!(flush_lost(&db, &mut checkpoint, 15, ts + 3600), Ok(5));
+
+        assert_eq!(load_lost(&db, ts - 60, ts + 60), 10);
+        assert_eq!(load_lost(&db, ts - 60, ts + 86400), 15);
+        assert_eq!(load_lost(&db, ts + 86400, ts + 2 * 86400), 0);
+    }
+
+    #[test]
+    fn test_link_lifecycle_persisted() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+        let mut checkpoint = Checkpoint::new();
+        let ts = 1625320925; // 07/03/2021 14:02:05 UTC
+
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            443,
+        );
+        l.prot(Prot::TCP);
+        l.rx(100).tx(10);
+        l.open(ts).tcp_state(TcpState::Established);
+
+        let mut p = Process::new(1234);
+        p.name(String::from("curl"));
+        p.rx(100).tx(10);
+        p.tlinks.push(l);
+
+        assert_eq!(flush(&mut db, &mut checkpoint, vec![p.clone()], ts), Ok(1));
+
+        // Closed a few buckets later, without any new traffic, then done with by the kernel
+        p.tlinks[0].close(ts + 300).tcp_state(TcpState::FinWait1);
+        assert_eq!(flush(&mut db, &mut checkpoint, vec![p.clone()], ts + 300), Ok(1));
+        p.tlinks[0].tcp_state(TcpState::Close);
+        assert_eq!(flush(&mut db, &mut checkpoint, vec![p], ts + 360), Ok(1));
+
+        let procs = load_procs(&db, &Selection::new(ts - 60, ts + 600));
+        let l = &procs[0].tlinks[0];
+
+        assert_eq!((l.rx, l.tx), (100, 10), "traffic counted twice");
+        assert_eq!((l.opened, l.closed), (Some(ts), Some(ts + 300)));
+        assert_eq!(l.tcp_state, Some(TcpState::Close), "not the last state");
+        assert_eq!(l.duration(ts + 1000), Some(300), "connection duration incorrect");
+
+        // The last state of the time range
+        let procs = load_procs(&db, &Selection::new(ts - 60, ts + 60));
+        assert_eq!(procs[0].tlinks[0].tcp_state, Some(TcpState::Established));
+    }
+
+    #[test]
+    fn test_reused_pid_kept_apart() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+        let mut checkpoint = Checkpoint::new();
+        let ts = 1625320925; // 07/03/2021 14:02:05 UTC
+
+        let mut wget = Process::new(1234);
+        wget.name(String::from("wget"));
+        wget.rx(100).tx(10);
+        wget.start = Some(ts - 60);
+
+        let mut curl = Process::new(1234);
+        curl.name(String::from("curl"));
+        curl.rx(50).tx(5);
+        curl.start = Some(ts - 10);
+
+        flush(&mut db, &mut checkpoint, vec![wget.clone()], ts).unwrap();
+
+        // Exited without any new traffic, its pid reused in the same bucket
+        wget.exit(ts + 1);
+        assert_eq!(flush(&mut db, &mut checkpoint, vec![wget, curl], ts + 2), Ok(2));
+
+        let procs = load_procs(&db, &Selection::new(ts - 60, ts + 60));
+        let names: Vec<&str> = procs.iter().map(|p| p.name.as_str()).collect();
+
+        assert_eq!(names, vec!["wget", "curl"], "processes of the same pid merged");
+        assert_eq!((procs[0].rx, procs[0].tx, procs[0].exited), (100, 10, Some(ts + 1)));
+        assert_eq!((procs[1].rx, procs[1].tx, procs[1].exited), (50, 5, None));
+        assert_eq!(procs[1].start, Some(ts - 10));
+    }
+
+    #[test]
+    fn test_process_details_persisted() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+        let ts = 1625320925; // 07/03/2021 14:02:05 UTC
+
+        let mut p = Process::new(1234);
+        p.name(String::from("sudo"));
+        p.rx(100).tx(10);
+        p.exe = String::from("/usr/bin/sudo");
+        p.cmdline = vec![String::from("sudo"), String::from("-i")];
+        p.ppid = Some(4321);
+        p.uid = Some(1000);
+        p.euid = Some(0);
+        p.user = String::from("alice");
+        p.euser = String::from("root");
+        p.start = Some(ts - 10);
+
+        update_db(&mut db, &vec![p.clone()], ts).unwrap();
+
+        // Same pid, another program started later
+        let mut q = Process::new(1234);
+        q.name(String::from("curl"));
+        q.rx(5);
+        q.exe = String::from("/usr/bin/curl");
+        q.uid = Some(1000);
+        q.start = Some(ts + 3600);
+
+        update_db(&mut db, &vec![q], ts + 3600).unwrap();
+
+        let procs = load_procs(&db, &Selection::new(ts - 60, ts + 60));
+        assert_eq!(procs[0].exe, "/usr/bin/sudo");
+        assert_eq!(procs[0].cmdline, p.cmdline);
+        assert_eq!((procs[0].ppid, procs[0].uid, procs[0].euid), (Some(4321), Some(1000), Some(0)));
+        assert_eq!((procs[0].user.as_str(), procs[0].euser.as_str()), ("alice", "root"));
+        assert_eq!(procs[0].start, Some(ts - 10));
+
+        let procs = load_procs(&db, &Selection::new(ts + 3540, ts + 3660));
+        assert_eq!(procs[0].exe, "/usr/bin/curl");
+        assert!(procs[0].cmdline.is_empty(), "arguments of another program");
+        assert_eq!((procs[0].ppid, procs[0].user.as_str()), (None, "alice"));
+    }
 }
@@ -1,12 +1,175 @@
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::ffi::CStr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
 use socket2::SockAddr;
+use lazy_static::lazy_static;
 
 use libc::{getnameinfo, c_char, c_int, socklen_t};
 
 const MAX_HOST_LEN: socklen_t = 256;
 const MAX_SERV_LEN: socklen_t = 256;
 
+// Threads resolving the names, and addresses waiting for one of them at most
+const WORKERS: usize = 4;
+const QUEUE_SIZE: usize = 256;
+
+// How long a name, or the lack of one, is kept
+const TTL: Duration = Duration::from_secs(3600);
+const NEGATIVE_TTL: Duration = Duration::from_secs(300);
+
+// Addresses in the cache before the expired ones are purged
+const MAX_CACHED: usize = 4096;
+
+// Whether the names are resolved at all, see set_enabled()
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+lazy_static! {
+    static ref RESOLVER: Resolver = Resolver::new(WORKERS, QUEUE_SIZE, lookup);
+}
+
+// Names of the services, from the host or a fixture in the tests
+#[cfg(not(test))]
+use self::name_info as service_info;
+#[cfg(test)]
+use self::tests::service_info;
+
+// Called with the name of an address once resolved
+pub type Callback = Box<dyn FnOnce(&str) + Send>;
+
+enum Entry {
+    Pending(Vec<Callback>), // Queued or being resolved, the callbacks waiting for the name
+    Resolved(Option<String>, Instant), // The name if any, kept until then
+}
+
+///
+/// Pool of threads resolving the names of addresses in the background, so that a slow resolver
+/// does not hold the capture. The names, and the addresses without one, are cached for a while.
+///
+pub struct Resolver {
+    queue: Mutex<SyncSender<IpAddr>>,
+    cache: Arc<Mutex<HashMap<IpAddr, Entry>>>,
+}
+
+impl Resolver {
+    ///
+    /// Start 'workers' threads resolving the addresses with 'lookup', at most 'queue_size' of them
+    /// waiting.
+    ///
+    pub fn new<F>(workers: usize, queue_size: usize, lookup: F) -> Self
+    where
+        F: Fn(IpAddr) -> Option<String> + Send + Sync + 'static,
+    {
+        let (queue, addrs) = sync_channel::<IpAddr>(queue_size);
+        let addrs = Arc::new(Mutex::new(addrs));
+        let cache = Arc::new(Mutex::new(HashMap::new()));
+        let lookup = Arc::new(lookup);
+
+        for _ in 0..workers {
+            let addrs = addrs.clone();
+            let cache = cache.clone();
+            let lookup = lookup.clone();
+
+            thread::spawn(move || loop {
+                let addr = match addrs.lock().unwrap().recv() {
+                    Ok(addr) => addr,
+                    Err(_) => break,
+                };
+
+                let name = lookup(addr);
+                let ttl = if name.is_some() { TTL } else { NEGATIVE_TTL };
+                let entry = Entry::Resolved(name.clone(), Instant::now() + ttl);
+
+                // The callbacks are called without the cache locked
+                let waiting = match cache.lock().unwrap().insert(addr, entry) {
+                    Some(Entry::Pending(waiting)) => waiting,
+                    _ => Vec::new(),
+                };
+
+                if let Some(name) = name {
+                    for callback in waiting {
+                        callback(&name);
+                    }
+                }
+            });
+        }
+
+        Resolver {
+            queue: Mutex::new(queue),
+            cache,
+        }
+    }
+
+    ///
+    /// Returns the name of 'addr' if cached. Otherwise 'addr' is queued to be resolved and
+    /// 'callback' called with its name, if it has one. When the queue is full the address is
+    /// dropped, to be asked again later.
+    ///
+    pub fn resolve(&self, addr: IpAddr, callback: Callback) -> Option<String> {
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+
+        match cache.get_mut(&addr) {
+            Some(Entry::Resolved(name, until)) if *until > now => return name.clone(),
+            Some(Entry::Pending(waiting)) => {
+                waiting.push(callback);
+                return None;
+            }
+            _ => (),
+        }
+
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, entry| match entry {
+                Entry::Resolved(_, until) => *until > now,
+                Entry::Pending(_) => true,
+            });
+        }
+        if cache.len() >= MAX_CACHED {
+            cache.retain(|_, entry| matches!(entry, Entry::Pending(_)));
+        }
+
+        if self.queue.lock().unwrap().try_send(addr).is_ok() {
+            cache.insert(addr, Entry::Pending(vec![callback]));
+        }
+
+        None
+    }
+}
+
+///
+/// Resolve the names of the remote addresses or not, e.g. for privacy: no address is then sent to
+/// the resolvers.
+///
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+///
+/// Returns the name of 'addr' if known, see Resolver::resolve(). Always None when disabled.
+///
+pub fn resolve(addr: IpAddr, callback: Callback) -> Option<String> {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return None;
+    }
+
+    RESOLVER.resolve(addr, callback)
+}
+
+// The name of 'addr', None when getnameinfo() only has the address itself
+fn lookup(addr: IpAddr) -> Option<String> {
+    let (host, _service) = reverse_lookup(addr, 0);
+
+    match host.parse::<IpAddr>() {
+        Ok(_) => None,
+        Err(_) if host.is_empty() => None,
+        Err(_) => Some(host),
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Copy, Clone)]
 enum Flags {
@@ -21,6 +184,29 @@ enum Flags {
 /// Get the domain name associated to an IP address.
 ///
 pub fn reverse_lookup(addr: IpAddr, port: u16) -> (String, String) {
+    name_info(addr, port, Flags::NiNofqdn as c_int)
+}
+
+///
+/// Returns the name of the service registered for 'port' (as in /etc/services), of UDP if 'udp'
+/// else of TCP. None when the port has no name. The hosts are not looked up, nothing is sent.
+///
+pub fn service(port: u16, udp: bool) -> Option<String> {
+    let mut flags = Flags::NiNumerichost as c_int;
+    if udp {
+        flags |= Flags::NiDgram as c_int;
+    }
+
+    let (_host, service) = service_info(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port, flags);
+
+    match service.parse::<u16>() {
+        Ok(_) => None,
+        Err(_) if service.is_empty() => None,
+        Err(_) => Some(service),
+    }
+}
+
+fn name_info(addr: IpAddr, port: u16, flags: c_int) -> (String, String) {
     let socket = SocketAddr::new(addr, port);
     let sock: SockAddr = socket.into();
 
@@ -33,7 +219,7 @@ pub fn reverse_lookup(addr: IpAddr, port: u16) -> (String, String) {
             sock.len() as socklen_t,
             c_host.as_mut_ptr(), MAX_HOST_LEN,
             c_serv.as_mut_ptr(), MAX_SERV_LEN,
-            Flags::NiNofqdn as c_int,
+            flags,
         )
     };
 
@@ -54,6 +240,131 @@ pub fn reverse_lookup(addr: IpAddr, port: u16) -> (String, String) {
 mod tests {
     use super::*;
     use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    // Extract of /etc/services, whatever the one of the host has
+    const SERVICES: &str = "
+        ssh      22/tcp
+        domain   53/tcp
+        domain   53/udp
+        http     80/tcp    www
+        https    443/tcp
+        https    443/udp
+        imaps    993/tcp
+        mdns     5353/udp
+    ";
+
+    // What getnameinfo() returns for a port with SERVICES: its name, else its number
+    pub fn service_info(_addr: IpAddr, port: u16, flags: c_int) -> (String, String) {
+        let udp = (flags & Flags::NiDgram as c_int) != 0;
+        let entry = format!("{}/{}", port, if udp { "udp" } else { "tcp" });
+
+        let name = SERVICES.lines().find_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?;
+
+            (fields.next()? == entry).then(|| name.to_string())
+        });
+
+        (String::from("0.0.0.0"), name.unwrap_or_else(|| port.to_string()))
+    }
+
+    // Names only the addresses 10.0.0.x
+    fn fake_name(addr: IpAddr) -> Option<String> {
+        match addr {
+            IpAddr::V4(v4) if v4.octets()[..3] == [10, 0, 0] => {
+                Some(format!("host{}", v4.octets()[3]))
+            }
+            _ => None,
+        }
+    }
+
+    // Names the addresses with fake_name(), slowly, counting the lookups in 'lookups'
+    fn fake_lookup(lookups: Arc<AtomicUsize>) -> impl Fn(IpAddr) -> Option<String> + Send + Sync {
+        move |addr| {
+            lookups.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+
+            fake_name(addr)
+        }
+    }
+
+    // Names the addresses with fake_name(), each one sent to 'started' then held until 'release'
+    // gets a message or is closed
+    fn gated_lookup(started: Sender<IpAddr>, release: Receiver<()>)
+        -> impl Fn(IpAddr) -> Option<String> + Send + Sync
+    {
+        let (started, release) = (Mutex::new(started), Mutex::new(release));
+
+        move |addr| {
+            let _ = started.lock().unwrap().send(addr);
+            let _ = release.lock().unwrap().recv();
+
+            fake_name(addr)
+        }
+    }
+
+    #[test]
+    fn resolver_cache() {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let resolver = Resolver::new(2, 8, fake_lookup(lookups.clone()));
+        let (tx, rx) = channel();
+        let named = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let unnamed = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1));
+
+        // Both callbacks called with the single lookup
+        for n in 0..2 {
+            let tx = tx.clone();
+            let callback = Box::new(move |name: &str| tx.send((n, name.to_string())).unwrap());
+            assert_eq!(resolver.resolve(named, callback), None);
+        }
+        let unnamed_tx = tx.clone();
+        let callback = Box::new(move |name: &str| unnamed_tx.send((2, name.to_string())).unwrap());
+        assert_eq!(resolver.resolve(unnamed, callback), None);
+
+        let mut answers = vec![rx.recv().unwrap(), rx.recv().unwrap()];
+        answers.sort();
+        assert_eq!(answers, vec![(0, String::from("host1")), (1, String::from("host1"))]);
+
+        // Answered from the cache, names and lack of
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(lookups.load(Ordering::SeqCst), 2, "address looked up twice");
+        assert_eq!(resolver.resolve(named, Box::new(|_| ())), Some(String::from("host1")));
+        assert_eq!(resolver.resolve(unnamed, Box::new(|_| ())), None);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(lookups.load(Ordering::SeqCst), 2, "cached address looked up");
+        assert!(rx.try_recv().is_err(), "callback called without a name");
+    }
+
+    #[test]
+    fn resolver_queue_full() {
+        let (started_tx, started) = channel();
+        let (release, release_rx) = channel();
+        let (named_tx, named) = channel();
+        let resolver = Resolver::new(1, 1, gated_lookup(started_tx, release_rx));
+        let addr = |n| IpAddr::V4(Ipv4Addr::new(10, 0, 0, n));
+
+        // The first address being resolved...
+        assert_eq!(resolver.resolve(addr(1), Box::new(|_| ())), None);
+        assert_eq!(started.recv().unwrap(), addr(1));
+
+        // ...the second one queued and the third one dropped
+        let callback = Box::new(move |name: &str| named_tx.send(name.to_string()).unwrap());
+        assert_eq!(resolver.resolve(addr(2), callback), None);
+        assert_eq!(resolver.resolve(addr(3), Box::new(|_| ())), None);
+
+        let cache = resolver.cache.lock().unwrap();
+        assert_eq!(cache.len(), 2, "dropped address kept as pending");
+        assert!(!cache.contains_key(&addr(3)));
+        drop(cache);
+
+        // Then only the second one is looked up
+        drop(release);
+        assert_eq!(named.recv().unwrap(), "host2");
+        let looked_up: Vec<IpAddr> = started.try_iter().collect();
+        assert_eq!(looked_up, vec![addr(2)], "dropped address looked up");
+    }
 
     #[test]
     fn reverse_lookup_ipv4() {
@@ -74,4 +385,12 @@ mod tests {
         assert_eq!(host, "2606:4700:3033::681f:4bdf".to_string());
         assert_eq!(serv, "https".to_string());
     }
+
+    #[test]
+    fn service_names() {
+        assert_eq!(service(22, false), Some(String::from("ssh")));
+        assert_eq!(service(53, true), Some(String::from("domain")));
+        assert_eq!(service(5353, false), None, "UDP service of a TCP port");
+        assert_eq!(service(0, false), None);
+    }
 }
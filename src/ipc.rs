@@ -0,0 +1,615 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write, ErrorKind};
+use std::net::IpAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use crate::net::{Process, Link, Prot, TcpState};
+
+///
+/// Version of the protocol spoken over the socket, sent by the daemon as "NETW <version>" to each
+/// new client. The daemon then sends messages made of lines of text:
+///
+///     SNAPSHOT <lost> | DELTA <lost>
+///     P <pid> <rx> <tx> <name>
+///     I <ppid> <uid> <euid> <start> <exited> <user> <euser> <exe>
+///     A <argument>
+///     L <prot> <saddr> <lport> <daddr> <dport> <rx> <tx> <opened> <closed> <state> <app>
+///       <server> <domain>
+///     END
+///
+/// A snapshot replaces everything the client knows, a delta holds the traffic to add to it (see
+/// Process::delta()), both with the number of events lost by the probes of the daemon since it
+/// started. The 'I' line describes the last 'P' line if anything is known of it, '-'
+/// standing for what is not, its start being a UNIX timestamp in nanoseconds and its exit one in
+/// seconds. Each 'A' line is one of its arguments. Each 'L' line is a link of
+/// the last 'P' line, its opening and closing times are UNIX timestamps or '-' when unknown, as
+/// are its TCP state (see TcpState::name()), application protocol and server. The protocol is
+/// given by its identifier, see Prot::id().
+///
+pub const PROTOCOL_VERSION: u32 = 10;
+
+// How long a slow client may block the daemon before being dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// How long a client waits for data before giving the hand back to its caller
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+pub enum Message {
+    Snapshot(Vec<Process>, u64),
+    Delta(Vec<Process>, u64),
+}
+
+impl Message {
+    ///
+    /// Apply the message to the processes known by a client.
+    ///
+    pub fn apply(self, procs: &mut Vec<Process>) {
+        match self {
+            Message::Snapshot(snapshot, _) => *procs = snapshot,
+            Message::Delta(deltas, _) => {
+                for d in deltas.iter() {
+                    match procs.iter_mut().find(|p| *p == d) {
+                        Some(p) => p.merge(d),
+                        None => procs.push(d.clone()),
+                    }
+                }
+            }
+        }
+    }
+
+    ///
+    /// Number of events lost by the probes of the daemon, its traffic is lower than the real one.
+    ///
+    pub fn lost(&self) -> u64 {
+        match self {
+            Message::Snapshot(_, lost) | Message::Delta(_, lost) => *lost,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let (kind, procs, lost) = match self {
+            Message::Snapshot(procs, lost) => ("SNAPSHOT", procs, lost),
+            Message::Delta(procs, lost) => ("DELTA", procs, lost),
+        };
+        let mut buffer = format!("{} {}\n", kind, lost);
+
+        for p in procs.iter() {
+            buffer.push_str(&format!("P {} {} {} {}\n", p.pid, p.rx, p.tx, sanitize(&p.name)));
+
+            if p.has_details() {
+                buffer.push_str(&format!(
+                    "I {} {} {} {} {} {} {} {}\n",
+                    id_str(p.ppid), id_str(p.uid), id_str(p.euid), time_str(p.start),
+                    time_str(p.exited), word_str(&p.user), word_str(&p.euser), sanitize(&p.exe)
+                ));
+            }
+            for arg in p.cmdline.iter() {
+                buffer.push_str(&format!("A {}\n", sanitize(arg)));
+            }
+
+            for l in p.links() {
+                buffer.push_str(&format!(
+                    "L {} {} {} {} {} {} {} {} {} {} {} {} {}\n",
+                    l.prot.id(), l.saddr, l.lport, l.daddr, l.dport, l.rx, l.tx,
+                    time_str(l.opened), time_str(l.closed),
+                    word_str(l.tcp_state.map(|state| state.name()).unwrap_or_default()),
+                    word_str(&l.app_proto), word_str(&l.server), sanitize(&l.domain)
+                ));
+            }
+        }
+        buffer.push_str("END\n");
+
+        buffer
+    }
+}
+
+///
+/// Names are the last field of a line, only line breaks must be kept out of them.
+///
+fn sanitize(s: &str) -> String {
+    s.chars().map(|c| if c.is_control() { '?' } else { c }).collect()
+}
+
+fn id_str(id: Option<u32>) -> String {
+    match id {
+        Some(id) => id.to_string(),
+        None => String::from("-"),
+    }
+}
+
+fn time_str(ts: Option<i64>) -> String {
+    match ts {
+        Some(ts) => ts.to_string(),
+        None => String::from("-"),
+    }
+}
+
+// Server names, application protocols and user names have nothing to escape, see server_name.rs
+// and classify.rs
+fn word_str(word: &str) -> &str {
+    match word {
+        "" => "-",
+        _ => word,
+    }
+}
+
+fn parse_word(value: &str) -> String {
+    match value {
+        "-" => String::new(),
+        _ => String::from(value),
+    }
+}
+
+fn parse_id(value: &str) -> Option<Option<u32>> {
+    match value {
+        "-" => Some(None),
+        _ => value.parse().ok().map(Some),
+    }
+}
+
+fn parse_time(value: &str) -> Option<Option<i64>> {
+    match value {
+        "-" => Some(None),
+        _ => value.parse().ok().map(Some),
+    }
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("unexpected message from the daemon: {}", line))
+}
+
+///
+/// Rebuild the messages from the lines received.
+///
+struct Decoder {
+    greeted: bool,
+    current: Option<Message>,
+}
+
+impl Decoder {
+    fn new() -> Self {
+        Decoder {
+            greeted: false,
+            current: None,
+        }
+    }
+
+    ///
+    /// Returns the message completed by 'line', if any.
+    ///
+    fn decode(&mut self, line: &str) -> io::Result<Option<Message>> {
+        if !self.greeted {
+            if line != format!("NETW {}", PROTOCOL_VERSION) {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("unsupported daemon protocol: {}", line),
+                ));
+            }
+            self.greeted = true;
+            return Ok(None);
+        }
+
+        let procs = match &mut self.current {
+            Some(Message::Snapshot(procs, _)) | Some(Message::Delta(procs, _)) => procs,
+            None => {
+                let (kind, lost) = line.split_once(' ').ok_or_else(|| invalid(line))?;
+                let lost = lost.parse().map_err(|_| invalid(line))?;
+
+                self.current = match kind {
+                    "SNAPSHOT" => Some(Message::Snapshot(Vec::new(), lost)),
+                    "DELTA" => Some(Message::Delta(Vec::new(), lost)),
+                    _ => return Err(invalid(line)),
+                };
+                return Ok(None);
+            }
+        };
+
+        if line == "END" {
+            return Ok(self.current.take());
+        }
+
+        if line.starts_with("P ") {
+            let fields: Vec<&str> = line.splitn(5, ' ').collect();
+            if fields.len() != 5 {
+                return Err(invalid(line));
+            }
+
+            let mut p = Process::new(fields[1].parse().map_err(|_| invalid(line))?);
+            p.rx(fields[2].parse().map_err(|_| invalid(line))?);
+            p.tx(fields[3].parse().map_err(|_| invalid(line))?);
+            p.name(String::from(fields[4]));
+            procs.push(p);
+        } else if line.starts_with("I ") {
+            let fields: Vec<&str> = line.splitn(9, ' ').collect();
+            if fields.len() != 9 {
+                return Err(invalid(line));
+            }
+
+            let p = procs.last_mut().ok_or_else(|| invalid(line))?;
+            p.ppid = parse_id(fields[1]).ok_or_else(|| invalid(line))?;
+            p.uid = parse_id(fields[2]).ok_or_else(|| invalid(line))?;
+            p.euid = parse_id(fields[3]).ok_or_else(|| invalid(line))?;
+            p.start = parse_time(fields[4]).ok_or_else(|| invalid(line))?;
+            p.exited = parse_time(fields[5]).ok_or_else(|| invalid(line))?;
+            p.user = parse_word(fields[6]);
+            p.euser = parse_word(fields[7]);
+            p.exe = String::from(fields[8]);
+        } else if let Some(arg) = line.strip_prefix("A ") {
+            let p = procs.last_mut().ok_or_else(|| invalid(line))?;
+            p.cmdline.push(String::from(arg));
+        } else if line.starts_with("L ") {
+            let fields: Vec<&str> = line.splitn(14, ' ').collect();
+            if fields.len() != 14 {
+                return Err(invalid(line));
+            }
+
+            let saddr: IpAddr = fields[2].parse().map_err(|_| invalid(line))?;
+            let daddr: IpAddr = fields[4].parse().map_err(|_| invalid(line))?;
+            let mut l = Link::new(
+                saddr,
+                daddr,
+                fields[3].parse().map_err(|_| invalid(line))?,
+                fields[5].parse().map_err(|_| invalid(line))?,
+            );
+            l.rx(fields[6].parse().map_err(|_| invalid(line))?);
+            l.tx(fields[7].parse().map_err(|_| invalid(line))?);
+            l.opened = parse_time(fields[8]).ok_or_else(|| invalid(line))?;
+            l.closed = parse_time(fields[9]).ok_or_else(|| invalid(line))?;
+            l.tcp_state = match fields[10] {
+                "-" => None,
+                name => Some(TcpState::parse(name).ok_or_else(|| invalid(line))?),
+            };
+            l.app_proto(parse_word(fields[11]));
+            l.server(parse_word(fields[12]));
+            l.domain(String::from(fields[13]));
+
+            let prot = fields[1].parse().ok().and_then(Prot::from_id).ok_or_else(|| invalid(line))?;
+            l.prot(prot);
+
+            let p = procs.last_mut().ok_or_else(|| invalid(line))?;
+            p.links_of(prot).push(l);
+        } else {
+            return Err(invalid(line));
+        }
+
+        Ok(None)
+    }
+}
+
+///
+/// Daemon side: publish the captured traffic to the clients attached to the socket.
+///
+pub struct Server {
+    path: String,
+    listener: UnixListener,
+    clients: Vec<UnixStream>,
+    pending: Vec<UnixStream>,   // accepted, waiting for their first snapshot
+    published: Vec<Process>,    // what the clients know
+    lost: u64,                  // events lost the clients know of
+}
+
+impl Server {
+    ///
+    /// Listen on the Unix socket 'path'. A socket left behind by a daemon which did not stop
+    /// cleanly is replaced, one still in use is not.
+    ///
+    pub fn bind(path: &str) -> io::Result<Server> {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("a daemon is already listening on {}", path),
+            ));
+        }
+        let _ = fs::remove_file(path);
+
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+
+        // Anyone can attach a UI, only the capture needs privileges
+        fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+
+        Ok(Server {
+            path: String::from(path),
+            listener,
+            clients: Vec::new(),
+            pending: Vec::new(),
+            published: Vec::new(),
+            lost: 0,
+        })
+    }
+
+    ///
+    /// Accept the clients waiting to attach. Returns how many there were.
+    ///
+    pub fn accept(&mut self) -> io::Result<usize> {
+        let mut accepted = 0;
+
+        loop {
+            match self.listener.accept() {
+                Ok((mut stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+                    if stream.write_all(format!("NETW {}\n", PROTOCOL_VERSION).as_bytes()).is_ok() {
+                        self.pending.push(stream);
+                        accepted += 1;
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(accepted)
+    }
+
+    pub fn has_clients(&self) -> bool {
+        !self.clients.is_empty() || !self.pending.is_empty()
+    }
+
+    ///
+    /// Send the new state of the processes 'procs', and the number of events 'lost' by the probes:
+    /// a snapshot to the new clients and what changed since the last call to the others. Everyone
+    /// gets a snapshot when the counters were reset or processes forgotten (see net::rollover()).
+    /// Returns the number of clients dropped because they left or were too slow.
+    ///
+    pub fn publish(&mut self, procs: Vec<Process>, lost: u64) -> usize {
+        let before = self.clients.len() + self.pending.len();
+        let reset = procs.iter().any(|p| match self.published.iter().find(|x| *x == p) {
+            Some(prev) => p.rx < prev.rx || p.tx < prev.tx,
+            None => false,
+        }) || self.published.iter().any(|x| !procs.contains(x));
+
+        if reset {
+            self.pending.append(&mut self.clients);
+        } else {
+            let deltas: Vec<Process> = procs
+                .iter()
+                .filter_map(|p| p.delta(self.published.iter().find(|x| *x == p)))
+                .collect();
+
+            if !deltas.is_empty() || lost != self.lost {
+                let message = Message::Delta(deltas, lost).encode();
+                self.clients.retain(|mut c| c.write_all(message.as_bytes()).is_ok());
+            }
+        }
+
+        if !self.pending.is_empty() {
+            let message = Message::Snapshot(procs.clone(), lost).encode();
+
+            for mut c in self.pending.drain(..) {
+                if c.write_all(message.as_bytes()).is_ok() {
+                    self.clients.push(c);
+                }
+            }
+        }
+        self.published = procs;
+        self.lost = lost;
+
+        before - self.clients.len()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+///
+/// UI side: follow the traffic published by the daemon.
+///
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    line: Vec<u8>,
+    decoder: Decoder,
+}
+
+impl Client {
+    pub fn connect(path: &str) -> io::Result<Client> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+        Ok(Client {
+            reader: BufReader::new(stream),
+            line: Vec::new(),
+            decoder: Decoder::new(),
+        })
+    }
+
+    ///
+    /// Returns the next message from the daemon, or None if nothing was received for a while.
+    ///
+    pub fn poll(&mut self) -> io::Result<Option<Message>> {
+        loop {
+            // A line cut by the timeout is kept and completed by the next read
+            match self.reader.read_until(b'\n', &mut self.line) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "daemon stopped")),
+                Ok(_) if self.line.ends_with(b"\n") => {
+                    // Only the end of line, a link without domain ends with a space
+                    let line = String::from_utf8_lossy(&self.line);
+                    let line = line.trim_end_matches('\n').to_string();
+                    self.line.clear();
+
+                    if let Some(message) = self.decoder.decode(&line)? {
+                        return Ok(Some(message));
+                    }
+                }
+                Ok(_) => (),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn curl(rx: isize, tx: isize) -> Process {
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            443,
+        );
+        l.prot(Prot::TCP);
+        l.rx(rx).tx(tx);
+        l.domain(String::from("example.com"));
+        l.server(String::from("www.example.com"));
+        l.app_proto(String::from("https"));
+        l.open(1625320925).tcp_state(TcpState::Established);
+
+        let mut p = Process::new(1234);
+        p.name(String::from("curl -v"));
+        p.rx(rx).tx(tx);
+        p.tlinks.push(l);
+
+        let mut ping = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 1) ),
+            7,
+            0,
+        );
+        ping.prot(Prot::ICMP(8, 0));
+        ping.tx(64);
+        p.ilinks.push(ping);
+
+        p
+    }
+
+    fn receive(client: &mut Client) -> Message {
+        for _ in 0..10 {
+            if let Some(message) = client.poll().unwrap() {
+                return message;
+            }
+        }
+        panic!("nothing received from the daemon");
+    }
+
+    #[test]
+    fn decode_encoded() {
+        let mut decoder = Decoder::new();
+        let mut procs = Vec::new();
+        let mut lost = 0;
+
+        assert!(decoder.decode(&format!("NETW {}", PROTOCOL_VERSION)).unwrap().is_none());
+
+        let messages = [
+            Message::Snapshot(vec![curl(100, 10)], 0),
+            Message::Delta(vec![curl(5, 1)], 3),
+        ];
+
+        for message in messages {
+            let encoded = message.encode();
+            let mut decoded = None;
+
+            for line in encoded.lines() {
+                decoded = decoder.decode(line).unwrap();
+            }
+            let decoded = decoded.expect("message not complete");
+            lost = decoded.lost();
+            decoded.apply(&mut procs);
+        }
+
+        assert_eq!(procs.len(), 1);
+        assert_eq!(procs[0].name, "curl -v");
+        assert_eq!((procs[0].rx, procs[0].tx), (105, 11), "delta not added");
+        assert_eq!((procs[0].tlinks[0].rx, procs[0].tlinks[0].tx), (105, 11));
+        assert_eq!(procs[0].tlinks[0].domain, "example.com");
+        assert_eq!(procs[0].tlinks[0].server, "www.example.com");
+        assert_eq!(procs[0].ilinks[0].server, "");
+        assert_eq!(procs[0].tlinks[0].app_proto, "https");
+        assert_eq!(procs[0].ilinks[0].app_proto, "");
+        assert_eq!(procs[0].tlinks[0].opened, Some(1625320925));
+        assert_eq!(procs[0].tlinks[0].closed, None);
+        assert_eq!(procs[0].tlinks[0].tcp_state, Some(TcpState::Established));
+        assert_eq!(procs[0].ilinks[0].tcp_state, None);
+        assert_eq!(procs[0].ilinks[0].prot, Prot::ICMP(8, 0));
+        assert_eq!(procs[0].ilinks[0].tx, 128);
+        assert_eq!(lost, 3, "lost events not decoded");
+
+        assert!(decoder.decode("P 1234 abc 0 curl").is_err());
+        assert!(decoder.decode("SNAPSHOT").is_err(), "snapshot without lost events");
+    }
+
+    #[test]
+    fn decode_details() {
+        let mut decoder = Decoder::new();
+        let mut procs = Vec::new();
+
+        let mut p = curl(100, 10);
+        p.exe = String::from("/usr/bin/curl");
+        p.cmdline = vec![String::from("curl"), String::from("-d"), String::from("")];
+        p.ppid = Some(1);
+        p.uid = Some(1000);
+        p.user = String::from("alice");
+        p.start = Some(1625320900);
+        p.exited = Some(1625320930);
+
+        decoder.decode(&format!("NETW {}", PROTOCOL_VERSION)).unwrap();
+        for line in Message::Snapshot(vec![p, Process::new(4321)], 0).encode().lines() {
+            if let Some(message) = decoder.decode(line).unwrap() {
+                message.apply(&mut procs);
+            }
+        }
+
+        assert_eq!(procs[0].exe, "/usr/bin/curl");
+        assert_eq!(procs[0].cmdline, vec!["curl", "-d", ""]);
+        assert_eq!((procs[0].ppid, procs[0].uid, procs[0].euid), (Some(1), Some(1000), None));
+        assert_eq!((procs[0].user.as_str(), procs[0].euser.as_str()), ("alice", ""));
+        assert_eq!((procs[0].start, procs[0].exited), (Some(1625320900), Some(1625320930)));
+        assert!(!procs[1].has_details(), "details of the previous process");
+
+        assert!(decoder.decode("I 1 1000 - - - alice").is_err());
+        assert!(decoder.decode("I x 1000 - - - alice - /usr/bin/curl").is_err());
+    }
+
+    #[test]
+    fn client_follows_server() {
+        let path = std::env::temp_dir().join(format!("netw-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let mut server = Server::bind(path).unwrap();
+        let mut client = Client::connect(path).unwrap();
+        let mut procs = Vec::new();
+
+        assert_eq!(server.accept().unwrap(), 1);
+        assert_eq!(server.publish(vec![curl(100, 10)], 0), 0);
+        receive(&mut client).apply(&mut procs);
+
+        server.publish(vec![curl(150, 20)], 0);
+        match receive(&mut client) {
+            Message::Delta(deltas, _) => assert_eq!((deltas[0].rx, deltas[0].tx), (50, 10)),
+            Message::Snapshot(_, _) => panic!("snapshot sent instead of a delta"),
+        };
+
+        // Nothing but events lost since
+        server.publish(vec![curl(150, 20)], 2);
+        match receive(&mut client) {
+            Message::Delta(deltas, lost) => assert_eq!((deltas.len(), lost), (0, 2)),
+            Message::Snapshot(_, _) => panic!("snapshot sent instead of a delta"),
+        };
+
+        // Counters reset at the start of a new day
+        server.publish(vec![curl(1, 1)], 2);
+        receive(&mut client).apply(&mut procs);
+        assert_eq!((procs[0].rx, procs[0].tx), (1, 1), "reset not published");
+
+        drop(client);
+        let dropped = server.publish(vec![curl(2, 2)], 2) + server.publish(vec![curl(3, 3)], 2);
+        assert_eq!(dropped, 1, "client gone but not dropped");
+
+        drop(server);
+        assert!(!std::path::Path::new(path).exists(), "socket not removed");
+    }
+}
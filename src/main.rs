@@ -1,10 +1,9 @@
 #![feature(ip)]
 
-use bcc::{BPF, Kprobe, BccError};
-
-use std::{thread, time, error::Error, io, time::Duration};
+use std::{thread, time, error::Error, io, time::Duration, time::Instant};
 use std::thread::JoinHandle;
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver};
 use std::mem::drop;
 use std::path::Path;
 
@@ -14,7 +13,6 @@ use ctrlc;
 use clap;
 
 use rusqlite::{Connection};
-use chrono::Utc;
 
 #[macro_use]
 extern crate num_derive; // FromPrimitive()
@@ -22,7 +20,22 @@ extern crate libc;
 
 mod net;
 mod dns;
+mod passive_dns;
+mod server_name;
+mod classify;
+mod procfs;
 mod database;
+mod migrations;
+mod retention;
+mod clock;
+mod query;
+mod ipc;
+mod capture;
+mod synthetic;
+mod replay;
+mod bcc_backend;
+#[cfg(feature = "core")]
+mod core_backend;
 
 /*
  * For tui
@@ -34,21 +47,44 @@ mod util;
 use util::event::{Config, Event, Events};
 use termion::{event::Key, input::MouseTerminal, raw::IntoRawMode, screen::AlternateScreen};
 use tui::{backend::TermionBackend, Terminal};
-use database::{create_db, open_db, update_db, get_procs, get_dates};
-use crate::net::Process;
+use database::{create_db, open_db, open_db_read_only, flush, flush_lost, bucket_of, Checkpoint};
+use crate::net::SharedProcs;
+use crate::clock::Tz;
+use crate::retention::Policy;
+use crate::query::{Filter, Format};
+use crate::ipc::{Server, Client};
+use crate::capture::{CaptureBackend, Options};
+use crate::replay::Recorder;
 
 enum ExitCode {
     Success,
     Failure,
 }
 
+///
+/// A day, as a range of UNIX timestamps.
+///
 #[derive(Clone)]
 pub struct Date {
-    pub int_form: u32,
-    pub str_form: String, // MM/DD
+    pub start: i64,       // first second of the day
+    pub end: i64,         // first second of the next day
+    pub str_form: String, // MM/DD/YY
 }
 
 impl Date {
+    ///
+    /// Returns the day containing the UNIX timestamp 'ts' in the time zone 'tz'.
+    ///
+    pub fn day_of(ts: i64, tz: Tz) -> Self {
+        let (start, end) = tz.day_bounds(ts);
+
+        Date {
+            start,
+            end,
+            str_form: tz.format(start, "%m/%d/%y"),
+        }
+    }
+
     pub fn get_dates_str() -> Vec<String> {
         let dates = DATES.lock().unwrap().to_vec();
         let mut dates_str = Vec::new();
@@ -61,11 +97,6 @@ impl Date {
     }
 }
 
-lazy_static! {
-    // TODO: ring buffer to limit size in memory
-    // TODO: save in some shared memory so UI can connect to running daemon??
-    static ref PROCESSES: Mutex<Vec<Process>> = Mutex::new(Vec::new());
-}
 lazy_static! {
     // TODO: ring buffer too so we can flush regularly to file, without having duplicates in it
     static ref LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
@@ -76,6 +107,15 @@ lazy_static! {
 
 static DEBUG: bool = true;
 
+// Granularity at which the long running threads check if they must stop
+const POLL_DELAY: Duration = Duration::from_millis(200);
+
+// Time between two applications of the retention policy
+const MAINTENANCE_DELAY: Duration = Duration::from_secs(3600);
+
+// Time between two reads of the maps of the probes: traffic added up and events lost
+const READ_DELAY: Duration = Duration::from_secs(1);
+
 macro_rules! log {
     ($x:expr) => {
         let mut tmp = LOGS.lock().unwrap();
@@ -90,21 +130,132 @@ macro_rules! log {
 /// Raw terminal display.
 ///
 /// * `runnable` - A reference shared by all threads
+/// * `procs`    - What the capture found
 ///
-fn display(runnable: Arc<AtomicBool>) {
+fn display(runnable: Arc<AtomicBool>, procs: SharedProcs) {
     while runnable.load(Ordering::SeqCst) {
         thread::sleep(time::Duration::new(1, 0));
 
-        let procs = PROCESSES.lock().unwrap();
-
         // clear the screen
         print!("{esc}[2J{esc}[1;1H", esc = 27 as char);
 
-        for p in procs.iter() {
-            println!("{}", p);
+        print_procs(&procs);
+    }
+}
+
+///
+/// Print the processes and their links on stdout.
+///
+fn print_procs(procs: &SharedProcs) {
+    for p in procs.lock().unwrap().iter() {
+        println!("{}", p);
+
+        p.print_tlinks();
+        p.print_ulinks();
+        p.print_ilinks();
+    }
+}
+
+///
+/// Print the traffic recorded in the database 'source' matching the query options, on stdout.
+///
+fn query(source: &String, matches: &clap::ArgMatches, tz: Tz) -> Result<(), Box<dyn Error>> {
+    let today = tz.day_bounds(clock::now());
+
+    let from = match matches.value_of("from") {
+        Some(value) => tz.parse_time(value).ok_or(format!("invalid date {}", value))?,
+        None => today.0,
+    };
+    let to = match matches.value_of("to") {
+        Some(value) => tz.parse_time(value).ok_or(format!("invalid date {}", value))?,
+        None => today.1,
+    };
+
+    let mut filter = Filter::new(from, to);
+
+    filter.process = matches.value_of("process").map(String::from);
+    filter.remote = matches.value_of("remote").map(String::from);
+    filter.port = match matches.value_of("port") {
+        Some(port) => Some(port.parse().map_err(|_| format!("invalid port {}", port))?),
+        None => None,
+    };
+    filter.prot = match matches.value_of("protocol") {
+        Some(prot) => {
+            Some(net::ProtFilter::parse(prot).ok_or(format!("invalid protocol {}", prot))?)
+        }
+        None => None,
+    };
+    filter.app_proto = matches.value_of("app").map(str::to_lowercase);
+
+    let format = Format::parse(matches.value_of("format").unwrap()).unwrap();
+
+    if !Path::new(source).exists() {
+        return Err(format!("database {} not found", source).into());
+    }
+    let db = open_db_read_only(source)?;
+    let output = match matches.value_of("top") {
+        Some(n) => {
+            let n = n.parse().map_err(|_| format!("invalid number of processes {}", n))?;
+            query::print_procs(&query::select(&db, &filter, Some(n)), format)
+        },
+        None => query::print_links(&query::select(&db, &filter, None), format),
+    };
+    print!("{}", output);
+
+    Ok(())
+}
+
+///
+/// Publish the live traffic on the Unix socket 'path' for the UIs attached to the daemon.
+///
+fn run_server(runnable: Arc<AtomicBool>, path: String, procs: SharedProcs) {
+    let mut server = match Server::bind(&path) {
+        Ok(server) => server,
+        Err(e) => {
+            log!(String::from(format!("[-] UIs cannot attach to {}: {}", &path, e)));
+            return;
+        }
+    };
+    log!(String::from(format!("[+] Listening for UIs on {}", &path)));
+
+    while runnable.load(Ordering::SeqCst) {
+        thread::sleep(POLL_DELAY);
+
+        match server.accept() {
+            Ok(0) => (),
+            Ok(n) => { log!(String::from(format!("[+] {} UI(s) attached", n))); },
+            Err(e) => { log!(String::from(format!("[-] Failed to accept a UI: {}", e))); },
+        }
+
+        if !server.has_clients() {
+            continue;
+        }
+
+        let live = procs.lock().unwrap().to_vec();
+
+        match server.publish(live, procs.lost_events()) {
+            0 => (),
+            n => { log!(String::from(format!("[+] {} UI(s) detached", n))); },
+        }
+    }
+}
 
-            p.print_tlinks();
-            p.print_ulinks();
+///
+/// Follow the traffic published by a daemon in 'procs', in place of the capture.
+///
+fn run_client(runnable: Arc<AtomicBool>, mut client: Client, procs: SharedProcs) {
+    while runnable.load(Ordering::SeqCst) {
+        match client.poll() {
+            Ok(Some(message)) => {
+                // Shown by the UI in place of the ones of a capture of its own
+                procs.set_lost_events(message.lost());
+                message.apply(&mut procs.lock().unwrap());
+            }
+            Ok(None) => (),
+            Err(e) => {
+                log!(String::from(format!("[-] Detached from the daemon: {}", e)));
+                break;
+            }
         }
     }
 }
@@ -113,42 +264,34 @@ fn display(runnable: Arc<AtomicBool>) {
 /// Terminal UI
 ///
 /// * `runnable` - A reference shared by all threads
+/// * `procs`    - What the capture found, shown when the source is "realtime"
 ///
-fn tui(runnable: Arc<AtomicBool>, source: String) -> Result<(), Box<dyn Error>> {
+fn tui(runnable: Arc<AtomicBool>, source: String, tz: Tz, procs: SharedProcs)
+    -> Result<(), Box<dyn Error>>
+{
     let mut tick_rate = 500;
     let enhanced_graphics = true;
-    let procs: Vec<Process>;
     let mut app = ui::App::new(" netw ", enhanced_graphics);
 
+    app.tz(tz);
+
     /*
      * Select the input source to display data from.
      */
     if source == "realtime" {
-        procs = PROCESSES.lock().unwrap().to_vec();
+        app.procs(procs.lock().unwrap().to_vec());
+        app.live(procs);
     } else {
-        let db = open_db(&source).unwrap();
+        let db = open_db(&source)?;
         log!(String::from(format!("[+] Database {} opened", &source)));
 
         // TODO: make the same as 'freq'
         tick_rate = 500; //120000;
-        procs = get_procs(&db);
-
-        let mut dates = DATES.lock().unwrap();
-
-        for date in get_dates(&db).iter() {
-            let tmp = *date / 10000;
-            let year = *date % 100;
-            let month = tmp / 100;
-            let day = tmp % 100;
-
-            let date_str = String::from(format!("{:02}/{:02}/{:02}", month, day, year));
-            dates.push( Date { int_form: *date, str_form: date_str } );
-        };
-
         app.db(db);
-    }
 
-    app.procs(procs);
+        // Load the dates and the processes of the selected one
+        app.on_tick();
+    }
 
     let events = Events::with_config(Config {
         tick_rate: Duration::from_millis(tick_rate),
@@ -187,96 +330,257 @@ fn tui(runnable: Arc<AtomicBool>, source: String) -> Result<(), Box<dyn Error>>
     Ok(())
 }
 
+///
+/// Write to the database the traffic captured since the last flush. Errors are logged, the data
+/// not written is kept for the next flush.
+///
+fn flush_db(db: &mut Connection, checkpoint: &mut Checkpoint, procs: &SharedProcs, ts: i64) {
+    let live = procs.lock().unwrap().to_vec();
+
+    if let Err(e) = flush(db, checkpoint, live, ts) {
+        log!(String::from(format!("[-] Failed to update the database: {}", e)));
+    }
+
+    if let Err(e) = flush_lost(db, checkpoint, procs.lost_events(), ts) {
+        log!(String::from(format!("[-] Failed to record the lost events: {}", e)));
+    }
+}
+
+///
+/// Apply the retention policy to the database: roll old links up, delete what is past the
+/// horizon and compact the file. It has its own thread and its own connection to the database so
+/// that the regular updates done by the daemon are not delayed by it.
+///
+/// * `runnable` - A reference shared by all threads
+/// * `policy`   - How long to keep the data
+/// * `tz`       - Time zone used to decide when a day starts
+///
+fn run_maintenance(runnable: Arc<AtomicBool>, filename: String, policy: Policy, tz: Tz) {
+    let vacuum_delay = Duration::from_secs(policy.vacuum_every * 3600);
+    let mut last_check: Option<Instant> = None;
+    let mut last_vacuum = Instant::now();
+
+    let mut db = match open_db(&filename) {
+        Ok(db) => db,
+        Err(e) => {
+            log!(String::from(format!("[-] Retention disabled, cannot open {}: {}", &filename, e)));
+            return;
+        }
+    };
+
+    while runnable.load(Ordering::SeqCst) {
+        thread::sleep(POLL_DELAY);
+
+        if last_check.map_or(false, |t| t.elapsed() < MAINTENANCE_DELAY) {
+            continue;
+        }
+        last_check = Some(Instant::now());
+
+        let now = clock::now();
+
+        if let Some(cutoff) = policy.links_cutoff(now, tz) {
+            match retention::rollup(&mut db, cutoff, tz) {
+                Ok(0) => (),
+                Ok(days) => {
+                    log!(String::from(format!("[+] Links of {} day(s) summarized", days)));
+                }
+                Err(e) => {
+                    log!(String::from(format!("[-] Failed to summarize old links: {}", e)));
+                }
+            }
+        }
+
+        if let Some(horizon) = policy.horizon(now, tz) {
+            if let Err(e) = retention::expire(&mut db, horizon) {
+                log!(String::from(format!("[-] Failed to delete old traffic: {}", e)));
+            }
+        }
+
+        if policy.vacuum_every > 0 && last_vacuum.elapsed() >= vacuum_delay {
+            match retention::vacuum(&db) {
+                Ok(_) => {
+                    log!(String::from(format!("[+] Database {} compacted", &filename)));
+                }
+                Err(e) => {
+                    log!(String::from(format!("[-] Failed to compact the database: {}", e)));
+                }
+            }
+            last_vacuum = Instant::now();
+        }
+    }
+}
+
 ///
 /// Run in daemon mode. The data retrieved by the probes is stored in a SQL database.
 ///
+/// The traffic is recorded in time buckets (see database::BUCKET_WIDTH). The database is updated
+/// every 'freq' seconds and each time a bucket ends, so that the traffic lands in the bucket it
+/// was captured in. When the day changes, in the time zone 'tz', the in-memory counters start from
+/// zero for the new one.
+///
 /// * `runnable` - A reference shared by all threads
 /// * `freq`     - Time, in seconds, between two updates of the db
+/// * `tz`       - Time zone used to decide when a day starts
+/// * `policy`   - How long to keep the data in the database
+/// * `procs`    - What the capture found
+/// * `captured` - Disconnected once the capture returned, with what it read last
 ///
-fn run_daemon(runnable: Arc<AtomicBool>, filename: String, _freq: u64) {
-    // TODO: use freq
-    let delay = Duration::new(2, 0);
-    let date = Utc::now().format("%m%d%Y").to_string();
-    let date: u32 = date.parse().unwrap();
+fn run_daemon(
+    runnable: Arc<AtomicBool>, filename: String, freq: u64, tz: Tz, policy: Policy,
+    procs: SharedProcs, captured: Receiver<()>
+)
+{
+    let delay = Duration::new(freq, 0);
+    let mut now = clock::now();
+    let mut bucket = bucket_of(now);
+    let mut day = Date::day_of(now, tz);
+    let mut checkpoint = Checkpoint::new();
+    let mut last_flush = Instant::now();
     let mut db: Connection;
 
     if !Path::new(&filename).exists() {
         db = create_db(&filename).unwrap();
         log!(String::from(format!("[+] Database {} created", &filename)));
     } else {
-        db = open_db(&filename).unwrap();
+        db = match open_db(&filename) {
+            Ok(db) => db,
+            Err(e) => {
+                log!(String::from(format!("[-] Cannot open database {}: {}", &filename, e)));
+                runnable.store(false, Ordering::SeqCst);
+                return;
+            }
+        };
         log!(String::from(format!("[+] Database {} opened", &filename)));
     }
 
+    let th_maintenance = {
+        let runnable = runnable.clone();
+        let filename = filename.clone();
+        thread::spawn(move || run_maintenance(runnable, filename, policy, tz))
+    };
+
     while runnable.load(Ordering::SeqCst) {
-        thread::sleep(delay);
+        // Wake up regularly to not delay the exit when asked to stop
+        thread::sleep(POLL_DELAY);
+
+        now = clock::now();
+
+        if now >= day.end || now < day.start {
+            /*
+             * New day: write what is left for the previous one and start counting from zero.
+             */
+            let previous = net::rollover(&mut procs.lock().unwrap());
 
-        let procs = PROCESSES.lock().unwrap().to_vec();
+            if let Err(e) = flush(&mut db, &mut checkpoint, previous, bucket) {
+                log!(String::from(format!("[-] Failed to update the database: {}", e)));
+            }
+            checkpoint.restart();
+            bucket = bucket_of(now);
+            day = Date::day_of(now, tz);
+            last_flush = Instant::now();
+
+            log!(String::from(format!("[+] New day: {}", day.str_form)));
+            continue;
+        }
+
+        if bucket_of(now) != bucket {
+            // What was captured until now belongs to the bucket which just ended
+            flush_db(&mut db, &mut checkpoint, &procs, bucket);
+            bucket = bucket_of(now);
+            last_flush = Instant::now();
+            continue;
+        }
+
+        if last_flush.elapsed() < delay {
+            continue;
+        }
 
-        let _ret = update_db(&mut db, &procs, date);
+        flush_db(&mut db, &mut checkpoint, &procs, bucket);
+        last_flush = Instant::now();
 
         // TODO: save logs to file
     }
+
+    // Do not lose what was captured since the last flush, up to the last read of the capture
+    let _ = captured.recv();
+    flush_db(&mut db, &mut checkpoint, &procs, bucket);
+    log!(String::from(format!("[+] Database {} updated before exit", &filename)));
+
+    th_maintenance.join().unwrap();
 }
 
 ///
-/// Compile BPF code and start the probes.
+/// Log the records rejected by the decoder since the previous call, see filters.c for their header.
+///
+fn report_malformed(procs: &SharedProcs) {
+    if let Some((n, error)) = procs.take_malformed() {
+        log!(String::from(format!("[-] {} malformed records dropped: {}", n, error)));
+    }
+}
+
+///
+/// Start the probes with the capture backend 'backend' and read what they capture until stopped,
+/// or until the backend has nothing more.
 ///
 /// * `runnable` - A reference shared by all threads
+/// * `procs`    - Where to add what is captured
 ///
-fn capture(runnable: Arc<AtomicBool>, tcp: bool, udp: bool) -> Result<(), BccError> {
-    let filters = include_str!("bpf/filters.c");
-
-    log!(String::from("[+] Compiling and loading BPF filters..."));
-
-    let mut filters = BPF::new(filters)?;
-
-    // TCP probes
-    if tcp {
-        Kprobe::new()
-            .handler("kprobe__tcp_sendmsg")
-            .function("tcp_sendmsg")
-            .attach(&mut filters)?;
-        Kprobe::new()
-            .handler("kprobe__tcp_cleanup_rbuf")
-            .function("tcp_cleanup_rbuf")
-            .attach(&mut filters)?;
-
-        let tcp4_table = filters.table("tcp4_data")?;
-        let tcp6_table = filters.table("tcp6_data")?;
-        filters.init_perf_map(tcp4_table, net::tcp4_cb)?;
-        filters.init_perf_map(tcp6_table, net::tcp6_cb)?;
-    }
+fn capture(
+    runnable: Arc<AtomicBool>, mut backend: Box<dyn CaptureBackend>, options: Options,
+    procs: SharedProcs
+) -> Result<(), Box<dyn Error>>
+{
+    log!(String::from(format!(
+        "[+] Starting the capture ({})...", backend.description(&options)
+    )));
 
-    // UDP probes
-    if udp {
-        Kprobe::new()
-            .handler("kprobe__udp_sendmsg")
-            .function("udp_sendmsg")
-            .attach(&mut filters)?;
-        Kprobe::new()
-            .handler("kprobe__udp_recvmsg")
-            .function("udp_recvmsg")
-            .attach(&mut filters)?;
-        Kprobe::new()
-            .handler("kprobe__udpv6_sendmsg")
-            .function("udpv6_sendmsg")
-            .attach(&mut filters)?;
-        Kprobe::new()
-            .handler("kprobe__udpv6_recvmsg")
-            .function("udpv6_recvmsg")
-            .attach(&mut filters)?;
-
-        let udp4_table = filters.table("udp4_data")?;
-        let udp6_table = filters.table("udp6_data")?;
-        filters.init_perf_map(udp4_table, net::udp4_cb)?;
-        filters.init_perf_map(udp6_table, net::udp6_cb)?;
-    }
+    backend.start(&options, procs.clone())?;
+
+    let mut last_read = Instant::now();
 
     log!(String::from("[+] All done! Running..."));
 
     while runnable.load(Ordering::SeqCst) {
-        filters.perf_map_poll(200);
+        backend.poll(POLL_DELAY)?;
+
+        if backend.finished() {
+            log!(String::from("[+] Nothing more to capture"));
+            break;
+        }
+
+        if last_read.elapsed() < READ_DELAY {
+            continue;
+        }
+        last_read = Instant::now();
+
+        if let Some(recorder) = options.recorder.as_ref() {
+            recorder.lock().unwrap().flush().map_err(|e| format!("recording: {}", e))?;
+        }
+
+        if options.aggregate {
+            backend.drain_traffic();
+        }
+
+        // The records which did not fit in the buffers, their traffic is missing
+        if let Some(count) = backend.lost_events() {
+            match procs.set_lost_events(count) {
+                0 => (),
+                n => {
+                    log!(String::from(format!("[-] {} events lost, traffic undercounted", n)));
+                }
+            }
+        }
+
+        report_malformed(&procs);
+    }
+
+    // What was added up since the last read, whatever its generation
+    backend.stop();
+
+    report_malformed(&procs);
+
+    if let Some(recorder) = options.recorder.as_ref() {
+        recorder.lock().unwrap().flush().map_err(|e| format!("recording: {}", e))?;
     }
 
     Ok(())
@@ -289,6 +593,7 @@ fn main() {
     let arc_daemon = runnable.clone();
     let mut th_ui: Option<JoinHandle<()>> = None;
     let mut th_daemon: Option<JoinHandle<()>> = None;
+    let (capture_done, captured) = mpsc::channel::<()>();
     let mut test = false;
     let mut set_ctrlc = false;
     let mut set_probes = true;
@@ -305,12 +610,71 @@ fn main() {
     let freq = matches.value_of("frequency").unwrap();
     let freq: u64 = freq.parse().unwrap();
     let output = String::from( matches.value_of("output").unwrap() );
+    let socket = String::from( matches.value_of("socket").unwrap() );
+    let tz = match Tz::parse(matches.value_of("timezone").unwrap()) {
+        Some(tz) => tz,
+        None => {
+            eprintln!("Error: invalid time zone, expected local, utc or an offset like +02:00 \
+                (whole quarters of an hour)");
+            std::process::exit(ExitCode::Failure as i32);
+        }
+    };
+
+    /*
+     * Retention options.
+     */
+    let retention = |name: &str, unit: &str| -> u64 {
+        match matches.value_of(name).unwrap().parse() {
+            Ok(n) => n,
+            Err(_) => {
+                eprintln!("Error: invalid --{}, expected a number of {} or 0", name, unit);
+                std::process::exit(ExitCode::Failure as i32);
+            }
+        }
+    };
+    let policy = Policy {
+        keep_links: retention("keep-links", "days"),
+        keep_all: retention("keep-all", "days"),
+        vacuum_every: retention("vacuum-every", "hours"),
+    };
 
     /*
      * Capture options.
      */
     let tcp: bool = matches.value_of("tcp").unwrap().parse().unwrap();
     let udp: bool = matches.value_of("udp").unwrap().parse().unwrap();
+    let icmp: bool = matches.value_of("icmp").unwrap().parse().unwrap();
+    let rdns: bool = matches.value_of("rdns").unwrap().parse().unwrap();
+    let aggregate: bool = matches.value_of("aggregate").unwrap().parse().unwrap();
+    let mut backend = String::from(matches.value_of("backend").unwrap());
+    let events = matches.value_of("events").map(String::from);
+    let speed: f64 = match matches.value_of("speed").unwrap().parse() {
+        Ok(speed) if speed >= 0.0 => speed,
+        _ => {
+            eprintln!("Error: invalid speed, expected a positive number or 0");
+            std::process::exit(ExitCode::Failure as i32);
+        }
+    };
+    let recorder = match matches.value_of("record") {
+        Some(_) if aggregate => {
+            eprintln!("Error: --record needs the events of every packet, not --aggregate");
+            std::process::exit(ExitCode::Failure as i32);
+        }
+        Some(path) => match Recorder::create(path) {
+            Ok(recorder) => Some(Arc::new(Mutex::new(recorder))),
+            Err(e) => {
+                eprintln!("Error: cannot record to {}: {}", path, e);
+                std::process::exit(ExitCode::Failure as i32);
+            }
+        },
+        None => None,
+    };
+    let mut replay = false;
+
+    dns::set_enabled(rdns);
+
+    // What the capture (or the daemon the UI is attached to) finds, shared by the threads
+    let procs = net::new_procs();
 
     // TODO: add in config
     //      -> how far long ago (date) to display in the UI
@@ -322,9 +686,16 @@ fn main() {
         "daemon" => {
             set_ctrlc = true;
 
+            let procs_daemon = procs.clone();
             th_daemon = Some(thread::spawn(move || {
-                run_daemon(arc_daemon, output, freq);
+                run_daemon(arc_daemon, output, freq, tz, policy, procs_daemon, captured);
             }));
+
+            let arc_server = runnable.clone();
+            let procs_server = procs.clone();
+            thread::spawn(move || {
+                run_server(arc_server, socket, procs_server);
+            });
         },
         "test" => {
             test = true;
@@ -332,50 +703,111 @@ fn main() {
             println!("[debug] test mode");
         },
         "ui" => {
-            let source = matches.value_of("source").unwrap();
-            let source = String::from(source);
-
-            if source != "realtime" {
+            // Records read from a file are displayed as they are captured, without any database
+            // nor daemon
+            let offline = backend == "synthetic" || backend == "replay";
+            let source = match offline {
+                true => String::from("realtime"),
+                false => String::from(matches.value_of("source").unwrap()),
+            };
+
+            if offline {
+                log!(format!("[+] Capturing with the {} backend", &backend));
+            } else if source != "realtime" {
                 set_probes = false;
+            } else {
+                // Attach to a running daemon rather than capturing, no need to be root then
+                match Client::connect(&socket) {
+                    Ok(client) => {
+                        set_probes = false;
+                        log!(String::from(format!("[+] Attached to the daemon on {}", &socket)));
+
+                        let arc_client = runnable.clone();
+                        let procs_client = procs.clone();
+                        thread::spawn(move || {
+                            run_client(arc_client, client, procs_client);
+                        });
+                    }
+                    Err(_) => {
+                        log!(String::from(format!("[+] No daemon on {}, capturing", &socket)));
+                    }
+                }
             }
 
+            let procs_ui = procs.clone();
             th_ui = Some(thread::spawn(move || {
-                let _ret = tui(arc_display, source);
+                if let Err(e) = tui(arc_display, source, tz, procs_ui) {
+                    eprintln!("Error: {}", e);
+                }
             }));
         },
+        "query" => {
+            set_probes = false;
+
+            let source = String::from(matches.value_of("source").unwrap());
+
+            if let Err(e) = query(&source, &matches, tz) {
+                eprintln!("Error: {}", e);
+                std::process::exit(ExitCode::Failure as i32);
+            }
+        },
         "raw" => {
             set_ctrlc = true;
+            let procs_display = procs.clone();
             thread::spawn(move || {
-                display(arc_display);
+                display(arc_display, procs_display);
+            });
+        },
+        "replay" => {
+            // What the probes recorded with --record, no need to be root
+            set_ctrlc = true;
+            replay = true;
+            backend = String::from("replay");
+
+            let procs_display = procs.clone();
+            thread::spawn(move || {
+                display(arc_display, procs_display);
             });
         },
         _ => unreachable!(),
     }
 
     if set_ctrlc {
+        let procs_test = procs.clone();
         ctrlc::set_handler(move || {
             arc_main.store(false, Ordering::SeqCst);
             if test {
-                let _ret = net::log_iperf_to_file();
+                let _ret = net::log_iperf_to_file(&procs_test);
             }
         })
         .expect("Failed to set handler for SIGINT/SIGTERM");
     }
 
+    let mut exit_code = ExitCode::Success;
+
     if set_probes {
-        match capture(runnable, tcp, udp) {
-            Err(e) => {
-                eprintln!("Error: {}", e);
-                std::process::exit(ExitCode::Failure as i32);
-            }
-            _ => {
-                std::process::exit(ExitCode::Success as i32);
-            }
+        let options = Options { tcp, udp, icmp, aggregate, recorder };
+        let result = capture::backend(&backend, events.as_deref(), speed)
+            .and_then(|backend| capture(runnable.clone(), backend, options, procs.clone()));
+
+        if let Err(e) = result {
+            eprintln!("Error: {}", e);
+            exit_code = ExitCode::Failure;
+
+            // Stop the other threads, there is nothing more to capture
+            runnable.store(false, Ordering::SeqCst);
+        } else if replay {
+            // The whole recording was replayed
+            runnable.store(false, Ordering::SeqCst);
+            print_procs(&procs);
         }
     }
 
+    // The daemon writes what the capture read last
+    drop(capture_done);
+
     /*
-     * Wait for threads to finish.
+     * Wait for threads to finish. The daemon does a last write to the database before exiting.
      */
     match th_ui {
         Some(th) => th.join().unwrap(),
@@ -385,4 +817,6 @@ fn main() {
         Some(th) => th.join().unwrap(),
         None     => (),
     }
+
+    std::process::exit(exit_code as i32);
 }
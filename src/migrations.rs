@@ -0,0 +1,559 @@
+use rusqlite::{Connection, Result, Transaction, NO_PARAMS, ffi};
+
+use crate::net::Prot;
+
+///
+/// Schema migrations, in order: MIGRATIONS[n] upgrades a database from version n to n+1. The
+/// version of a database is stored in its 'user_version' pragma, which is 0 for a new database
+/// and for the ones created before the schema was versioned.
+///
+/// Never modify a migration once released, add a new one instead.
+///
+const MIGRATIONS: [fn(&Transaction) -> Result<()>; 12] = [
+    v1_initial_schema,
+    v2_time_buckets,
+    v3_daily_summaries,
+    v4_link_lifecycle,
+    v5_lost_events,
+    v6_link_server,
+    v7_link_app_proto,
+    v8_process_info,
+    v9_process_start,
+    v10_summary_links,
+    v11_start_nanoseconds,
+    v12_link_tcp_state,
+];
+
+///
+/// Version of the schema this build reads and writes.
+///
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+pub fn get_version(db: &Connection) -> Result<u32> {
+    db.query_row("PRAGMA user_version;", NO_PARAMS, |row| row.get(0))
+}
+
+///
+/// Upgrade 'db' to the latest version of the schema. Each migration runs in its own transaction so
+/// that a failure leaves the database at the last version successfully applied.
+///
+/// Databases created by a newer version of netw are refused since there is no way to know what
+/// changed in their schema.
+///
+pub fn migrate(db: &mut Connection) -> Result<()> {
+    migrate_to(db, SCHEMA_VERSION)
+}
+
+///
+/// Check that 'db', opened read-only, has the schema this build reads: an outdated one must be
+/// upgraded first by opening it for writing.
+///
+pub fn check_version(db: &Connection) -> Result<()> {
+    let version = get_version(db)?;
+
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    Err(rusqlite::Error::SqliteFailure(
+        ffi::Error::new(ffi::SQLITE_CANTOPEN),
+        Some(match version > SCHEMA_VERSION {
+            true => format!(
+                "database schema version {} is newer than the supported one ({}), upgrade netw",
+                version, SCHEMA_VERSION
+            ),
+            false => format!(
+                "database schema version {} is outdated ({}), run netw on it to upgrade it",
+                version, SCHEMA_VERSION
+            ),
+        }),
+    ))
+}
+
+///
+/// Upgrade 'db' up to the version 'target' of the schema.
+///
+pub fn migrate_to(db: &mut Connection, target: u32) -> Result<()> {
+    let version = get_version(db)?;
+
+    if version > SCHEMA_VERSION {
+        return Err(rusqlite::Error::SqliteFailure(
+            ffi::Error::new(ffi::SQLITE_CANTOPEN),
+            Some(format!(
+                "database schema version {} is newer than the supported one ({}), upgrade netw",
+                version, SCHEMA_VERSION
+            )),
+        ));
+    }
+
+    for v in version..target {
+        let transaction = db.transaction()?;
+
+        MIGRATIONS[v as usize](&transaction)?;
+
+        // Pragmas do not accept bound parameters
+        transaction.execute_batch(&format!("PRAGMA user_version = {};", v + 1))?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+///
+/// Schema used before it was versioned, traffic per process and per link for each day. Tables
+/// are only created if missing since databases of that time already have them, at version 0.
+///
+fn v1_initial_schema(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "CREATE TABLE IF NOT EXISTS processes (
+            p_pid       INTEGER NOT NULL,
+            p_date_id   TEXT NOT NULL,
+            p_name      TEXT NOT NULL DEFAULT '',
+            p_rx        INTEGER,
+            p_tx        INTEGER,
+            CONSTRAINT processes_fk_0 FOREIGN KEY (p_date_id) REFERENCES dates(date_id),
+            PRIMARY KEY (p_pid, p_date_id)
+        );
+        CREATE TABLE IF NOT EXISTS protocols (
+            prot_id     INTEGER PRIMARY KEY ASC,
+            prot_name   TEXT NOT NULL DEFAULT ''
+        );
+        CREATE TABLE IF NOT EXISTS dates (
+            date_id     INTEGER PRIMARY KEY ASC,
+            date_int    INTEGER UNIQUE NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS links (
+            l_p_pid     INTEGER,
+            l_date_id   TEXT NOT NULL,
+            l_saddr     TEXT NULL DEFAULT '',
+            l_daddr     TEXT NULL DEFAULT '',
+            l_lport     INTEGER,
+            l_dport     INTEGER,
+            l_rx        INTEGER,
+            l_tx        INTEGER,
+            l_prot_id   INTEGER,
+            l_domain    TEXT NOT NULL DEFAULT '',
+            CONSTRAINT links_fk_0 FOREIGN KEY (l_p_pid) REFERENCES processes(p_id),
+            CONSTRAINT links_fk_1 FOREIGN KEY (l_date_id) REFERENCES dates(date_id),
+            CONSTRAINT links_fk_2 FOREIGN KEY (l_prot_id) REFERENCES protocols(prot_id),
+            PRIMARY KEY (l_p_pid, l_date_id, l_saddr, l_daddr, l_lport, l_dport)
+        );"
+    )
+}
+
+///
+/// Replace the MMDDYYYY dates by time buckets identified by a UNIX timestamp (see
+/// database::BUCKET_WIDTH). The traffic of a day is moved to the bucket starting at midnight UTC
+/// of that day, the daemon used to compute dates in UTC.
+///
+fn v2_time_buckets(transaction: &Transaction) -> Result<()> {
+    // Timestamp of midnight UTC for a MMDDYYYY date
+    let date_to_ts = "CAST(strftime('%s', printf('%04d-%02d-%02d',
+        d.date_int % 10000, d.date_int / 1000000, (d.date_int / 10000) % 100)) AS INTEGER)";
+
+    transaction.execute_batch(&format!(
+        "CREATE TABLE processes_v2 (
+            p_pid       INTEGER NOT NULL,
+            p_ts        INTEGER NOT NULL,
+            p_name      TEXT NOT NULL DEFAULT '',
+            p_rx        INTEGER,
+            p_tx        INTEGER,
+            PRIMARY KEY (p_pid, p_ts)
+        );
+        CREATE TABLE links_v2 (
+            l_p_pid     INTEGER,
+            l_ts        INTEGER NOT NULL,
+            l_saddr     TEXT NULL DEFAULT '',
+            l_daddr     TEXT NULL DEFAULT '',
+            l_lport     INTEGER,
+            l_dport     INTEGER,
+            l_rx        INTEGER,
+            l_tx        INTEGER,
+            l_prot_id   INTEGER,
+            l_domain    TEXT NOT NULL DEFAULT '',
+            CONSTRAINT links_fk_0 FOREIGN KEY (l_p_pid, l_ts) REFERENCES processes(p_pid, p_ts),
+            CONSTRAINT links_fk_1 FOREIGN KEY (l_prot_id) REFERENCES protocols(prot_id),
+            PRIMARY KEY (l_p_pid, l_ts, l_prot_id, l_saddr, l_daddr, l_lport, l_dport)
+        );
+
+        INSERT INTO processes_v2 (p_pid, p_ts, p_name, p_rx, p_tx)
+            SELECT p.p_pid, {ts}, p.p_name, p.p_rx, p.p_tx
+            FROM processes p JOIN dates d ON d.date_id = p.p_date_id;
+        INSERT INTO links_v2 (l_p_pid, l_ts, l_saddr, l_daddr, l_lport, l_dport,
+                l_rx, l_tx, l_prot_id, l_domain)
+            SELECT l.l_p_pid, {ts}, l.l_saddr, l.l_daddr, l.l_lport, l.l_dport,
+                l.l_rx, l.l_tx, l.l_prot_id, l.l_domain
+            FROM links l JOIN dates d ON d.date_id = l.l_date_id;
+
+        DROP TABLE links;
+        DROP TABLE processes;
+        DROP TABLE dates;
+        ALTER TABLE processes_v2 RENAME TO processes;
+        ALTER TABLE links_v2 RENAME TO links;
+
+        CREATE INDEX processes_ts ON processes(p_ts);
+        CREATE INDEX links_ts ON links(l_ts);",
+        ts = date_to_ts,
+    ))?;
+
+    for prot in [Prot::TCP, Prot::UDP].iter() {
+        transaction.execute(
+            "INSERT OR REPLACE INTO protocols (prot_id, prot_name) VALUES (?1, ?2)",
+            rusqlite::params![prot.id(), prot.name()],
+        )?;
+    }
+
+    Ok(())
+}
+
+///
+/// Daily traffic per process and per remote address, what is left of the links once they are
+/// older than the retention period (see retention.rs).
+///
+fn v3_daily_summaries(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "CREATE TABLE summaries (
+            s_day       INTEGER NOT NULL,
+            s_p_pid     INTEGER NOT NULL,
+            s_p_name    TEXT NOT NULL DEFAULT '',
+            s_daddr     TEXT NOT NULL DEFAULT '',
+            s_domain    TEXT NOT NULL DEFAULT '',
+            s_rx        INTEGER,
+            s_tx        INTEGER,
+            PRIMARY KEY (s_day, s_p_pid, s_daddr)
+        );"
+    )
+}
+
+///
+/// When the TCP connections were opened and closed, as UNIX timestamps. NULL when not seen, e.g.
+/// for UDP or for the connections opened before the capture started.
+///
+fn v4_link_lifecycle(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "ALTER TABLE links ADD COLUMN l_opened INTEGER NULL;
+        ALTER TABLE links ADD COLUMN l_closed INTEGER NULL;"
+    )
+}
+
+///
+/// Number of records dropped by the probes per time bucket, the traffic recorded in these
+/// buckets is lower than the real one.
+///
+fn v5_lost_events(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "CREATE TABLE lost (
+            lo_ts       INTEGER PRIMARY KEY,
+            lo_count    INTEGER NOT NULL DEFAULT 0
+        );"
+    )
+}
+
+///
+/// Name of the server asked for by the process on each TCP link, read from the first bytes it sent
+/// (see server_name.rs), kept in the daily summaries. Empty when none was found, e.g. for what is
+/// already recorded.
+///
+fn v6_link_server(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "ALTER TABLE links ADD COLUMN l_server TEXT NOT NULL DEFAULT '';
+        ALTER TABLE summaries ADD COLUMN s_server TEXT NOT NULL DEFAULT '';"
+    )
+}
+
+///
+/// Application protocol spoken on each link, from its ports or the first bytes sent on it (see
+/// classify.rs). Empty when unknown.
+///
+fn v7_link_app_proto(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "ALTER TABLE links ADD COLUMN l_app_proto TEXT NOT NULL DEFAULT '';"
+    )
+}
+
+///
+/// What each process is, once per process rather than in each time bucket: its executable, its
+/// arguments separated by NUL characters as in /proc/<pid>/cmdline, its parent, its users and its
+/// start as a UNIX timestamp, 0 when unknown. The names of the users are kept apart.
+///
+fn v8_process_info(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "CREATE TABLE process_info (
+            pi_pid      INTEGER NOT NULL,
+            pi_start    INTEGER NOT NULL DEFAULT 0,
+            pi_exe      TEXT NOT NULL DEFAULT '',
+            pi_cmdline  TEXT NOT NULL DEFAULT '',
+            pi_ppid     INTEGER NULL,
+            pi_uid      INTEGER NULL,
+            pi_euid     INTEGER NULL,
+            PRIMARY KEY (pi_pid, pi_start)
+        );
+        CREATE TABLE users (
+            u_uid       INTEGER PRIMARY KEY,
+            u_name      TEXT NOT NULL DEFAULT ''
+        );"
+    )
+}
+
+///
+/// Processes identified by their pid and their start, 0 when unknown as for what is already
+/// recorded, so that a reused pid is another process: the traffic of each process, of its links
+/// and its daily summaries are kept apart. When the processes exited, NULL if not seen.
+///
+fn v9_process_start(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "CREATE TABLE processes_v9 (
+            p_pid       INTEGER NOT NULL,
+            p_start     INTEGER NOT NULL DEFAULT 0,
+            p_ts        INTEGER NOT NULL,
+            p_name      TEXT NOT NULL DEFAULT '',
+            p_rx        INTEGER,
+            p_tx        INTEGER,
+            PRIMARY KEY (p_pid, p_start, p_ts)
+        );
+        CREATE TABLE links_v9 (
+            l_p_pid     INTEGER,
+            l_p_start   INTEGER NOT NULL DEFAULT 0,
+            l_ts        INTEGER NOT NULL,
+            l_saddr     TEXT NULL DEFAULT '',
+            l_daddr     TEXT NULL DEFAULT '',
+            l_lport     INTEGER,
+            l_dport     INTEGER,
+            l_rx        INTEGER,
+            l_tx        INTEGER,
+            l_prot_id   INTEGER,
+            l_domain    TEXT NOT NULL DEFAULT '',
+            l_opened    INTEGER NULL,
+            l_closed    INTEGER NULL,
+            l_server    TEXT NOT NULL DEFAULT '',
+            l_app_proto TEXT NOT NULL DEFAULT '',
+            CONSTRAINT links_fk_0 FOREIGN KEY (l_p_pid, l_p_start, l_ts)
+                REFERENCES processes(p_pid, p_start, p_ts),
+            CONSTRAINT links_fk_1 FOREIGN KEY (l_prot_id) REFERENCES protocols(prot_id),
+            PRIMARY KEY (l_p_pid, l_p_start, l_ts, l_prot_id, l_saddr, l_daddr, l_lport, l_dport)
+        );
+        CREATE TABLE summaries_v9 (
+            s_day       INTEGER NOT NULL,
+            s_p_pid     INTEGER NOT NULL,
+            s_p_start   INTEGER NOT NULL DEFAULT 0,
+            s_p_name    TEXT NOT NULL DEFAULT '',
+            s_daddr     TEXT NOT NULL DEFAULT '',
+            s_domain    TEXT NOT NULL DEFAULT '',
+            s_rx        INTEGER,
+            s_tx        INTEGER,
+            s_server    TEXT NOT NULL DEFAULT '',
+            PRIMARY KEY (s_day, s_p_pid, s_p_start, s_daddr)
+        );
+
+        INSERT INTO processes_v9 (p_pid, p_ts, p_name, p_rx, p_tx)
+            SELECT p_pid, p_ts, p_name, p_rx, p_tx FROM processes;
+        INSERT INTO links_v9 (l_p_pid, l_ts, l_saddr, l_daddr, l_lport, l_dport, l_rx, l_tx,
+                l_prot_id, l_domain, l_opened, l_closed, l_server, l_app_proto)
+            SELECT l_p_pid, l_ts, l_saddr, l_daddr, l_lport, l_dport, l_rx, l_tx,
+                l_prot_id, l_domain, l_opened, l_closed, l_server, l_app_proto
+            FROM links;
+        INSERT INTO summaries_v9 (s_day, s_p_pid, s_p_name, s_daddr, s_domain, s_rx, s_tx,
+                s_server)
+            SELECT s_day, s_p_pid, s_p_name, s_daddr, s_domain, s_rx, s_tx, s_server
+            FROM summaries;
+
+        DROP TABLE links;
+        DROP TABLE processes;
+        DROP TABLE summaries;
+        ALTER TABLE processes_v9 RENAME TO processes;
+        ALTER TABLE links_v9 RENAME TO links;
+        ALTER TABLE summaries_v9 RENAME TO summaries;
+
+        CREATE INDEX processes_ts ON processes(p_ts);
+        CREATE INDEX links_ts ON links(l_ts);
+
+        ALTER TABLE process_info ADD COLUMN pi_exited INTEGER NULL;"
+    )
+}
+
+///
+/// Daily summaries per link rather than per remote address: their protocol, local address, remote
+/// port and application protocol are kept, only the local port is left out. What is already
+/// rolled up did not keep them and is taken as TCP from any local address.
+///
+fn v10_summary_links(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "CREATE TABLE summaries_v10 (
+            s_day       INTEGER NOT NULL,
+            s_p_pid     INTEGER NOT NULL,
+            s_p_start   INTEGER NOT NULL DEFAULT 0,
+            s_p_name    TEXT NOT NULL DEFAULT '',
+            s_prot_id   INTEGER NOT NULL DEFAULT 0,
+            s_saddr     TEXT NOT NULL DEFAULT '',
+            s_daddr     TEXT NOT NULL DEFAULT '',
+            s_dport     INTEGER NOT NULL DEFAULT 0,
+            s_domain    TEXT NOT NULL DEFAULT '',
+            s_server    TEXT NOT NULL DEFAULT '',
+            s_app_proto TEXT NOT NULL DEFAULT '',
+            s_rx        INTEGER,
+            s_tx        INTEGER,
+            CONSTRAINT summaries_fk_0 FOREIGN KEY (s_prot_id) REFERENCES protocols(prot_id),
+            PRIMARY KEY (s_day, s_p_pid, s_p_start, s_prot_id, s_saddr, s_daddr, s_dport)
+        );
+
+        INSERT INTO summaries_v10 (s_day, s_p_pid, s_p_start, s_p_name, s_saddr, s_daddr,
+                s_domain, s_server, s_rx, s_tx)
+            SELECT s_day, s_p_pid, s_p_start, s_p_name,
+                CASE WHEN instr(s_daddr, ':') > 0 THEN '::' ELSE '0.0.0.0' END,
+                s_daddr, s_domain, s_server, s_rx, s_tx
+            FROM summaries;
+
+        DROP TABLE summaries;
+        ALTER TABLE summaries_v10 RENAME TO summaries;"
+    )
+}
+
+///
+/// The starts of the processes are kept to the nanosecond the probes tell, not to the second,
+/// so that a pid reused within the same second is another process. 0 is still an unknown start.
+///
+fn v11_start_nanoseconds(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "PRAGMA defer_foreign_keys = ON;
+
+        UPDATE processes SET p_start = p_start * 1000000000 WHERE p_start != 0;
+        UPDATE links SET l_p_start = l_p_start * 1000000000 WHERE l_p_start != 0;
+        UPDATE process_info SET pi_start = pi_start * 1000000000 WHERE pi_start != 0;
+        UPDATE summaries SET s_p_start = s_p_start * 1000000000 WHERE s_p_start != 0;"
+    )
+}
+
+///
+/// The last state of the TCP connections the kernel told of, named as net::TcpState, NULL when
+/// it did not. The connections recorded before only had their opening and closing by their
+/// process: closed if closed since opened, established else.
+///
+fn v12_link_tcp_state(transaction: &Transaction) -> Result<()> {
+    transaction.execute_batch(
+        "ALTER TABLE links ADD COLUMN l_tcp_state TEXT NULL;
+
+        UPDATE links SET l_tcp_state =
+                CASE WHEN l_closed >= COALESCE(l_opened, 0) THEN 'closed' ELSE 'established' END
+            WHERE l_opened IS NOT NULL OR l_closed IS NOT NULL;"
+    )
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{load_procs, Selection};
+
+    ///
+    /// Database as created before versioning, with the traffic of one process on 07/03/2021.
+    ///
+    fn fixture_v1() -> Connection {
+        let mut db = Connection::open_in_memory().unwrap();
+
+        migrate_to(&mut db, 1).unwrap();
+        db.execute_batch(
+            "PRAGMA user_version = 0;
+            INSERT INTO dates (date_int) VALUES (07032021);
+            INSERT INTO processes (p_pid, p_date_id, p_name, p_rx, p_tx)
+                VALUES (1234, 1, 'curl', 100, 10);
+            INSERT INTO links (l_p_pid, l_date_id, l_saddr, l_daddr, l_lport, l_dport,
+                    l_rx, l_tx, l_prot_id, l_domain)
+                VALUES (1234, 1, '192.168.1.2', '10.10.100.200', 4321, 443, 100, 10, 0, '');"
+        ).unwrap();
+
+        db
+    }
+
+    #[test]
+    fn migrate_new_db() {
+        let mut db = Connection::open_in_memory().unwrap();
+
+        migrate(&mut db).unwrap();
+
+        assert_eq!(get_version(&db), Ok(SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn migrate_unversioned_db() {
+        let mut db = fixture_v1();
+
+        migrate(&mut db).unwrap();
+
+        assert_eq!(get_version(&db), Ok(SCHEMA_VERSION));
+
+        // 07/03/2021 00:00:00 UTC
+        let procs = load_procs(&db, &Selection::new(1625270400, 1625356800));
+
+        assert_eq!(procs.len(), 1, "process lost during the migration");
+        assert_eq!(procs[0].name, "curl");
+        assert_eq!((procs[0].rx, procs[0].tx), (100, 10), "process traffic incorrect");
+        assert_eq!(procs[0].tlinks.len(), 1, "link lost during the migration");
+        assert_eq!((procs[0].tlinks[0].rx, procs[0].tlinks[0].tx), (100, 10));
+    }
+
+    #[test]
+    fn migrate_is_idempotent() {
+        let mut db = fixture_v1();
+
+        migrate(&mut db).unwrap();
+        migrate(&mut db).unwrap();
+
+        assert_eq!(get_version(&db), Ok(SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn migrate_starts_to_nanoseconds() {
+        let mut db = Connection::open_in_memory().unwrap();
+
+        migrate_to(&mut db, 10).unwrap();
+        db.execute_batch(
+            "INSERT INTO processes (p_pid, p_start, p_ts, p_name) VALUES (1234, 1625320900, 0, ''),
+                (1235, 0, 0, '');"
+        ).unwrap();
+        migrate(&mut db).unwrap();
+
+        let starts: Vec<i64> = db.prepare("SELECT p_start FROM processes ORDER BY p_pid;").unwrap()
+            .query_map(NO_PARAMS, |row| row.get(0)).unwrap()
+            .map(|x| x.unwrap())
+            .collect();
+        assert_eq!(starts, vec![1_625_320_900_000_000_000, 0], "starts not in nanoseconds");
+    }
+
+    #[test]
+    fn migrate_link_tcp_states() {
+        let mut db = Connection::open_in_memory().unwrap();
+
+        migrate_to(&mut db, 11).unwrap();
+        db.execute_batch(
+            "INSERT INTO processes (p_pid, p_ts, p_name) VALUES (1234, 0, 'curl');
+            INSERT INTO links (l_p_pid, l_ts, l_lport, l_dport, l_prot_id, l_opened, l_closed)
+                VALUES (1234, 0, 1, 443, 0, 100, 110), (1234, 0, 2, 443, 0, 120, 110),
+                    (1234, 0, 3, 443, 0, 100, NULL), (1234, 0, 4, 443, 0, NULL, 110),
+                    (1234, 0, 5, 53, 1, NULL, NULL);"
+        ).unwrap();
+        migrate(&mut db).unwrap();
+
+        let states: Vec<Option<String>> = db
+            .prepare("SELECT l_tcp_state FROM links ORDER BY l_lport;").unwrap()
+            .query_map(NO_PARAMS, |row| row.get(0)).unwrap()
+            .map(|x| x.unwrap())
+            .collect();
+        assert_eq!(states, vec![
+            Some(String::from("closed")), Some(String::from("established")),
+            Some(String::from("established")), Some(String::from("closed")), None,
+        ]);
+    }
+
+    #[test]
+    fn refuse_newer_db() {
+        let mut db = Connection::open_in_memory().unwrap();
+
+        db.execute_batch(&format!("PRAGMA user_version = {};", SCHEMA_VERSION + 1)).unwrap();
+
+        assert!(migrate(&mut db).is_err(), "database from a newer version opened");
+        assert!(check_version(&db).is_err(), "database from a newer version read");
+    }
+}
@@ -1,15 +1,29 @@
-use std::{ptr, fmt, fs};
+use std::{fmt, fs};
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::fs::File;
 use std::io::prelude::*;
+use std::sync::{Arc, LockResult, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use crate::PROCESSES;
-use crate::dns::reverse_lookup;
+use crate::dns;
+use crate::passive_dns;
+use crate::procfs;
+use crate::clock::Tz;
+use crate::classify;
+use crate::server_name;
 
 use rusqlite::types::{FromSql, FromSqlResult, FromSqlError, ValueRef};
 
 extern crate num;
 
+// Size of the header of the records, the data follows: kind, version, length and padding
+const RECORD_HEADER: usize = 16;
+
+// Version of the layouts of the records, see bpf/records.h
+pub const RECORD_VERSION: u16 = 5;
+
 #[derive(Copy, Clone, Debug, FromPrimitive)]
 pub enum DataUnit {
     Bytes,
@@ -23,43 +37,401 @@ pub enum DataUnit {
 pub enum Prot {
     TCP,
     UDP,
+    ICMP(u8, u8), // type and code of the messages, ICMPv6 ones for IPv6 links
     NONE,
 }
 
+// Identifiers of the ICMP messages in the protocols table, see Prot::id()
+const ICMP_ID: u32 = 0x10000;
+
+impl Prot {
+    ///
+    /// Identifier in the protocols table: 0 for TCP, 1 for UDP and 0x1TTCC for the ICMP messages
+    /// of type TT and code CC.
+    ///
+    pub fn id(&self) -> u32 {
+        match self {
+            Prot::TCP => 0,
+            Prot::UDP => 1,
+            Prot::ICMP(icmp_type, code) => ICMP_ID | (*icmp_type as u32) << 8 | *code as u32,
+            Prot::NONE => u32::MAX,
+        }
+    }
+
+    pub fn from_id(id: i64) -> Option<Prot> {
+        match id {
+            0 => Some(Prot::TCP),
+            1 => Some(Prot::UDP),
+            id if id & !0xFFFF == ICMP_ID as i64 => {
+                Some(Prot::ICMP((id >> 8) as u8, id as u8))
+            }
+            _ => None,
+        }
+    }
+
+    ///
+    /// Name of the protocol, without the details of the messages.
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            Prot::NONE => "NONE",
+            Prot::TCP => "TCP",
+            Prot::UDP => "UDP",
+            Prot::ICMP(_, _) => "ICMP",
+        }
+    }
+}
+
 impl fmt::Display for Prot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Prot::ICMP(icmp_type, code) => write!(f, "ICMP {}/{}", icmp_type, code),
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+///
+/// Protocol selected by a query: TCP, UDP, or the ICMP messages of one type and code or of any.
+///
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ProtFilter {
+    TCP,
+    UDP,
+    ICMP(Option<(u8, u8)>),
+}
+
+impl ProtFilter {
+    ///
+    /// Parse the value given on the command line: "tcp", "udp", "icmp" or "icmp/<type>/<code>".
+    ///
+    pub fn parse(value: &str) -> Option<ProtFilter> {
+        match value.to_lowercase().as_str() {
+            "tcp"  => Some(ProtFilter::TCP),
+            "udp"  => Some(ProtFilter::UDP),
+            "icmp" => Some(ProtFilter::ICMP(None)),
+            value => {
+                let fields: Vec<&str> = value.strip_prefix("icmp/")?.split('/').collect();
+
+                match fields[..] {
+                    [icmp_type, code] => {
+                        Some(ProtFilter::ICMP(Some((icmp_type.parse().ok()?, code.parse().ok()?))))
+                    }
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    pub fn matches(&self, prot: Prot) -> bool {
+        match (self, prot) {
+            (ProtFilter::TCP, Prot::TCP) | (ProtFilter::UDP, Prot::UDP) => true,
+            (ProtFilter::ICMP(None), Prot::ICMP(..)) => true,
+            (ProtFilter::ICMP(Some(message)), Prot::ICMP(icmp_type, code)) => {
+                *message == (icmp_type, code)
+            }
+            _ => false,
+        }
+    }
+
+    ///
+    /// Name of the protocols selected in the protocols table, see Prot::name().
+    ///
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProtFilter::TCP => Prot::TCP.name(),
+            ProtFilter::UDP => Prot::UDP.name(),
+            ProtFilter::ICMP(_) => Prot::ICMP(0, 0).name(),
+        }
+    }
+
+    ///
+    /// Identifier of the protocol selected in the protocols table, None when several are.
+    ///
+    pub fn id(&self) -> Option<u32> {
+        match self {
+            ProtFilter::TCP => Some(Prot::TCP.id()),
+            ProtFilter::UDP => Some(Prot::UDP.id()),
+            ProtFilter::ICMP(message) => message.map(|(icmp_type, code)| {
+                Prot::ICMP(icmp_type, code).id()
+            }),
+        }
+    }
+}
+
+impl FromSql for Prot {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_i64() {
+            Ok(id) => Prot::from_id(id).ok_or(FromSqlError::InvalidType),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+///
+/// Whether a connection is still open, from its TCP state: only known for the TCP links the
+/// kernel told about while capturing.
+///
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum State {
+    Unknown,
+    Open,
+    Closed,
+}
+
+impl fmt::Display for State {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f, "{}",
             match self {
-                Prot::NONE => "NONE",
-                Prot::TCP => "TCP",
-                Prot::UDP => "UDP",
+                State::Unknown => "unknown",
+                State::Open => "open",
+                State::Closed => "closed",
             },
         )
     }
 }
 
-impl FromSql for Prot {
-    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
-        match value.as_i64() {
-            Ok(0) => Ok(Prot::TCP),
-            Ok(1) => Ok(Prot::UDP),
-            _ => Err(FromSqlError::InvalidType),
+///
+/// State of a TCP connection as the kernel has it, the values are the TCP_* ones of the kernel
+/// (include/net/tcp_states.h).
+///
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TcpState {
+    Established = 1,
+    SynSent,
+    SynRecv,
+    FinWait1,
+    FinWait2,
+    TimeWait,
+    Close,
+    CloseWait,
+    LastAck,
+    Listen,
+    Closing,
+    NewSynRecv,
+}
+
+// Names of the states, as ss(8) shows them
+const TCP_STATES: [(TcpState, &str); 12] = [
+    (TcpState::Established, "established"),
+    (TcpState::SynSent, "syn-sent"),
+    (TcpState::SynRecv, "syn-recv"),
+    (TcpState::FinWait1, "fin-wait-1"),
+    (TcpState::FinWait2, "fin-wait-2"),
+    (TcpState::TimeWait, "time-wait"),
+    (TcpState::Close, "closed"),
+    (TcpState::CloseWait, "close-wait"),
+    (TcpState::LastAck, "last-ack"),
+    (TcpState::Listen, "listen"),
+    (TcpState::Closing, "closing"),
+    (TcpState::NewSynRecv, "new-syn-recv"),
+];
+
+impl TcpState {
+    ///
+    /// Returns the state of the kernel value 'state', None if the kernel has no such state.
+    ///
+    pub fn from_kernel(state: u32) -> Option<TcpState> {
+        TCP_STATES.iter().map(|(x, _)| *x).find(|x| *x as u32 == state)
+    }
+
+    ///
+    /// Returns the state named 'name', as displayed.
+    ///
+    pub fn parse(name: &str) -> Option<TcpState> {
+        TCP_STATES.iter().find(|(_, x)| *x == name).map(|(state, _)| *state)
+    }
+
+    pub fn name(&self) -> &'static str {
+        TCP_STATES.iter().find(|(state, _)| state == self).unwrap().1
+    }
+}
+
+impl fmt::Display for TcpState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+///
+/// The processes seen communicating and their links, filled by the callbacks of the probes and
+/// read by the threads displaying or saving them, with the counters of the capture and what the
+/// processes executed. Each capture has its own, e.g. each test.
+///
+pub struct Procs {
+    procs: Mutex<Vec<Process>>,
+    execs: Mutex<HashMap<u32, Exec>>, // what the processes executed last, per pid, see exec_cb()
+    descriptions: Mutex<HashMap<u32, (Option<i64>, Process)>>, // per pid and start, see describe()
+    lost: AtomicU64,                // records dropped by the probes, see filters.c
+    malformed: AtomicU64,           // records rejected by the decoder
+    reported_malformed: AtomicU64,  // the ones of them already reported
+    last_malformed: Mutex<String>,  // why the last one was rejected
+}
+
+pub type SharedProcs = Arc<Procs>;
+
+pub fn new_procs() -> SharedProcs {
+    Arc::new(Procs {
+        procs: Mutex::new(Vec::new()),
+        execs: Mutex::new(HashMap::new()),
+        descriptions: Mutex::new(HashMap::new()),
+        lost: AtomicU64::new(0),
+        malformed: AtomicU64::new(0),
+        reported_malformed: AtomicU64::new(0),
+        last_malformed: Mutex::new(String::new()),
+    })
+}
+
+impl Procs {
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, Vec<Process>>> {
+        self.procs.lock()
+    }
+
+    ///
+    /// Number of records lost by the probes since the capture started.
+    ///
+    pub fn lost_events(&self) -> u64 {
+        self.lost.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// Update the number of records lost, read from the probes. Returns how many were lost since
+    /// the previous update.
+    ///
+    pub fn set_lost_events(&self, count: u64) -> u64 {
+        count.saturating_sub(self.lost.swap(count, Ordering::SeqCst))
+    }
+
+    ///
+    /// Returns the structure 'T' held by 'x', None if malformed, it is then counted and reported
+    /// by take_malformed().
+    ///
+    fn decoded<T: Decode>(&self, x: &[u8]) -> Option<T> {
+        decode(x).map_err(|error| self.malformed(error)).ok()
+    }
+
+    fn malformed(&self, error: String) {
+        *self.last_malformed.lock().unwrap() = error;
+        self.malformed.fetch_add(1, Ordering::SeqCst);
+    }
+
+    ///
+    /// Returns the number of malformed records dropped since the previous call, if any, and why
+    /// the last one was.
+    ///
+    pub fn take_malformed(&self) -> Option<(u64, String)> {
+        let count = self.malformed.load(Ordering::SeqCst);
+
+        match count.saturating_sub(self.reported_malformed.swap(count, Ordering::SeqCst)) {
+            0 => None,
+            n => Some((n, self.last_malformed.lock().unwrap().clone())),
+        }
+    }
+
+    ///
+    /// Returns what the process 'pid' executed last, if seen by the probes since the capture
+    /// started.
+    ///
+    pub fn exec_of(&self, pid: u32) -> Option<Exec> {
+        self.execs.lock().unwrap().get(&pid).cloned()
+    }
+
+    ///
+    /// Lock the processes for a record of the process 'p'. When its pid runs no process known, 'p'
+    /// is a new one: it is described first, without the lock, see describe().
+    ///
+    fn lock_for(&self, p: &mut Process) -> MutexGuard<'_, Vec<Process>> {
+        let procs = self.procs.lock().unwrap();
+
+        if procs.iter().any(|x| x.pid == p.pid && x.exited.is_none()) {
+            return procs;
+        }
+        drop(procs);
+
+        self.describe(p);
+        self.procs.lock().unwrap()
+    }
+
+    ///
+    /// Name and describe the new process 'p': with what the probes saw it execute, else with what
+    /// /proc says. Its start, which identifies it, is known from then on if it can be.
+    ///
+    /// The description is kept per pid and start, so that /proc and the users are read once for
+    /// each process, not for each of its records once it exited.
+    ///
+    fn describe(&self, p: &mut Process) {
+        let exec = self.exec_of(p.pid);
+        let start = match exec.as_ref() {
+            Some(exec) => exec.start,
+            None => procfs::start_of(p.pid),
+        };
+
+        let cached = match self.descriptions.lock().unwrap().get(&p.pid) {
+            Some((described, description)) if *described == start => Some(description.clone()),
+            _ => None,
+        };
+        let description = cached.unwrap_or_else(|| {
+            let mut description = Process::new(p.pid);
+
+            match exec {
+                Some(exec) => {
+                    description.name(exec.comm.clone()).exec(&exec);
+                    description.start = exec.start;
+                }
+                None => { description.name(process_name(p.pid)); }
+            }
+            procfs::describe(&mut description);
+
+            let mut descriptions = self.descriptions.lock().unwrap();
+            if descriptions.len() >= MAX_EXECS && !descriptions.contains_key(&p.pid) {
+                descriptions.clear();
+            }
+            descriptions.insert(p.pid, (start, description.clone()));
+
+            description
+        });
+
+        if p.name.is_empty() {
+            p.name(description.name.clone());
         }
+        p.details(&description);
     }
 }
 
+///
+/// A program executed by a process, as seen by the probes.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exec {
+    pub comm: String,
+    pub exe: String,
+    pub cmdline: Vec<String>,
+    pub ppid: u32,
+    pub uid: u32,
+    pub euid: u32,
+    pub start: Option<i64>, // UNIX timestamp in nanoseconds
+}
+
 #[derive(Clone)]
 pub struct Process {
     pub pid: u32,
     pub name: String,
-    //command: String,
+    pub exe: String,            // Path of the executable, empty when unknown as what follows
+    pub cmdline: Vec<String>,   // Arguments, the program first
+    pub ppid: Option<u32>,
+    pub uid: Option<u32>,       // Real user
+    pub euid: Option<u32>,      // Effective user, e.g. root for a setuid program
+    pub user: String,           // Names of 'uid' and 'euid', see procfs::user_name()
+    pub euser: String,
+    pub start: Option<i64>,     // UNIX timestamp in nanoseconds, with the pid what identifies it
+    pub exited: Option<i64>,    // UNIX timestamp, a process of the same pid is then another one
     pub tlinks: Vec<Link>,
     pub ulinks: Vec<Link>,
+    pub ilinks: Vec<Link>,
     pub rx: isize,
     pub tx: isize,
-    pub date: u32,
-    //status: u8, // TODO: enum
+    pub names: HashMap<IpAddr, String>, // Names asked by the process for the addresses, passive DNS
 }
 
 impl Process {
@@ -67,11 +439,21 @@ impl Process {
         Process {
             pid: pid,
             name: String::new(),
+            exe: String::new(),
+            cmdline: Vec::new(),
+            ppid: None,
+            uid: None,
+            euid: None,
+            user: String::new(),
+            euser: String::new(),
+            start: None,
+            exited: None,
             tlinks: Vec::new(),
             ulinks: Vec::new(),
+            ilinks: Vec::new(),
             rx: 0,
             tx: 0,
-            date: 0,
+            names: HashMap::new(),
         }
     }
 
@@ -91,6 +473,70 @@ impl Process {
         self
     }
 
+    ///
+    /// Describe the process with what the probes saw it execute, see Procs::exec_of(). Its start
+    /// is left as is, it identifies the process.
+    ///
+    pub fn exec(&mut self, exec: &Exec) -> &mut Self {
+        self.exe = exec.exe.clone();
+        self.cmdline = exec.cmdline.clone();
+        self.ppid = Some(exec.ppid);
+        self.uid = Some(exec.uid);
+        self.euid = Some(exec.euid);
+        self.user = procfs::user_name(exec.uid);
+        self.euser = procfs::user_name(exec.euid);
+        self
+    }
+
+    pub fn exit(&mut self, ts: i64) -> &mut Self {
+        self.exited = Some(ts);
+        self
+    }
+
+    ///
+    /// Whether the process 'pid' started at 'start' may be this one: the same pid, and not known
+    /// to have started at another time.
+    ///
+    pub fn may_be(&self, pid: u32, start: Option<i64>) -> bool {
+        self.pid == pid && match (self.start, start) {
+            (Some(a), Some(b)) => procfs::same_start(a, b),
+            _ => true,
+        }
+    }
+
+    ///
+    /// Take what is known of the description of 'other', the same process.
+    ///
+    pub fn details(&mut self, other: &Process) -> &mut Self {
+        if !other.exe.is_empty() {
+            self.exe = other.exe.clone();
+        }
+        if !other.cmdline.is_empty() {
+            self.cmdline = other.cmdline.clone();
+        }
+        if !other.user.is_empty() {
+            self.user = other.user.clone();
+        }
+        if !other.euser.is_empty() {
+            self.euser = other.euser.clone();
+        }
+        self.ppid = other.ppid.or(self.ppid);
+        self.uid = other.uid.or(self.uid);
+        self.euid = other.euid.or(self.euid);
+        self.start = other.start.or(self.start);
+        self.exited = other.exited.or(self.exited);
+        self
+    }
+
+    ///
+    /// Whether anything is known of the process besides its name.
+    ///
+    pub fn has_details(&self) -> bool {
+        !self.exe.is_empty() || !self.cmdline.is_empty() || self.ppid.is_some()
+            || self.uid.is_some() || self.euid.is_some() || self.start.is_some()
+            || self.exited.is_some()
+    }
+
     #[allow(dead_code)]
     pub fn rx(&mut self, rx: isize) -> &mut Self {
         self.rx = rx;
@@ -104,11 +550,6 @@ impl Process {
     }
 
     #[allow(dead_code)]
-    pub fn date(&mut self, date: u32) -> &mut Self {
-        self.date = date;
-        self
-    }
-
     pub fn get_pid(&self) -> u32 {
         self.pid
     }
@@ -126,6 +567,28 @@ impl Process {
         &self.ulinks
     }
 
+    pub fn get_ilinks(&self) -> &Vec<Link> {
+        &self.ilinks
+    }
+
+    ///
+    /// All the links of the process, whatever their protocol.
+    ///
+    pub fn links(&self) -> impl Iterator<Item = &Link> {
+        self.tlinks.iter().chain(self.ulinks.iter()).chain(self.ilinks.iter())
+    }
+
+    ///
+    /// The links of the process using the protocol 'prot'.
+    ///
+    pub fn links_of(&mut self, prot: Prot) -> &mut Vec<Link> {
+        match prot {
+            Prot::TCP => &mut self.tlinks,
+            Prot::ICMP(_, _) => &mut self.ilinks,
+            _ => &mut self.ulinks,
+        }
+    }
+
     #[allow(dead_code)]
     pub fn get_rx_tx(&self) -> (isize, isize) {
         (self.rx, self.tx)
@@ -143,6 +606,12 @@ impl Process {
         }
     }
 
+    pub fn print_ilinks(&self) {
+        for l in self.ilinks.iter() {
+            println!("{}", l);
+        }
+    }
+
     #[allow(dead_code)]
     pub fn to_string_with_links(&self) -> String {
         let mut buffer: String = self.to_string();
@@ -154,7 +623,7 @@ impl Process {
             buffer.push('\n');
         }
 
-        for l in self.ulinks.iter() {
+        for l in self.ulinks.iter().chain(self.ilinks.iter()) {
             buffer.push_str("    ");
             buffer.push_str(l.to_string().as_str());
             buffer.push('\n');
@@ -164,8 +633,56 @@ impl Process {
         buffer
     }
 
+    ///
+    /// Returns what is known of the process besides its name, e.g. "python3 -m http.server
+    /// (/usr/bin/python3.12), user alice as root, parent 1, started 2021-07-03 14:02:05", the time
+    /// in the time zone 'tz'.
+    ///
+    pub fn details_str(&self, tz: Tz) -> String {
+        let user = |name: &String, uid: Option<u32>| match uid {
+            Some(_) if !name.is_empty() => name.clone(),
+            Some(uid) => uid.to_string(),
+            None => String::from("?"),
+        };
+
+        let mut details = Vec::new();
+
+        match (self.cmdline.is_empty(), self.exe.is_empty()) {
+            (false, false) => details.push(format!("{} ({})", self.cmdline.join(" "), self.exe)),
+            (false, true) => details.push(self.cmdline.join(" ")),
+            (true, false) => details.push(self.exe.clone()),
+            (true, true) => (),
+        }
+        match (self.uid, self.euid) {
+            (None, None) => (),
+            (uid, euid) if uid == euid || euid.is_none() => {
+                details.push(format!("user {}", user(&self.user, uid)));
+            }
+            (uid, euid) => details.push(format!("user {} as {}",
+                user(&self.user, uid), user(&self.euser, euid))),
+        }
+        if let Some(ppid) = self.ppid {
+            details.push(format!("parent {}", ppid));
+        }
+        if let Some(start) = self.start {
+            let start = start / procfs::NANOSECONDS;
+            details.push(format!("started {}", tz.format(start, "%Y-%m-%d %H:%M:%S")));
+        }
+        if let Some(exited) = self.exited {
+            details.push(format!("exited {}", tz.format(exited, "%Y-%m-%d %H:%M:%S")));
+        }
+
+        match details.is_empty() {
+            true => String::from("no details"),
+            false => details.join(", "),
+        }
+    }
+
     pub fn overview_str(&self) -> String {
-        format!("{} ({})", self.name, self.pid)
+        match self.exited {
+            Some(_) => format!("{} ({}, exited)", self.name, self.pid),
+            None => format!("{} ({})", self.name, self.pid),
+        }
     }
 
     pub fn data_amount_str(&self) -> String {
@@ -197,11 +714,155 @@ impl Process {
     {
         (self.pid, &self.name, &self.tlinks, &self.ulinks, self.rx, self.tx)
     }
+
+    ///
+    /// Returns a copy of the process only accounting for the traffic seen since 'previous', or
+    /// None if nothing changed, its exit included. Links without new traffic nor change of state
+    /// are left out.
+    ///
+    /// * `previous` - The same process as it was at the last checkpoint, if it existed then
+    ///
+    pub fn delta(&self, previous: Option<&Process>) -> Option<Process> {
+        let (prev_rx, prev_tx) = match previous {
+            Some(prev) => (prev.rx, prev.tx),
+            None       => (0, 0),
+        };
+
+        let mut p = Process::new(self.pid);
+        p.name(self.name.clone()).details(self);
+        p.rx(self.rx - prev_rx).tx(self.tx - prev_tx);
+
+        p.tlinks = links_delta(&self.tlinks, previous.map(|prev| &prev.tlinks));
+        p.ulinks = links_delta(&self.ulinks, previous.map(|prev| &prev.ulinks));
+        p.ilinks = links_delta(&self.ilinks, previous.map(|prev| &prev.ilinks));
+
+        // Connections may open or close, and processes exit, without any traffic
+        let exited = self.exited.is_some() && previous.and_then(|prev| prev.exited).is_none();
+
+        if p.rx == 0 && p.tx == 0 && p.links().next().is_none() && !exited {
+            return None;
+        }
+
+        Some(p)
+    }
+
+    ///
+    /// Add the traffic of 'delta', as returned by Process::delta(), to the process and its links.
+    ///
+    pub fn merge(&mut self, delta: &Process) {
+        self.rx += delta.rx;
+        self.tx += delta.tx;
+
+        if !delta.name.is_empty() {
+            self.name = delta.name.clone();
+        }
+        self.details(delta);
+
+        for d in delta.links() {
+            let links = self.links_of(d.prot);
+
+            match links.iter_mut().find(|l| *l == d) {
+                Some(l) => {
+                    l.rx += d.rx;
+                    l.tx += d.tx;
+                    l.domain = d.domain.clone();
+                    if !d.server.is_empty() {
+                        l.server = d.server.clone();
+                    }
+                    if !d.app_proto.is_empty() {
+                        l.app_proto = d.app_proto.clone();
+                    }
+                    l.opened = d.opened.or(l.opened);
+                    l.closed = d.closed.or(l.closed);
+                    l.tcp_state = d.tcp_state.or(l.tcp_state);
+                }
+                None => links.push(d.clone()),
+            }
+        }
+    }
+
+    ///
+    /// Remember that the process asked for the name 'name' and got the address 'addr', and name
+    /// its links to 'addr' after it. Older names are forgotten past MAX_NAMES.
+    ///
+    pub fn learn(&mut self, addr: IpAddr, name: &str) {
+        if self.names.len() >= MAX_NAMES && !self.names.contains_key(&addr) {
+            self.names.clear();
+        }
+        self.names.insert(addr, name.to_string());
+
+        for links in [&mut self.tlinks, &mut self.ulinks, &mut self.ilinks] {
+            for l in links.iter_mut().filter(|l| l.daddr == addr) {
+                l.domain(name.to_string());
+            }
+        }
+    }
+
+    ///
+    /// Start counting from zero again, for the process and all its links.
+    ///
+    pub fn reset(&mut self) {
+        self.rx = 0;
+        self.tx = 0;
+
+        for links in [&mut self.tlinks, &mut self.ulinks, &mut self.ilinks] {
+            for l in links.iter_mut() {
+                l.rx = 0;
+                l.tx = 0;
+            }
+        }
+    }
+}
+
+///
+/// Split the in-memory counters at a day boundary: the counters start from zero again so that what
+/// is captured from now on is accounted to the new day, and the processes which exited are
+/// forgotten. Returns the processes as they were just before, i.e. with the traffic belonging to
+/// the previous day.
+///
+pub fn rollover(procs: &mut Vec<Process>) -> Vec<Process> {
+    let previous = procs.to_vec();
+
+    procs.retain(|p| p.exited.is_none());
+    for p in procs.iter_mut() {
+        p.reset();
+    }
+
+    previous
+}
+
+///
+/// Returns the links of 'current' with only the traffic not already accounted for in 'previous'.
+///
+fn links_delta(current: &Vec<Link>, previous: Option<&Vec<Link>>) -> Vec<Link> {
+    let mut delta = Vec::new();
+
+    for l in current.iter() {
+        let (prev_rx, prev_tx, prev_opened, prev_closed, prev_state) =
+            match previous.and_then(|links| links.iter().find(|x| *x == l)) {
+                Some(prev) => (prev.rx, prev.tx, prev.opened, prev.closed, prev.tcp_state),
+                None       => (0, 0, None, None, None),
+            };
+
+        let same_state =
+            l.opened == prev_opened && l.closed == prev_closed && l.tcp_state == prev_state;
+
+        if l.rx == prev_rx && l.tx == prev_tx && same_state {
+            continue;
+        }
+
+        let mut d = l.clone();
+        d.rx(l.rx - prev_rx).tx(l.tx - prev_tx);
+        delta.push(d);
+    }
+
+    delta
 }
 
+// Processes with the same pid are different ones if started at different times, see known()
 impl PartialEq for Process {
     fn eq(&self, other: &Self) -> bool {
-        self.pid == other.pid
+        self.pid == other.pid && self.start == other.start
     }
 }
 impl Eq for Process {}
@@ -245,6 +906,11 @@ pub struct Link {
     pub tx: isize,
     pub prot: Prot,
     pub domain: String,
+    pub server: String, // Name of the server asked for by the process, see server_name.rs
+    pub app_proto: String, // Application protocol spoken, see classify.rs
+    pub opened: Option<i64>, // UNIX timestamp of the last connect or accept
+    pub closed: Option<i64>, // UNIX timestamp of the last close
+    pub tcp_state: Option<TcpState>, // Last state the kernel told of, TCP only
 }
 
 impl Link {
@@ -258,9 +924,69 @@ impl Link {
             tx: 0,
             prot: Prot::NONE,
             domain: String::new(),
+            server: String::new(),
+            app_proto: String::new(),
+            opened: None,
+            closed: None,
+            tcp_state: None,
+        }
+    }
+
+    ///
+    /// A new connection started at the UNIX timestamp 'ts', the same link may be reused once
+    /// closed.
+    ///
+    pub fn open(&mut self, ts: i64) -> &mut Self {
+        self.opened = Some(ts);
+        self
+    }
+
+    pub fn close(&mut self, ts: i64) -> &mut Self {
+        self.closed = Some(ts);
+        self
+    }
+
+    pub fn tcp_state(&mut self, state: TcpState) -> &mut Self {
+        self.tcp_state = Some(state);
+        self
+    }
+
+    ///
+    /// Whether the connection is open, as its TCP state tells: closed once the kernel is done
+    /// with it, whatever the process did.
+    ///
+    pub fn state(&self) -> State {
+        match self.tcp_state {
+            Some(TcpState::Close) => State::Closed,
+            Some(_) => State::Open,
+            None => State::Unknown,
+        }
+    }
+
+    ///
+    /// Returns for how long, in seconds, the connection has been or was open at the UNIX
+    /// timestamp 'now'. None when it was opened before the capture started.
+    ///
+    pub fn duration(&self, now: i64) -> Option<i64> {
+        match (self.state(), self.opened, self.closed) {
+            (State::Open, Some(opened), _) => Some(now - opened),
+            (State::Closed, Some(opened), Some(closed)) if closed >= opened => {
+                Some(closed - opened)
+            }
+            _ => None,
         }
     }
 
+    ///
+    /// An IPv6 socket talking to an IPv4 peer sees it as "::ffff:a.b.c.d", the same peer must be
+    /// the same link whatever the socket.
+    ///
+    pub fn unmap(&mut self) -> &mut Self {
+        self.saddr = unmapped(self.saddr);
+        self.daddr = unmapped(self.daddr);
+        self
+    }
+
     pub fn add_data(&mut self, size: isize, is_rx: u32) {
         match is_rx {
             0 => self.tx += size,
@@ -291,6 +1017,16 @@ impl Link {
         self
     }
 
+    pub fn server(&mut self, name: String) -> &mut Self {
+        self.server = name;
+        self
+    }
+
+    pub fn app_proto(&mut self, name: String) -> &mut Self {
+        self.app_proto = name;
+        self
+    }
+
     pub fn get_saddr(&self) -> String {
         String::from(&self.saddr.to_string())
     }
@@ -315,8 +1051,8 @@ impl Link {
     }
 
     #[allow(dead_code)]
-    pub fn get_prot(&self) -> u8 {
-        self.prot as u8
+    pub fn get_prot(&self) -> u32 {
+        self.prot.id()
     }
 
     pub fn get_domain(&self) -> &String {
@@ -324,9 +1060,12 @@ impl Link {
     }
 
     pub fn get_all_info(&self) ->
-        (String, String, u16, u16, isize, isize, u8, &String)
+        (String, String, u16, u16, isize, isize, u32, &String, &String, Option<i64>, Option<i64>)
     {
-        (self.get_saddr(), self.get_daddr(), self.lport, self.dport, self.rx, self.tx, self.prot as u8, self.get_domain())
+        (
+            self.get_saddr(), self.get_daddr(), self.lport, self.dport, self.rx, self.tx,
+            self.prot.id(), self.get_domain(), &self.server, self.opened, self.closed,
+        )
     }
 }
 
@@ -375,20 +1114,34 @@ impl fmt::Display for Link {
         //    }
         //)
 
-        let destination = if self.domain.is_empty() {
+        // The name the process asked for first, the one of the address else
+        let destination = if !self.server.is_empty() {
+            self.server.to_owned()
+        } else if self.domain.is_empty() {
             self.daddr.to_string().to_owned()
         } else {
             self.domain.to_owned()
         };
 
+        let lifecycle = match (self.tcp_state, self.duration(crate::clock::now())) {
+            (None, _) => String::new(),
+            (Some(state), Some(duration)) => format!(" [{} {}]", state, duration_str(duration)),
+            (Some(state), None) => format!(" [{}]", state),
+        };
+
         write!(
-            f, "    {p} {}:{} <-> {}:{} RX: {:.2}{u0} TX: {:.2}{u1}",
+            f, "    {p} {}:{} <-> {}:{} RX: {:.2}{u0} TX: {:.2}{u1}{}",
             self.saddr,
             self.lport,
             destination,//self.domain,
             self.dport,
             rx,
             tx,
+            lifecycle,
+            p = match self.app_proto.as_str() {
+                "" => self.prot.to_string(),
+                app_proto => format!("{}/{}", self.prot, app_proto),
+            },
             u0 = match rx_unit {
                 DataUnit::Bytes => "B",
                 DataUnit::KBytes => "KB",
@@ -403,15 +1156,13 @@ impl fmt::Display for Link {
                 DataUnit::GBytes => "GB",
                 DataUnit::TBytes => "TB",
             },
-            p = match self.prot {
-                Prot::NONE => "NONE",
-                Prot::TCP => "TCP",
-                Prot::UDP => "UDP",
-            }
         )
     }
 }
 
+// Name of the process in the records, NUL terminated unless 16 bytes long (TASK_COMM_LEN)
+const COMM_LEN: usize = 16;
+
 // TODO: may need to separate TCP/UDP if we track the connection state or do other specific things
 #[repr(C)]
 struct ipv4_data_t {
@@ -422,6 +1173,7 @@ struct ipv4_data_t {
     dport: u16,
     size: u32,
     is_rx: u32,
+    comm: [u8; COMM_LEN],
 }
 
 #[repr(C)]
@@ -433,31 +1185,282 @@ struct ipv6_data_t {
     dport: u16,
     size: u32,
     is_rx: u32,
+    comm: [u8; COMM_LEN],
 }
 
-pub fn tcp4_cb() -> Box<dyn FnMut(&[u8]) + Send> {
-    Box::new(|x| {
-        let data = parse_struct_ipv4(x);
-
-        let p = Process::new(data.pid);
-
-        let mut l = Link::new(
-            IpAddr::V4( Ipv4Addr::from(data.saddr.to_be()) ),
-            IpAddr::V4( Ipv4Addr::from(data.daddr.to_be()) ),
-            data.lport,
-            data.dport,
-        );
-        l.prot(Prot::TCP);
-
-        update_procs_and_links(p, l, data.size as isize, data.is_rx, Prot::TCP);
-    })
+// ICMP messages sent or received by a process, the echo identifier of ping sockets as local port
+#[repr(C)]
+struct icmp4_data_t {
+    pid: u32,
+    saddr: u32,
+    daddr: u32,
+    id: u16,
+    icmp_type: u8,
+    code: u8,
+    size: u32,
+    is_rx: u32,
+    comm: [u8; COMM_LEN],
 }
 
-pub fn tcp6_cb() -> Box<dyn FnMut(&[u8]) + Send> {
-    Box::new(|x| {
-        let data = parse_struct_ipv6(x);
-
-        let p = Process::new(data.pid);
+#[repr(C)]
+struct icmp6_data_t {
+    saddr: u128,
+    daddr: u128,
+    pid: u32,
+    id: u16,
+    icmp_type: u8,
+    code: u8,
+    size: u32,
+    is_rx: u32,
+    comm: [u8; COMM_LEN],
+}
+
+// Traffic added up in the kernel per process and per link, see filters.c
+#[repr(C)]
+struct flow_t {
+    saddr: u128,
+    daddr: u128,
+    kind: u32,
+    pid: u32,
+    lport: u16,
+    dport: u16,
+    icmp_type: u8,
+    icmp_code: u8,
+    generation: u16,
+    comm: [u8; COMM_LEN],
+}
+
+#[repr(C)]
+struct bytes_t {
+    rx: u64,
+    tx: u64,
+}
+
+// Kinds of records, see bpf/records.h
+pub const RECORD_TCP4: u32 = 0;
+pub const RECORD_TCP6: u32 = 1;
+pub const RECORD_UDP4: u32 = 2;
+pub const RECORD_UDP6: u32 = 3;
+pub const RECORD_TCP4_EVENT: u32 = 4;
+pub const RECORD_TCP6_EVENT: u32 = 5;
+pub const RECORD_ICMP4: u32 = 6;
+pub const RECORD_ICMP6: u32 = 7;
+pub const RECORD_DNS: u32 = 8;
+pub const RECORD_TCP_PAYLOAD: u32 = 9;
+pub const RECORD_UDP_PAYLOAD: u32 = 10;
+pub const RECORD_EXEC: u32 = 11;
+pub const RECORD_EXIT: u32 = 12;
+
+// Lifecycle events of a TCP connection, see bpf/records.h
+pub const EVENT_CONNECT: u32 = 0;
+pub const EVENT_ACCEPT: u32 = 1;
+pub const EVENT_CLOSE: u32 = 2;
+pub const EVENT_STATE: u32 = 3;
+
+// Start of a DNS response received by a process, see bpf/records.h
+const DNS_PAYLOAD: usize = 512;
+
+#[repr(C)]
+struct dns_data_t {
+    pid: u32,
+    size: u32,
+    is_tcp: u32, // 'payload' read from a TCP stream, see passive_dns::parse_stream()
+    comm: [u8; COMM_LEN],
+    payload: [u8; DNS_PAYLOAD],
+}
+
+// First bytes sent by a process on a TCP connection it opened or to a UDP peer, see filters.c
+const PEEK_PAYLOAD: usize = 2048;
+
+const AF_INET: u32 = 2;
+const AF_INET6: u32 = 10;
+
+#[repr(C)]
+struct payload_data_t {
+    saddr: u128,
+    daddr: u128,
+    pid: u32,
+    lport: u16,
+    dport: u16,
+    family: u32,
+    size: u32,
+    comm: [u8; COMM_LEN],
+    payload: [u8; PEEK_PAYLOAD],
+}
+
+// Program executed by a process, see bpf/records.h
+const EXE_LEN: usize = 256;
+const CMDLINE_LEN: usize = 512;
+
+#[repr(C)]
+struct exec_data_t {
+    pid: u32,
+    ppid: u32,
+    uid: u32,
+    euid: u32,
+    start: u64, // Nanoseconds since boot
+    size: u32,
+    comm: [u8; COMM_LEN],
+    exe: [u8; EXE_LEN],
+    cmdline: [u8; CMDLINE_LEN],
+}
+
+// Programs executed remembered before they are forgotten, see exec_cb()
+const MAX_EXECS: usize = 4096;
+
+// Process exiting, see filters.c
+#[repr(C)]
+struct exit_data_t {
+    start: u64, // Nanoseconds since boot
+    pid: u32,
+    pad: u32,
+}
+
+// Names learnt per process before they are forgotten, see Process::learn()
+const MAX_NAMES: usize = 1024;
+
+#[repr(C)]
+struct ipv4_event_t {
+    pid: u32,
+    saddr: u32,
+    daddr: u32,
+    lport: u16,
+    dport: u16,
+    event: u32,
+    state: u32,
+    comm: [u8; COMM_LEN],
+}
+
+#[repr(C)]
+struct ipv6_event_t {
+    saddr: u128,
+    daddr: u128,
+    pid: u32,
+    lport: u16,
+    dport: u16,
+    event: u32,
+    state: u32,
+    comm: [u8; COMM_LEN],
+}
+
+///
+/// Data of a record decoded field by field from the bytes written by the probes, in the byte
+/// order of the host.
+///
+trait Decode: Sized {
+    fn read(fields: &mut Fields) -> Self;
+}
+
+// Integers of the structures of the probes
+trait Field {
+    const SIZE: usize;
+
+    fn from_bytes(x: &[u8]) -> Self;
+}
+
+macro_rules! field {
+    ($($t:ty),*) => {
+        $(impl Field for $t {
+            const SIZE: usize = std::mem::size_of::<$t>();
+
+            fn from_bytes(x: &[u8]) -> Self {
+                <$t>::from_ne_bytes(x.try_into().unwrap())
+            }
+        })*
+    };
+}
+
+field!(u8, u16, u32, u64, u128);
+
+impl<const N: usize> Field for [u8; N] {
+    const SIZE: usize = N;
+
+    fn from_bytes(x: &[u8]) -> Self {
+        x.try_into().unwrap()
+    }
+}
+
+// What is left to read of a structure, its fields are not padded
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn read<T: Field>(&mut self) -> T {
+        let (field, rest) = self.0.split_at(T::SIZE);
+        self.0 = rest;
+
+        T::from_bytes(field)
+    }
+}
+
+// The fields in the order of the structure
+macro_rules! decode {
+    ($t:ident { $($field:ident),* }) => {
+        impl Decode for $t {
+            fn read(fields: &mut Fields) -> Self {
+                $t { $($field: fields.read()),* }
+            }
+        }
+    };
+}
+
+decode!(ipv4_data_t { pid, saddr, daddr, lport, dport, size, is_rx, comm });
+decode!(ipv6_data_t { saddr, daddr, pid, lport, dport, size, is_rx, comm });
+decode!(icmp4_data_t { pid, saddr, daddr, id, icmp_type, code, size, is_rx, comm });
+decode!(icmp6_data_t { saddr, daddr, pid, id, icmp_type, code, size, is_rx, comm });
+decode!(ipv4_event_t { pid, saddr, daddr, lport, dport, event, state, comm });
+decode!(ipv6_event_t { saddr, daddr, pid, lport, dport, event, state, comm });
+decode!(flow_t {
+    saddr, daddr, kind, pid, lport, dport, icmp_type, icmp_code, generation, comm
+});
+decode!(bytes_t { rx, tx });
+decode!(dns_data_t { pid, size, is_tcp, comm, payload });
+decode!(payload_data_t { saddr, daddr, pid, lport, dport, family, size, comm, payload });
+decode!(exec_data_t { pid, ppid, uid, euid, start, size, comm, exe, cmdline });
+decode!(exit_data_t { start, pid, pad });
+
+///
+/// Returns the structure 'T' held by 'x', an error if 'x' is not exactly its size.
+///
+fn decode<T: Decode>(x: &[u8]) -> Result<T, String> {
+    let size = std::mem::size_of::<T>();
+
+    if x.len() != size {
+        let name = std::any::type_name::<T>().rsplit("::").next().unwrap();
+        return Err(format!("{} of {} bytes, expected {}", name, x.len(), size));
+    }
+
+    Ok(T::read(&mut Fields(x)))
+}
+
+pub fn tcp4_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<ipv4_data_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
+
+        let p = seen(data.pid, &data.comm);
+
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::from(data.saddr.to_be()) ),
+            IpAddr::V4( Ipv4Addr::from(data.daddr.to_be()) ),
+            data.lport,
+            data.dport,
+        );
+        l.prot(Prot::TCP);
+
+        update_procs_and_links(&procs, p, l, data.size as isize, data.is_rx, Prot::TCP);
+    })
+}
+
+pub fn tcp6_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<ipv6_data_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
+
+        let p = seen(data.pid, &data.comm);
 
         let mut l = Link::new(
             IpAddr::V6( Ipv6Addr::from(data.saddr.to_be()) ),
@@ -467,15 +1470,18 @@ pub fn tcp6_cb() -> Box<dyn FnMut(&[u8]) + Send> {
         );
         l.prot(Prot::TCP);
 
-        update_procs_and_links(p, l, data.size as isize, data.is_rx, Prot::TCP);
+        update_procs_and_links(&procs, p, l, data.size as isize, data.is_rx, Prot::TCP);
     })
 }
 
-pub fn udp4_cb() -> Box<dyn FnMut(&[u8]) + Send> {
-    Box::new(|x| {
-        let data = parse_struct_ipv4(x);
+pub fn udp4_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<ipv4_data_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
 
-        let p = Process::new(data.pid);
+        let p = seen(data.pid, &data.comm);
 
         let mut l = Link::new(
             IpAddr::V4( Ipv4Addr::from(data.saddr.to_be()) ),
@@ -485,15 +1491,18 @@ pub fn udp4_cb() -> Box<dyn FnMut(&[u8]) + Send> {
         );
         l.prot(Prot::UDP);
 
-        update_procs_and_links(p, l, data.size as isize, data.is_rx, Prot::UDP);
+        update_procs_and_links(&procs, p, l, data.size as isize, data.is_rx, Prot::UDP);
     })
 }
 
-pub fn udp6_cb() -> Box<dyn FnMut(&[u8]) + Send> {
-    Box::new(|x| {
-        let data = parse_struct_ipv6(x);
+pub fn udp6_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<ipv6_data_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
 
-        let p = Process::new(data.pid);
+        let p = seen(data.pid, &data.comm);
 
         let mut l = Link::new(
             IpAddr::V6( Ipv6Addr::from(data.saddr.to_be()) ),
@@ -503,87 +1512,831 @@ pub fn udp6_cb() -> Box<dyn FnMut(&[u8]) + Send> {
         );
         l.prot(Prot::UDP);
 
-        update_procs_and_links(p, l, data.size as isize, data.is_rx, Prot::UDP);
+        update_procs_and_links(&procs, p, l, data.size as isize, data.is_rx, Prot::UDP);
+    })
+}
+
+pub fn icmp4_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<icmp4_data_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
+
+        let p = seen(data.pid, &data.comm);
+        let prot = Prot::ICMP(data.icmp_type, data.code);
+
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::from(data.saddr.to_be()) ),
+            IpAddr::V4( Ipv4Addr::from(data.daddr.to_be()) ),
+            data.id,
+            0,
+        );
+        l.prot(prot);
+
+        update_procs_and_links(&procs, p, l, data.size as isize, data.is_rx, prot);
+    })
+}
+
+pub fn icmp6_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<icmp6_data_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
+
+        let p = seen(data.pid, &data.comm);
+        let prot = Prot::ICMP(data.icmp_type, data.code);
+
+        let mut l = Link::new(
+            IpAddr::V6( Ipv6Addr::from(data.saddr.to_be()) ),
+            IpAddr::V6( Ipv6Addr::from(data.daddr.to_be()) ),
+            data.id,
+            0,
+        );
+        l.prot(prot);
+
+        update_procs_and_links(&procs, p, l, data.size as isize, data.is_rx, prot);
+    })
+}
+
+pub fn tcp4_event_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<ipv4_event_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
+
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::from(data.saddr.to_be()) ),
+            IpAddr::V4( Ipv4Addr::from(data.daddr.to_be()) ),
+            data.lport,
+            data.dport,
+        );
+        l.prot(Prot::TCP);
+
+        let state = TcpState::from_kernel(data.state);
+        let p = seen(data.pid, &data.comm);
+
+        update_link_state(&procs, p, l, data.event, state, crate::clock::now());
     })
 }
 
 ///
-/// Record the current network connection.
+/// Learn the addresses of the answers of a DNS response received by a process, see
+/// Process::learn(). What is not a response is ignored, e.g. queries to a local DNS server.
 ///
-fn update_procs_and_links(
-    mut p: Process, mut l: Link, packets_size: isize,
-    is_rx: u32, prot: Prot
-)
-{
-    let mut procs = PROCESSES.lock().unwrap();
+pub fn dns_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<dns_data_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
+
+        let size = (data.size as usize).min(DNS_PAYLOAD);
+        let answers = match data.is_tcp {
+            0 => passive_dns::parse_response(&data.payload[..size]),
+            _ => passive_dns::parse_stream(&data.payload[..size]),
+        };
+        let answers = answers.unwrap_or_default();
+
+        if answers.is_empty() {
+            return;
+        }
+
+        let mut p = seen(data.pid, &data.comm);
+        let mut list = procs.lock_for(&mut p);
+        let known_p = known(&mut list, p);
 
-    if procs.contains(&p) {
-        /*
-         * We have already seen this process having network connection open.
-         */
-        let known_p = procs.iter_mut().find(|x| x.pid == p.pid).unwrap();
+        for (addr, name) in answers {
+            known_p.learn(addr, &name);
+        }
+    })
+}
 
-        known_p.add_data(packets_size, is_rx);
+///
+/// Classify the link of protocol 'prot' on which a process sent its first bytes, see
+/// classify::by_payload(), and name the TCP ones after the server asked for, see
+/// server_name::parse(). Nothing is done when the bytes tell neither.
+///
+pub fn payload_cb(procs: SharedProcs, prot: Prot) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<payload_data_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
 
-        let links = if prot == Prot::TCP { &mut known_p.tlinks } else { &mut known_p.ulinks };
+        let size = (data.size as usize).min(PEEK_PAYLOAD);
+        let payload = &data.payload[..size];
+        let server = match prot {
+            Prot::TCP => server_name::parse(payload),
+            _ => None,
+        };
+        let app_proto = classify::by_payload(prot, data.lport, data.dport, payload);
 
-        if links.contains(&l) {
-            let known_link = links.iter_mut().find(|x| **x == l).unwrap();
+        if server.is_none() && app_proto.is_none() {
+            return;
+        }
 
-            known_link.add_data(packets_size, is_rx);
-        } else {
+        let (saddr, daddr) = match data.family {
+            AF_INET => (
+                IpAddr::V4( Ipv4Addr::from((data.saddr as u32).to_be()) ),
+                IpAddr::V4( Ipv4Addr::from((data.daddr as u32).to_be()) ),
+            ),
+            _ => (
+                IpAddr::V6( Ipv6Addr::from(data.saddr.to_be()) ),
+                IpAddr::V6( Ipv6Addr::from(data.daddr.to_be()) ),
+            ),
+        };
+
+        let mut l = Link::new(saddr, daddr, data.lport, data.dport);
+        l.prot(prot);
+
+        update_link_payload(&procs, seen(data.pid, &data.comm), l, server, app_proto);
+    })
+}
+
+///
+/// Remember what the process of an exec record executed, see Procs::exec_of(), and rename and
+/// describe it if already known: a process keeps the name of its parent until it executes a
+/// program.
+///
+pub fn exec_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<exec_data_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
+
+        let size = (data.size as usize).min(CMDLINE_LEN);
+        let exec = Exec {
+            comm: comm_str(&data.comm),
+            exe: comm_str(&data.exe),
+            cmdline: procfs::split_args(&data.cmdline[..size]),
+            ppid: data.ppid,
+            uid: data.uid,
+            euid: data.euid,
+            start: match data.start {
+                0 => None,
+                start => procfs::start_time(start),
+            },
+        };
+
+        // Its users are looked up before taking the lock
+        let mut described = Process::new(data.pid);
+        described.exec(&exec);
+
+        let mut list = procs.lock().unwrap();
 
-            if l.daddr.is_global() {
-                let (host, _service) = reverse_lookup(l.daddr, l.dport);
-                l.domain(host);
+        if let Some(known_p) = list.iter_mut().find(|x| x.pid == data.pid && x.exited.is_none()) {
+            // Started at another time, the pid was reused without its exit seen
+            match known_p.may_be(data.pid, exec.start) {
+                true => known_p.name(exec.comm.clone()).details(&described),
+                false => known_p.exit(crate::clock::now()),
+            };
+        }
+        drop(list);
+
+        procs.descriptions.lock().unwrap().remove(&data.pid);
+
+        let mut execs = procs.execs.lock().unwrap();
+        if execs.len() >= MAX_EXECS && !execs.contains_key(&data.pid) {
+            execs.clear();
+        }
+        execs.insert(data.pid, exec);
+    })
+}
+
+///
+/// Mark the process which exited as such, the next records of its pid are of a new process, and
+/// forget what it executed.
+///
+pub fn exit_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<exit_data_t>(x) {
+            Some(data) => data,
+            None => return,
+        };
+
+        let start = match data.start {
+            0 => None,
+            start => procfs::start_time(start),
+        };
+        let ts = crate::clock::now();
+
+        // The records of the perf buffers may come out of order, a new process of the pid may
+        // already be known
+        for p in procs.lock().unwrap().iter_mut() {
+            if p.exited.is_none() && p.may_be(data.pid, start) {
+                p.exit(ts);
             }
+        }
+
+        // Unless it is known of another process, its start and the one of the exit both told by
+        // the probes
+        let mut execs = procs.execs.lock().unwrap();
+        let forget = match execs.get(&data.pid) {
+            Some(exec) => exec.start.is_none() || start.is_none() || exec.start == start,
+            None => false,
+        };
+        if forget {
+            execs.remove(&data.pid);
+        }
+    })
+}
+
+// A process first seen in a record, named after the 'comm' read by the probes if any
+fn seen(pid: u32, comm: &[u8]) -> Process {
+    let mut p = Process::new(pid);
+    p.name(comm_str(comm));
+    p
+}
+
+// The string of the probes in 'x', up to its first NUL
+fn comm_str(x: &[u8]) -> String {
+    let end = x.iter().position(|&c| c == 0).unwrap_or(x.len());
+
+    String::from_utf8_lossy(&x[..end]).into_owned()
+}
+
+///
+/// Callback of the ring buffer and of the perf buffers: each record is handed to the callback of
+/// its kind, in the order of the RECORD_* values of filters.c. Malformed records are dropped, see
+/// Procs::take_malformed().
+///
+pub fn record_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    let mut callbacks = [
+        tcp4_cb(procs.clone()), tcp6_cb(procs.clone()), udp4_cb(procs.clone()),
+        udp6_cb(procs.clone()), tcp4_event_cb(procs.clone()), tcp6_event_cb(procs.clone()),
+        icmp4_cb(procs.clone()), icmp6_cb(procs.clone()), dns_cb(procs.clone()),
+        payload_cb(procs.clone(), Prot::TCP), payload_cb(procs.clone(), Prot::UDP),
+        exec_cb(procs.clone()), exit_cb(procs.clone()),
+    ];
+
+    Box::new(move |x| {
+        match split_record(x) {
+            Ok((kind, data)) => match callbacks.get_mut(kind as usize) {
+                Some(callback) => callback(data),
+                None => procs.malformed(format!("record of unknown kind {}", kind)),
+            },
+            Err(e) => procs.malformed(e),
+        }
+    })
+}
+
+///
+/// Returns the kind and the data of a record, an error if shorter than its header says or of
+/// another version. The perf buffers may pad the records, the padding is left out.
+///
+pub fn split_record(x: &[u8]) -> Result<(u32, &[u8]), String> {
+    if x.len() < RECORD_HEADER {
+        return Err(format!("record of {} bytes, shorter than its header", x.len()));
+    }
 
-            l.add_data(packets_size, is_rx);
+    let kind = u32::from_ne_bytes([x[0], x[1], x[2], x[3]]);
+    let version = u16::from_ne_bytes([x[4], x[5]]);
+    let length = u16::from_ne_bytes([x[6], x[7]]) as usize;
+
+    if version != RECORD_VERSION {
+        return Err(format!("record of version {}, expected {}", version, RECORD_VERSION));
+    }
+    if RECORD_HEADER + length > x.len() {
+        return Err(format!("record of {} bytes, its header says {}", x.len() - RECORD_HEADER,
+            length));
+    }
+
+    Ok((kind, &x[RECORD_HEADER..RECORD_HEADER + length]))
+}
 
-            links.push(l);
+///
+/// Returns the record of kind 'kind' carrying 'data', as the probes send it.
+///
+pub fn join_record(kind: u32, data: &[u8]) -> Vec<u8> {
+    let mut record = vec![0u8; RECORD_HEADER];
+
+    record[..4].copy_from_slice(&kind.to_ne_bytes());
+    record[4..6].copy_from_slice(&RECORD_VERSION.to_ne_bytes());
+    record[6..8].copy_from_slice(&(data.len() as u16).to_ne_bytes());
+    record.extend_from_slice(data);
+
+    record
+}
+
+///
+/// Returns the record the probes send for 'size' bytes sent or received by the process 'pid' on
+/// the link 'l', as handed to record_cb(). A link mixing IPv4 and IPv6 addresses
+/// is sent as IPv6, the IPv4 address mapped.
+///
+pub fn traffic_record(pid: u32, l: &Link, size: u32, is_rx: bool) -> Vec<u8> {
+    let is_rx = is_rx as u32;
+
+    match (l.prot, ipv4_pair(l)) {
+        (Prot::ICMP(icmp_type, code), Some((saddr, daddr))) => record(RECORD_ICMP4, &icmp4_data_t {
+            pid, saddr, daddr, id: l.lport, icmp_type, code, size, is_rx, comm: [0; COMM_LEN],
+        }),
+        (Prot::ICMP(icmp_type, code), None) => {
+            let (saddr, daddr) = ipv6_pair(l);
+            record(RECORD_ICMP6, &icmp6_data_t {
+                saddr, daddr, pid, id: l.lport, icmp_type, code, size, is_rx, comm: [0; COMM_LEN],
+            })
         }
-    } else {
-        /*
-         * First time we see this process communicating over the network.
-         */
-        let path_comm = format!("/proc/{}/comm", p.pid);
-        let content_comm = fs::read_to_string(path_comm);
-        //let path_cmdline = format!("/proc/{}/cmdline", data.pid);
-        //let content_cmdline = fs::read_to_string(path_cmdline);
-
-        let name = match content_comm {
-            Ok(mut content) => { content.pop(); content },
-            Err(_error) => String::from("file not found"),
+        (prot, Some((saddr, daddr))) => {
+            let kind = if prot == Prot::UDP { RECORD_UDP4 } else { RECORD_TCP4 };
+            record(kind, &ipv4_data_t {
+                pid, saddr, daddr, lport: l.lport, dport: l.dport, size, is_rx,
+                comm: [0; COMM_LEN],
+            })
+        }
+        (prot, None) => {
+            let (saddr, daddr) = ipv6_pair(l);
+            let kind = if prot == Prot::UDP { RECORD_UDP6 } else { RECORD_TCP6 };
+            record(kind, &ipv6_data_t {
+                saddr, daddr, pid, lport: l.lport, dport: l.dport, size, is_rx,
+                comm: [0; COMM_LEN],
+            })
+        }
+    }
+}
+
+///
+/// Returns the record the probes send when the process 'pid' opens or closes the TCP connection
+/// 'l', or when it changes state, 'event' being one of the EVENT_* values and 'state' the state
+/// of the connection then.
+///
+pub fn event_record(pid: u32, l: &Link, event: u32, state: TcpState) -> Vec<u8> {
+    let (lport, dport, state) = (l.lport, l.dport, state as u32);
+
+    match ipv4_pair(l) {
+        Some((saddr, daddr)) => record(RECORD_TCP4_EVENT, &ipv4_event_t {
+            pid, saddr, daddr, lport, dport, event, state, comm: [0; COMM_LEN],
+        }),
+        None => {
+            let (saddr, daddr) = ipv6_pair(l);
+            record(RECORD_TCP6_EVENT, &ipv6_event_t {
+                saddr, daddr, pid, lport, dport, event, state, comm: [0; COMM_LEN],
+            })
+        }
+    }
+}
+
+///
+/// Returns the record the probes send when the process 'pid' receives the DNS response to the
+/// question 'name' with the addresses 'addrs'.
+///
+pub fn dns_record(pid: u32, name: &str, addrs: &[IpAddr]) -> Vec<u8> {
+    dns_read_record(pid, &passive_dns::response(name, addrs), false)
+}
+
+///
+/// Returns the record the probes send when the process 'pid' reads the DNS response to the
+/// question 'name' with the addresses 'addrs' from a TCP connection, after its length.
+///
+pub fn dns_tcp_record(pid: u32, name: &str, addrs: &[IpAddr]) -> Vec<u8> {
+    let response = passive_dns::response(name, addrs);
+    let mut read = (response.len() as u16).to_be_bytes().to_vec();
+    read.extend_from_slice(&response);
+
+    dns_read_record(pid, &read, true)
+}
+
+// The DNS record of what a read returned, cut as by the probes
+fn dns_read_record(pid: u32, read: &[u8], is_tcp: bool) -> Vec<u8> {
+    let size = read.len().min(DNS_PAYLOAD);
+    let mut data = dns_data_t {
+        pid, size: size as u32, is_tcp: is_tcp as u32, comm: [0; COMM_LEN],
+        payload: [0; DNS_PAYLOAD],
+    };
+
+    data.payload[..size].copy_from_slice(&read[..size]);
+
+    record(RECORD_DNS, &data)
+}
+
+///
+/// Returns the record the probes send when the process 'pid' sends 'payload' first on the link
+/// 'l', a TCP connection it opened or a UDP peer.
+///
+pub fn payload_record(pid: u32, l: &Link, payload: &[u8]) -> Vec<u8> {
+    let ((saddr, daddr), family) = match ipv4_pair(l) {
+        Some((saddr, daddr)) => ((saddr as u128, daddr as u128), AF_INET),
+        None => (ipv6_pair(l), AF_INET6),
+    };
+    let size = payload.len().min(PEEK_PAYLOAD);
+    let mut data = payload_data_t {
+        saddr, daddr, pid, lport: l.lport, dport: l.dport, family, size: size as u32,
+        comm: [0; COMM_LEN], payload: [0; PEEK_PAYLOAD],
+    };
+
+    data.payload[..size].copy_from_slice(&payload[..size]);
+
+    let kind = match l.prot {
+        Prot::UDP => RECORD_UDP_PAYLOAD,
+        _ => RECORD_TCP_PAYLOAD,
+    };
+
+    record(kind, &data)
+}
+
+///
+/// Returns the record the probes send when the process 'pid' executes the program of 'exec', cut
+/// as by the probes.
+///
+pub fn exec_record(pid: u32, exec: &Exec) -> Vec<u8> {
+    let mut data = exec_data_t {
+        pid, ppid: exec.ppid, uid: exec.uid, euid: exec.euid,
+        start: exec.start.map_or(0, procfs::since_boot), size: 0,
+        comm: [0; COMM_LEN], exe: [0; EXE_LEN], cmdline: [0; CMDLINE_LEN],
+    };
+    let cmdline: Vec<u8> = exec.cmdline.iter().flat_map(|arg| arg.bytes().chain([0])).collect();
+    let size = cmdline.len().min(CMDLINE_LEN);
+
+    copy_str(&mut data.comm, &exec.comm);
+    copy_str(&mut data.exe, &exec.exe);
+    data.cmdline[..size].copy_from_slice(&cmdline[..size]);
+    data.size = size as u32;
+
+    record(RECORD_EXEC, &data)
+}
+
+///
+/// Returns the record the probes send when the process 'pid', started at 'start', exits.
+///
+pub fn exit_record(pid: u32, start: Option<i64>) -> Vec<u8> {
+    record(RECORD_EXIT, &exit_data_t { start: start.map_or(0, procfs::since_boot), pid, pad: 0 })
+}
+
+// Copy 'x' to the string 'to' of the probes, cut to keep the NUL terminating it
+fn copy_str(to: &mut [u8], x: &str) {
+    let size = x.len().min(to.len() - 1);
+
+    to[..size].copy_from_slice(&x.as_bytes()[..size]);
+}
+
+// The addresses of 'l' as the probes store them, if both are IPv4 ones
+fn ipv4_pair(l: &Link) -> Option<(u32, u32)> {
+    match (l.saddr, l.daddr) {
+        (IpAddr::V4(saddr), IpAddr::V4(daddr)) => {
+            Some((u32::from(saddr).to_be(), u32::from(daddr).to_be()))
+        }
+        _ => None,
+    }
+}
+
+fn ipv6_pair(l: &Link) -> (u128, u128) {
+    let v6 = |addr: IpAddr| match addr {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()).to_be(),
+        IpAddr::V6(v6) => u128::from(v6).to_be(),
+    };
+
+    (v6(l.saddr), v6(l.daddr))
+}
+
+// A record of the probes: the header, then the data
+fn record<T>(kind: u32, data: &T) -> Vec<u8> {
+    join_record(kind, unsafe {
+        std::slice::from_raw_parts(data as *const T as *const u8, std::mem::size_of::<T>())
+    })
+}
+
+///
+/// Account the traffic of an entry of the "traffic" map of the probes, 'key' and 'value' as read
+/// from the map. Entries of unknown kinds are ignored, malformed ones dropped.
+///
+pub fn account_flow(procs: &SharedProcs, key: &[u8], value: &[u8]) {
+    let (flow, bytes) = match (procs.decoded::<flow_t>(key), procs.decoded::<bytes_t>(value)) {
+        (Some(flow), Some(bytes)) => (flow, bytes),
+        _ => return,
+    };
+
+    let v4 = |addr: u128| IpAddr::V4( Ipv4Addr::from((addr as u32).to_be()) );
+    let v6 = |addr: u128| IpAddr::V6( Ipv6Addr::from(addr.to_be()) );
+
+    let icmp = Prot::ICMP(flow.icmp_type, flow.icmp_code);
+
+    let (saddr, daddr, dport, prot) = match flow.kind {
+        RECORD_TCP4 => (v4(flow.saddr), v4(flow.daddr), flow.dport, Prot::TCP),
+        RECORD_TCP6 => (v6(flow.saddr), v6(flow.daddr), flow.dport, Prot::TCP),
+        RECORD_UDP4 => (v4(flow.saddr), v4(flow.daddr), flow.dport, Prot::UDP),
+        RECORD_UDP6 => (v6(flow.saddr), v6(flow.daddr), flow.dport, Prot::UDP),
+        RECORD_ICMP4 => (v4(flow.saddr), v4(flow.daddr), 0, icmp),
+        RECORD_ICMP6 => (v6(flow.saddr), v6(flow.daddr), 0, icmp),
+        _ => return,
+    };
+
+    for (size, is_rx) in [(bytes.rx, 1), (bytes.tx, 0)] {
+        if size == 0 {
+            continue;
+        }
+
+        let mut l = Link::new(saddr, daddr, flow.lport, dport);
+        l.prot(prot);
+
+        update_procs_and_links(procs, seen(flow.pid, &flow.comm), l, size as isize, is_rx, prot);
+    }
+}
+
+///
+/// Whether the entry of the map of the traffic with this 'key' is to be read and emptied, now that
+/// the probes account in the generation 'current'. The entries of the previous generation may
+/// still be added to by the probes that read it just before: they are left for the next read.
+/// Without 'current', the probes are detached and every entry is read, whatever its generation.
+///
+pub fn flow_drained(key: &[u8], current: Option<u16>) -> bool {
+    let current = match current {
+        Some(current) => current,
+        None => return true,
+    };
+
+    match decode::<flow_t>(key) {
+        Ok(flow) => flow.generation != current && flow.generation != current.wrapping_sub(1),
+        Err(_) => true,
+    }
+}
+
+///
+/// Whether the kernel 'release' (as in "uname -r") has BPF ring buffers, added in 5.8.
+///
+pub fn has_ringbuf(release: &str) -> bool {
+    let mut numbers = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>().unwrap_or(0));
+
+    match (numbers.next(), numbers.next()) {
+        (Some(major), Some(minor)) => (major, minor) >= (5, 8),
+        _ => false,
+    }
+}
+
+pub fn tcp6_event_cb(procs: SharedProcs) -> Box<dyn FnMut(&[u8]) + Send> {
+    Box::new(move |x| {
+        let data = match procs.decoded::<ipv6_event_t>(x) {
+            Some(data) => data,
+            None => return,
         };
-        // TODO: some kind of verbose mode
-        //let _cmdline = match content_cmdline {
-        //    Ok(mut content) => { content.pop(); content },
-        //    Err(error) => String::from("file not found"),
-        //};
 
-        if l.daddr.is_global() {
-            let (host, _service) = reverse_lookup(l.daddr, l.dport);
-            l.domain(host);
+        let mut l = Link::new(
+            IpAddr::V6( Ipv6Addr::from(data.saddr.to_be()) ),
+            IpAddr::V6( Ipv6Addr::from(data.daddr.to_be()) ),
+            data.lport,
+            data.dport,
+        );
+        l.prot(Prot::TCP);
+
+        let state = TcpState::from_kernel(data.state);
+        let p = seen(data.pid, &data.comm);
+
+        update_link_state(&procs, p, l, data.event, state, crate::clock::now());
+    })
+}
+
+///
+/// Record that a TCP connection was opened or closed at the UNIX timestamp 'ts', or that its
+/// state changed. 'state' is the TCP state of the socket then, if the kernel value is known.
+///
+fn update_link_state(
+    shared: &SharedProcs, mut p: Process, mut l: Link, event: u32, state: Option<TcpState>,
+    ts: i64
+)
+{
+    l.unmap();
+
+    if event == EVENT_CLOSE || event == EVENT_STATE {
+        // The state changes whatever process runs then, e.g. when a packet is received
+        let mut procs = match event {
+            EVENT_STATE => shared.lock().unwrap(),
+            _ => shared.lock_for(&mut p),
+        };
+
+        // Opened before the capture started and never used since, nothing to change
+        if let Some(known_l) = link_of(&mut procs, p.pid, &l) {
+            if event == EVENT_CLOSE {
+                known_l.close(ts);
+            }
+            if let Some(state) = state {
+                known_l.tcp_state(state);
+            }
+        }
+        return;
+    }
+
+    if event != EVENT_CONNECT && event != EVENT_ACCEPT {
+        return;
+    }
+
+    let mut procs = shared.lock_for(&mut p);
+    let known_p = known(&mut procs, p);
+
+    let known_l = match known_p.tlinks.iter().position(|x| *x == l) {
+        Some(i) => &mut known_p.tlinks[i],
+        None => {
+            identify(shared, &known_p.names, &mut l);
+            known_p.tlinks.push(l);
+            known_p.tlinks.last_mut().unwrap()
+        }
+    };
+
+    known_l.open(ts);
+    if let Some(state) = state {
+        known_l.tcp_state(state);
+    }
+}
+
+///
+/// Returns the TCP link 'l' of the process 'pid', else the one of any process not closed yet: a
+/// socket may be closed by another process than the one which opened it, e.g. a child.
+///
+fn link_of<'a>(procs: &'a mut [Process], pid: u32, l: &Link) -> Option<&'a mut Link> {
+    match procs.iter().position(|x| x.pid == pid && x.tlinks.contains(l)) {
+        Some(i) => procs[i].tlinks.iter_mut().find(|x| *x == l),
+        None => procs
+            .iter_mut()
+            .flat_map(|x| x.tlinks.iter_mut())
+            .find(|x| *x == l && x.state() != State::Closed),
+    }
+}
+
+///
+/// Record what the first bytes sent by the process 'p' on the link 'l' told: the server asked for
+/// and the application protocol, if found.
+///
+fn update_link_payload(
+    shared: &SharedProcs, mut p: Process, mut l: Link, server: Option<String>,
+    app_proto: Option<String>
+)
+{
+    let mut procs = shared.lock_for(&mut p);
+
+    l.unmap();
+
+    let known_p = known(&mut procs, p);
+
+    let prot = l.prot;
+    let known_l = match known_p.links_of(prot).iter().position(|x| *x == l) {
+        Some(i) => &mut known_p.links_of(prot)[i],
+        None => {
+            identify(shared, &known_p.names, &mut l);
+            known_p.links_of(prot).push(l);
+            known_p.links_of(prot).last_mut().unwrap()
+        }
+    };
+
+    if let Some(server) = server {
+        known_l.server(server);
+    }
+    if let Some(app_proto) = app_proto {
+        known_l.app_proto(app_proto);
+    }
+}
+
+///
+/// Returns the process of the record from which 'p' was made, 'procs' being the processes known.
+/// It is the running process of its pid, else 'p', described by Procs::lock_for(). The records of a
+/// process which exited, e.g. of the traffic added up by the probes, are still accounted to it as
+/// long as its pid runs nothing else.
+///
+fn known(procs: &mut Vec<Process>, p: Process) -> &mut Process {
+    let i = match procs.iter().position(|x| x.pid == p.pid && x.exited.is_none()) {
+        Some(i) => i,
+        None => {
+            let exited = |x: &Process| x.pid == p.pid && match (x.start, p.start) {
+                (_, None) => true,
+                (Some(a), Some(b)) => procfs::same_start(a, b),
+                (None, Some(_)) => false,
+            };
+            match procs.iter().rposition(exited) {
+                Some(i) => i,
+                None => {
+                    procs.push(p);
+                    procs.len() - 1
+                }
+            }
         }
+    };
+
+    &mut procs[i]
+}
 
-        p.name(name);
-        p.add_data(packets_size, is_rx);
+///
+/// Returns the name of the process 'pid' as found in /proc, for the records without any.
+///
+fn process_name(pid: u32) -> String {
+    let path_comm = format!("/proc/{}/comm", pid);
+    let content_comm = fs::read_to_string(path_comm);
+    //let path_cmdline = format!("/proc/{}/cmdline", data.pid);
+    //let content_cmdline = fs::read_to_string(path_cmdline);
+
+    // TODO: some kind of verbose mode
+    //let _cmdline = match content_cmdline {
+    //    Ok(mut content) => { content.pop(); content },
+    //    Err(error) => String::from("file not found"),
+    //};
+
+    match content_comm {
+        Ok(mut content) => { content.pop(); content },
+        Err(_error) => String::from("file not found"),
+    }
+}
 
+///
+/// Record the current network connection.
+///
+fn update_procs_and_links(
+    shared: &SharedProcs, mut p: Process, mut l: Link, packets_size: isize,
+    is_rx: u32, prot: Prot
+)
+{
+    let mut procs = shared.lock_for(&mut p);
+
+    l.unmap();
+
+    let known_p = known(&mut procs, p);
+
+    known_p.add_data(packets_size, is_rx);
+
+    let links = known_p.links_of(prot);
+
+    if links.contains(&l) {
+        let known_link = links.iter_mut().find(|x| **x == l).unwrap();
+
+        known_link.add_data(packets_size, is_rx);
+    } else {
+        identify(shared, &known_p.names, &mut l);
         l.add_data(packets_size, is_rx);
 
-        let links = if prot == Prot::TCP { &mut p.tlinks } else { &mut p.ulinks };
+        known_p.links_of(prot).push(l);
+    }
+}
+
+///
+/// Name and classify the new link 'l' of a process knowing the names 'names', before the probes
+/// tell more about it.
+///
+fn identify(procs: &SharedProcs, names: &HashMap<IpAddr, String>, l: &mut Link) {
+    l.domain(link_domain(procs, names, l.daddr));
+    l.app_proto(classify::by_port(l.prot, l.lport, l.dport));
+}
+
+///
+/// Returns the name of the remote address 'addr' of a new link: the one the process asked for, see
+/// Process::learn(), else the one of the reverse lookup if already known.
+///
+fn link_domain(procs: &SharedProcs, names: &HashMap<IpAddr, String>, addr: IpAddr) -> String {
+    match names.get(&addr) {
+        Some(name) => name.clone(),
+        None if addr.is_global() => domain(procs, addr).unwrap_or_default(),
+        None => String::new(),
+    }
+}
+
+///
+/// Returns the name of the remote address 'addr' if already known. Otherwise it is resolved in the
+/// background and the links to 'addr' are named once it is.
+///
+fn domain(procs: &SharedProcs, addr: IpAddr) -> Option<String> {
+    let procs = procs.clone();
 
-        links.push(l);
-        procs.push(p);
+    dns::resolve(addr, Box::new(move |name| set_domain(&procs, addr, name)))
+}
+
+// Name the links to 'addr' which have no name yet
+fn set_domain(procs: &SharedProcs, addr: IpAddr, name: &str) {
+    let mut procs = procs.lock().unwrap();
+
+    for p in procs.iter_mut() {
+        let links = p.tlinks.iter_mut().chain(p.ulinks.iter_mut()).chain(p.ilinks.iter_mut());
+
+        for l in links.filter(|l| l.daddr == addr && l.domain.is_empty()) {
+            l.domain(name.to_string());
+        }
     }
 }
 
-fn parse_struct_ipv4(addr: &[u8]) -> ipv4_data_t {
-    unsafe { ptr::read(addr.as_ptr() as *const ipv4_data_t) }
+///
+/// Returns the IPv4 address 'addr' maps to, if any.
+///
+fn unmapped(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4( Ipv4Addr::from((hi as u32) << 16 | lo as u32) )
+            }
+            _ => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
 }
 
-fn parse_struct_ipv6(addr: &[u8]) -> ipv6_data_t {
-    unsafe { ptr::read(addr.as_ptr() as *const ipv6_data_t) }
+///
+/// Human readable duration, e.g. "1h02m", "2m05s" or "12s".
+///
+fn duration_str(secs: i64) -> String {
+    let secs = secs.max(0);
+
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60),
+    }
 }
 
 fn group_bytes(bytes: isize) -> (f64, DataUnit) {
@@ -629,8 +2382,8 @@ fn group_bytes(bytes: isize) -> (f64, DataUnit) {
  *           UDP 10.0.10.100:5201 <-> 10.0.10.100:57922 RX: 498.78MB TX: 4.00B
  *
  */
-pub fn log_iperf_to_file() -> std::io::Result<()> {
-    let procs = PROCESSES.lock().unwrap();
+pub fn log_iperf_to_file(procs: &SharedProcs) -> std::io::Result<()> {
+    let procs = procs.lock().unwrap();
     let mut tcp4_rx = 0;
     let mut tcp4_tx = 0;
     let mut tcp6_rx = 0;
@@ -698,22 +2451,9 @@ mod tests {
         )
     }
 
-    fn remove_all_procs() {
-        // clear the vector of process in case other test executed before
-        let procs = PROCESSES.lock();
-
-        match procs {
-            Ok(mut x) => {
-                // no elements should match that condition
-                x.retain(|e| e.pid == u32::MAX);
-            },
-            _ => (),
-        }
-    }
-
     #[test]
     fn tcp4_cb_one_process_multiple_links() {
-        remove_all_procs();
+        let procs = new_procs();
 
         let data0 = ipv4_data_t {
             pid: 1234,
@@ -723,6 +2463,7 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 1,
+            comm: [0; COMM_LEN],
         };
         let data1 = ipv4_data_t {
             pid: 1234,
@@ -732,13 +2473,14 @@ mod tests {
             dport: 80,
             size: 567890,
             is_rx: 0,
+            comm: [0; COMM_LEN],
         };
-        let mut ptr = tcp4_cb();
+        let mut ptr = tcp4_cb(procs.clone());
 
         ptr( unsafe {any_as_u8_slice(&data0)} );
         ptr( unsafe {any_as_u8_slice(&data1)} );
 
-        let procs = PROCESSES.lock().unwrap();
+        let procs = procs.lock().unwrap();
 
         assert_eq!(procs.len(), 1, "number of process incorrect");
 
@@ -762,7 +2504,7 @@ mod tests {
 
     #[test]
     fn tcp4_cb_multiple_process_one_link() {
-        remove_all_procs();
+        let procs = new_procs();
 
         let data0 = ipv4_data_t {
             pid: 1234,
@@ -772,6 +2514,7 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 1,
+            comm: [0; COMM_LEN],
         };
         let data1 = ipv4_data_t {
             pid: 5678,
@@ -781,20 +2524,21 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 0,
+            comm: [0; COMM_LEN],
         };
-        let mut ptr = tcp4_cb();
+        let mut ptr = tcp4_cb(procs.clone());
 
         ptr( unsafe {any_as_u8_slice(&data0)} );
         ptr( unsafe {any_as_u8_slice(&data1)} );
 
-        let procs = PROCESSES.lock().unwrap();
+        let procs = procs.lock().unwrap();
 
         assert_eq!(procs.len(), 2, "number of process incorrect");
     }
 
     #[test]
     fn tcp6_cb_one_process_multiple_links() {
-        remove_all_procs();
+        let procs = new_procs();
 
         let data0 = ipv6_data_t {
             pid: 1234,
@@ -804,6 +2548,7 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 1,
+            comm: [0; COMM_LEN],
         };
         let data1 = ipv6_data_t {
             pid: 1234,
@@ -813,13 +2558,14 @@ mod tests {
             dport: 80,
             size: 567890,
             is_rx: 0,
+            comm: [0; COMM_LEN],
         };
-        let mut ptr = tcp6_cb();
+        let mut ptr = tcp6_cb(procs.clone());
 
         ptr( unsafe {any_as_u8_slice(&data0)} );
         ptr( unsafe {any_as_u8_slice(&data1)} );
 
-        let procs = PROCESSES.lock().unwrap();
+        let procs = procs.lock().unwrap();
 
         assert_eq!(procs.len(), 1, "number of process incorrect");
 
@@ -842,7 +2588,7 @@ mod tests {
 
     #[test]
     fn tcp6_cb_multiple_process_one_link() {
-        remove_all_procs();
+        let procs = new_procs();
 
         let data0 = ipv6_data_t {
             pid: 1234,
@@ -852,6 +2598,7 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 1,
+            comm: [0; COMM_LEN],
         };
         let data1 = ipv6_data_t {
             pid: 5678,
@@ -861,20 +2608,21 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 0,
+            comm: [0; COMM_LEN],
         };
-        let mut ptr = tcp6_cb();
+        let mut ptr = tcp6_cb(procs.clone());
 
         ptr( unsafe {any_as_u8_slice(&data0)} );
         ptr( unsafe {any_as_u8_slice(&data1)} );
 
-        let procs = PROCESSES.lock().unwrap();
+        let procs = procs.lock().unwrap();
 
         assert_eq!(procs.len(), 2, "number of process incorrect");
     }
 
     #[test]
     fn udp4_cb_one_process_multiple_links() {
-        remove_all_procs();
+        let procs = new_procs();
 
         let data0 = ipv4_data_t {
             pid: 1234,
@@ -884,6 +2632,7 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 1,
+            comm: [0; COMM_LEN],
         };
         let data1 = ipv4_data_t {
             pid: 1234,
@@ -893,13 +2642,14 @@ mod tests {
             dport: 80,
             size: 567890,
             is_rx: 0,
+            comm: [0; COMM_LEN],
         };
-        let mut ptr = udp4_cb();
+        let mut ptr = udp4_cb(procs.clone());
 
         ptr( unsafe {any_as_u8_slice(&data0)} );
         ptr( unsafe {any_as_u8_slice(&data1)} );
 
-        let procs = PROCESSES.lock().unwrap();
+        let procs = procs.lock().unwrap();
 
         assert_eq!(procs.len(), 1, "number of process incorrect");
 
@@ -923,7 +2673,7 @@ mod tests {
 
     #[test]
     fn udp4_cb_multiple_process_one_link() {
-        remove_all_procs();
+        let procs = new_procs();
 
         let data0 = ipv4_data_t {
             pid: 1234,
@@ -933,6 +2683,7 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 1,
+            comm: [0; COMM_LEN],
         };
         let data1 = ipv4_data_t {
             pid: 5678,
@@ -942,20 +2693,21 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 0,
+            comm: [0; COMM_LEN],
         };
-        let mut ptr = udp4_cb();
+        let mut ptr = udp4_cb(procs.clone());
 
         ptr( unsafe {any_as_u8_slice(&data0)} );
         ptr( unsafe {any_as_u8_slice(&data1)} );
 
-        let procs = PROCESSES.lock().unwrap();
+        let procs = procs.lock().unwrap();
 
         assert_eq!(procs.len(), 2, "number of process incorrect");
     }
 
     #[test]
     fn udp6_cb_one_process_multiple_links() {
-        remove_all_procs();
+        let procs = new_procs();
 
         let data0 = ipv6_data_t {
             pid: 1234,
@@ -965,6 +2717,7 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 1,
+            comm: [0; COMM_LEN],
         };
         let data1 = ipv6_data_t {
             pid: 1234,
@@ -974,13 +2727,14 @@ mod tests {
             dport: 80,
             size: 567890,
             is_rx: 0,
+            comm: [0; COMM_LEN],
         };
-        let mut ptr = udp6_cb();
+        let mut ptr = udp6_cb(procs.clone());
 
         ptr( unsafe {any_as_u8_slice(&data0)} );
         ptr( unsafe {any_as_u8_slice(&data1)} );
 
-        let procs = PROCESSES.lock().unwrap();
+        let procs = procs.lock().unwrap();
 
         assert_eq!(procs.len(), 1, "number of process incorrect");
 
@@ -1003,7 +2757,7 @@ mod tests {
 
     #[test]
     fn udp6_cb_multiple_process_one_link() {
-        remove_all_procs();
+        let procs = new_procs();
 
         let data0 = ipv6_data_t {
             pid: 1234,
@@ -1013,6 +2767,7 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 1,
+            comm: [0; COMM_LEN],
         };
         let data1 = ipv6_data_t {
             pid: 5678,
@@ -1022,15 +2777,936 @@ mod tests {
             dport: 80,
             size: 56789,
             is_rx: 0,
+            comm: [0; COMM_LEN],
         };
-        let mut ptr = udp6_cb();
+        let mut ptr = udp6_cb(procs.clone());
 
         ptr( unsafe {any_as_u8_slice(&data0)} );
         ptr( unsafe {any_as_u8_slice(&data1)} );
 
-        let procs = PROCESSES.lock().unwrap();
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs.len(), 2, "number of process incorrect");
+    }
+
+    #[test]
+    fn udp6_cb_v4_mapped() {
+        let procs = new_procs();
+
+        // Sent from an IPv6 socket, received on an IPv4 one
+        let data0 = ipv6_data_t {
+            pid: 1234,
+            saddr: 2667071024275936381264908679913668608,   // (LE) ::ffff:192.168.1.2
+            daddr: 266365032538441272591406800061312532480, // (LE) ::ffff:10.10.100.200
+            lport: 4321,
+            dport: 53,
+            size: 40,
+            is_rx: 0,
+            comm: [0; COMM_LEN],
+        };
+        let data1 = ipv4_data_t {
+            pid: 1234,
+            saddr: 33663168,    // (little endian) 192.168.1.2
+            daddr: 3361999370,  // (little endian) 10.10.100.200
+            lport: 4321,
+            dport: 53,
+            size: 120,
+            is_rx: 1,
+            comm: [0; COMM_LEN],
+        };
+
+        udp6_cb(procs.clone())( unsafe {any_as_u8_slice(&data0)} );
+        udp4_cb(procs.clone())( unsafe {any_as_u8_slice(&data1)} );
+
+        let procs = procs.lock().unwrap();
+        let links = procs[0].get_ulinks();
+
+        assert_eq!(links.len(), 1, "same peer seen as two links");
+        assert_eq!(links[0].daddr, IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ));
+        assert_eq!(links[0].saddr, IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ));
+        assert_eq!((links[0].rx, links[0].tx), (120, 40));
+
+        // Not an IPv4-mapped address
+        let ip = IpAddr::V6( Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1) );
+        assert_eq!(unmapped(ip), ip);
+    }
+
+    #[test]
+    fn rollover_resets_counters() {
+        let procs = new_procs();
+
+        let data = ipv4_data_t {
+            pid: 1234,
+            saddr: 33663168,    // (little endian) 192.168.1.2
+            daddr: 3361999370,  // (little endian) 10.10.100.200
+            lport: 4321,
+            dport: 80,
+            size: 56789,
+            is_rx: 1,
+            comm: [0; COMM_LEN],
+        };
+        let mut ptr = tcp4_cb(procs.clone());
+
+        ptr( unsafe {any_as_u8_slice(&data)} );
+
+        let previous = rollover(&mut procs.lock().unwrap());
+
+        assert_eq!(previous[0].rx, 56789, "traffic of the previous day lost");
+        assert_eq!(previous[0].tlinks[0].rx, 56789, "link traffic of the previous day lost");
+
+        ptr( unsafe {any_as_u8_slice(&data)} );
+
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs.len(), 1, "number of process incorrect");
+        assert_eq!(procs[0].rx, 56789, "process counter not reset");
+        assert_eq!(procs[0].tlinks[0].rx, 56789, "link counter not reset");
+    }
+
+    #[test]
+    fn tcp4_event_cb_lifecycle() {
+        let procs = new_procs();
+
+        let mut event = ipv4_event_t {
+            pid: 1234,
+            saddr: 33663168,    // (little endian) 192.168.1.2
+            daddr: 3361999370,  // (little endian) 10.10.100.200
+            lport: 4321,
+            dport: 80,
+            event: EVENT_CONNECT,
+            state: TcpState::SynSent as u32,
+            comm: [0; COMM_LEN],
+        };
+        let data = ipv4_data_t {
+            pid: 1234,
+            saddr: 33663168,    // (little endian) 192.168.1.2
+            daddr: 3361999370,  // (little endian) 10.10.100.200
+            lport: 4321,
+            dport: 80,
+            size: 56789,
+            is_rx: 1,
+            comm: [0; COMM_LEN],
+        };
+        let mut ptr_event = tcp4_event_cb(procs.clone());
+        let mut ptr_data = tcp4_cb(procs.clone());
+
+        ptr_event( unsafe {any_as_u8_slice(&event)} );
+
+        {
+            let procs = procs.lock().unwrap();
+
+            assert_eq!(procs.len(), 1, "process not known before its first packet");
+            assert_eq!(procs[0].tlinks[0].tcp_state, Some(TcpState::SynSent));
+            assert_eq!(procs[0].tlinks[0].state(), State::Open);
+            assert_eq!(procs[0].tlinks[0].rx, 0);
+        }
+
+        // Established when the answer of the server is received, whatever process runs then
+        event.pid = 0;
+        event.event = EVENT_STATE;
+        event.state = TcpState::Established as u32;
+        ptr_event( unsafe {any_as_u8_slice(&event)} );
+        ptr_data( unsafe {any_as_u8_slice(&data)} );
+
+        // Closed by a child process, the kernel is not done with the connection yet
+        event.pid = 4321;
+        event.event = EVENT_CLOSE;
+        ptr_event( unsafe {any_as_u8_slice(&event)} );
+
+        {
+            let procs = procs.lock().unwrap();
+
+            assert_eq!(procs.len(), 1, "number of process incorrect");
+            assert_eq!(procs[0].tlinks.len(), 1, "number of links incorrect");
+            assert_eq!(procs[0].tlinks[0].rx, 56789, "link traffic incorrect");
+            assert_eq!(procs[0].tlinks[0].tcp_state, Some(TcpState::Established));
+            assert!(procs[0].tlinks[0].closed.is_some(), "close not recorded");
+        }
+
+        event.pid = 0;
+        event.event = EVENT_STATE;
+        event.state = TcpState::Close as u32;
+        ptr_event( unsafe {any_as_u8_slice(&event)} );
+
+        // A state the kernel does not have is ignored
+        event.state = 42;
+        ptr_event( unsafe {any_as_u8_slice(&event)} );
+
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs.len(), 1, "process of the state change added");
+        assert_eq!(procs[0].tlinks[0].state(), State::Closed, "link not closed");
+        assert!(procs[0].tlinks[0].to_string().contains("[closed"));
+    }
+
+    #[test]
+    fn icmp4_cb_echo() {
+        let procs = new_procs();
+
+        let mut data = icmp4_data_t {
+            pid: 1234,
+            saddr: 33663168,    // (little endian) 192.168.1.2
+            daddr: 3361999370,  // (little endian) 10.10.100.200
+            id: 42,
+            icmp_type: 8,       // echo request
+            code: 0,
+            size: 64,
+            is_rx: 0,
+            comm: [0; COMM_LEN],
+        };
+        let mut ptr = icmp4_cb(procs.clone());
+
+        ptr( unsafe {any_as_u8_slice(&data)} );
+        ptr( unsafe {any_as_u8_slice(&data)} );
+
+        data.icmp_type = 0;     // echo reply
+        data.is_rx = 1;
+        ptr( unsafe {any_as_u8_slice(&data)} );
+
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs.len(), 1, "number of process incorrect");
+        assert_eq!((procs[0].rx, procs[0].tx), (64, 128), "process traffic incorrect");
+        assert!(procs[0].tlinks.is_empty() && procs[0].ulinks.is_empty());
+        assert_eq!(procs[0].ilinks.len(), 2, "one link per type of message");
+        assert_eq!(procs[0].ilinks[0].prot, Prot::ICMP(8, 0));
+        assert_eq!(procs[0].ilinks[0].tx, 128);
+        assert_eq!(procs[0].ilinks[1].rx, 64);
+    }
+
+    #[test]
+    fn record_cb_dispatch() {
+        let procs = new_procs();
+
+        let data = ipv4_data_t {
+            pid: 1234,
+            saddr: 33663168,    // (little endian) 192.168.1.2
+            daddr: 3361999370,  // (little endian) 10.10.100.200
+            lport: 4321,
+            dport: 53,
+            size: 100,
+            is_rx: 0,
+            comm: [0; COMM_LEN],
+        };
+        let mut ptr = record_cb(procs.clone());
+
+        // An UDP datagram, then a record of unknown kind
+        for kind in [2u32, 42] {
+            ptr(&join_record(kind, unsafe {any_as_u8_slice(&data)}));
+        }
+
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs.len(), 1, "number of process incorrect");
+        assert_eq!(procs[0].tx, 100, "process tx incorrect");
+        assert_eq!(procs[0].ulinks.len(), 1, "record not handed to the UDP callback");
+        assert!(procs[0].tlinks.is_empty());
+    }
+
+    #[test]
+    fn dns_cb_names_links() {
+        let procs = new_procs();
+        let mut ptr = record_cb(procs.clone());
+
+        let addr = |last| IpAddr::V4( Ipv4Addr::new(10, 10, 100, last) );
+        let link = |last| {
+            let saddr = IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) );
+            let mut l = Link::new(saddr, addr(last), 4321, 443);
+            l.prot(Prot::TCP);
+            l
+        };
+        // Named once the response is seen, or as soon as opened
+        ptr(&traffic_record(1234, &link(200), 100, false));
+        ptr(&dns_record(1234, "intranet.example.com", &[addr(200), addr(201)]));
+        ptr(&traffic_record(1234, &link(201), 100, false));
+
+        // Names asked by another process, over TCP
+        ptr(&dns_tcp_record(5678, "other.example.com", &[addr(202)]));
+        ptr(&traffic_record(1234, &link(202), 100, false));
+
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs.len(), 2, "number of process incorrect");
+        let domains: Vec<&str> = procs[0].tlinks.iter().map(|l| l.domain.as_str()).collect();
+        assert_eq!(domains, vec!["intranet.example.com", "intranet.example.com", ""]);
+        assert_eq!(procs[1].names.get(&addr(202)).unwrap(), "other.example.com");
+        assert_eq!((procs[1].rx, procs[1].tx), (0, 0));
+    }
+
+    #[test]
+    fn payload_cb_names_servers() {
+        let procs = new_procs();
+        let mut cb = record_cb(procs.clone());
+
+        let mut https = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            443,
+        );
+        https.prot(Prot::TCP);
+        let mut http = Link::new(
+            IpAddr::V6( Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2) ),
+            IpAddr::V6( Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1) ),
+            4322,
+            80,
+        );
+        http.prot(Prot::TCP);
+
+        // Sent on a known link, on a new one, and bytes telling nothing
+        cb(&event_record(1234, &https, EVENT_CONNECT, TcpState::SynSent));
+        cb(&payload_record(1234, &https, &server_name::client_hello("www.example.com")));
+        cb(&payload_record(1234, &http, &server_name::http_request("intranet.example.com")));
+        cb(&payload_record(1234, &http, b"\x00\x01unknown"));
+
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs.len(), 1, "number of process incorrect");
+        let servers: Vec<&str> = procs[0].tlinks.iter().map(|l| l.server.as_str()).collect();
+        assert_eq!(servers, vec!["www.example.com", "intranet.example.com"]);
+        let app_protos: Vec<&str> = procs[0].tlinks.iter().map(|l| l.app_proto.as_str()).collect();
+        assert_eq!(app_protos, vec!["https", "http"]);
+        assert_eq!(procs[0].tlinks[0].state(), State::Open);
+        assert_eq!((procs[0].tlinks[1].rx, procs[0].tlinks[1].tx), (0, 0));
+    }
+
+    #[test]
+    fn payload_cb_classifies_udp() {
+        let procs = new_procs();
+        let mut cb = record_cb(procs.clone());
+
+        let mut dns = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 1) ),
+            5353,
+            53,
+        );
+        dns.prot(Prot::UDP);
+        let mut wireguard = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 2) ),
+            44333,
+            51820,
+        );
+        wireguard.prot(Prot::UDP);
+
+        // Classified by its port until the probes peek at its first bytes
+        cb(&traffic_record(1234, &dns, 40, false));
+        cb(&traffic_record(1234, &wireguard, 148, false));
+        assert_eq!(procs.lock().unwrap()[0].ulinks[1].app_proto, "");
+
+        let mut handshake = vec![1, 0, 0, 0];
+        handshake.resize(148, 0x5a);
+        cb(&payload_record(1234, &wireguard, &handshake));
+        // A name in a UDP payload is no server asked for
+        cb(&payload_record(1234, &dns, &server_name::http_request("example.com")));
+
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs[0].ulinks.len(), 2);
+        assert_eq!(procs[0].ulinks[0].app_proto, "dns");
+        assert_eq!(procs[0].ulinks[0].server, "");
+        assert_eq!(procs[0].ulinks[1].app_proto, "wireguard");
+        assert_eq!(procs[0].tlinks.len(), 0, "UDP payload accounted to TCP");
+    }
+
+    #[test]
+    fn split_record_malformed() {
+        let data = [7u8; 24];
+        let record = join_record(RECORD_UDP4, &data);
+        assert_eq!(split_record(&record), Ok((RECORD_UDP4, &data[..])));
+
+        // Padded by a perf buffer
+        let mut padded = record.clone();
+        padded.extend_from_slice(&[0u8; 4]);
+        assert_eq!(split_record(&padded), Ok((RECORD_UDP4, &data[..])));
+
+        assert!(split_record(&record[..RECORD_HEADER - 1]).is_err(), "short header accepted");
+        assert!(split_record(&record[..record.len() - 1]).is_err(), "truncated data accepted");
+
+        let mut other = record.clone();
+        other[4..6].copy_from_slice(&(RECORD_VERSION + 1).to_ne_bytes());
+        assert_eq!(split_record(&other), Err(format!("record of version {}, expected {}",
+            RECORD_VERSION + 1, RECORD_VERSION)));
+    }
+
+    #[test]
+    fn decode_sizes() {
+        for size in 0..80 {
+            assert_eq!(decode::<ipv4_data_t>(&vec![0u8; size]).is_ok(), size == 40);
+            assert_eq!(decode::<ipv6_event_t>(&vec![0u8; size]).is_ok(), size == 64);
+        }
+
+        assert_eq!(decode::<icmp6_data_t>(&[0u8; 10]).err().unwrap(),
+            "icmp6_data_t of 10 bytes, expected 64");
+        assert!(decode::<exec_data_t>(&[0u8; 816]).is_ok());
+    }
+
+    // Offset and size of each field of 'name' in records.h, laid out as a C compiler does
+    fn c_layout(name: &str) -> (Vec<(usize, usize)>, usize) {
+        let header = include_str!("bpf/records.h");
+        let value = |n: &str| -> usize {
+            if n == "TASK_COMM_LEN" {
+                return COMM_LEN;
+            }
+            if let Ok(value) = n.parse() {
+                return value;
+            }
+            header.lines()
+                .find_map(|l| l.strip_prefix(&format!("#define {} ", n)))
+                .unwrap_or_else(|| panic!("{} not defined", n)).trim().parse().unwrap()
+        };
+        let body = header.split(&format!("struct {} {{\n", name)).nth(1)
+            .unwrap_or_else(|| panic!("struct {} not found", name));
+
+        let (mut fields, mut offset, mut align) = (vec![], 0, 1);
+        for line in body.lines().take_while(|l| *l != "};") {
+            let line = line.split("//").next().unwrap().trim().trim_end_matches(';');
+            let (ty, field) = line.rsplit_once(' ').unwrap();
+            let size = match ty {
+                "u8" | "char" => 1,
+                "u16" => 2,
+                "u32" => 4,
+                "u64" => 8,
+                "unsigned __int128" => 16,
+                _ => panic!("{}: type {} unknown", name, ty),
+            };
+            let count = match field.split_once('[') {
+                Some((_, dim)) => value(dim.trim_end_matches(']')),
+                None => 1,
+            };
+            offset += (size - offset % size) % size;
+            fields.push((offset, size * count));
+            offset += size * count;
+            align = align.max(size);
+        }
+        (fields, offset + (align - offset % align) % align)
+    }
+
+    // Offset and size of each field of a structure, in the order given
+    macro_rules! rust_layout {
+        ($t:ident { $($field:ident),* }) => {{
+            let x: $t = unsafe { std::mem::MaybeUninit::zeroed().assume_init() };
+            let base = &x as *const $t as usize;
+            let fields = vec![$((
+                &x.$field as *const _ as usize - base,
+                std::mem::size_of_val(&x.$field),
+            )),*];
+            (fields, std::mem::size_of::<$t>())
+        }};
+    }
+
+    #[test]
+    fn record_layouts() {
+        let layouts = vec![
+            ("ipv4_data_t", rust_layout!(ipv4_data_t {
+                pid, saddr, daddr, lport, dport, size, is_rx, comm })),
+            ("ipv6_data_t", rust_layout!(ipv6_data_t {
+                saddr, daddr, pid, lport, dport, size, is_rx, comm })),
+            ("icmp4_data_t", rust_layout!(icmp4_data_t {
+                pid, saddr, daddr, id, icmp_type, code, size, is_rx, comm })),
+            ("icmp6_data_t", rust_layout!(icmp6_data_t {
+                saddr, daddr, pid, id, icmp_type, code, size, is_rx, comm })),
+            ("ipv4_event_t", rust_layout!(ipv4_event_t {
+                pid, saddr, daddr, lport, dport, event, state, comm })),
+            ("ipv6_event_t", rust_layout!(ipv6_event_t {
+                saddr, daddr, pid, lport, dport, event, state, comm })),
+            ("flow_t", rust_layout!(flow_t {
+                saddr, daddr, kind, pid, lport, dport, icmp_type, icmp_code, generation, comm })),
+            ("bytes_t", rust_layout!(bytes_t { rx, tx })),
+            ("dns_data_t", rust_layout!(dns_data_t { pid, size, is_tcp, comm, payload })),
+            ("payload_data_t", rust_layout!(payload_data_t {
+                saddr, daddr, pid, lport, dport, family, size, comm, payload })),
+            ("exec_data_t", rust_layout!(exec_data_t {
+                pid, ppid, uid, euid, start, size, comm, exe, cmdline })),
+            ("exit_data_t", rust_layout!(exit_data_t { start, pid, pad })),
+        ];
+
+        for (name, (fields, size)) in layouts {
+            let (c_fields, c_size) = c_layout(name);
+            assert_eq!(fields, c_fields, "fields of {}", name);
+            assert_eq!(size, c_size, "size of {}", name);
+
+            // Decoded field after field, see Fields: only the end may be padded
+            fields.iter().fold(0, |offset, &(at, size)| {
+                assert_eq!(at, offset, "padding in {}", name);
+                at + size
+            });
+        }
+        assert_eq!(c_layout("header_t").1, RECORD_HEADER);
+    }
+
+    #[test]
+    fn record_constants() {
+        let header = include_str!("bpf/records.h");
+        let value = |name: &str| -> u32 {
+            header.lines()
+                .find_map(|l| l.strip_prefix(&format!("#define {} ", name)))
+                .unwrap().trim().parse().unwrap()
+        };
+
+        assert_eq!(value("RECORD_VERSION"), RECORD_VERSION as u32);
+        let kinds = [
+            ("RECORD_TCP4", RECORD_TCP4), ("RECORD_TCP6", RECORD_TCP6),
+            ("RECORD_UDP4", RECORD_UDP4), ("RECORD_UDP6", RECORD_UDP6),
+            ("RECORD_TCP4_EVENT", RECORD_TCP4_EVENT), ("RECORD_TCP6_EVENT", RECORD_TCP6_EVENT),
+            ("RECORD_ICMP4", RECORD_ICMP4), ("RECORD_ICMP6", RECORD_ICMP6),
+            ("RECORD_DNS", RECORD_DNS), ("RECORD_TCP_PAYLOAD", RECORD_TCP_PAYLOAD),
+            ("RECORD_UDP_PAYLOAD", RECORD_UDP_PAYLOAD), ("RECORD_EXEC", RECORD_EXEC),
+            ("RECORD_EXIT", RECORD_EXIT),
+            ("EVENT_CONNECT", EVENT_CONNECT), ("EVENT_ACCEPT", EVENT_ACCEPT),
+            ("EVENT_CLOSE", EVENT_CLOSE), ("EVENT_STATE", EVENT_STATE),
+        ];
+        for (name, kind) in kinds.iter() {
+            assert_eq!(value(name), *kind, "{}", name);
+        }
+    }
+
+    // Deterministic pseudo-random numbers for the fuzz tests, xorshift64
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    #[test]
+    fn exec_cb_names_processes() {
+        let procs = new_procs();
+        let mut cb = record_cb(procs.clone());
+
+        // Above the highest pid possible, none in /proc
+        let (curl, wget) = (5000000, 5000001);
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            443,
+        );
+        l.prot(Prot::TCP);
+
+        let start = (crate::clock::now() - 60) * procfs::NANOSECONDS + 123_456_789;
+        let exec = Exec {
+            comm: String::from("curl"),
+            exe: String::from("/usr/bin/curl"),
+            cmdline: vec![String::from("curl"), String::from("-s"), String::from("")],
+            ppid: 1,
+            uid: 1000,
+            euid: 0,
+            start: Some(start),
+        };
+
+        // Gone before its first record, then known before executing
+        cb(&exec_record(curl, &exec));
+        cb(&traffic_record(curl, &l, 100, false));
+        cb(&traffic_record(wget, &l, 100, false));
+        assert_eq!(procs.lock().unwrap()[1].name, "file not found");
+        assert!(!procs.lock().unwrap()[1].has_details());
+        cb(&exec_record(wget, &Exec {
+            comm: String::from("wget"), exe: String::from("/usr/bin/wget"), ..exec.clone()
+        }));
+
+        assert_eq!(procs.exec_of(curl), Some(exec.clone()));
+        assert_eq!(procs.exec_of(wget + 1), None);
+        assert_eq!(new_procs().exec_of(curl), None, "program executed seen by another capture");
+
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs[0].name, "curl", "process not named after the program executed");
+        assert_eq!(procs[0].exe, "/usr/bin/curl");
+        assert_eq!(procs[0].cmdline, exec.cmdline, "arguments incorrect");
+        assert_eq!((procs[0].ppid, procs[0].uid, procs[0].euid), (Some(1), Some(1000), Some(0)));
+        assert_eq!(procs[0].euser, "root");
+        assert_eq!(procs[0].start, Some(start));
+        assert_eq!(procs[1].name, "wget", "process not renamed");
+        assert_eq!(procs[1].exe, "/usr/bin/wget", "process not described");
+    }
+
+    #[test]
+    fn pid_reuse() {
+        let procs = new_procs();
+        let mut cb = record_cb(procs.clone());
+
+        // Above the highest pid possible, none in /proc
+        let pid = 5000000;
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            443,
+        );
+        l.prot(Prot::TCP);
+
+        let start = (crate::clock::now() - 60) * procfs::NANOSECONDS + 123_456_789;
+        let exec = |comm: &str, start: i64| Exec {
+            comm: String::from(comm),
+            exe: format!("/usr/bin/{}", comm),
+            cmdline: vec![String::from(comm)],
+            ppid: 1,
+            uid: 1000,
+            euid: 1000,
+            start: Some(start),
+        };
+
+        cb(&exec_record(pid, &exec("wget", start)));
+        cb(&traffic_record(pid, &l, 100, false));
+        let before = procs.lock().unwrap()[0].clone();
+        cb(&exit_record(pid, Some(start)));
+
+        assert!(procs.lock().unwrap()[0].exited.is_some(), "exit not seen");
+        assert!(procs.lock().unwrap()[0].delta(Some(&before)).is_some(), "exit not a change");
+        assert_eq!(procs.exec_of(pid), None, "program executed not forgotten");
+
+        // Traffic added up by the probes, read after the exit
+        cb(&traffic_record(pid, &l, 100, false));
+        assert_eq!(procs.lock().unwrap().len(), 1, "late traffic of another process");
+        assert_eq!(procs.lock().unwrap()[0].tx, 200);
+        assert!(procs.descriptions.lock().unwrap().contains_key(&pid), "description not kept");
+
+        cb(&exec_record(pid, &exec("curl", start + 10 * procfs::NANOSECONDS)));
+        assert!(procs.descriptions.lock().unwrap().is_empty(), "description of another process");
+        cb(&traffic_record(pid, &l, 50, false));
+
+        // Exit of the second one lost, the third one started later
+        cb(&exec_record(pid, &exec("ssh", start + 20 * procfs::NANOSECONDS)));
+        cb(&traffic_record(pid, &l, 10, false));
+
+        // Out of order, the exit of the second one once the third one is known
+        cb(&exit_record(pid, Some(start + 10 * procfs::NANOSECONDS)));
+
+        let mut list = procs.lock().unwrap();
+        let names: Vec<&str> = list.iter().map(|p| p.name.as_str()).collect();
+
+        assert_eq!(names, vec!["wget", "curl", "ssh"], "reused pid not another process");
+        assert_eq!((list[1].tx, list[1].start), (50, Some(start + 10 * procfs::NANOSECONDS)));
+        assert!(list[1].exited.is_some(), "process started earlier not exited");
+        assert!(list[2].exited.is_none(), "exit of another process");
+        assert_eq!((list[2].tx, list[2].tlinks.len()), (10, 1));
+        assert!(list[0] != list[1], "processes of a reused pid equal");
+
+        let previous = rollover(&mut list);
+        assert_eq!(previous.len(), 3, "traffic of the processes which exited lost");
+        assert_eq!(list.len(), 1, "processes which exited not forgotten");
+        assert_eq!(list[0].name, "ssh");
+        drop(list);
+
+        // Whichever process of the pid exits when its start is unknown
+        cb(&exit_record(pid, None));
+        assert_eq!(procs.exec_of(pid), None, "program executed not forgotten");
+    }
+
+    #[test]
+    fn process_details() {
+        let mut p = Process::new(1234);
+        assert_eq!(p.details_str(Tz::Utc), "no details");
+
+        p.exe = String::from("/usr/bin/python3.12");
+        p.cmdline = vec![String::from("python3"), String::from("-m"), String::from("http.server")];
+        p.ppid = Some(1);
+        p.uid = Some(1000);
+        p.euid = Some(0);
+        p.euser = String::from("root");
+        p.start = Some(1625320925 * procfs::NANOSECONDS + 500_000_000);
+
+        assert_eq!(p.details_str(Tz::Utc), "python3 -m http.server (/usr/bin/python3.12), \
+            user 1000 as root, parent 1, started 2021-07-03 14:02:05");
+
+        let mut q = Process::new(1234);
+        q.details(&p);
+        assert_eq!(q.details_str(Tz::Utc), p.details_str(Tz::Utc), "details not copied");
+    }
+
+    #[test]
+    fn fuzz_record_cb() {
+        let procs = new_procs();
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+
+        let mut tcp = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            80,
+        );
+        tcp.prot(Prot::TCP);
+        let mut ping = Link::new(
+            IpAddr::V6( Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2) ),
+            IpAddr::V6( Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1) ),
+            42,
+            0,
+        );
+        ping.prot(Prot::ICMP(128, 0));
+
+        let valid = [
+            traffic_record(1234, &tcp, 1000, true),
+            traffic_record(1234, &ping, 64, false),
+            event_record(1234, &tcp, EVENT_CONNECT, TcpState::SynSent),
+            payload_record(1234, &tcp, &server_name::http_request("example.com")),
+            exec_record(1234, &Exec {
+                comm: String::from("curl"),
+                exe: String::from("/usr/bin/curl"),
+                cmdline: vec![String::from("curl"), String::from("http://example.com")],
+                ppid: 1,
+                uid: 0,
+                euid: 0,
+                start: None,
+            }),
+            exit_record(4321, None),
+        ];
+
+        let mut callback = record_cb(procs.clone());
+        let mut rejected = 0;
+
+        for _ in 0..10000 {
+            let mut record = valid[rng.below(valid.len())].clone();
+
+            match rng.below(5) {
+                // Truncated
+                0 => record.truncate(rng.below(record.len())),
+                // Version or length changed
+                1 => record[4 + rng.below(4)] ^= 1 << rng.below(8),
+                // Unknown kind
+                2 => {
+                    let kind = RECORD_EXIT + 1 + rng.next() as u32 % 1000;
+                    record[..4].copy_from_slice(&kind.to_ne_bytes());
+                }
+                // Garbage, valid or not
+                3 => {
+                    record = (0..rng.below(96)).map(|_| rng.next() as u8).collect();
+                    callback(&record);
+                    continue;
+                }
+                // Valid, padded or not, see fuzz_decode() for random data
+                _ => {
+                    record.resize(record.len() + rng.below(8), 0);
+                    callback(&record);
+                    continue;
+                }
+            }
+
+            callback(&record);
+            rejected += 1;
+        }
+
+        let (count, error) = procs.take_malformed().unwrap();
+        assert!(count >= rejected, "{} malformed records counted, expected {}", count, rejected);
+        assert!(!error.is_empty());
+        assert_eq!(procs.take_malformed(), None, "malformed records reported twice");
+        assert!(!procs.lock().unwrap().is_empty(), "valid records dropped");
+    }
+
+    #[test]
+    fn fuzz_decode() {
+        let mut rng = Rng(42);
+
+        for _ in 0..10000 {
+            let x: Vec<u8> = (0..rng.below(80)).map(|_| rng.next() as u8).collect();
+
+            assert_eq!(decode::<ipv4_data_t>(&x).is_ok(), x.len() == 40);
+            assert_eq!(decode::<ipv6_data_t>(&x).is_ok(), x.len() == 64);
+            assert_eq!(decode::<icmp4_data_t>(&x).is_ok(), x.len() == 40);
+            assert_eq!(decode::<ipv4_event_t>(&x).is_ok(), x.len() == 40);
+            assert_eq!(decode::<flow_t>(&x).is_ok(), x.len() == 64);
+            assert_eq!(decode::<bytes_t>(&x).is_ok(), x.len() == 16);
+            assert_eq!(decode::<exit_data_t>(&x).is_ok(), x.len() == 16);
+        }
+    }
+
+    #[test]
+    fn account_flow_entries() {
+        let procs = new_procs();
+
+        let tcp = flow_t {
+            saddr: 33663168,    // (little endian) 192.168.1.2
+            daddr: 3361999370,  // (little endian) 10.10.100.200
+            kind: RECORD_TCP4,
+            pid: 1234,
+            lport: 4321,
+            dport: 443,
+            icmp_type: 0,
+            icmp_code: 0,
+            generation: 0,
+            comm: *b"curl\0\0\0\0\0\0\0\0\0\0\0\0",
+        };
+        let ping = flow_t {
+            kind: RECORD_ICMP4,
+            lport: 42,
+            dport: 0,
+            icmp_type: 8,       // echo request
+            ..tcp
+        };
+
+        let bytes = bytes_t { rx: 1000, tx: 100 };
+        account_flow(&procs, unsafe {any_as_u8_slice(&tcp)}, unsafe {any_as_u8_slice(&bytes)});
+
+        let bytes = bytes_t { rx: 0, tx: 64 };
+        account_flow(&procs, unsafe {any_as_u8_slice(&ping)}, unsafe {any_as_u8_slice(&bytes)});
+
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs.len(), 1, "number of process incorrect");
+        assert_eq!((procs[0].rx, procs[0].tx), (1000, 164), "process traffic incorrect");
+        assert_eq!(procs[0].name, "curl", "process not named after the flow");
+        assert_eq!(procs[0].tlinks.len(), 1);
+        assert_eq!((procs[0].tlinks[0].rx, procs[0].tlinks[0].tx), (1000, 100));
+        assert_eq!(procs[0].tlinks[0].daddr, IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ));
+        assert_eq!(procs[0].ilinks.len(), 1, "number of ICMP links incorrect");
+        assert_eq!(procs[0].ilinks[0].prot, Prot::ICMP(8, 0));
+        assert_eq!((procs[0].ilinks[0].lport, procs[0].ilinks[0].dport), (42, 0));
+    }
+
+    #[test]
+    fn flow_generations() {
+        let flow = |generation| flow_t {
+            saddr: 0,
+            daddr: 0,
+            kind: RECORD_UDP4,
+            pid: 1234,
+            lport: 4321,
+            dport: 53,
+            icmp_type: 0,
+            icmp_code: 0,
+            generation,
+            comm: [0; COMM_LEN],
+        };
+        let drained = |generation, current| {
+            flow_drained(unsafe {any_as_u8_slice(&flow(generation))}, Some(current))
+        };
+
+        assert!(!drained(7, 7), "current generation drained");
+        assert!(!drained(6, 7), "previous generation drained");
+        assert!(drained(5, 7), "older generation kept");
+        assert!(!drained(u16::MAX, 0), "previous generation drained across the wrap");
+        assert!(drained(u16::MAX - 1, 0), "older generation kept across the wrap");
+        assert!(flow_drained(&[0; 3], Some(0)), "malformed entry kept");
+        assert!(flow_drained(unsafe {any_as_u8_slice(&flow(7))}, None), "entry kept when stopped");
+    }
+
+    #[test]
+    fn traffic_drained_at_exit() {
+        let procs = new_procs();
+        let flow = |pid, generation| flow_t {
+            saddr: 33663168,    // (little endian) 192.168.1.2
+            daddr: 3361999370,  // (little endian) 10.10.100.200
+            kind: RECORD_TCP4,
+            pid,
+            lport: 4321,
+            dport: 443,
+            icmp_type: 0,
+            icmp_code: 0,
+            generation,
+            comm: *b"curl\0\0\0\0\0\0\0\0\0\0\0\0",
+        };
+
+        // The map of the probes as the last read of the capture left it, with generation 7
+        let mut traffic = vec![
+            (flow(1, 6), bytes_t { rx: 1000, tx: 100 }),
+            (flow(2, 7), bytes_t { rx: 2000, tx: 200 }),
+        ];
+
+        // Moving to a new generation and reading is not enough to empty it...
+        traffic.retain(|(key, value)| {
+            let key = unsafe {any_as_u8_slice(key)};
+            if !flow_drained(key, Some(8)) {
+                return true;
+            }
+            account_flow(&procs, key, unsafe {any_as_u8_slice(value)});
+            false
+        });
+        assert_eq!(traffic.len(), 1, "generation 7 read while the probes may add to it");
+
+        // ...once the probes are detached, every entry is
+        for (key, value) in traffic.iter().filter(|(key, _)| {
+            flow_drained(unsafe {any_as_u8_slice(key)}, None)
+        }) {
+            account_flow(&procs, unsafe {any_as_u8_slice(key)}, unsafe {any_as_u8_slice(value)});
+        }
+
+        let procs = procs.lock().unwrap();
+        let total = procs.iter().fold((0, 0), |(rx, tx), p| (rx + p.rx, tx + p.tx));
 
         assert_eq!(procs.len(), 2, "number of process incorrect");
+        assert_eq!(total, (3000, 300), "traffic lost at exit");
+    }
+
+    #[test]
+    fn ringbuf_kernels() {
+        assert!(has_ringbuf("5.19.16-200.fc36.x86_64"));
+        assert!(has_ringbuf("6.1.0-13-amd64"));
+        assert!(has_ringbuf("5.8.0"));
+        assert!(!has_ringbuf("5.4.0-150-generic"));
+        assert!(!has_ringbuf("4.19.0-25-amd64"));
+        assert!(!has_ringbuf("unknown"));
+    }
+
+    #[test]
+    fn prot_id() {
+        for prot in [Prot::TCP, Prot::UDP, Prot::ICMP(8, 0), Prot::ICMP(3, 13)] {
+            assert_eq!(Prot::from_id(prot.id() as i64), Some(prot));
+        }
+
+        assert_eq!(Prot::ICMP(128, 0).to_string(), "ICMP 128/0");
+        assert_eq!(Prot::ICMP(3, 13).name(), "ICMP");
+        assert_eq!(Prot::from_id(2), None);
+    }
+
+    #[test]
+    fn link_state_and_delta() {
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            443,
+        );
+        l.prot(Prot::TCP);
+
+        assert_eq!(l.state(), State::Unknown);
+        assert_eq!(l.duration(1000), None);
+
+        l.open(1000).tcp_state(TcpState::Established);
+        assert_eq!(l.state(), State::Open);
+        assert_eq!(l.duration(1065), Some(65));
+        assert_eq!(duration_str(65), "1m05s");
+
+        let mut p = Process::new(1234);
+        p.tlinks.push(l.clone());
+        let previous = p.clone();
+
+        // Reset by the peer without any traffic, still a change to record
+        p.tlinks[0].tcp_state(TcpState::Close);
+        let delta = p.delta(Some(&previous)).expect("a change of state is a change");
+        assert_eq!(delta.tlinks[0].state(), State::Closed);
+        assert_eq!(delta.tlinks[0].duration(2000), None, "closed by the process");
+
+        p.tlinks[0].close(1012);
+        let delta = p.delta(Some(&previous)).unwrap();
+        assert_eq!(delta.tlinks[0].closed, Some(1012));
+        assert_eq!(delta.tlinks[0].duration(2000), Some(12));
+
+        // Same address and ports reused for a new connection
+        p.tlinks[0].open(1020).tcp_state(TcpState::SynSent);
+        assert_eq!(p.tlinks[0].state(), State::Open);
+    }
+
+    #[test]
+    fn tcp_state_names() {
+        for value in 1..=12 {
+            let state = TcpState::from_kernel(value).unwrap();
+
+            assert_eq!(state as u32, value);
+            assert_eq!(TcpState::parse(state.name()), Some(state));
+        }
+        assert_eq!(TcpState::Close.to_string(), "closed");
+        assert_eq!(TcpState::from_kernel(0), None);
+        assert_eq!(TcpState::from_kernel(13), None);
+        assert_eq!(TcpState::parse("open"), None);
     }
 
     #[test]
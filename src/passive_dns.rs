@@ -0,0 +1,296 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::convert::TryInto;
+
+/*
+ * DNS responses as received by the processes (RFC 1035), to learn the names they asked for the
+ * addresses they then connect to. Only what is needed of the format is read:
+ *
+ *     header     u16 id, u16 flags, u16 questions, u16 answers, u16 authorities, u16 additionals
+ *     question   name, u16 type, u16 class
+ *     answer     name, u16 type, u16 class, u32 TTL, u16 length, data
+ *
+ * A name is a list of labels, each prefixed with its length and ended by an empty one, or by a
+ * pointer (the two highest bits of the length set) to the rest of the name elsewhere in the
+ * message. All the integers are big endian.
+ */
+
+const HEADER_LEN: usize = 12;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const RCODE_MASK: u16 = 0x000f;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+// Longest name, and most pointers followed while reading one, against loops
+const MAX_NAME_LEN: usize = 255;
+const MAX_POINTERS: usize = 16;
+
+///
+/// Returns the addresses of the answers of the DNS response 'message', each with the name the
+/// client asked for: the one of the question, whatever CNAME records led to the address.
+///
+/// 'message' may be cut, the answers read until then are returned. An error is returned if it is
+/// not a response, or if its question cannot be read.
+///
+pub fn parse_response(message: &[u8]) -> Result<Vec<(IpAddr, String)>, String> {
+    if message.len() < HEADER_LEN {
+        return Err(format!("DNS message of {} bytes, shorter than its header", message.len()));
+    }
+
+    let flags = read_u16(message, 2).unwrap();
+    let questions = read_u16(message, 4).unwrap();
+    let answers = read_u16(message, 6).unwrap();
+
+    if flags & FLAG_RESPONSE == 0 {
+        return Err(String::from("DNS query, not a response"));
+    }
+    if questions != 1 {
+        return Err(format!("DNS response to {} questions, expected 1", questions));
+    }
+
+    let (question, mut offset) = read_name(message, HEADER_LEN)?;
+    offset += 4; // Type and class
+
+    let mut addrs = Vec::new();
+
+    // No answer, e.g. the name does not exist
+    if flags & RCODE_MASK != 0 {
+        return Ok(addrs);
+    }
+
+    for _ in 0..answers {
+        let next = match read_name(message, offset) {
+            Ok((_, next)) => next,
+            Err(_) => break,
+        };
+
+        let (kind, class, length) = match (
+            read_u16(message, next),
+            read_u16(message, next + 2),
+            read_u16(message, next + 8),
+        ) {
+            (Some(kind), Some(class), Some(length)) => (kind, class, length as usize),
+            _ => break,
+        };
+
+        let data = match message.get(next + 10..next + 10 + length) {
+            Some(data) => data,
+            None => break,
+        };
+
+        match (kind, class, data.len()) {
+            (TYPE_A, CLASS_IN, 4) => {
+                let octets: [u8; 4] = data.try_into().unwrap();
+                addrs.push((IpAddr::V4( Ipv4Addr::from(octets) ), question.clone()));
+            }
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let octets: [u8; 16] = data.try_into().unwrap();
+                addrs.push((IpAddr::V6( Ipv6Addr::from(octets) ), question.clone()));
+            }
+            _ => (), // CNAME and others
+        }
+
+        offset = next + 10 + length;
+    }
+
+    Ok(addrs)
+}
+
+///
+/// Returns the addresses of the answers of the DNS response in 'read', what a read of a TCP
+/// connection returned, see parse_response(). Over TCP each message follows its length on 2 bytes,
+/// read with it or on its own before it.
+///
+pub fn parse_stream(read: &[u8]) -> Result<Vec<(IpAddr, String)>, String> {
+    let prefixed = match read_u16(read, 0) {
+        Some(length) if length as usize >= HEADER_LEN => {
+            parse_response(&read[2..read.len().min(2 + length as usize)]).ok()
+        }
+        _ => None,
+    };
+
+    match prefixed {
+        Some(answers) => Ok(answers),
+        None => parse_response(read),
+    }
+}
+
+///
+/// Returns the DNS response to the question 'name' with the addresses 'addrs', as a server would
+/// send it. Used to build records without probes, e.g. in the tests.
+///
+pub fn response(name: &str, addrs: &[IpAddr]) -> Vec<u8> {
+    let mut message = Vec::new();
+
+    for field in [0x1234, FLAG_RESPONSE, 1, addrs.len() as u16, 0, 0] {
+        message.extend_from_slice(&u16::to_be_bytes(field));
+    }
+
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&TYPE_A.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    for addr in addrs {
+        let (kind, data) = match addr {
+            IpAddr::V4(v4) => (TYPE_A, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (TYPE_AAAA, v6.octets().to_vec()),
+        };
+
+        // The name of the question, pointed to
+        message.extend_from_slice(&(0xc000 | HEADER_LEN as u16).to_be_bytes());
+        message.extend_from_slice(&kind.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&300u32.to_be_bytes());
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(&data);
+    }
+
+    message
+}
+
+fn read_u16(message: &[u8], offset: usize) -> Option<u16> {
+    let bytes = message.get(offset..offset + 2)?;
+
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+///
+/// Returns the name at 'offset' in lower case and the offset right after it, pointers followed.
+///
+fn read_name(message: &[u8], mut offset: usize) -> Result<(String, usize), String> {
+    let mut name = String::new();
+    let mut next = None;
+    let mut pointers = 0;
+
+    loop {
+        let length = *message.get(offset).ok_or("DNS name cut")? as usize;
+
+        match length & 0xc0 {
+            0x00 if length == 0 => break,
+            0x00 => {
+                let label = message.get(offset + 1..offset + 1 + length).ok_or("DNS name cut")?;
+
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                name.push_str(&String::from_utf8_lossy(label).to_lowercase());
+
+                if name.len() > MAX_NAME_LEN {
+                    return Err(String::from("DNS name too long"));
+                }
+                offset += 1 + length;
+            }
+            0xc0 => {
+                let pointer = read_u16(message, offset).ok_or("DNS name cut")? & 0x3fff;
+
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(String::from("DNS name with a loop of pointers"));
+                }
+
+                next.get_or_insert(offset + 2);
+                offset = pointer as usize;
+            }
+            _ => return Err(format!("DNS label of unknown type {:#x}", length)),
+        }
+    }
+
+    Ok((name, next.unwrap_or(offset + 1)))
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_with_cname() {
+        // api.example.com is an alias of lb.example.net, the answers point to the names
+        let mut message = vec![
+            0x12, 0x34, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0,
+            3, b'a', b'p', b'i', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, 0, 1, 0, 1,
+        ];
+        // CNAME to lb.example.net
+        message.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 1, 0x2c, 0, 16]);
+        message.extend_from_slice(&[2, b'l', b'b', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e']);
+        message.extend_from_slice(&[3, b'n', b'e', b't', 0]);
+        // A and AAAA of lb.example.net
+        let lb = 45u8;
+        message.extend_from_slice(&[0xc0, lb, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+        message.extend_from_slice(&[0xc0, lb, 0, 28, 0, 1, 0, 0, 0, 60, 0, 16]);
+        message.extend_from_slice(&Ipv6Addr::new(0x2606, 0x2800, 0x220, 1, 0, 0, 0, 0x68).octets());
+
+        let answers = parse_response(&message).unwrap();
+
+        assert_eq!(answers, vec![
+            (IpAddr::V4( Ipv4Addr::new(93, 184, 216, 34) ), String::from("api.example.com")),
+            (IpAddr::V6( Ipv6Addr::new(0x2606, 0x2800, 0x220, 1, 0, 0, 0, 0x68) ),
+                String::from("api.example.com")),
+        ]);
+
+        // Cut in the AAAA record, the A one is still there
+        assert_eq!(parse_response(&message[..message.len() - 4]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn response_built() {
+        let addrs = [
+            IpAddr::V4( Ipv4Addr::new(10, 0, 0, 1) ),
+            IpAddr::V6( Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1) ),
+        ];
+
+        let answers = parse_response(&response("Intranet.Example.COM", &addrs)).unwrap();
+
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[1], (addrs[1], String::from("intranet.example.com")));
+    }
+
+    #[test]
+    fn response_over_tcp() {
+        let addrs = [IpAddr::V4( Ipv4Addr::new(10, 0, 0, 1) )];
+        let message = response("example.com", &addrs);
+        let length = (message.len() as u16).to_be_bytes();
+
+        // Read after its length, with it, with the next one
+        let mut read = length.to_vec();
+        read.extend_from_slice(&message);
+        assert_eq!(parse_stream(&message).unwrap().len(), 1, "response alone not read");
+        assert_eq!(parse_stream(&read).unwrap().len(), 1, "response after its length not read");
+        read.extend_from_slice(&length);
+        assert_eq!(parse_stream(&read).unwrap().len(), 1, "response followed by another not read");
+
+        assert!(parse_stream(&length).is_err(), "length alone accepted");
+    }
+
+    #[test]
+    fn response_invalid() {
+        let mut message = response("example.com", &[IpAddr::V4( Ipv4Addr::new(10, 0, 0, 1) )]);
+
+        assert!(parse_response(&message[..HEADER_LEN - 1]).is_err(), "short header accepted");
+        assert!(parse_response(&message[..HEADER_LEN + 4]).is_err(), "cut question accepted");
+
+        // Name error, no answer read
+        message[3] |= 3;
+        assert_eq!(parse_response(&message), Ok(Vec::new()));
+
+        // A query
+        message[2] &= 0x7f;
+        assert!(parse_response(&message).is_err(), "query accepted");
+
+        // A question pointing to itself
+        let mut looping = response("example.com", &[]);
+        looping.truncate(HEADER_LEN);
+        looping.extend_from_slice(&[0xc0, HEADER_LEN as u8, 0, 1, 0, 1]);
+        assert_eq!(parse_response(&looping), Err(String::from("DNS name with a loop of pointers")));
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fs;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use libc::{c_char, getpwuid_r, passwd, sysconf, _SC_CLK_TCK};
+
+use crate::net::Process;
+
+/*
+ * What /proc tells about the processes still running, and the names of their users.
+ *
+ * The start of a process is counted from the boot, in clock ticks in /proc/<pid>/stat and in
+ * nanoseconds by the probes: both are turned into UNIX timestamps in nanoseconds with the boot time
+ * of /proc/stat. The probes are the more precise, see same_start().
+ */
+
+pub const NANOSECONDS: i64 = 1_000_000_000;
+
+// Users looked up before they are forgotten
+const MAX_USERS: usize = 1024;
+
+// Size of the buffer of getpwuid_r(), enough for the usual entries of /etc/passwd
+const PASSWD_BUFFER: usize = 4096;
+
+lazy_static! {
+    static ref BOOT_TIME: Option<i64> = boot_time();
+
+    // Names of the users per uid, empty for the ones without any
+    static ref USERS: Mutex<HashMap<u32, String>> = Mutex::new(HashMap::new());
+}
+
+///
+/// Fill what is still unknown of the process 'p' with what /proc says, if it still runs: its
+/// executable, its arguments, its parent, its users and when it started.
+///
+pub fn describe(p: &mut Process) {
+    let dir = format!("/proc/{}", p.pid);
+
+    if p.exe.is_empty() {
+        if let Ok(exe) = fs::read_link(format!("{}/exe", dir)) {
+            p.exe = exe.to_string_lossy().into_owned();
+        }
+    }
+
+    if p.cmdline.is_empty() {
+        if let Ok(cmdline) = fs::read(format!("{}/cmdline", dir)) {
+            p.cmdline = split_args(&cmdline);
+        }
+    }
+
+    if let Ok(status) = fs::read_to_string(format!("{}/status", dir)) {
+        let (ppid, uid, euid) = parse_status(&status);
+
+        p.ppid = p.ppid.or(ppid);
+        p.uid = p.uid.or(uid);
+        p.euid = p.euid.or(euid);
+    }
+
+    if p.start.is_none() {
+        p.start = start_of(p.pid);
+    }
+
+    name_users(p);
+}
+
+///
+/// Returns when the process 'pid' started, if it still runs.
+///
+pub fn start_of(pid: u32) -> Option<i64> {
+    fs::read_to_string(format!("/proc/{}/stat", pid)).ok().and_then(|x| parse_start(&x))
+}
+
+///
+/// Name the users of the process 'p' after its uids, if not already.
+///
+pub fn name_users(p: &mut Process) {
+    if p.user.is_empty() {
+        p.user = p.uid.map(user_name).unwrap_or_default();
+    }
+    if p.euser.is_empty() {
+        p.euser = p.euid.map(user_name).unwrap_or_default();
+    }
+}
+
+///
+/// Returns the name of the user 'uid', empty if it has none.
+///
+pub fn user_name(uid: u32) -> String {
+    let mut users = USERS.lock().unwrap();
+
+    if users.len() >= MAX_USERS && !users.contains_key(&uid) {
+        users.clear();
+    }
+
+    users.entry(uid).or_insert_with(|| lookup_user(uid).unwrap_or_default()).clone()
+}
+
+fn lookup_user(uid: u32) -> Option<String> {
+    let mut entry: passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as c_char; PASSWD_BUFFER];
+    let mut result: *mut passwd = std::ptr::null_mut();
+
+    let ret = unsafe {
+        getpwuid_r(uid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut result)
+    };
+
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+
+    let name = unsafe { CStr::from_ptr(entry.pw_name) };
+
+    Some(name.to_string_lossy().into_owned())
+}
+
+///
+/// Returns the UNIX timestamp in nanoseconds of the start of a process, 'since_boot' nanoseconds
+/// after the boot as counted by the probes.
+///
+pub fn start_time(since_boot: u64) -> Option<i64> {
+    BOOT_TIME.map(|boot| boot * NANOSECONDS + since_boot as i64)
+}
+
+///
+/// Returns the nanoseconds since the boot at the UNIX timestamp in nanoseconds 'ts', the reverse
+/// of start_time().
+///
+pub fn since_boot(ts: i64) -> u64 {
+    BOOT_TIME.map_or(0, |boot| (ts - boot * NANOSECONDS).max(0) as u64)
+}
+
+///
+/// Whether the starts 'a' and 'b' may be the one of the same process: /proc only tells it to the
+/// clock tick, the probes to the nanosecond.
+///
+pub fn same_start(a: i64, b: i64) -> bool {
+    (a - b).abs() < NANOSECONDS / clock_ticks().unwrap_or(NANOSECONDS)
+}
+
+///
+/// Returns the arguments of a process from its command line as in /proc/<pid>/cmdline, each one
+/// terminated by a NUL byte. An argument cut at the end is kept as is.
+///
+pub fn split_args(cmdline: &[u8]) -> Vec<String> {
+    let cmdline = cmdline.strip_suffix(&[0]).unwrap_or(cmdline);
+
+    if cmdline.is_empty() {
+        return Vec::new();
+    }
+
+    cmdline.split(|&c| c == 0).map(|arg| String::from_utf8_lossy(arg).into_owned()).collect()
+}
+
+// Parent, real and effective user in /proc/<pid>/status
+fn parse_status(status: &str) -> (Option<u32>, Option<u32>, Option<u32>) {
+    let field = |name: &str| {
+        status.lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map(|values| values.split_whitespace().filter_map(|x| x.parse().ok()).collect())
+            .unwrap_or_else(Vec::new)
+    };
+
+    let ppid: Vec<u32> = field("PPid:");
+    let uids: Vec<u32> = field("Uid:");
+
+    (ppid.first().copied(), uids.first().copied(), uids.get(1).copied())
+}
+
+// Start of the process in /proc/<pid>/stat, its 22nd field, after the name which may hold spaces
+fn parse_start(stat: &str) -> Option<i64> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    let ticks: i64 = fields.split_whitespace().nth(19)?.parse().ok()?;
+    let ticks_per_second = clock_ticks()?;
+
+    BOOT_TIME.map(|boot| boot * NANOSECONDS + ticks * (NANOSECONDS / ticks_per_second))
+}
+
+// Clock ticks per second of /proc/<pid>/stat
+fn clock_ticks() -> Option<i64> {
+    match unsafe { sysconf(_SC_CLK_TCK) } as i64 {
+        ticks if ticks > 0 && ticks <= NANOSECONDS => Some(ticks),
+        _ => None,
+    }
+}
+
+// The "btime" line of /proc/stat
+fn boot_time() -> Option<i64> {
+    fs::read_to_string("/proc/stat").ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))
+        .and_then(|btime| btime.trim().parse().ok())
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describe_self() {
+        let mut p = Process::new(std::process::id());
+
+        describe(&mut p);
+
+        assert!(!p.exe.is_empty(), "executable not read");
+        assert!(!p.cmdline.is_empty(), "arguments not read");
+        assert_eq!(p.uid, Some(unsafe { libc::getuid() }));
+        assert_eq!(p.ppid, Some(std::os::unix::process::parent_id()));
+        // Within the current second
+        let now = (crate::clock::now() + 1) * NANOSECONDS;
+        assert!(p.start.unwrap() < now, "started in the future");
+        assert!(p.start.unwrap() > now - 3600 * NANOSECONDS, "started long ago");
+        assert_eq!(user_name(0), "root");
+        assert_eq!(p.user, user_name(p.uid.unwrap()));
+    }
+
+    #[test]
+    fn parse_proc_files() {
+        let status = "Name:\tsudo\nPPid:\t4321\nUid:\t1000\t0\t0\t0\nGid:\t1000\t1000\t1000\n";
+        assert_eq!(parse_status(status), (Some(4321), Some(1000), Some(0)));
+        assert_eq!(parse_status("Name:\tx\n"), (None, None, None));
+
+        let boot = BOOT_TIME.unwrap();
+        let ticks = unsafe { sysconf(_SC_CLK_TCK) } as i64;
+        let stat = format!("42 (a (b) c) S 1 42 42 0 -1 4194560 0 0 0 0 0 0 0 0 20 0 1 0 {} 0 0",
+            ticks * 60);
+        assert_eq!(parse_start(&stat), Some((boot + 60) * NANOSECONDS));
+        assert_eq!(parse_start("42 (x) S 1"), None);
+
+        let start = (boot + 90) * NANOSECONDS + 123_456_789;
+        assert_eq!(start_time(since_boot(start)), Some(start), "start rounded");
+        assert!(same_start((boot + 60) * NANOSECONDS + 1_000, parse_start(&stat).unwrap()));
+        assert!(!same_start(start, start - NANOSECONDS));
+    }
+
+    #[test]
+    fn split_cmdlines() {
+        assert_eq!(split_args(b"sh\0-c\0\0"), vec!["sh", "-c", ""]);
+        assert_eq!(split_args(b"curl\0-s\0https://exa"), vec!["curl", "-s", "https://exa"]);
+        assert_eq!(split_args(b""), Vec::<String>::new());
+    }
+}
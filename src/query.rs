@@ -0,0 +1,503 @@
+use rusqlite::Connection;
+
+use crate::database::{load_procs, Selection};
+use crate::net::{Process, Link, ProtFilter};
+
+///
+/// Traffic to select from the database. Links must match all the given criteria.
+///
+pub struct Filter {
+    pub from: i64,               // UNIX timestamp, included
+    pub to: i64,                 // UNIX timestamp, excluded
+    pub process: Option<String>, // name or pid
+    pub remote: Option<String>,  // remote address, domain or server, subdomains included
+    pub port: Option<u16>,       // local or remote port
+    pub prot: Option<ProtFilter>,
+    pub app_proto: Option<String>, // application protocol, see classify.rs
+}
+
+impl Filter {
+    pub fn new(from: i64, to: i64) -> Self {
+        Filter {
+            from,
+            to,
+            process: None,
+            remote: None,
+            port: None,
+            prot: None,
+            app_proto: None,
+        }
+    }
+
+    fn match_link(&self, l: &Link) -> bool {
+        if let Some(remote) = &self.remote {
+            let subdomain = format!(".{}", remote);
+            let matches = |name: &String| *name == *remote || name.ends_with(&subdomain);
+
+            if l.get_daddr() != *remote && !matches(&l.domain) && !matches(&l.server) {
+                return false;
+            }
+        }
+
+        if let Some(port) = self.port {
+            if l.lport != port && l.dport != port {
+                return false;
+            }
+        }
+
+        if let Some(app_proto) = &self.app_proto {
+            if l.app_proto != *app_proto {
+                return false;
+            }
+        }
+
+        match self.prot {
+            Some(prot) => prot.matches(l.prot),
+            None => true,
+        }
+    }
+
+    ///
+    /// Whether some criteria apply to the links that the database loaders cannot select, in which
+    /// case only the traffic of the matching links is accounted to the processes.
+    ///
+    fn on_links(&self) -> bool {
+        self.remote.is_some() || self.port.is_some() || self.app_proto.is_some()
+    }
+
+    fn selection(&self) -> Selection {
+        let mut selection = Selection::new(self.from, self.to);
+
+        match &self.process {
+            Some(process) => match process.parse::<u32>() {
+                Ok(pid) => selection.pid(pid),
+                Err(_) => selection.name(process.clone()),
+            },
+            None => &mut selection,
+        };
+
+        if let Some(prot) = self.prot {
+            selection.prot(prot);
+        }
+
+        selection
+    }
+}
+
+///
+/// How to print the result of a query.
+///
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Format> {
+        match value {
+            "table" => Some(Format::Table),
+            "json"  => Some(Format::Json),
+            "csv"   => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+///
+/// A cell of the output, numbers are not quoted in JSON.
+///
+enum Value {
+    Int(i64),
+    Text(String),
+    Null,
+}
+
+impl Value {
+    fn text(&self) -> String {
+        match self {
+            Value::Int(i) => i.to_string(),
+            Value::Text(s) => s.clone(),
+            Value::Null => String::new(),
+        }
+    }
+
+    fn from(value: Option<i64>) -> Value {
+        match value {
+            Some(i) => Value::Int(i),
+            None => Value::Null,
+        }
+    }
+}
+
+///
+/// Returns the processes, and their links, matching 'filter', the ones with the most traffic first.
+/// When the filter applies to the links, the traffic of a process is the one of its matching links
+/// and processes without any are left out.
+///
+/// * `limit` - Only the 'limit' processes with the most traffic
+///
+pub fn select(db: &Connection, filter: &Filter, limit: Option<usize>) -> Vec<Process> {
+    let mut selection = filter.selection();
+
+    if !filter.on_links() {
+        if let Some(n) = limit {
+            selection.limit(n);
+        }
+
+        return load_procs(db, &selection);
+    }
+
+    // The traffic of the processes changes once their links are filtered, so does their order
+    let mut procs = load_procs(db, &selection);
+
+    for p in procs.iter_mut() {
+        p.tlinks.retain(|l| filter.match_link(l));
+        p.ulinks.retain(|l| filter.match_link(l));
+        p.ilinks.retain(|l| filter.match_link(l));
+
+        let (rx, tx) = p.links().fold((0, 0), |(rx, tx), l| (rx + l.rx, tx + l.tx));
+
+        p.rx(rx).tx(tx);
+    }
+
+    procs.retain(|p| p.links().next().is_some());
+    procs.sort_by(|a, b| (b.rx + b.tx).cmp(&(a.rx + a.tx)).then(a.pid.cmp(&b.pid)));
+
+    if let Some(n) = limit {
+        procs.truncate(n);
+    }
+
+    procs
+}
+
+///
+/// One row per process: pid, name and amount of data in bytes.
+///
+pub fn print_procs(procs: &Vec<Process>, format: Format) -> String {
+    let header = ["pid", "process", "rx", "tx"];
+    let rows = procs
+        .iter()
+        .map(|p| vec![
+            Value::Int(p.pid as i64),
+            Value::Text(p.name.clone()),
+            Value::Int(p.rx as i64),
+            Value::Int(p.tx as i64),
+        ])
+        .collect();
+
+    render(&header, rows, format)
+}
+
+///
+/// One row per link, with the process it belongs to.
+///
+pub fn print_links(procs: &Vec<Process>, format: Format) -> String {
+    let header = [
+        "pid", "process", "protocol", "app_proto", "saddr", "lport", "daddr", "dport", "domain",
+        "server", "rx", "tx", "state", "opened", "closed",
+    ];
+    let mut rows = Vec::new();
+
+    for p in procs {
+        for l in p.links() {
+            rows.push(vec![
+                Value::Int(p.pid as i64),
+                Value::Text(p.name.clone()),
+                Value::Text(l.prot.to_string()),
+                Value::Text(l.app_proto.clone()),
+                Value::Text(l.get_saddr()),
+                Value::Int(l.lport as i64),
+                Value::Text(l.get_daddr()),
+                Value::Int(l.dport as i64),
+                Value::Text(l.domain.clone()),
+                Value::Text(l.server.clone()),
+                Value::Int(l.rx as i64),
+                Value::Int(l.tx as i64),
+                Value::Text(l.tcp_state.map_or("unknown", |state| state.name()).to_string()),
+                Value::from(l.opened),
+                Value::from(l.closed),
+            ]);
+        }
+    }
+
+    render(&header, rows, format)
+}
+
+fn render(header: &[&str], rows: Vec<Vec<Value>>, format: Format) -> String {
+    match format {
+        Format::Table => render_table(header, rows),
+        Format::Json => render_json(header, rows),
+        Format::Csv => render_csv(header, rows),
+    }
+}
+
+///
+/// Columns aligned on their widest cell, numbers to the right.
+///
+fn render_table(header: &[&str], rows: Vec<Vec<Value>>) -> String {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+
+    for row in rows.iter() {
+        for (i, v) in row.iter().enumerate() {
+            widths[i] = widths[i].max(v.text().chars().count());
+        }
+    }
+
+    let header: Vec<String> = header
+        .iter()
+        .enumerate()
+        .map(|(i, h)| format!("{:<w$}", h.to_uppercase(), w = widths[i]))
+        .collect();
+    let mut buffer = header.join("  ").trim_end().to_string();
+    buffer.push('\n');
+
+    for row in rows.iter() {
+        let cells: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(i, v)| match v {
+                Value::Int(_) | Value::Null => format!("{:>w$}", v.text(), w = widths[i]),
+                Value::Text(_) => format!("{:<w$}", v.text(), w = widths[i]),
+            })
+            .collect();
+
+        buffer.push_str(cells.join("  ").trim_end());
+        buffer.push('\n');
+    }
+
+    buffer
+}
+
+///
+/// An array of objects, one per row.
+///
+fn render_json(header: &[&str], rows: Vec<Vec<Value>>) -> String {
+    let objects: Vec<String> = rows
+        .iter()
+        .map(|row| {
+            let fields: Vec<String> = row
+                .iter()
+                .enumerate()
+                .map(|(i, v)| match v {
+                    Value::Int(n) => format!("\"{}\":{}", header[i], n),
+                    Value::Text(s) => format!("\"{}\":\"{}\"", header[i], json_escape(s)),
+                    Value::Null => format!("\"{}\":null", header[i]),
+                })
+                .collect();
+
+            format!("{{{}}}", fields.join(","))
+        })
+        .collect();
+
+    format!("[{}]\n", objects.join(",\n "))
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+///
+/// RFC 4180: a header line, fields quoted only when needed.
+///
+fn render_csv(header: &[&str], rows: Vec<Vec<Value>>) -> String {
+    let mut buffer = header.join(",");
+    buffer.push('\n');
+
+    for row in rows.iter() {
+        let cells: Vec<String> = row.iter().map(|v| csv_escape(&v.text())).collect();
+
+        buffer.push_str(&cells.join(","));
+        buffer.push('\n');
+    }
+
+    buffer
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        String::from(s)
+    }
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_db, update_db};
+    use crate::net::Prot;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    // 07/03/2021 14:02:05 UTC
+    const TS: i64 = 1625320925;
+
+    fn link(dport: u16, prot: Prot, domain: &str, rx: isize, tx: isize) -> Link {
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, dport as u8) ),
+            4321,
+            dport,
+        );
+        l.prot(prot);
+        l.rx(rx).tx(tx);
+        l.domain(String::from(domain));
+
+        l
+    }
+
+    ///
+    /// firefox browsing and resolving names, curl downloading, ping waiting for its replies, all on
+    /// 07/03/2021.
+    ///
+    fn fixture() -> Connection {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+
+        let mut firefox = Process::new(1000);
+        firefox.name(String::from("firefox"));
+        firefox.rx(1100).tx(110);
+        firefox.tlinks.push(link(443, Prot::TCP, "www.example.com", 1000, 100));
+        firefox.ulinks.push(link(53, Prot::UDP, "dns.example.net", 100, 10));
+        firefox.tlinks[0].app_proto(String::from("https"));
+        firefox.ulinks[0].app_proto(String::from("dns"));
+
+        let mut curl = Process::new(2000);
+        curl.name(String::from("curl"));
+        curl.rx(5000).tx(50);
+        curl.tlinks.push(link(80, Prot::TCP, "example.com", 5000, 50));
+        curl.tlinks[0].server(String::from("static.example.org"));
+        curl.tlinks[0].app_proto(String::from("http"));
+
+        let mut ping = Process::new(3000);
+        ping.name(String::from("ping"));
+        ping.rx(64).tx(64);
+        ping.ilinks.push(link(0, Prot::ICMP(8, 0), "", 0, 64));
+        ping.ilinks.push(link(0, Prot::ICMP(0, 0), "", 64, 0));
+
+        update_db(&mut db, &vec![firefox, curl, ping], TS).unwrap();
+
+        db
+    }
+
+    #[test]
+    fn select_by_process() {
+        let db = fixture();
+        let mut filter = Filter::new(TS - 3600, TS + 3600);
+
+        filter.process = Some(String::from("firefox"));
+        let procs = select(&db, &filter, None);
+        assert_eq!(procs.len(), 1);
+        assert_eq!((procs[0].rx, procs[0].tx), (1100, 110), "process traffic incorrect");
+
+        filter.process = Some(String::from("2000"));
+        let procs = select(&db, &filter, None);
+        assert_eq!(procs[0].name, "curl", "process not selected by pid");
+
+        // Nothing outside of the time range
+        let procs = select(&db, &Filter::new(TS + 3600, TS + 7200), None);
+        assert!(procs.is_empty());
+    }
+
+    #[test]
+    fn select_by_link() {
+        let db = fixture();
+        let mut filter = Filter::new(TS - 3600, TS + 3600);
+
+        // Subdomains of example.com, only firefox and curl over TCP
+        filter.remote = Some(String::from("example.com"));
+        let procs = select(&db, &filter, Some(10));
+        assert_eq!(procs.len(), 2);
+        assert_eq!(procs[0].name, "curl", "top talkers not sorted");
+        assert_eq!((procs[1].rx, procs[1].tx), (1000, 100), "UDP traffic not filtered out");
+
+        filter.remote = None;
+        filter.port = Some(53);
+        let procs = select(&db, &filter, None);
+        assert_eq!(procs.len(), 1);
+        assert_eq!(procs[0].ulinks.len(), 1);
+        assert_eq!(procs[0].tlinks.len(), 0);
+
+        filter.port = None;
+        filter.prot = Some(ProtFilter::TCP);
+        filter.remote = Some(String::from("10.10.100.80"));
+        let procs = select(&db, &filter, None);
+        assert_eq!(procs.len(), 1);
+        assert_eq!(procs[0].name, "curl", "process not selected by remote address");
+
+        // Name of the server asked for, only curl
+        filter.remote = Some(String::from("example.org"));
+        let procs = select(&db, &filter, None);
+        assert_eq!(procs.len(), 1);
+        assert_eq!(procs[0].name, "curl", "process not selected by server");
+
+        // Application protocol, only the DNS link of firefox
+        filter.remote = None;
+        filter.prot = None;
+        filter.app_proto = Some(String::from("dns"));
+        let procs = select(&db, &filter, None);
+        assert_eq!(procs.len(), 1);
+        assert_eq!((procs[0].rx, procs[0].tx), (100, 10), "process traffic not filtered");
+        assert_eq!(procs[0].tlinks.len(), 0);
+
+        // Any type of ICMP message
+        filter.app_proto = None;
+        filter.prot = ProtFilter::parse("icmp");
+        let procs = select(&db, &filter, None);
+        assert_eq!(procs.len(), 1);
+        assert_eq!(procs[0].ilinks.len(), 2, "ICMP messages not all selected");
+
+        // Only the echo replies
+        filter.prot = ProtFilter::parse("icmp/0/0");
+        let procs = select(&db, &filter, None);
+        assert_eq!(procs.len(), 1);
+        assert_eq!(procs[0].ilinks.len(), 1, "ICMP messages of another type selected");
+        assert_eq!((procs[0].ilinks[0].prot, procs[0].rx), (Prot::ICMP(0, 0), 64));
+    }
+
+    #[test]
+    fn print_formats() {
+        let db = fixture();
+        let mut filter = Filter::new(TS - 3600, TS + 3600);
+
+        filter.process = Some(String::from("curl"));
+        let procs = select(&db, &filter, None);
+
+        assert_eq!(
+            print_procs(&procs, Format::Table),
+            "PID   PROCESS  RX    TX\n2000  curl     5000  50\n"
+        );
+        assert_eq!(
+            print_procs(&procs, Format::Json),
+            "[{\"pid\":2000,\"process\":\"curl\",\"rx\":5000,\"tx\":50}]\n"
+        );
+        assert_eq!(
+            print_links(&procs, Format::Csv),
+            "pid,process,protocol,app_proto,saddr,lport,daddr,dport,domain,server,rx,tx,state,\
+             opened,closed\n\
+             2000,curl,TCP,http,192.168.1.2,4321,10.10.100.80,80,example.com,static.example.org,\
+             5000,50,unknown,,\n"
+        );
+
+        assert_eq!(json_escape("a\"b\\c\n"), "a\\\"b\\\\c\\n");
+        assert_eq!(csv_escape("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+}
@@ -0,0 +1,332 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::clock;
+use crate::net::{self, SharedProcs};
+use crate::capture::{CaptureBackend, Options};
+
+/*
+ * Recordings of the records of the probes, to replay them later without privileges.
+ *
+ * All the integers are little endian:
+ *
+ *     header   "NETWREC" 0x00, u32 version, u32 version of the records (RECORD_VERSION of net),
+ *              i64 UNIX timestamp of the start
+ *     record   u64 microseconds since the start, u32 kind (RECORD_* of net), u32 length,
+ *              the data as the probes sent it
+ *
+ * The data is kept in the byte order of the host which recorded it, as the probes wrote it.
+ */
+
+const MAGIC: &[u8; 8] = b"NETWREC\0";
+pub const VERSION: u32 = 1;
+
+// Largest data of a record, much more than any of filters.c
+const MAX_RECORD: u32 = 4096;
+
+///
+/// Writes the records of the probes to a file, as they come.
+///
+pub struct Recorder {
+    out: BufWriter<File>,
+    start: Instant,
+}
+
+pub type SharedRecorder = Arc<Mutex<Recorder>>;
+
+impl Recorder {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&(net::RECORD_VERSION as u32).to_le_bytes())?;
+        out.write_all(&clock::now().to_le_bytes())?;
+
+        Ok(Recorder {
+            out,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn write(&mut self, kind: u32, data: &[u8]) -> io::Result<()> {
+        let at = self.start.elapsed().as_micros() as u64;
+
+        self.out.write_all(&at.to_le_bytes())?;
+        self.out.write_all(&kind.to_le_bytes())?;
+        self.out.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.out.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+///
+/// Returns 'callback' writing each record to 'recorder', if any, before handling it. Malformed
+/// records are not written, 'callback' drops them.
+///
+/// Write errors are reported by the next flush of the recorder.
+///
+pub fn recording(
+    recorder: &Option<SharedRecorder>, mut callback: Box<dyn FnMut(&[u8]) + Send>
+) -> Box<dyn FnMut(&[u8]) + Send>
+{
+    let recorder = match recorder {
+        Some(recorder) => recorder.clone(),
+        None => return callback,
+    };
+
+    Box::new(move |x| {
+        if let Ok((kind, data)) = net::split_record(x) {
+            let _ = recorder.lock().unwrap().write(kind, data);
+        }
+
+        callback(x);
+    })
+}
+
+///
+/// Capture source replaying a recording, at the speed it was recorded times 'speed', or as fast
+/// as possible when 'speed' is 0. Stops at the end of the file.
+///
+pub struct Replay {
+    input: BufReader<File>,
+    speed: f64,
+    started: Option<Instant>,
+    next: Option<(u64, u32, Vec<u8>)>,
+    finished: bool,
+    callback: Option<Box<dyn FnMut(&[u8]) + Send>>,
+}
+
+impl Replay {
+    pub fn open(path: &str, speed: f64) -> Result<Self, Box<dyn Error>> {
+        let mut input = BufReader::new(File::open(path).map_err(|e| format!("{}: {}", path, e))?);
+        let mut header = [0u8; 24];
+
+        input.read_exact(&mut header).map_err(|_| format!("{}: not a recording", path))?;
+
+        if &header[..8] != MAGIC {
+            return Err(format!("{}: not a recording", path).into());
+        }
+
+        let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if version != VERSION {
+            return Err(format!("{}: recording version {} unsupported, expected {}",
+                path, version, VERSION).into());
+        }
+
+        let version = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+        if version != net::RECORD_VERSION as u32 {
+            return Err(format!("{}: records of version {} unsupported, expected {}",
+                path, version, net::RECORD_VERSION).into());
+        }
+
+        Ok(Replay {
+            input,
+            speed,
+            started: None,
+            next: None,
+            finished: false,
+            callback: None,
+        })
+    }
+
+    ///
+    /// Returns the next record, None at the end of the file.
+    ///
+    fn read_record(&mut self) -> Result<Option<(u64, u32, Vec<u8>)>, Box<dyn Error>> {
+        let mut header = [0u8; 16];
+
+        // End of the file between two records
+        match self.input.read(&mut header[..1])? {
+            0 => return Ok(None),
+            _ => self.input.read_exact(&mut header[1..]).map_err(|_| "truncated recording")?,
+        }
+
+        let mut at = [0u8; 8];
+        at.copy_from_slice(&header[..8]);
+        let kind = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        let length = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+
+        if length > MAX_RECORD {
+            return Err(format!("corrupted recording, record of {} bytes", length).into());
+        }
+
+        let mut data = vec![0u8; length as usize];
+        self.input.read_exact(&mut data).map_err(|_| "truncated recording")?;
+
+        Ok(Some((u64::from_le_bytes(at), kind, data)))
+    }
+}
+
+impl CaptureBackend for Replay {
+    fn description(&self, _options: &Options) -> String {
+        match self.speed {
+            speed if speed > 0.0 => format!("replay at x{}", speed),
+            _ => String::from("replay as fast as possible"),
+        }
+    }
+
+    fn start(&mut self, options: &Options, procs: SharedProcs) -> Result<(), Box<dyn Error>> {
+        self.callback = Some(recording(&options.recorder, net::record_cb(procs)));
+        self.started = Some(Instant::now());
+        Ok(())
+    }
+
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        let deadline = Instant::now() + timeout;
+
+        while !self.finished {
+            let (at, kind, data) = match self.next.take() {
+                Some(record) => record,
+                None => match self.read_record()? {
+                    Some(record) => record,
+                    None => {
+                        self.finished = true;
+                        break;
+                    }
+                },
+            };
+
+            // Wait for the record to be due, at most until the deadline
+            if self.speed > 0.0 {
+                let due = self.started.unwrap()
+                    + Duration::from_micros((at as f64 / self.speed) as u64);
+                let now = Instant::now();
+
+                if due > deadline {
+                    self.next = Some((at, kind, data));
+                    thread::sleep(deadline.saturating_duration_since(now));
+                    break;
+                }
+                thread::sleep(due.saturating_duration_since(now));
+            }
+
+            (self.callback.as_mut().unwrap())(&net::join_record(kind, &data));
+
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // The records already are per packet
+    fn drain_traffic(&mut self) {}
+
+    fn lost_events(&mut self) -> Option<u64> {
+        Some(0)
+    }
+
+    fn finished(&self) -> bool {
+        self.finished
+    }
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use crate::net::{new_procs, Link, Prot};
+
+    fn recording_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("netw-{}-{}.rec", name, std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn replay(path: &str) -> Result<SharedProcs, Box<dyn Error>> {
+        let procs = new_procs();
+        let options = Options::new();
+        let mut replay = Replay::open(path, 0.0)?;
+
+        replay.start(&options, procs.clone())?;
+        while !replay.finished() {
+            replay.poll(Duration::from_millis(100))?;
+        }
+
+        Ok(procs)
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let path = recording_path("roundtrip");
+        let mut tcp = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            80,
+        );
+        tcp.prot(Prot::TCP);
+        let mut udp = Link::new(
+            IpAddr::V6( Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2) ),
+            IpAddr::V6( Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1) ),
+            5353,
+            53,
+        );
+        udp.prot(Prot::UDP);
+
+        // The last one padded, as a perf buffer may hand it
+        {
+            let recorder = Some(Arc::new(Mutex::new(Recorder::create(&path).unwrap())));
+            let mut callback = recording(&recorder, net::record_cb(new_procs()));
+            let mut padded = net::traffic_record(1234, &tcp, 500, true);
+            padded.extend_from_slice(&[0u8; 4]);
+
+            callback(&net::traffic_record(1234, &udp, 120, true));
+            callback(&net::traffic_record(1234, &tcp, 1000, false));
+            callback(&padded);
+            callback(&padded[..20]);
+
+            recorder.unwrap().lock().unwrap().flush().unwrap();
+        }
+
+        let procs = replay(&path).unwrap();
+        let procs = procs.lock().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(procs.len(), 1, "number of process incorrect");
+        assert_eq!((procs[0].rx, procs[0].tx), (620, 1000));
+        assert_eq!((procs[0].tlinks[0].rx, procs[0].tlinks[0].tx), (500, 1000));
+        assert_eq!(procs[0].ulinks[0].get_daddr(), "fd00::1");
+    }
+
+    #[test]
+    fn replay_invalid_files() {
+        let path = recording_path("invalid");
+
+        fs::write(&path, b"NETW").unwrap();
+        assert!(Replay::open(&path, 1.0).is_err(), "short header accepted");
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        header.extend_from_slice(&(net::RECORD_VERSION as u32).to_le_bytes());
+        header.extend_from_slice(&[0u8; 8]);
+        fs::write(&path, &header).unwrap();
+        assert!(Replay::open(&path, 1.0).is_err(), "unknown version accepted");
+
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(net::RECORD_VERSION as u32 + 1).to_le_bytes());
+        fs::write(&path, &header).unwrap();
+        assert!(Replay::open(&path, 1.0).is_err(), "unknown version of the records accepted");
+
+        // Valid header, truncated record
+        header[12..16].copy_from_slice(&(net::RECORD_VERSION as u32).to_le_bytes());
+        header.extend_from_slice(&[0u8; 12]);
+        fs::write(&path, &header).unwrap();
+        assert!(replay(&path).is_err(), "truncated record accepted");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+use rusqlite::{Connection, Result, NO_PARAMS, params};
+
+use crate::clock::Tz;
+
+const SECS_PER_DAY: i64 = 24 * 3600;
+
+///
+/// How long the data is kept in the database. A value of 0 disables the corresponding step.
+///
+#[derive(Copy, Clone)]
+pub struct Policy {
+    pub keep_links: u64,   // days of per-link rows, older ones are rolled up in daily summaries
+    pub keep_all: u64,     // days after which everything is deleted, summaries included
+    pub vacuum_every: u64, // hours between two VACUUM of the database
+}
+
+impl Policy {
+    ///
+    /// Returns the UNIX timestamp before which the per-link rows are rolled up, the first second
+    /// of the day 'keep_links' days ago in the time zone 'tz'.
+    ///
+    pub fn links_cutoff(&self, now: i64, tz: Tz) -> Option<i64> {
+        match self.keep_links {
+            0 => None,
+            days => Some(tz.day_bounds(now - days as i64 * SECS_PER_DAY).0),
+        }
+    }
+
+    ///
+    /// Returns the UNIX timestamp before which everything is deleted.
+    ///
+    pub fn horizon(&self, now: i64, tz: Tz) -> Option<i64> {
+        match self.keep_all {
+            0 => None,
+            days => Some(tz.day_bounds(now - days as i64 * SECS_PER_DAY).0),
+        }
+    }
+}
+
+///
+/// Roll the links recorded before 'cutoff' up into daily summaries per process and per link, their
+/// local ports aside, and delete them. Each day is done in its own transaction to not hold the
+/// database for too long.
+///
+/// Returns the number of days rolled up.
+///
+pub fn rollup(db: &mut Connection, cutoff: i64, tz: Tz) -> Result<usize> {
+    let mut days = 0;
+
+    loop {
+        let oldest: Option<i64> = db.query_row(
+            "SELECT MIN(l_ts) FROM links WHERE l_ts < ?1;", params![cutoff],
+            |row| row.get(0)
+        )?;
+
+        let (start, end) = match oldest {
+            Some(ts) => tz.day_bounds(ts),
+            None => break,
+        };
+
+        // Only whole days, the rest will be done once the day is entirely before the cutoff
+        if end > cutoff {
+            break;
+        }
+
+        let transaction = db.transaction()?;
+
+        transaction.execute(
+            "INSERT INTO summaries (s_day, s_p_pid, s_p_start, s_p_name, s_prot_id, s_saddr,
+                    s_daddr, s_dport, s_domain, s_server, s_app_proto, s_rx, s_tx)
+                SELECT ?1, l.l_p_pid, l.l_p_start, COALESCE(MAX(p.p_name), ''), l.l_prot_id,
+                    l.l_saddr, l.l_daddr, l.l_dport, MAX(l.l_domain), MAX(l.l_server),
+                    MAX(l.l_app_proto), SUM(l.l_rx), SUM(l.l_tx)
+                FROM links l
+                LEFT JOIN processes p ON p.p_pid = l.l_p_pid AND p.p_start = l.l_p_start
+                    AND p.p_ts = l.l_ts
+                WHERE l.l_ts >= ?1 AND l.l_ts < ?2
+                GROUP BY l.l_p_pid, l.l_p_start, l.l_prot_id, l.l_saddr, l.l_daddr, l.l_dport
+             ON CONFLICT(s_day, s_p_pid, s_p_start, s_prot_id, s_saddr, s_daddr, s_dport)
+             DO UPDATE SET s_rx = s_rx + excluded.s_rx, s_tx = s_tx + excluded.s_tx",
+            params![start, end],
+        )?;
+        transaction.execute(
+            "DELETE FROM links WHERE l_ts >= ?1 AND l_ts < ?2",
+            params![start, end],
+        )?;
+        transaction.commit()?;
+
+        days += 1;
+    }
+
+    Ok(days)
+}
+
+///
+/// Delete everything recorded before 'horizon'. Returns the number of rows deleted.
+///
+pub fn expire(db: &mut Connection, horizon: i64) -> Result<usize> {
+    let transaction = db.transaction()?;
+    let mut deleted = 0;
+
+    deleted += transaction.execute("DELETE FROM links WHERE l_ts < ?1", params![horizon])?;
+    deleted += transaction.execute("DELETE FROM processes WHERE p_ts < ?1", params![horizon])?;
+    deleted += transaction.execute(
+        "DELETE FROM process_info WHERE NOT EXISTS (
+            SELECT 1 FROM processes WHERE p_pid = pi_pid AND p_start = pi_start)",
+        NO_PARAMS
+    )?;
+    deleted += transaction.execute("DELETE FROM summaries WHERE s_day < ?1", params![horizon])?;
+    deleted += transaction.execute("DELETE FROM lost WHERE lo_ts < ?1", params![horizon])?;
+
+    transaction.commit()?;
+
+    Ok(deleted)
+}
+
+///
+/// Give back to the file system the space freed by the deleted rows.
+///
+pub fn vacuum(db: &Connection) -> Result<()> {
+    db.execute("VACUUM;", NO_PARAMS)?;
+
+    Ok(())
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{create_db, update_db, load_procs, Selection};
+    use crate::net::{Process, Link, Prot};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    // 07/03/2021 14:02:05 UTC
+    const TS: i64 = 1625320925;
+
+    fn curl(rx: isize, tx: isize) -> Process {
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            443,
+        );
+        l.prot(Prot::TCP).server(String::from("example.com"));
+        l.rx(rx).tx(tx);
+
+        let mut p = Process::new(1234);
+        p.name(String::from("curl"));
+        p.rx(rx).tx(tx);
+        p.tlinks.push(l);
+
+        p
+    }
+
+    fn count(db: &Connection, table: &str) -> i64 {
+        db.query_row(&format!("SELECT COUNT(*) FROM {};", table), NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn rollup_old_links() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+
+        // Two buckets on 07/03, one on 07/04 and one on 07/05
+        update_db(&mut db, &vec![curl(100, 10)], TS).unwrap();
+        update_db(&mut db, &vec![curl(50, 5)], TS + 3600).unwrap();
+        update_db(&mut db, &vec![curl(1, 1)], TS + SECS_PER_DAY).unwrap();
+        update_db(&mut db, &vec![curl(2, 2)], TS + 2 * SECS_PER_DAY).unwrap();
+
+        let policy = Policy { keep_links: 1, keep_all: 0, vacuum_every: 0 };
+        let cutoff = policy.links_cutoff(TS + 2 * SECS_PER_DAY, Tz::Utc).unwrap();
+
+        assert_eq!(rollup(&mut db, cutoff, Tz::Utc), Ok(1), "wrong number of days rolled up");
+        assert_eq!(count(&db, "links"), 2, "links of 07/03 not deleted");
+        assert_eq!(count(&db, "processes"), 4, "processes must be kept");
+
+        let (day, name, server, rx, tx): (i64, String, String, isize, isize) = db.query_row(
+            "SELECT s_day, s_p_name, s_server, s_rx, s_tx FROM summaries;", NO_PARAMS,
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        ).unwrap();
+
+        assert_eq!(day, 1625270400, "summary not on 07/03");
+        assert_eq!(name, "curl");
+        assert_eq!(server, "example.com");
+        assert_eq!((rx, tx), (150, 15), "summary traffic incorrect");
+
+        // The links of 07/03 are loaded from the summary
+        let procs = load_procs(&db, &Selection::new(1625270400, 1625356800));
+
+        assert_eq!(procs.len(), 1, "process of the rolled up day lost");
+        assert_eq!(procs[0].tlinks.len(), 1, "rolled up link not loaded");
+        let l = &procs[0].tlinks[0];
+        assert_eq!((l.prot, l.lport, l.dport), (Prot::TCP, 0, 443), "summary link incorrect");
+        assert_eq!(l.saddr, IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ));
+        assert_eq!((l.rx, l.tx), (150, 15), "traffic of the rolled up link incorrect");
+
+        // Nothing left to do
+        assert_eq!(rollup(&mut db, cutoff, Tz::Utc), Ok(0));
+    }
+
+    #[test]
+    fn rollup_reused_pid() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+        let (mut first, mut second) = (curl(100, 10), curl(50, 5));
+
+        first.start = Some(TS - 60);
+        second.start = Some(TS - 10);
+        update_db(&mut db, &vec![first, second], TS).unwrap();
+
+        assert_eq!(rollup(&mut db, TS + SECS_PER_DAY, Tz::Utc), Ok(1));
+
+        let starts: Vec<i64> = db.prepare("SELECT s_p_start FROM summaries ORDER BY s_p_start;")
+            .unwrap()
+            .query_map(NO_PARAMS, |row| row.get(0))
+            .unwrap()
+            .map(|start| start.unwrap())
+            .collect();
+        assert_eq!(starts, vec![TS - 60, TS - 10], "processes of the same pid summed up");
+    }
+
+    #[test]
+    fn expire_past_horizon() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+
+        update_db(&mut db, &vec![curl(100, 10)], TS).unwrap();
+        update_db(&mut db, &vec![curl(1, 1)], TS + SECS_PER_DAY).unwrap();
+
+        // Roll 07/03 up
+        assert_eq!(rollup(&mut db, 1625356800, Tz::Utc), Ok(1));
+
+        let policy = Policy { keep_links: 0, keep_all: 1, vacuum_every: 0 };
+        let horizon = policy.horizon(TS + 2 * SECS_PER_DAY, Tz::Utc).unwrap();
+
+        // The summary and the process of 07/03, its links were already rolled up
+        assert_eq!(expire(&mut db, horizon), Ok(2));
+        assert_eq!(count(&db, "summaries"), 0);
+
+        let procs = load_procs(&db, &Selection::new(TS, TS + SECS_PER_DAY + 60));
+        assert_eq!((procs[0].rx, procs[0].tx), (1, 1), "traffic of 07/04 must be kept");
+
+        vacuum(&db).unwrap();
+    }
+}
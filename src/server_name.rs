@@ -0,0 +1,245 @@
+/*
+ * Name of the server a process asked for, read from the first bytes it sent on a TCP connection:
+ * the server name indication of a TLS ClientHello (RFC 8446, RFC 6066) or the Host header of a
+ * plaintext HTTP/1.x request. Only what is needed of the formats is read:
+ *
+ *     TLS record     u8 type (22, handshake), u16 version, u16 length
+ *     handshake      u8 type (1, ClientHello), u24 length, u16 version, 32 bytes random,
+ *                    u8 + session id, u16 + cipher suites, u8 + compression methods,
+ *                    u16 + extensions
+ *     extension      u16 type (0, server name), u16 length, data
+ *     server name    u16 + list of (u8 type (0, host name), u16 + name)
+ *
+ * "u16 + x" being 'x' prefixed with its length on a u16. All the integers are big endian.
+ */
+
+const CONTENT_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+// Longest name kept, as for DNS
+const MAX_NAME_LEN: usize = 255;
+
+///
+/// Returns the name of the server in 'payload', the first bytes sent by a client, if it starts a
+/// TLS ClientHello with a server name or an HTTP request with a Host header. 'payload' may be cut,
+/// the name is then only found if it comes before the cut.
+///
+pub fn parse(payload: &[u8]) -> Option<String> {
+    let name = match payload.first() {
+        Some(&CONTENT_HANDSHAKE) => tls_server_name(payload)?,
+        Some(_) => http_host(payload)?,
+        None => return None,
+    };
+
+    valid_name(&name).then(|| name.to_lowercase())
+}
+
+fn tls_server_name(payload: &[u8]) -> Option<String> {
+    let mut x = payload;
+
+    take(&mut x, 5)?; // Record header
+    let handshake = take(&mut x, 4)?;
+    if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+
+    take(&mut x, 2 + 32)?; // Version and random
+    let length = take_u8(&mut x)?;
+    take(&mut x, length)?; // Session id
+    let length = take_u16(&mut x)?;
+    take(&mut x, length)?; // Cipher suites
+    let length = take_u8(&mut x)?;
+    take(&mut x, length)?; // Compression methods
+    take_u16(&mut x)?; // Extensions, until the cut
+
+    loop {
+        let kind = take_u16(&mut x)? as u16;
+        let length = take_u16(&mut x)?;
+        let mut data = take(&mut x, length)?;
+
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        take_u16(&mut data)?; // List
+        while !data.is_empty() {
+            let name_type = take_u8(&mut data)? as u8;
+            let length = take_u16(&mut data)?;
+            let name = take(&mut data, length)?;
+
+            if name_type == NAME_TYPE_HOST_NAME {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+
+        return None;
+    }
+}
+
+fn http_host(payload: &[u8]) -> Option<String> {
+    if !is_http_request(payload) {
+        return None;
+    }
+
+    let text = String::from_utf8_lossy(payload);
+    let lines = text.split("\r\n").skip(1);
+
+    // Up to the end of the headers, the last line may be cut
+    let host = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(header, _)| header.eq_ignore_ascii_case("host"))?
+        .1
+        .trim();
+
+    // The port, if any, an IPv6 address being between brackets
+    let name = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+
+    Some(name.to_string())
+}
+
+///
+/// Whether 'payload' starts with the request line of an HTTP/1.x request, e.g. "GET / HTTP/1.1".
+/// HTTP/2 starts with a request line of its own, and has no Host header.
+///
+pub fn is_http_request(payload: &[u8]) -> bool {
+    let line = match payload.split(|&c| c == b'\r').next() {
+        Some(line) => String::from_utf8_lossy(line),
+        None => return false,
+    };
+    let words: Vec<&str> = line.split(' ').collect();
+
+    match words[..] {
+        [method, _, version] => {
+            !method.is_empty()
+                && method.bytes().all(|c| c.is_ascii_uppercase())
+                && version.starts_with("HTTP/1.")
+        }
+        _ => false,
+    }
+}
+
+// A host name or an address, nothing which would need escaping
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || "-._:".contains(c))
+}
+
+fn take<'a>(x: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if x.len() < n {
+        return None;
+    }
+
+    let (taken, rest) = x.split_at(n);
+    *x = rest;
+
+    Some(taken)
+}
+
+fn take_u8(x: &mut &[u8]) -> Option<usize> {
+    take(x, 1).map(|bytes| bytes[0] as usize)
+}
+
+fn take_u16(x: &mut &[u8]) -> Option<usize> {
+    take(x, 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+///
+/// Returns a TLS ClientHello for the server 'name', as a client would send it first. Used to build
+/// records without probes, e.g. in the tests.
+///
+pub fn client_hello(name: &str) -> Vec<u8> {
+    let with_length = |data: &[u8]| [&(data.len() as u16).to_be_bytes(), data].concat();
+
+    let mut server_name = vec![NAME_TYPE_HOST_NAME];
+    server_name.extend_from_slice(&with_length(name.as_bytes()));
+
+    // Supported groups first, the server name is not always the first extension
+    let mut extensions = vec![0x00, 0x0a];
+    extensions.extend_from_slice(&with_length(&with_length(&[0x00, 0x1d])));
+    extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+    extensions.extend_from_slice(&with_length(&with_length(&server_name)));
+
+    let mut hello = vec![0x03, 0x03];
+    hello.extend_from_slice(&[0x5a; 32]);
+    hello.extend_from_slice(&[0]); // Session id
+    hello.extend_from_slice(&with_length(&[0x13, 0x01])); // TLS_AES_128_GCM_SHA256
+    hello.extend_from_slice(&[1, 0]); // No compression
+    hello.extend_from_slice(&with_length(&extensions));
+
+    let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+    handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&hello);
+
+    let mut record = vec![CONTENT_HANDSHAKE, 0x03, 0x01];
+    record.extend_from_slice(&with_length(&handshake));
+
+    record
+}
+
+///
+/// Returns an HTTP request to the server 'name', as a client would send it first.
+///
+pub fn http_request(name: &str) -> Vec<u8> {
+    format!("GET / HTTP/1.1\r\nUser-Agent: netw\r\nHost: {}\r\nAccept: */*\r\n\r\n", name)
+        .into_bytes()
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_hello_server_name() {
+        let hello = client_hello("Www.Example.com");
+
+        assert_eq!(parse(&hello), Some(String::from("www.example.com")));
+
+        // Cut before the end of the name
+        assert_eq!(parse(&hello[..hello.len() - 2]), None);
+
+        // Not a ClientHello
+        let mut server_hello = hello.clone();
+        server_hello[5] = 2;
+        assert_eq!(parse(&server_hello), None);
+    }
+
+    #[test]
+    fn client_hello_without_server_name() {
+        let mut hello = client_hello("example.com");
+
+        // Another kind of extension in place of the server name
+        let position = hello.len() - "example.com".len() - 9;
+        hello[position + 1] = 0x2b;
+
+        assert_eq!(parse(&hello), None);
+    }
+
+    #[test]
+    fn http_host_header() {
+        assert_eq!(parse(&http_request("example.com")), Some(String::from("example.com")));
+        assert_eq!(parse(b"POST /api HTTP/1.0\r\nhost: API.example.com:8080\r\n\r\n"),
+            Some(String::from("api.example.com")));
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: [fd00::1]:8080\r\n\r\n"),
+            Some(String::from("fd00::1")));
+
+        // Host header after the cut, in the body, or not HTTP
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nAccept: */*\r\n"), None);
+        assert_eq!(parse(b"POST / HTTP/1.1\r\n\r\nHost: example.com\r\n"), None);
+        assert_eq!(parse(b"SSH-2.0-OpenSSH_9.6\r\n"), None);
+        assert_eq!(parse(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"), None);
+
+        // Nothing to escape in a name
+        assert_eq!(parse(b"GET / HTTP/1.1\r\nHost: a b\r\n\r\n"), None);
+    }
+}
@@ -0,0 +1,453 @@
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::thread;
+use std::time::Duration;
+
+use crate::net::{self, Exec, Link, Prot, SharedProcs, TcpState};
+use crate::net::{EVENT_ACCEPT, EVENT_CLOSE, EVENT_CONNECT, EVENT_STATE};
+use crate::clock;
+use crate::procfs;
+use crate::capture::{CaptureBackend, Options};
+use crate::replay::recording;
+use crate::server_name;
+
+///
+/// Capture source without probes: the records are built in code or read from a file, and handed
+/// to the same callbacks as the ones of the probes. No privileges needed, e.g. for the tests.
+///
+/// In a file, one record per line, '#' starting a comment:
+///
+/// ```text
+/// # protocol pid local remote rx|tx bytes
+/// tcp 1234 10.0.0.2:43210 93.184.216.34:443 tx 1500
+/// udp 1234 [fd00::2]:5353 [fd00::1]:53 rx 120
+/// # ICMP: type/code after the protocol, echo identifier as local port
+/// icmp/8/0 4321 10.0.0.2:42 10.0.0.1:0 tx 64
+/// # TCP connections: connect, accept or close, or the kernel changing their state
+/// tcp 1234 10.0.0.2:43210 93.184.216.34:443 close
+/// tcp 0 10.0.0.2:43210 93.184.216.34:443 state time-wait
+/// # First bytes sent on a TCP connection: TLS ClientHello or HTTP request to a server
+/// tcp 1234 10.0.0.2:43210 93.184.216.34:443 sni www.example.com
+/// tcp 1234 10.0.0.2:43212 93.184.216.34:80 host www.example.com
+/// # First bytes sent on any link, in hexadecimal: a QUIC Initial packet
+/// udp 1234 10.0.0.2:43214 93.184.216.34:443 payload c30000000108
+/// # DNS responses received: pid, name asked for, addresses of the answers, over UDP or TCP
+/// dns 1234 example.com 93.184.216.34 2606:2800:220:1:248:1893:25c8:1946
+/// dns/tcp 1234 example.org 93.184.216.34
+/// # Programs executed: pid, parent, user or real/effective users, executable, arguments
+/// exec 1234 1000 1000 /usr/bin/curl curl -s https://www.example.com
+/// exec 1235 1000 1000/0 /usr/bin/sudo sudo id
+/// # Processes exiting: pid, its next records are of a new process
+/// exit 1235
+/// ```
+///
+pub struct Synthetic {
+    records: Vec<Vec<u8>>,
+    callback: Option<Box<dyn FnMut(&[u8]) + Send>>,
+}
+
+impl Synthetic {
+    pub fn new() -> Self {
+        Synthetic {
+            records: Vec::new(),
+            callback: None,
+        }
+    }
+
+    ///
+    /// Returns the records of the file 'path', see above for the format.
+    ///
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn Error>> {
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+        Synthetic::parse(&content).map_err(|e| format!("{}: {}", path, e).into())
+    }
+
+    pub fn parse(content: &str) -> Result<Self, String> {
+        let mut synthetic = Synthetic::new();
+
+        for (n, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            synthetic.parse_line(line).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        }
+
+        Ok(synthetic)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        match fields[0] {
+            "dns" | "dns/tcp" => return self.parse_dns(&fields),
+            "exec" => return self.parse_exec(&fields),
+            "exit" => return self.parse_exit(&fields),
+            _ => (),
+        }
+
+        if fields.len() < 5 || fields.len() > 6 {
+            return Err(format!(
+                "expected 'protocol pid local remote what [bytes|name|payload|state]': {}", line
+            ));
+        }
+
+        let prot = parse_prot(fields[0]).ok_or(format!("unknown protocol {}", fields[0]))?;
+        let pid = fields[1].parse().map_err(|_| format!("invalid pid {}", fields[1]))?;
+        let local: SocketAddr = fields[2].parse()
+            .map_err(|_| format!("invalid address {}", fields[2]))?;
+        let remote: SocketAddr = fields[3].parse()
+            .map_err(|_| format!("invalid address {}", fields[3]))?;
+
+        let mut l = Link::new(local.ip(), remote.ip(), local.port(), remote.port());
+        l.prot(prot);
+
+        let size = || -> Result<u32, String> {
+            let size = fields.get(5).ok_or("number of bytes missing")?;
+            size.parse().map_err(|_| format!("invalid number of bytes {}", size))
+        };
+
+        let name = || fields.get(5).copied().ok_or("server name missing");
+
+        let payload = || -> Result<Vec<u8>, String> {
+            let hex = fields.get(5).ok_or("payload missing")?;
+            parse_hex(hex).ok_or(format!("invalid payload {}", hex))
+        };
+
+        match (fields[4], prot) {
+            ("rx", _) => { self.traffic(pid, &l, size()?, true); }
+            ("tx", _) => { self.traffic(pid, &l, size()?, false); }
+            ("connect", Prot::TCP) => {
+                self.event(pid, &l, EVENT_CONNECT, TcpState::SynSent)
+                    .event(0, &l, EVENT_STATE, TcpState::Established);
+            }
+            ("accept", Prot::TCP) => { self.event(pid, &l, EVENT_ACCEPT, TcpState::Established); }
+            ("close", Prot::TCP) => {
+                self.event(pid, &l, EVENT_CLOSE, TcpState::Established)
+                    .event(0, &l, EVENT_STATE, TcpState::Close);
+            }
+            ("state", Prot::TCP) => {
+                let name = fields.get(5).ok_or("state missing")?;
+                let state = TcpState::parse(name).ok_or(format!("invalid state {}", name))?;
+                self.event(pid, &l, EVENT_STATE, state);
+            }
+            ("sni", Prot::TCP) => { self.payload(pid, &l, &server_name::client_hello(name()?)); }
+            ("host", Prot::TCP) => { self.payload(pid, &l, &server_name::http_request(name()?)); }
+            ("payload", Prot::TCP | Prot::UDP) => { self.payload(pid, &l, &payload()?); }
+            (what, _) => return Err(format!("unexpected {} for {}", what, prot)),
+        }
+
+        Ok(())
+    }
+
+    fn parse_dns(&mut self, fields: &[&str]) -> Result<(), String> {
+        if fields.len() < 4 {
+            return Err(format!("expected 'dns pid name address...': {}", fields.join(" ")));
+        }
+        let is_tcp = fields[0] == "dns/tcp";
+
+        let pid = fields[1].parse().map_err(|_| format!("invalid pid {}", fields[1]))?;
+        let addrs = fields[3..].iter()
+            .map(|addr| addr.parse().map_err(|_| format!("invalid address {}", addr)))
+            .collect::<Result<Vec<IpAddr>, String>>()?;
+
+        self.dns(pid, fields[2], &addrs, is_tcp);
+
+        Ok(())
+    }
+
+    fn parse_exec(&mut self, fields: &[&str]) -> Result<(), String> {
+        if fields.len() < 5 {
+            return Err(format!(
+                "expected 'exec pid ppid uid[/euid] path [argument...]': {}", fields.join(" ")
+            ));
+        }
+
+        let pid = fields[1].parse().map_err(|_| format!("invalid pid {}", fields[1]))?;
+        let ppid = fields[2].parse().map_err(|_| format!("invalid pid {}", fields[2]))?;
+        let uid = |value: &str| value.parse().map_err(|_| format!("invalid uid {}", value));
+        let (uid, euid) = match fields[3].split_once('/') {
+            Some((uid_value, euid_value)) => (uid(uid_value)?, uid(euid_value)?),
+            None => (uid(fields[3])?, uid(fields[3])?),
+        };
+
+        self.exec(pid, &Exec {
+            comm: fields[4].rsplit('/').next().unwrap().to_string(),
+            exe: fields[4].to_string(),
+            cmdline: fields[5..].iter().map(|arg| arg.to_string()).collect(),
+            ppid,
+            uid,
+            euid,
+            start: Some(clock::now() * procfs::NANOSECONDS),
+        });
+
+        Ok(())
+    }
+
+    fn parse_exit(&mut self, fields: &[&str]) -> Result<(), String> {
+        if fields.len() != 2 {
+            return Err(format!("expected 'exit pid': {}", fields.join(" ")));
+        }
+
+        let pid = fields[1].parse().map_err(|_| format!("invalid pid {}", fields[1]))?;
+
+        self.exit(pid, None);
+
+        Ok(())
+    }
+
+    ///
+    /// Add 'size' bytes received ('is_rx') or sent by the process 'pid' on the link 'l'.
+    ///
+    pub fn traffic(&mut self, pid: u32, l: &Link, size: u32, is_rx: bool) -> &mut Self {
+        self.records.push(net::traffic_record(pid, l, size, is_rx));
+        self
+    }
+
+    ///
+    /// Add the opening or closing of the TCP connection 'l' by the process 'pid', or the change of
+    /// its state, 'event' being one of net::EVENT_* and 'state' the state of 'l' then.
+    ///
+    pub fn event(&mut self, pid: u32, l: &Link, event: u32, state: TcpState) -> &mut Self {
+        self.records.push(net::event_record(pid, l, event, state));
+        self
+    }
+
+    ///
+    /// Add the DNS response to the question 'name' received by the process 'pid', over UDP or
+    /// 'is_tcp', the addresses of its answers being 'addrs'.
+    ///
+    pub fn dns(&mut self, pid: u32, name: &str, addrs: &[IpAddr], is_tcp: bool) -> &mut Self {
+        self.records.push(match is_tcp {
+            true => net::dns_tcp_record(pid, name, addrs),
+            false => net::dns_record(pid, name, addrs),
+        });
+        self
+    }
+
+    ///
+    /// Add 'payload' as the first bytes sent by the process 'pid' on the link 'l', a TCP
+    /// connection or a UDP peer.
+    ///
+    pub fn payload(&mut self, pid: u32, l: &Link, payload: &[u8]) -> &mut Self {
+        self.records.push(net::payload_record(pid, l, payload));
+        self
+    }
+
+    ///
+    /// Add the execution of the program of 'exec' by the process 'pid'.
+    ///
+    pub fn exec(&mut self, pid: u32, exec: &Exec) -> &mut Self {
+        self.records.push(net::exec_record(pid, exec));
+        self
+    }
+
+    ///
+    /// Add the exit of the process 'pid' started at 'start', None for whichever runs.
+    ///
+    pub fn exit(&mut self, pid: u32, start: Option<i64>) -> &mut Self {
+        self.records.push(net::exit_record(pid, start));
+        self
+    }
+}
+
+impl CaptureBackend for Synthetic {
+    fn description(&self, _options: &Options) -> String {
+        format!("synthetic, {} records", self.records.len())
+    }
+
+    fn start(&mut self, options: &Options, procs: SharedProcs) -> Result<(), Box<dyn Error>> {
+        self.callback = Some(recording(&options.recorder, net::record_cb(procs)));
+        Ok(())
+    }
+
+    ///
+    /// Hand all the records at once, the capture is then finished.
+    ///
+    fn poll(&mut self, timeout: Duration) -> Result<(), Box<dyn Error>> {
+        if self.records.is_empty() {
+            thread::sleep(timeout);
+            return Ok(());
+        }
+
+        let callback = self.callback.as_mut().unwrap();
+
+        for record in self.records.drain(..) {
+            callback(&record);
+        }
+
+        Ok(())
+    }
+
+    // The records already are per packet
+    fn drain_traffic(&mut self) {}
+
+    fn lost_events(&mut self) -> Option<u64> {
+        Some(0)
+    }
+
+    fn finished(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+// "tcp", "udp" or "icmp/<type>/<code>"
+fn parse_prot(value: &str) -> Option<Prot> {
+    let mut parts = value.split('/');
+
+    match (parts.next()?, parts.next(), parts.next(), parts.next()) {
+        ("tcp", None, None, None) => Some(Prot::TCP),
+        ("udp", None, None, None) => Some(Prot::UDP),
+        ("icmp", Some(icmp_type), Some(code), None) => {
+            Some(Prot::ICMP(icmp_type.parse().ok()?, code.parse().ok()?))
+        }
+        _ => None,
+    }
+}
+
+fn parse_hex(value: &str) -> Option<Vec<u8>> {
+    if value.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(value.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/*
+ * TESTS
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use crate::net::{new_procs, State};
+    use crate::database::{create_db, flush, load_procs, Checkpoint, Selection};
+
+    static EVENTS: &str = "
+        # A connection to a web server, two downloads and the close
+        dns 1234 www.example.com 10.10.100.200
+        tcp 1234 192.168.1.2:43210 10.10.100.200:443 connect
+        tcp 1234 192.168.1.2:43210 10.10.100.200:443 sni api.example.com
+        tcp 1234 192.168.1.2:43210 10.10.100.200:443 tx 500
+        tcp 1234 192.168.1.2:43210 10.10.100.200:443 rx 1500
+        tcp 1234 192.168.1.2:43210 10.10.100.200:443 rx 1500
+        tcp 1234 192.168.1.2:43210 10.10.100.200:443 close
+
+        udp 1234 [fd00::2]:5353 [fd00::1]:53 tx 40   # DNS query
+        udp 1234 [fd00::2]:5353 [fd00::1]:53 rx 120
+
+        exec 4321 1000 1000/0 /usr/bin/ping ping -c 1 10.10.100.200
+        icmp/8/0 4321 192.168.1.2:42 10.10.100.200:0 tx 64
+        icmp/0/0 4321 192.168.1.2:42 10.10.100.200:0 rx 64
+        exit 4321
+    ";
+
+    fn capture(synthetic: &mut Synthetic) -> SharedProcs {
+        let procs = new_procs();
+        let options = Options::new();
+
+        synthetic.start(&options, procs.clone()).unwrap();
+        synthetic.poll(Duration::from_millis(0)).unwrap();
+
+        procs
+    }
+
+    #[test]
+    fn synthetic_file_aggregated() {
+        let mut synthetic = Synthetic::parse(EVENTS).unwrap();
+        // The connect and the close followed by the change of state they make
+        assert_eq!(synthetic.records.len(), 15);
+
+        let procs = capture(&mut synthetic);
+        let procs = procs.lock().unwrap();
+
+        assert_eq!(procs.len(), 2, "number of process incorrect");
+        assert_eq!((procs[0].pid, procs[0].rx, procs[0].tx), (1234, 3120, 540));
+        assert_eq!(procs[0].tlinks.len(), 1);
+        assert_eq!((procs[0].tlinks[0].rx, procs[0].tlinks[0].tx), (3000, 500));
+        assert_eq!(procs[0].tlinks[0].state(), State::Closed);
+        assert_eq!(procs[0].tlinks[0].domain, "www.example.com");
+        assert_eq!(procs[0].tlinks[0].server, "api.example.com");
+        assert_eq!(procs[0].tlinks[0].app_proto, "https");
+        assert_eq!(procs[0].ulinks.len(), 1);
+        assert_eq!(procs[0].ulinks[0].get_daddr(), "fd00::1");
+        assert_eq!(procs[0].ulinks[0].app_proto, "dns", "well-known port not classified");
+        assert_eq!((procs[1].pid, procs[1].rx, procs[1].tx), (4321, 64, 64));
+        assert_eq!(procs[1].name, "ping", "process not named after the program executed");
+        assert_eq!(procs[1].cmdline, vec!["ping", "-c", "1", "10.10.100.200"]);
+        assert_eq!((procs[1].ppid, procs[1].uid, procs[1].euid), (Some(1000), Some(1000), Some(0)));
+        assert_eq!(procs[1].ilinks.len(), 2, "one link per type of message");
+        assert_eq!(procs[1].ilinks[0].prot, Prot::ICMP(8, 0));
+        assert!(procs[1].exited.is_some(), "exit not seen");
+        assert!(procs[0].exited.is_none());
+    }
+
+    #[test]
+    fn synthetic_file_errors() {
+        for line in [
+            "sctp 1234 192.168.1.2:1 10.0.0.1:2 tx 10",
+            "tcp pid 192.168.1.2:1 10.0.0.1:2 tx 10",
+            "tcp 1234 192.168.1.2 10.0.0.1:2 tx 10",
+            "tcp 1234 192.168.1.2:1 10.0.0.1:2 tx",
+            "udp 1234 192.168.1.2:1 10.0.0.1:2 close",
+            "tcp 0 192.168.1.2:1 10.0.0.1:2 state",
+            "tcp 0 192.168.1.2:1 10.0.0.1:2 state open",
+            "dns 1234 example.com",
+            "dns 1234 example.com 10.0.0.1:53",
+            "tcp 1234 192.168.1.2:1 10.0.0.1:2 sni",
+            "udp 1234 192.168.1.2:1 10.0.0.1:2 host example.com",
+            "udp 1234 192.168.1.2:1 10.0.0.1:2 payload c3000",
+            "icmp/8/0 1234 192.168.1.2:1 10.0.0.1:0 payload c300",
+            "exec 1234 1 0",
+            "exec pid 1 0 /usr/bin/curl curl",
+            "exec 1234 1 0/root /usr/bin/curl curl",
+            "exit",
+            "exit 1234 0",
+        ] {
+            let error = Synthetic::parse(&format!("\n{}", line)).err();
+            assert!(error.unwrap().starts_with("line 2: "), "{} accepted", line);
+        }
+    }
+
+    #[test]
+    fn synthetic_to_database() {
+        let mut db = create_db(&String::from(":memory:")).unwrap();
+        let mut checkpoint = Checkpoint::new();
+        let mut l = Link::new(
+            IpAddr::V4( Ipv4Addr::new(192, 168, 1, 2) ),
+            IpAddr::V4( Ipv4Addr::new(10, 10, 100, 200) ),
+            4321,
+            80,
+        );
+        l.prot(Prot::TCP);
+
+        let mut synthetic = Synthetic::new();
+        synthetic.traffic(1234, &l, 1000, true).traffic(1234, &l, 100, false);
+        synthetic.payload(1234, &l, &server_name::http_request("www.example.com"));
+
+        // A QUIC connection, only known by its first packet
+        synthetic.parse_line("udp 1234 192.168.1.2:4321 10.10.100.200:44333 tx 1200").unwrap();
+        synthetic.parse_line("udp 1234 192.168.1.2:4321 10.10.100.200:44333 payload c30000000108")
+            .unwrap();
+
+        let procs = capture(&mut synthetic).lock().unwrap().to_vec();
+        flush(&mut db, &mut checkpoint, procs, 3600).unwrap();
+
+        let procs = load_procs(&db, &Selection::new(0, 7200));
+
+        assert_eq!(procs.len(), 1, "number of process incorrect");
+        assert_eq!(procs[0].get_rx_tx(), (1000, 1300));
+        assert_eq!(procs[0].tlinks[0].get_daddr(), "10.10.100.200");
+        assert_eq!(procs[0].tlinks[0].server, "www.example.com");
+        assert_eq!(procs[0].tlinks[0].app_proto, "http");
+        assert_eq!(procs[0].ulinks[0].app_proto, "quic");
+    }
+}
@@ -7,9 +7,10 @@ use tui::{
     widgets::{Block, Borders, List, ListItem, Tabs},
     Frame,
 };
-use crate::{PROCESSES, LOGS, DATES};
-use crate::net::Process;
-use crate::database::get_procs;
+use crate::{LOGS, DATES};
+use crate::net::{Process, Link, State, SharedProcs};
+use crate::clock::Tz;
+use crate::database::{load_procs, load_dates, load_lost, get_days_range, Selection};
 
 use rusqlite::Connection;
 
@@ -21,12 +22,20 @@ pub struct App<'a> {
     pub show_help: bool,
     pub show_tcp: bool,
     pub show_udp: bool,
+    pub show_icmp: bool,
     pub show_all: bool,
+    pub show_closed: bool,
+    pub verbose: bool, // describe the processes, see Process::details_str()
+    pub app_proto: Option<String>, // only the links of this application protocol, see classify.rs
     pub procs: StatefulList<Process>,
+    pub lost: u64,  // events lost by the probes, the traffic shown is lower than the real one
     pub logs: StatefulList<String>,
     pub help: StatefulList<String>,
     pub enhanced_graphics: bool,
     pub db: Option<Connection>,
+    pub days: Option<(i64, i64)>, // first and last days of the database, see get_days_range()
+    pub live: Option<SharedProcs>, // What the capture finds, when not showing a database
+    pub tz: Tz,
 }
 
 impl<'a> App<'a> {
@@ -39,21 +48,33 @@ impl<'a> App<'a> {
             show_help: true,
             show_tcp: false,
             show_udp: false,
+            show_icmp: false,
             show_all: false,
+            show_closed: true,
+            verbose: false,
+            app_proto: None,
             procs: StatefulList::new(),
+            lost: 0,
             logs: StatefulList::with_items(LOGS.lock().unwrap().to_vec()),
             help: StatefulList::with_items(vec![
                 String::from("H: display/hide help"),
                 String::from("L: display/hide logs"),
                 String::from("t: display/hide TCP"),
                 String::from("u: display/hide UDP"),
-                String::from("a: display/hide all (TCP+UDP)"),
+                String::from("i: display/hide ICMP"),
+                String::from("a: display/hide all (TCP+UDP+ICMP)"),
+                String::from("c: display/hide closed connections"),
+                String::from("p: filter on the next application protocol"),
+                String::from("v: display/hide process details (command, user...)"),
                 String::from(""),
                 String::from("Arrows or hjkl: move around (main pane and tabs)"),
                 String::from("q: quit"),
             ]),
             enhanced_graphics,
             db: None,
+            days: None,
+            live: None,
+            tz: Tz::Local,
         }
     }
 
@@ -67,6 +88,16 @@ impl<'a> App<'a> {
         self
     }
 
+    pub fn live(&mut self, procs: SharedProcs) -> &mut Self {
+        self.live = Some(procs);
+        self
+    }
+
+    pub fn tz(&mut self, tz: Tz) -> &mut Self {
+        self.tz = tz;
+        self
+    }
+
     // TODO: scroll the process list
     pub fn on_up(&mut self) {
         self.procs.previous();
@@ -113,27 +144,80 @@ impl<'a> App<'a> {
             'u' => {
                 self.show_udp = !self.show_udp;
             }
+            'i' => {
+                self.show_icmp = !self.show_icmp;
+            }
             'a' => {
                 self.show_all = !self.show_all;
             }
-            // TODO
-            // 'v' for verbose
+            'c' => {
+                self.show_closed = !self.show_closed;
+            }
+            'p' => {
+                self.next_app_proto();
+            }
+            'v' => {
+                self.verbose = !self.verbose;
+            }
             _ => {}
         }
     }
 
+    ///
+    /// Only show the links of the next application protocol spoken by the processes, in
+    /// alphabetical order, and all of them again after the last one.
+    ///
+    pub fn next_app_proto(&mut self) {
+        let mut app_protos: Vec<&String> = self.procs.items
+            .iter()
+            .flat_map(|p| p.links())
+            .map(|l| &l.app_proto)
+            .filter(|app_proto| !app_proto.is_empty())
+            .collect();
+        app_protos.sort();
+        app_protos.dedup();
+
+        let next = match &self.app_proto {
+            Some(current) => app_protos.iter().find(|app_proto| **app_proto > current),
+            None => app_protos.first(),
+        };
+
+        self.app_proto = next.map(|app_proto| app_proto.to_string());
+    }
+
     pub fn on_tick(&mut self) {
         match self.db {
             Some(_) => {
                 let db = self.db.as_ref().unwrap();
-                self.procs = StatefulList::with_items(get_procs(&db));
+
+                // The days are only looked up again when a new one starts or old ones are removed
+                let days = get_days_range(db, self.tz);
+                if days != self.days {
+                    load_dates(&db, self.tz);
+                    self.tabs = TabsState::new(DATES.lock().unwrap().to_vec());
+                    self.days = days;
+                }
+
+                // Only the traffic of the selected day
+                let (procs, lost) = match self.tabs.selected() {
+                    Some(date) => (
+                        load_procs(&db, &Selection::new(date.start, date.end)),
+                        load_lost(&db, date.start, date.end),
+                    ),
+                    None => (Vec::new(), 0),
+                };
+                self.procs = StatefulList::with_items(procs);
+                self.lost = lost;
             }
             None    => {
-                self.procs = StatefulList::with_items(PROCESSES.lock().unwrap().to_vec());
+                if let Some(procs) = self.live.as_ref() {
+                    self.procs = StatefulList::with_items(procs.lock().unwrap().to_vec());
+                    self.lost = procs.lost_events();
+                }
+                self.tabs = TabsState::new(DATES.lock().unwrap().to_vec());
             }
         }
         self.logs = StatefulList::with_items(LOGS.lock().unwrap().to_vec());
-        self.tabs = TabsState::new(DATES.lock().unwrap().to_vec());
     }
 }
 
@@ -190,19 +274,25 @@ pub fn draw_tabs<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
 pub fn draw_procs<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     let style0 = Style::default().add_modifier(Modifier::BOLD);
     let style1 = Style::default().fg(Color::Blue).add_modifier(Modifier::BOLD);
-
-    let mut date = 0;
-    if app.tabs.titles.len() > 0 {
-        date = app.tabs.titles[app.tabs.index].int_form;
-    }
+    let show_closed = app.show_closed;
+    let app_proto = app.app_proto.clone();
+    let shown = |l: &Link| app_proto.as_ref().map_or(true, |app_proto| l.app_proto == *app_proto);
+
+    // Closed connections are dimmed, or hidden, as are the links of other application protocols
+    let link_item = |l: &Link| match l.state() {
+        _ if !shown(l) => None,
+        State::Closed if !show_closed => None,
+        State::Closed => Some(
+            ListItem::new(l.to_string()).style(Style::default().fg(Color::DarkGray))
+        ),
+        _ => Some(ListItem::new(l.to_string())),
+    };
 
     let entries: Vec<ListItem> = app
         .procs
         .items
         .iter()
-        .filter(|p| {
-            p.date == date
-        })
+        .filter(|p| app.app_proto.is_none() || p.links().any(&shown))
         .flat_map(|p| {
             let proc_fmt = Spans::from(vec![
                 Span::styled(p.overview_str(), style0),
@@ -211,21 +301,34 @@ pub fn draw_procs<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
 
             let mut tmp = vec![ ListItem::new(proc_fmt) ];
 
+            if app.verbose {
+                let details = format!("  {}", p.details_str(app.tz));
+                tmp.push(ListItem::new(details).style(Style::default().fg(Color::Gray)));
+            }
+
             if app.show_tcp || app.show_all {
                 let mut tlinks = p.get_tlinks()
                     .iter()
-                    .map(|t| ListItem::new(t.to_string())
-                ).collect();
+                    .filter_map(|t| link_item(t))
+                    .collect();
                 tmp.append(&mut tlinks);
             }
 
             if app.show_udp || app.show_all {
                 let mut ulinks = p.get_ulinks()
                     .iter()
-                    .map(|u| ListItem::new(u.to_string())
-                ).collect();
+                    .filter_map(|u| link_item(u))
+                    .collect();
                 tmp.append(&mut ulinks);
             }
+
+            if app.show_icmp || app.show_all {
+                let mut ilinks = p.get_ilinks()
+                    .iter()
+                    .filter_map(|i| link_item(i))
+                    .collect();
+                tmp.append(&mut ilinks);
+            }
             tmp
         })
         .collect::<Vec<ListItem>>();
@@ -233,8 +336,13 @@ pub fn draw_procs<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     // Number of line to display, not just nb of processes
     app.procs.nb_entries = entries.len();
 
+    let title = match app.lost {
+        0 => String::from(" Processes "),
+        n => format!(" Processes ({} events lost, traffic undercounted) ", n),
+    };
+
     let entries = List::new(entries)
-        .block(Block::default().borders(Borders::ALL).title(" Processes "))
+        .block(Block::default().borders(Borders::ALL).title(title))
         .highlight_style(Style::default().fg(Color::Green));
 
     f.render_stateful_widget(entries, area, &mut app.procs.state);
@@ -293,9 +401,13 @@ pub fn draw_optionals<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
     }
 }
 
-pub fn draw_filter<B: Backend>(f: &mut Frame<B>, _app: &mut App, area: Rect) {
+pub fn draw_filter<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
+    let title = match &app.app_proto {
+        Some(app_proto) => format!(" Filter: {} ", app_proto),
+        None => String::from(" Filter: none "),
+    };
     let filter = Block::default()
-         .title(" Filter (TODO) ")
+         .title(title)
          .borders(Borders::ALL);
     f.render_widget(filter, area);
 }
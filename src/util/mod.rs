@@ -24,6 +24,10 @@ impl TabsState {
         }
     }
 
+    pub fn selected(&self) -> Option<&Date> {
+        self.titles.get(self.index)
+    }
+
     pub fn next(&mut self) {
         self.index = (self.index + 1) % self.titles.len();
         unsafe { SELECTED_TAB = self.index; }